@@ -37,6 +37,7 @@ use lightning::util::logger::Logger;
 use lightning::util::config::UserConfig;
 use lightning::util::events::{EventsProvider, MessageSendEventsProvider};
 use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+use lightning::util::scid_utils::ShortChannelId;
 
 use utils::test_logger;
 
@@ -412,7 +413,7 @@ pub fn do_test(data: &[u8]) {
 					hops: vec![RouteHop {
 						pubkey: $dest.0.get_our_node_id(),
 						node_features: NodeFeatures::empty(),
-						short_channel_id: $dest.1,
+						short_channel_id: ShortChannelId($dest.1),
 						channel_features: ChannelFeatures::empty(),
 						fee_msat: 5000000,
 						cltv_expiry_delta: 200,
@@ -429,14 +430,14 @@ pub fn do_test(data: &[u8]) {
 					hops: vec![RouteHop {
 						pubkey: $middle.0.get_our_node_id(),
 						node_features: NodeFeatures::empty(),
-						short_channel_id: $middle.1,
+						short_channel_id: ShortChannelId($middle.1),
 						channel_features: ChannelFeatures::empty(),
 						fee_msat: 50000,
 						cltv_expiry_delta: 100,
 					},RouteHop {
 						pubkey: $dest.0.get_our_node_id(),
 						node_features: NodeFeatures::empty(),
-						short_channel_id: $dest.1,
+						short_channel_id: ShortChannelId($dest.1),
 						channel_features: ChannelFeatures::empty(),
 						fee_msat: 5000000,
 						cltv_expiry_delta: 200,
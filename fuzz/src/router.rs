@@ -1,4 +1,5 @@
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
 use bitcoin::blockdata::script::{Script, Builder};
 use bitcoin::blockdata::block::Block;
 use bitcoin::blockdata::transaction::Transaction;
@@ -11,6 +12,7 @@ use lightning::ln::msgs::RoutingMessageHandler;
 use lightning::ln::router::{Router, RouteHint};
 use lightning::util::logger::Logger;
 use lightning::util::ser::Readable;
+use lightning::util::scid_utils::ShortChannelId;
 
 use secp256k1::key::PublicKey;
 
@@ -85,6 +87,7 @@ impl ChainWatchInterface for DummyChainWatcher {
 			Some(&[0, _]) => Err(ChainError::NotSupported),
 			Some(&[1, _]) => Err(ChainError::NotWatched),
 			Some(&[2, _]) => Err(ChainError::UnknownTx),
+			Some(&[3, _]) => Err(ChainError::Unavailable),
 			Some(&[_, x]) => Ok((Builder::new().push_int(x as i64).into_script().to_v0_p2wsh(), 0)),
 			None => Err(ChainError::UnknownTx),
 			_ => unreachable!(),
@@ -156,7 +159,9 @@ pub fn do_test(data: &[u8]) {
 	});
 
 	let our_pubkey = get_pubkey!();
-	let router = Router::new(our_pubkey.clone(), chain_monitor, Arc::clone(&logger));
+	let router = Router::new(our_pubkey.clone(), Sha256dHash::hash(&[0; 32]), chain_monitor, Arc::clone(&logger));
+	// Gossip for any chain exercises the same code, so accept all of it
+	router.clear_chain_hash();
 
 	loop {
 		match get_slice!(1)[0] {
@@ -204,6 +209,7 @@ pub fn do_test(data: &[u8]) {
 								inbound_capacity_msat: 0,
 								is_live: true,
 								outbound_capacity_msat: 0,
+								outbound_htlc_slots: 483,
 							});
 						}
 						Some(&first_hops_vec[..])
@@ -216,11 +222,12 @@ pub fn do_test(data: &[u8]) {
 					for _ in 0..count {
 						last_hops_vec.push(RouteHint {
 							src_node_id: get_pubkey!(),
-							short_channel_id: slice_to_be64(get_slice!(8)),
+							short_channel_id: ShortChannelId(slice_to_be64(get_slice!(8))),
 							fee_base_msat: slice_to_be32(get_slice!(4)),
 							fee_proportional_millionths: slice_to_be32(get_slice!(4)),
 							cltv_expiry_delta: slice_to_be16(get_slice!(2)),
 							htlc_minimum_msat: slice_to_be64(get_slice!(8)),
+							htlc_maximum_msat: None,
 						});
 					}
 					&last_hops_vec[..]
@@ -29,6 +29,9 @@ pub enum ChainError {
 	NotWatched,
 	/// Tx doesn't exist or is unconfirmed
 	UnknownTx,
+	/// Client supports UTXO lookup but can't currently complete it, eg because its backend is
+	/// unreachable. The lookup may succeed if retried later.
+	Unavailable,
 }
 
 /// An interface to request notification of certain scripts as they appear the
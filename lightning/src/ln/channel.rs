@@ -1656,6 +1656,12 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		cmp::min(self.value_to_self_msat as i64 - self.get_outbound_pending_htlc_stats().1 as i64, 0) as u64)
 	}
 
+	/// Get the number of additional HTLCs we may offer, ie their max_accepted_htlcs less the number
+	/// of outbound HTLCs which are pending or in the holding cell.
+	pub fn get_available_outbound_htlc_slots(&self) -> u16 {
+		(self.their_max_accepted_htlcs as u32).saturating_sub(self.get_outbound_pending_htlc_stats().0) as u16
+	}
+
 	pub fn update_add_htlc(&mut self, msg: &msgs::UpdateAddHTLC, pending_forward_state: PendingHTLCStatus) -> Result<(), ChannelError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32 | ChannelState::RemoteShutdownSent as u32)) != (ChannelState::ChannelFunded as u32) {
 			return Err(ChannelError::Close("Got add HTLC message when channel was not in an operational state"));
@@ -464,6 +464,10 @@ pub struct ChannelDetails {
 	/// True if the channel is (a) confirmed and funding_locked messages have been exchanged, (b)
 	/// the peer is connected, and (c) no monitor update failure is pending resolution.
 	pub is_live: bool,
+	/// The number of additional HTLCs we may currently offer the remote peer, ie the
+	/// max_accepted_htlcs they gave us less the outbound HTLCs which are already pending. Routes
+	/// are never found through channels with none left.
+	pub outbound_htlc_slots: u16,
 }
 
 macro_rules! handle_error {
@@ -736,6 +740,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref> ChannelMan
 					outbound_capacity_msat,
 					user_id: channel.get_user_id(),
 					is_live: channel.is_live(),
+					outbound_htlc_slots: channel.get_available_outbound_htlc_slots(),
 				});
 			}
 		}
@@ -1194,7 +1199,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref> ChannelMan
 		let mut channel_lock = self.channel_state.lock().unwrap();
 		let err: Result<(), _> = loop {
 
-			let id = match channel_lock.short_to_id.get(&route.hops.first().unwrap().short_channel_id) {
+			let id = match channel_lock.short_to_id.get(&route.hops.first().unwrap().short_channel_id.0) {
 				None => return Err(APIError::ChannelUnavailable{err: "No channel available with first hop!"}),
 				Some(id) => id.clone(),
 			};
@@ -21,6 +21,7 @@ use util::errors::APIError;
 use util::ser::{Writeable, Writer, ReadableArgs};
 use util::config::UserConfig;
 use util::logger::Logger;
+use util::scid_utils::ShortChannelId;
 
 use bitcoin::util::hash::BitcoinHash;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
@@ -1203,7 +1204,7 @@ fn fake_network_test() {
 	hops.push(RouteHop {
 		pubkey: nodes[2].node.get_our_node_id(),
 		node_features: NodeFeatures::empty(),
-		short_channel_id: chan_2.0.contents.short_channel_id,
+		short_channel_id: ShortChannelId(chan_2.0.contents.short_channel_id),
 		channel_features: ChannelFeatures::empty(),
 		fee_msat: 0,
 		cltv_expiry_delta: chan_3.0.contents.cltv_expiry_delta as u32
@@ -1211,7 +1212,7 @@ fn fake_network_test() {
 	hops.push(RouteHop {
 		pubkey: nodes[3].node.get_our_node_id(),
 		node_features: NodeFeatures::empty(),
-		short_channel_id: chan_3.0.contents.short_channel_id,
+		short_channel_id: ShortChannelId(chan_3.0.contents.short_channel_id),
 		channel_features: ChannelFeatures::empty(),
 		fee_msat: 0,
 		cltv_expiry_delta: chan_4.1.contents.cltv_expiry_delta as u32
@@ -1219,7 +1220,7 @@ fn fake_network_test() {
 	hops.push(RouteHop {
 		pubkey: nodes[1].node.get_our_node_id(),
 		node_features: NodeFeatures::empty(),
-		short_channel_id: chan_4.0.contents.short_channel_id,
+		short_channel_id: ShortChannelId(chan_4.0.contents.short_channel_id),
 		channel_features: ChannelFeatures::empty(),
 		fee_msat: 1000000,
 		cltv_expiry_delta: TEST_FINAL_CLTV,
@@ -1232,7 +1233,7 @@ fn fake_network_test() {
 	hops.push(RouteHop {
 		pubkey: nodes[3].node.get_our_node_id(),
 		node_features: NodeFeatures::empty(),
-		short_channel_id: chan_4.0.contents.short_channel_id,
+		short_channel_id: ShortChannelId(chan_4.0.contents.short_channel_id),
 		channel_features: ChannelFeatures::empty(),
 		fee_msat: 0,
 		cltv_expiry_delta: chan_3.1.contents.cltv_expiry_delta as u32
@@ -1240,7 +1241,7 @@ fn fake_network_test() {
 	hops.push(RouteHop {
 		pubkey: nodes[2].node.get_our_node_id(),
 		node_features: NodeFeatures::empty(),
-		short_channel_id: chan_3.0.contents.short_channel_id,
+		short_channel_id: ShortChannelId(chan_3.0.contents.short_channel_id),
 		channel_features: ChannelFeatures::empty(),
 		fee_msat: 0,
 		cltv_expiry_delta: chan_2.1.contents.cltv_expiry_delta as u32
@@ -1248,7 +1249,7 @@ fn fake_network_test() {
 	hops.push(RouteHop {
 		pubkey: nodes[1].node.get_our_node_id(),
 		node_features: NodeFeatures::empty(),
-		short_channel_id: chan_2.0.contents.short_channel_id,
+		short_channel_id: ShortChannelId(chan_2.0.contents.short_channel_id),
 		channel_features: ChannelFeatures::empty(),
 		fee_msat: 1000000,
 		cltv_expiry_delta: TEST_FINAL_CLTV,
@@ -5521,9 +5522,9 @@ fn test_onion_failure() {
 	}, ||{}, true, Some(PERM|9), Some(msgs::HTLCFailChannelUpdate::ChannelClosed{short_channel_id: channels[1].0.contents.short_channel_id, is_permanent: true}));
 
 	let mut bogus_route = route.clone();
-	bogus_route.hops[1].short_channel_id -= 1;
+	bogus_route.hops[1].short_channel_id.0 -= 1;
 	run_onion_failure_test("unknown_next_peer", 0, &nodes, &bogus_route, &payment_hash, |_| {}, ||{}, true, Some(PERM|10),
-	  Some(msgs::HTLCFailChannelUpdate::ChannelClosed{short_channel_id: bogus_route.hops[1].short_channel_id, is_permanent:true}));
+	  Some(msgs::HTLCFailChannelUpdate::ChannelClosed{short_channel_id: bogus_route.hops[1].short_channel_id.0, is_permanent:true}));
 
 	let amt_to_forward = nodes[1].node.channel_state.lock().unwrap().by_id.get(&channels[1].2).unwrap().get_their_htlc_minimum_msat() - 1;
 	let mut bogus_route = route.clone();
@@ -272,6 +272,7 @@ pub enum NetAddress {
 	/// A new-style Tor onion address/port on which the peer is listening.
 	/// To create the human-readable "hostname", concatenate ed25519_pubkey, checksum, and version,
 	/// wrap as base32 and append ".onion".
+	/// On the wire this is address type 4, followed by the 35-byte address and the port.
 	OnionV3 {
 		/// The ed25519 long-term public key of the peer
 		ed25519_pubkey: [u8; 32],
@@ -1555,6 +1556,49 @@ mod tests {
 		do_encoding_node_announcement(false, false, true, false, true, false, false);
 	}
 
+	#[test]
+	fn decoding_node_announcement_truncated_onion_v3() {
+		let secp_ctx = Secp256k1::new();
+		let (privkey_1, pubkey_1) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let sig_1 = get_sig_on!(privkey_1, secp_ctx, String::from("01010101010101010101010101010101"));
+		let node_announcement = msgs::NodeAnnouncement {
+			signature: sig_1,
+			contents: msgs::UnsignedNodeAnnouncement {
+				features: NodeFeatures::empty(),
+				timestamp: 20190119,
+				node_id: pubkey_1,
+				rgb: [32; 3],
+				alias: [16; 32],
+				addresses: vec![msgs::NetAddress::OnionV3 {
+					ed25519_pubkey: [42; 32],
+					checksum: 32,
+					version: 3,
+					port: 9735
+				}],
+				excess_address_data: Vec::new(),
+				excess_data: Vec::new(),
+			},
+		};
+		let encoded_value = node_announcement.encode();
+		let read_announcement: msgs::NodeAnnouncement = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		assert!(read_announcement == node_announcement);
+
+		// An address length which cuts the 37-byte onion address (and port) short is rejected
+		// rather than leaving us with a truncated address...
+		let addr_len_pos = 64 + 2 + 4 + 33 + 3 + 32;
+		assert_eq!(&encoded_value[addr_len_pos..addr_len_pos + 2], &[0, 38]);
+		for short_addr_len in 1..38 {
+			let mut truncated_value = encoded_value.clone();
+			truncated_value[addr_len_pos + 1] = short_addr_len;
+			let read: Result<msgs::NodeAnnouncement, _> = Readable::read(&mut Cursor::new(&truncated_value[..]));
+			assert!(match read { Err(msgs::DecodeError::BadLengthDescriptor) => true, _ => false });
+		}
+
+		// ...as is a message which ends part-way through the address
+		let read: Result<msgs::NodeAnnouncement, _> = Readable::read(&mut Cursor::new(&encoded_value[..encoded_value.len() - 3]));
+		assert!(match read { Err(msgs::DecodeError::BadLengthDescriptor) => true, _ => false });
+	}
+
 	fn do_encoding_channel_update(non_bitcoin_chain_hash: bool, direction: bool, disable: bool, htlc_maximum_msat: bool) {
 		let secp_ctx = Secp256k1::new();
 		let (privkey_1, _) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
@@ -7,24 +7,41 @@ use secp256k1::key::PublicKey;
 use secp256k1::Secp256k1;
 use secp256k1;
 
+use bitcoin_hashes::sha256::Hash as Sha256;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
-use bitcoin_hashes::Hash;
-use bitcoin::blockdata::script::Builder;
+use bitcoin_hashes::{Hash, HashEngine};
+use bitcoin::blockdata::script::{Builder, Script};
 use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::transaction::Transaction;
 
-use chain::chaininterface::{ChainError, ChainWatchInterface};
+use chain::chaininterface::{ChainError, ChainListener, ChainWatchInterface};
+use ln::channel::MAX_FUNDING_SATOSHIS;
 use ln::channelmanager;
-use ln::features::{ChannelFeatures, NodeFeatures};
+use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
 use ln::msgs::{DecodeError,ErrorAction,LightningError,RoutingMessageHandler,NetAddress};
 use ln::msgs;
-use util::ser::{Writeable, Readable, Writer, ReadableArgs};
+use ln::onion_utils;
+use ln::wire;
+use util::ser::{Writeable, Readable, Writer, ReadableArgs, LengthCalculatingWriter};
 use util::logger::Logger;
+use util::byte_utils;
+use util::scid_utils;
+use util::scid_utils::ShortChannelId;
+use util::errors::APIError;
 
 use std::cmp;
 use std::sync::{RwLock,Arc};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::collections::{HashMap,BinaryHeap,BTreeMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::collections::{HashMap,HashSet,BinaryHeap,BTreeMap,VecDeque};
 use std::collections::btree_map::Entry as BtreeEntry;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+use std::marker::PhantomData;
+use std::mem;
+#[cfg(not(feature = "fuzztarget"))]
+use std::time::{SystemTime, UNIX_EPOCH};
 use std;
 
 /// A hop in a route
@@ -36,18 +53,49 @@ pub struct RouteHop {
 	/// amended to match the features present in the invoice this node generated.
 	pub node_features: NodeFeatures,
 	/// The channel that should be used from the previous hop to reach this node.
-	pub short_channel_id: u64,
+	pub short_channel_id: ShortChannelId,
 	/// The channel_announcement features of the channel that should be used from the previous hop
 	/// to reach this node.
 	pub channel_features: ChannelFeatures,
-	/// The fee taken on this hop. For the last hop, this should be the full value of the payment.
+	/// The fee taken on this hop, ie the fee the node at this hop charges to forward over the next
+	/// hop's channel, which the sender pays. The sender never pays a fee for the first hop's
+	/// channel, as it's our own. For the last hop, this should be the full value of the payment.
+	/// See Route::cost_breakdown for telling these apart.
 	pub fee_msat: u64,
 	/// The CLTV delta added for this hop. For the last hop, this should be the full CLTV value
 	/// expected at the destination, in excess of the current block height.
 	pub cltv_expiry_delta: u32,
 }
 
+impl RouteHop {
+	/// Creates a hop to the given node over the given channel with explicitly-specified fee and
+	/// CLTV delta, eg for constructing routes in tests or simulations. The node and channel
+	/// features are left empty.
+	pub fn new(pubkey: PublicKey, short_channel_id: ShortChannelId, fee_msat: u64, cltv_expiry_delta: u32) -> RouteHop {
+		RouteHop {
+			pubkey,
+			node_features: NodeFeatures::empty(),
+			short_channel_id,
+			channel_features: ChannelFeatures::empty(),
+			fee_msat,
+			cltv_expiry_delta,
+		}
+	}
+
+	/// Returns true if the node at this hop advertised support for variable-length (TLV) onion
+	/// payloads in its node_features, in which case its hop data should be encoded in the TLV
+	/// format rather than the legacy fixed-length realm-0 format.
+	pub fn supports_variable_length_onion(&self) -> bool {
+		self.node_features.supports_variable_length_onion()
+	}
+}
+
 /// A route from us through the network to a destination
+///
+/// Routes currently consist of a single path, as multi-path payments are not yet supported.
+/// Router::get_route breaks ties between equal-cost paths canonically, so identical inputs always
+/// result in an identical Route, and a direct channel to the destination is used over any other
+/// path which is no cheaper.
 #[derive(Clone, PartialEq)]
 pub struct Route {
 	/// The list of hops, NOT INCLUDING our own, where the last hop is the destination. Thus, this
@@ -56,6 +104,459 @@ pub struct Route {
 	pub hops: Vec<RouteHop>,
 }
 
+/// A compact summary of the channels a Route uses and the total fee it pays, see
+/// Route::fingerprint. Two routes have the same fingerprint if and only if (barring hash
+/// collisions) they use the same channels in the same order and pay the same total fee.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RouteFingerprint(pub [u8; 32]);
+
+impl Route {
+	/// Gets a fingerprint of this route, allowing cheap comparison of routes computed at
+	/// different times, eg to detect when the cheapest route to some destination changes.
+	pub fn fingerprint(&self) -> RouteFingerprint {
+		let mut sha = Sha256::engine();
+		for hop in self.hops.iter() {
+			sha.input(&byte_utils::be64_to_array(hop.short_channel_id.0));
+		}
+		sha.input(&byte_utils::be64_to_array(self.total_fees_msat()));
+		RouteFingerprint(Sha256::from_engine(sha).into_inner())
+	}
+
+	/// Gets the total fees paid to nodes along this route, ie the sum of fee_msat for all hops
+	/// except the last, whose fee_msat is the value delivered to the destination. Saturates at
+	/// u64::max_value() rather than overflowing.
+	pub fn total_fees_msat(&self) -> u64 {
+		let fee_hops = self.hops.len().saturating_sub(1);
+		self.hops.iter().take(fee_hops).fold(0u64, |total, hop| total.saturating_add(hop.fee_msat))
+	}
+
+	/// Splits what sending over this route costs us into the value delivered to the destination and
+	/// the fees actually paid to each intermediate node, eg for wallet balance accounting. Our own
+	/// channel (the first hop's) charges no fee, so our balance on it decreases by exactly
+	/// amount_sent_msat.
+	pub fn cost_breakdown(&self) -> RouteCostBreakdown {
+		let fee_hops = self.hops.len().saturating_sub(1);
+		RouteCostBreakdown {
+			final_value_msat: self.hops.last().map(|hop| hop.fee_msat).unwrap_or(0),
+			fees_paid_msat: self.hops.iter().take(fee_hops).map(|hop| (hop.pubkey, hop.fee_msat)).collect(),
+			total_fees_msat: self.total_fees_msat(),
+			amount_sent_msat: self.hops.iter().fold(0u64, |total, hop| total.saturating_add(hop.fee_msat)),
+		}
+	}
+
+	/// Gets the total CLTV delta of this route, ie the sum of cltv_expiry_delta for all hops
+	/// including the last. The first HTLC expires this many blocks after the current height.
+	/// Saturates at u32::max_value() rather than overflowing.
+	pub fn total_cltv_delta(&self) -> u32 {
+		self.hops.iter().fold(0u32, |total, hop| total.saturating_add(hop.cltv_expiry_delta))
+	}
+
+	/// Gets the hint from last_hops which this route's final hop was reached over, if any, given
+	/// the last_hops this route was found with, eg to tell how much of our traffic relies on
+	/// private channels. As in Router::get_route, hints may only provide the final hop. A hint
+	/// matches if it has the final hop's short_channel_id and starts at the previous hop's node
+	/// (which isn't checked for single-hop routes, as those start at our own node).
+	pub fn used_route_hint<'a>(&self, last_hops: &'a [RouteHint]) -> Option<&'a RouteHint> {
+		let final_hop = self.hops.last()?;
+		let src_node_id = if self.hops.len() > 1 { Some(&self.hops[self.hops.len() - 2].pubkey) } else { None };
+		last_hops.iter().find(|hint| hint.short_channel_id == final_hop.short_channel_id &&
+			src_node_id.map(|node_id| *node_id == hint.src_node_id).unwrap_or(true))
+	}
+
+	/// Gets the channel from first_hops which this route leaves us over, if any, given the
+	/// first_hops this route was found with, eg to reserve liquidity in it while the payment is
+	/// pending. Routes have a single path, so each share of a multi-path payment (see
+	/// split_value_over_first_hops) is a separate Route. A channel matches if it has the first
+	/// hop's short_channel_id and goes to the first hop's node.
+	pub fn first_hop_channel<'a>(&self, first_hops: &'a [channelmanager::ChannelDetails]) -> Option<&'a channelmanager::ChannelDetails> {
+		let first_hop = self.hops.first()?;
+		first_hops.iter().find(|chan| chan.short_channel_id == Some(first_hop.short_channel_id.0) && chan.remote_network_id == first_hop.pubkey)
+	}
+
+	/// Returns true if this route leaves us over a channel from first_hops which isn't currently
+	/// live, as may be the case when RouteParameters::include_non_live_first_hops is set, and so
+	/// can't be sent yet.
+	pub fn is_speculative(&self, first_hops: &[channelmanager::ChannelDetails]) -> bool {
+		self.first_hop_channel(first_hops).map(|chan| !chan.is_live).unwrap_or(false)
+	}
+
+	/// Gets the number of HTLCs which may currently be sent over this route's first hop at once,
+	/// given the first_hops this route was found with, ie its ChannelDetails::outbound_htlc_slots.
+	/// Only the HTLC limits of our own channels are known, so later hops may allow fewer. Returns
+	/// None if the route's first hop isn't in first_hops.
+	pub fn max_in_flight_htlcs(&self, first_hops: &[channelmanager::ChannelDetails]) -> Option<u16> {
+		self.first_hop_channel(first_hops).map(|chan| chan.outbound_htlc_slots)
+	}
+
+	/// Compares this route with another route for the same payment, eg to evaluate how routing
+	/// changes under different RouteParameters or fee policies. Deltas are other's totals minus
+	/// ours, so a positive fee_delta_msat means other pays more in fees.
+	pub fn compare(&self, other: &Route) -> RouteComparison {
+		let (our_fees_msat, their_fees_msat) = (self.total_fees_msat(), other.total_fees_msat());
+		let fee_delta_msat = if their_fees_msat >= our_fees_msat {
+			cmp::min(their_fees_msat - our_fees_msat, i64::max_value() as u64) as i64
+		} else {
+			-(cmp::min(our_fees_msat - their_fees_msat, i64::max_value() as u64) as i64)
+		};
+		let in_route = |route: &Route, short_channel_id: ShortChannelId| route.hops.iter().any(|hop| hop.short_channel_id == short_channel_id);
+		let mut comparison = RouteComparison {
+			fee_delta_msat,
+			cltv_delta: other.total_cltv_delta() as i64 - self.total_cltv_delta() as i64,
+			shared_short_channel_ids: Vec::new(),
+			short_channel_ids_only_in_self: Vec::new(),
+			short_channel_ids_only_in_other: Vec::new(),
+		};
+		for hop in self.hops.iter() {
+			if in_route(other, hop.short_channel_id) {
+				comparison.shared_short_channel_ids.push(hop.short_channel_id);
+			} else {
+				comparison.short_channel_ids_only_in_self.push(hop.short_channel_id);
+			}
+		}
+		for hop in other.hops.iter() {
+			if !in_route(self, hop.short_channel_id) {
+				comparison.short_channel_ids_only_in_other.push(hop.short_channel_id);
+			}
+		}
+		comparison
+	}
+
+	/// Constructs a route from explicitly-specified hops rather than from our view of the network,
+	/// checking that it is internally consistent: it must have between 1 and 20 hops, the final
+	/// hop's fee_msat and cltv_expiry_delta must be final_value_msat and final_cltv, no hop may go
+	/// to the node it came from, and the total fees and CLTV deltas must not overflow.
+	pub fn from_hops(hops: Vec<RouteHop>, final_value_msat: u64, final_cltv: u32) -> Result<Route, APIError> {
+		if hops.len() < 1 || hops.len() > 20 {
+			return Err(APIError::RouteError{err: "Route didn't go anywhere/had bogus size"});
+		}
+		let last_hop = hops.last().unwrap();
+		if last_hop.fee_msat != final_value_msat {
+			return Err(APIError::RouteError{err: "Final hop fee must be the value delivered to the destination"});
+		}
+		if last_hop.cltv_expiry_delta != final_cltv {
+			return Err(APIError::RouteError{err: "Final hop CLTV delta must be the final CLTV delta"});
+		}
+		for (prev_hop, hop) in hops.iter().zip(hops.iter().skip(1)) {
+			if prev_hop.pubkey == hop.pubkey {
+				return Err(APIError::RouteError{err: "Route went from a node back to itself"});
+			}
+		}
+		let mut total_fee_msat = 0u64;
+		for hop in hops.iter() {
+			total_fee_msat = match total_fee_msat.checked_add(hop.fee_msat) {
+				Some(total) => total,
+				None => return Err(APIError::RouteError{err: "Channel fees overflowed?!"}),
+			};
+		}
+		let mut total_cltv_delta = 0u32;
+		for hop in hops.iter() {
+			total_cltv_delta = match total_cltv_delta.checked_add(hop.cltv_expiry_delta) {
+				Some(total) => total,
+				None => return Err(APIError::RouteError{err: "Channel CLTV overflowed?!"}),
+			};
+		}
+		Ok(Route { hops })
+	}
+
+	/// Exports the HTLC each node along this route is expected to receive, eg for constructing the
+	/// onion with external tooling. starting_htlc_offset is the block height above which CLTV
+	/// deltas are counted, ie one more than the current block height, as ChannelManager uses when
+	/// sending. Amounts and expiries saturate rather than overflowing.
+	pub fn export_hops(&self, starting_htlc_offset: u32) -> Vec<ExportedRouteHop> {
+		let mut res = Vec::with_capacity(self.hops.len());
+		let mut amount_msat = 0u64;
+		let mut cltv_expiry = starting_htlc_offset;
+		for hop in self.hops.iter().rev() {
+			amount_msat = amount_msat.saturating_add(hop.fee_msat);
+			cltv_expiry = cltv_expiry.saturating_add(hop.cltv_expiry_delta);
+			res.push(ExportedRouteHop {
+				pubkey: hop.pubkey,
+				short_channel_id: hop.short_channel_id.0,
+				amount_msat,
+				cltv_expiry,
+			});
+		}
+		res.reverse();
+		res
+	}
+
+	/// Checks that this route is usable given router's current view of the network, ie that each
+	/// hop's channel exists between the previous hop (or us) and the hop's node, is enabled, and may
+	/// carry the amount sent over it, and that each node is paid at least the fee and CLTV delta
+	/// its channel requires. This may be used to sanity-check routes built or modified outside of
+	/// Router before sending over them.
+	///
+	/// As in Router::get_route, first_hops (if provided) replaces our own channels in router, and
+	/// last_hops may provide the final hop's channel, while fees on our own channels are not
+	/// checked. Inbound fees set via Router::set_inbound_fee are not considered.
+	pub fn validate_against<S>(&self, router: &Router<S>, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint]) -> Result<(), RouteValidationError> {
+		let network = router.network_map.read().unwrap();
+		for (hop_idx, hop) in self.hops.iter().enumerate() {
+			let short_channel_id = hop.short_channel_id;
+			let src_node_id = if hop_idx == 0 { &network.our_node_id } else { &self.hops[hop_idx - 1].pubkey };
+			let amount_msat = self.hops[hop_idx..].iter().fold(0u64, |total, hop| total.saturating_add(hop.fee_msat));
+
+			if hop_idx == 0 && first_hops.is_some() {
+				if !first_hops.unwrap().iter().any(|chan| chan.short_channel_id == Some(short_channel_id.0) && chan.remote_network_id == hop.pubkey) {
+					return Err(RouteValidationError::UnknownChannel { hop_idx, short_channel_id });
+				}
+				continue;
+			}
+
+			// (enabled, cltv_expiry_delta, htlc_minimum_msat, htlc_maximum_msat, fee_base_msat, fee_proportional_millionths)
+			let hint = if hop_idx == self.hops.len() - 1 {
+				last_hops.iter().find(|hint| hint.short_channel_id == short_channel_id && hint.src_node_id == *src_node_id)
+			} else { None };
+			let policy = if let Some(hint) = hint {
+				(true, hint.cltv_expiry_delta, hint.htlc_minimum_msat, effective_htlc_maximum_msat(hint.htlc_maximum_msat, None), hint.fee_base_msat, hint.fee_proportional_millionths)
+			} else {
+				let chan = match network.channels.get(&NetworkMap::get_key(short_channel_id.0, Sha256dHash::hash(&[0; 32]))) {
+					Some(chan) => chan,
+					None => return Err(RouteValidationError::UnknownChannel { hop_idx, short_channel_id }),
+				};
+				let directional_info = if chan.one_to_two.src_node_id == *src_node_id && chan.two_to_one.src_node_id == hop.pubkey {
+					&chan.one_to_two
+				} else if chan.two_to_one.src_node_id == *src_node_id && chan.one_to_two.src_node_id == hop.pubkey {
+					&chan.two_to_one
+				} else {
+					return Err(RouteValidationError::UnknownChannel { hop_idx, short_channel_id });
+				};
+				(directional_info.enabled, directional_info.cltv_expiry_delta, directional_info.htlc_minimum_msat,
+					effective_htlc_maximum_msat(directional_info.htlc_maximum_msat, chan.capacity_sats),
+					directional_info.fee_base_msat, directional_info.fee_proportional_millionths)
+			};
+			let (enabled, cltv_expiry_delta, htlc_minimum_msat, htlc_maximum_msat, fee_base_msat, fee_proportional_millionths) = policy;
+
+			if !enabled {
+				return Err(RouteValidationError::ChannelDisabled { hop_idx, short_channel_id });
+			}
+			if amount_msat < htlc_minimum_msat {
+				return Err(RouteValidationError::AmountBelowMinimum { hop_idx, short_channel_id, htlc_minimum_msat });
+			}
+			if amount_msat > htlc_maximum_msat {
+				return Err(RouteValidationError::AmountExceedsMaximum { hop_idx, short_channel_id, htlc_maximum_msat });
+			}
+			if hop_idx == 0 {
+				// As in get_route, fees on channels from us are ignored
+				continue;
+			}
+			let prev_hop = &self.hops[hop_idx - 1];
+			let required_fee_msat = amount_msat.checked_mul(fee_proportional_millionths as u64)
+				.and_then(|part| (fee_base_msat as u64).checked_add(part / 1000000)).unwrap_or(u64::max_value());
+			if prev_hop.fee_msat < required_fee_msat {
+				return Err(RouteValidationError::InsufficientFee { hop_idx: hop_idx - 1, required_fee_msat });
+			}
+			if prev_hop.cltv_expiry_delta < cltv_expiry_delta as u32 {
+				return Err(RouteValidationError::InsufficientCltvExpiryDelta { hop_idx: hop_idx - 1, required_cltv_expiry_delta: cltv_expiry_delta as u32 });
+			}
+		}
+		Ok(())
+	}
+
+	/// Checks that the given parts of a multi-path payment are usable together given router's
+	/// current view of the network, ie that each part is valid as checked by validate_against, that
+	/// all parts pay the same node a total of total_value_msat, and that the parts sending over the
+	/// same channel don't together send more than it can carry.
+	///
+	/// The amount a channel can carry is the outbound_capacity_msat of our channels in first_hops
+	/// or otherwise its on-chain capacity, if known. Amounts sent over a channel in either direction
+	/// count towards the same total.
+	pub fn validate_mpp<S>(parts: &[Route], router: &Router<S>, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], total_value_msat: u64) -> Result<(), MppValidationError> {
+		let target = match parts.first().and_then(|part| part.hops.last()) {
+			Some(hop) => hop.pubkey,
+			None => return Err(MppValidationError::NoParts),
+		};
+		let mut paid_msat = 0u64;
+		for (part_idx, part) in parts.iter().enumerate() {
+			let last_hop = match part.hops.last() {
+				Some(hop) => hop,
+				None => return Err(MppValidationError::InvalidPart { part_idx, error: None }),
+			};
+			if let Err(error) = part.validate_against(router, first_hops, last_hops) {
+				return Err(MppValidationError::InvalidPart { part_idx, error: Some(error) });
+			}
+			if last_hop.pubkey != target {
+				return Err(MppValidationError::TargetMismatch { part_idx });
+			}
+			paid_msat = paid_msat.saturating_add(last_hop.fee_msat);
+		}
+		if paid_msat != total_value_msat {
+			return Err(MppValidationError::IncorrectTotal { paid_msat });
+		}
+
+		let network = router.network_map.read().unwrap();
+		let mut channel_amounts: HashMap<ShortChannelId, u64> = HashMap::new();
+		for part in parts.iter() {
+			for (hop_idx, hop) in part.hops.iter().enumerate() {
+				let short_channel_id = hop.short_channel_id;
+				let amount_msat = part.hops[hop_idx..].iter().fold(0u64, |total, hop| total.saturating_add(hop.fee_msat));
+				let total_msat = channel_amounts.entry(short_channel_id).or_insert(0);
+				*total_msat = total_msat.saturating_add(amount_msat);
+
+				let first_hop = if hop_idx == 0 {
+					first_hops.and_then(|hops| hops.iter().find(|chan| chan.short_channel_id == Some(short_channel_id.0)))
+				} else { None };
+				let capacity_msat = if let Some(chan) = first_hop {
+					Some(chan.outbound_capacity_msat)
+				} else {
+					network.channels.get(&NetworkMap::get_key(short_channel_id.0, Sha256dHash::hash(&[0; 32])))
+						.and_then(|chan| chan.capacity_sats).map(|capacity_sats| capacity_sats.saturating_mul(1000))
+				};
+				if let Some(capacity_msat) = capacity_msat {
+					if *total_msat > capacity_msat {
+						return Err(MppValidationError::ChannelOversubscribed { short_channel_id, amount_msat: *total_msat, capacity_msat });
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// A reason a Route is not usable, as returned by Route::validate_against. Problems with the
+/// channel a hop is reached over give the index of that hop in Route::hops, while insufficient
+/// fees or CLTV deltas give the index of the hop whose fee_msat or cltv_expiry_delta is too low.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RouteValidationError {
+	/// The channel isn't known, or doesn't connect the previous hop to this one.
+	UnknownChannel {
+		/// The index of the hop reached over the channel.
+		hop_idx: usize,
+		/// The channel which couldn't be found.
+		short_channel_id: ShortChannelId,
+	},
+	/// The channel is disabled in the direction the route uses it, or its policy for that
+	/// direction is not yet known.
+	ChannelDisabled {
+		/// The index of the hop reached over the channel.
+		hop_idx: usize,
+		/// The disabled channel.
+		short_channel_id: ShortChannelId,
+	},
+	/// The amount sent over the channel is below its htlc_minimum_msat.
+	AmountBelowMinimum {
+		/// The index of the hop reached over the channel.
+		hop_idx: usize,
+		/// The channel which requires a larger amount.
+		short_channel_id: ShortChannelId,
+		/// The smallest amount which may be forwarded over the channel.
+		htlc_minimum_msat: u64,
+	},
+	/// The amount sent over the channel exceeds its htlc_maximum_msat (or its capacity if it doesn't
+	/// advertise one).
+	AmountExceedsMaximum {
+		/// The index of the hop reached over the channel.
+		hop_idx: usize,
+		/// The channel which is too small.
+		short_channel_id: ShortChannelId,
+		/// The largest amount which may be forwarded over the channel.
+		htlc_maximum_msat: u64,
+	},
+	/// A hop's fee_msat is less than the fee its node charges to forward over the next hop's
+	/// channel.
+	InsufficientFee {
+		/// The index of the hop whose fee_msat is too low.
+		hop_idx: usize,
+		/// The fee the hop's node charges.
+		required_fee_msat: u64,
+	},
+	/// A hop's cltv_expiry_delta is less than its node requires to forward over the next hop's
+	/// channel.
+	InsufficientCltvExpiryDelta {
+		/// The index of the hop whose cltv_expiry_delta is too low.
+		hop_idx: usize,
+		/// The CLTV delta the hop's node requires.
+		required_cltv_expiry_delta: u32,
+	},
+}
+
+/// A reason the parts of a multi-path payment are not usable together, as returned by
+/// Route::validate_mpp. Parts are identified by their index in the given slice.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MppValidationError {
+	/// No parts were given.
+	NoParts,
+	/// A part is not usable by itself, as returned by Route::validate_against, or None if it has no
+	/// hops.
+	InvalidPart {
+		/// The index of the unusable part.
+		part_idx: usize,
+		/// Why the part is unusable.
+		error: Option<RouteValidationError>,
+	},
+	/// A part pays a different node than the first part.
+	TargetMismatch {
+		/// The index of the part paying a different node.
+		part_idx: usize,
+	},
+	/// The parts don't pay the target the payment's total value.
+	IncorrectTotal {
+		/// The sum of the amounts the parts pay the target.
+		paid_msat: u64,
+	},
+	/// The parts together send more over a channel than it can carry.
+	ChannelOversubscribed {
+		/// The oversubscribed channel.
+		short_channel_id: ShortChannelId,
+		/// The amount sent over the channel by the parts checked so far.
+		amount_msat: u64,
+		/// The amount the channel can carry.
+		capacity_msat: u64,
+	},
+}
+
+/// A hop of a Route as exported by Route::export_hops, in terms of the HTLC the node at this hop
+/// receives rather than the fees and CLTV deltas Router works with. This is the format common to
+/// other Lightning implementations' route and onion-construction tools, and its fields will not
+/// change.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportedRouteHop {
+	/// The node_id of the node at this hop.
+	pub pubkey: PublicKey,
+	/// The channel over which the previous hop (or we, for the first hop) sends the HTLC to this
+	/// node. Unlike elsewhere in the router, this is the plain u64 used by other implementations.
+	pub short_channel_id: u64,
+	/// The amount of the HTLC this node receives. For all but the last hop, this node keeps the
+	/// difference between this and the next hop's amount_msat as its fee.
+	pub amount_msat: u64,
+	/// The absolute block height at which the HTLC this node receives expires.
+	pub cltv_expiry: u32,
+}
+
+/// What sending over a Route costs, as returned by Route::cost_breakdown. Sums saturate at
+/// u64::max_value() rather than overflowing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteCostBreakdown {
+	/// The value delivered to the destination, ie the last hop's fee_msat.
+	pub final_value_msat: u64,
+	/// The node_id of each intermediate node along the route and the fee we pay it, in route
+	/// order. This is empty for a direct payment to one of our peers.
+	pub fees_paid_msat: Vec<(PublicKey, u64)>,
+	/// The sum of fees_paid_msat, as returned by Route::total_fees_msat.
+	pub total_fees_msat: u64,
+	/// The amount which leaves our channel at the first hop, ie final_value_msat plus
+	/// total_fees_msat.
+	pub amount_sent_msat: u64,
+}
+
+/// The differences between two routes for the same payment, as returned by Route::compare. Each
+/// list of short_channel_ids is in the order the channels appear in the route they're taken
+/// from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteComparison {
+	/// The other route's total fees minus ours, see Route::total_fees_msat. Saturates rather than
+	/// overflowing.
+	pub fee_delta_msat: i64,
+	/// The other route's total CLTV delta minus ours, see Route::total_cltv_delta.
+	pub cltv_delta: i64,
+	/// The channels used by both routes.
+	pub shared_short_channel_ids: Vec<ShortChannelId>,
+	/// The channels used by our route but not the other.
+	pub short_channel_ids_only_in_self: Vec<ShortChannelId>,
+	/// The channels used by the other route but not ours.
+	pub short_channel_ids_only_in_other: Vec<ShortChannelId>,
+}
+
 impl Writeable for Route {
 	fn write<W: ::util::ser::Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
 		(self.hops.len() as u8).write(writer)?;
@@ -91,13 +592,18 @@ impl Readable for Route {
 	}
 }
 
-#[derive(PartialEq)]
-struct DirectionalChannelInfo {
+/// Details about one direction of a channel, as received in its most recent channel_update.
+#[derive(Clone, PartialEq)]
+pub struct DirectionalChannelInfo {
 	src_node_id: PublicKey,
 	last_update: u32,
 	enabled: bool,
 	cltv_expiry_delta: u16,
+	// As advertised in the channel_update, but never below MIN_HTLC_MINIMUM_MSAT.
 	htlc_minimum_msat: u64,
+	// As advertised in the channel_update, as channel_announcements carry no HTLC limits. This may
+	// exceed the channel's capacity, so routing always uses effective_htlc_maximum_msat instead.
+	htlc_maximum_msat: Option<u64>,
 	fee_base_msat: u32,
 	fee_proportional_millionths: u32,
 	last_update_message: Option<msgs::ChannelUpdate>,
@@ -105,25 +611,59 @@ struct DirectionalChannelInfo {
 
 impl std::fmt::Display for DirectionalChannelInfo {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-		write!(f, "src_node_id {}, last_update {}, enabled {}, cltv_expiry_delta {}, htlc_minimum_msat {}, fee_base_msat {}, fee_proportional_millionths {}", log_pubkey!(self.src_node_id), self.last_update, self.enabled, self.cltv_expiry_delta, self.htlc_minimum_msat, self.fee_base_msat, self.fee_proportional_millionths)?;
+		write!(f, "src_node_id {}, last_update {}, enabled {}, cltv_expiry_delta {}, htlc_minimum_msat {}, htlc_maximum_msat {:?}, fee_base_msat {}, fee_proportional_millionths {}", log_pubkey!(self.src_node_id), self.last_update, self.enabled, self.cltv_expiry_delta, self.htlc_minimum_msat, self.htlc_maximum_msat, self.fee_base_msat, self.fee_proportional_millionths)?;
 		Ok(())
 	}
 }
 
+impl DirectionalChannelInfo {
+	/// Returns false if we've never received a channel_update for this direction, in which case it
+	/// still has the disabled placeholder policy set when its channel_announcement was applied.
+	/// channel_updates never have a timestamp of 0 in practice.
+	fn has_update(&self) -> bool {
+		self.last_update != 0 || self.enabled
+	}
+}
+
 impl_writeable!(DirectionalChannelInfo, 0, {
 	src_node_id,
 	last_update,
 	enabled,
 	cltv_expiry_delta,
 	htlc_minimum_msat,
+	htlc_maximum_msat,
 	fee_base_msat,
 	fee_proportional_millionths,
 	last_update_message
 });
 
-#[derive(PartialEq)]
-struct ChannelInfo {
+impl DirectionalChannelInfo {
+	/// Reads a DirectionalChannelInfo as written by a Router of the given serialization version.
+	/// Version 1 didn't store htlc_maximum_msat.
+	fn read_versioned<R: ::std::io::Read>(reader: &mut R, ver: u8) -> Result<DirectionalChannelInfo, DecodeError> {
+		if ver >= 2 {
+			return Readable::read(reader);
+		}
+		Ok(DirectionalChannelInfo {
+			src_node_id: Readable::read(reader)?,
+			last_update: Readable::read(reader)?,
+			enabled: Readable::read(reader)?,
+			cltv_expiry_delta: Readable::read(reader)?,
+			htlc_minimum_msat: Readable::read(reader)?,
+			htlc_maximum_msat: None,
+			fee_base_msat: Readable::read(reader)?,
+			fee_proportional_millionths: Readable::read(reader)?,
+			last_update_message: Readable::read(reader)?,
+		})
+	}
+}
+
+/// Details about a channel (both directions), as stored in the Router's network map.
+#[derive(Clone, PartialEq)]
+pub struct ChannelInfo {
 	features: ChannelFeatures,
+	// The value of the funding output, if our ChainWatchInterface was able to look it up
+	capacity_sats: Option<u64>,
 	one_to_two: DirectionalChannelInfo,
 	two_to_one: DirectionalChannelInfo,
 	//this is cached here so we can send out it later if required by route_init_sync
@@ -133,19 +673,124 @@ struct ChannelInfo {
 
 impl std::fmt::Display for ChannelInfo {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-		write!(f, "features: {}, one_to_two: {}, two_to_one: {}", log_bytes!(self.features.encode()), self.one_to_two, self.two_to_one)?;
+		write!(f, "features: {}, capacity_sats: {:?}, one_to_two: {}, two_to_one: {}", log_bytes!(self.features.encode()), self.capacity_sats, self.one_to_two, self.two_to_one)?;
 		Ok(())
 	}
 }
 
+impl ChannelInfo {
+	/// Returns the features from the channel's channel_announcement, including any we don't
+	/// understand. Channels which require features we don't understand are never routed over.
+	pub fn features(&self) -> &ChannelFeatures {
+		&self.features
+	}
+
+	/// Returns true if the channel may be used for routing in at least one direction, ie we have
+	/// received a channel_update for that direction which did not disable it.
+	pub fn is_usable(&self) -> bool {
+		self.one_to_two.enabled || self.two_to_one.enabled
+	}
+
+	/// Returns true if the channel may be used for routing in both directions, as opposed to only
+	/// having a usable policy for one of them.
+	pub fn is_usable_in_both_directions(&self) -> bool {
+		self.one_to_two.enabled && self.two_to_one.enabled
+	}
+
+	/// Returns true if we've received a channel_update for at least one direction of the channel.
+	/// Channels without any are never routed over, and may be pruned via
+	/// Router::set_unupdated_channel_grace_blocks.
+	pub fn has_channel_update(&self) -> bool {
+		self.one_to_two.has_update() || self.two_to_one.has_update()
+	}
+
+	/// Returns the value of the channel's funding output, if it was looked up when the channel
+	/// was announced.
+	pub fn capacity_sats(&self) -> Option<u64> {
+		self.capacity_sats
+	}
+
+	/// Returns the largest amount we'd route over the channel in the given direction (from the
+	/// channel_announcement's node_id_1 if from_node_one is set, otherwise from node_id_2). This is
+	/// the htlc_maximum_msat of the latest channel_update for that direction clamped to the
+	/// channel's capacity, falling back to the capacity if the update doesn't include one, and to
+	/// the largest channel which may be opened without option_support_large_channel if the
+	/// capacity is unknown.
+	pub fn effective_htlc_maximum_msat(&self, from_node_one: bool) -> u64 {
+		let directional_info = if from_node_one { &self.one_to_two } else { &self.two_to_one };
+		effective_htlc_maximum_msat(directional_info.htlc_maximum_msat, self.capacity_sats)
+	}
+
+	/// Returns the inclusive range of amounts we'd route over the channel in the given direction
+	/// (as in effective_htlc_maximum_msat), ie from the htlc_minimum_msat of the latest
+	/// channel_update for that direction up to its effective_htlc_maximum_msat. Returns None if
+	/// the direction is disabled, or we haven't received a channel_update for it, or no amount
+	/// satisfies both limits.
+	pub fn routable_range(&self, from_node_one: bool) -> Option<(u64, u64)> {
+		let directional_info = if from_node_one { &self.one_to_two } else { &self.two_to_one };
+		let htlc_maximum_msat = self.effective_htlc_maximum_msat(from_node_one);
+		if !directional_info.enabled || directional_info.htlc_minimum_msat > htlc_maximum_msat {
+			return None;
+		}
+		Some((directional_info.htlc_minimum_msat, htlc_maximum_msat))
+	}
+
+	/// Returns the fees charged for forwarding over the channel from the channel_announcement's
+	/// node_id_1 and from its node_id_2, in that order, eg to find channels whose fees differ
+	/// greatly between directions, which may mean they're depleted on one side. Directions we
+	/// haven't received a channel_update for have fees of u32::max_value().
+	pub fn directional_fees(&self) -> (RoutingFees, RoutingFees) {
+		let fees = |directional_info: &DirectionalChannelInfo| RoutingFees {
+			fee_base_msat: directional_info.fee_base_msat,
+			fee_proportional_millionths: directional_info.fee_proportional_millionths,
+		};
+		(fees(&self.one_to_two), fees(&self.two_to_one))
+	}
+}
+
+/// The fees charged for forwarding over one direction of a channel, as returned by
+/// ChannelInfo::directional_fees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RoutingFees {
+	/// The base fee charged for each HTLC forwarded.
+	pub fee_base_msat: u32,
+	/// The proportional fee charged, in millionths of the amount forwarded.
+	pub fee_proportional_millionths: u32,
+}
+
+impl RoutingFees {
+	/// Gets the fee, in msat, charged for forwarding amount_msat.
+	pub fn fee_msat(&self, amount_msat: u64) -> u64 {
+		(self.fee_base_msat as u64).saturating_add(amount_msat.saturating_mul(self.fee_proportional_millionths as u64) / 1000000)
+	}
+}
+
 impl_writeable!(ChannelInfo, 0, {
 	features,
+	capacity_sats,
 	one_to_two,
 	two_to_one,
 	announcement_message
 });
 
-#[derive(PartialEq)]
+impl ChannelInfo {
+	/// Reads a ChannelInfo as written by a Router of the given serialization version. Version 1
+	/// didn't store capacity_sats.
+	fn read_versioned<R: ::std::io::Read>(reader: &mut R, ver: u8) -> Result<ChannelInfo, DecodeError> {
+		if ver >= 2 {
+			return Readable::read(reader);
+		}
+		Ok(ChannelInfo {
+			features: Readable::read(reader)?,
+			capacity_sats: None,
+			one_to_two: DirectionalChannelInfo::read_versioned(reader, ver)?,
+			two_to_one: DirectionalChannelInfo::read_versioned(reader, ver)?,
+			announcement_message: Readable::read(reader)?,
+		})
+	}
+}
+
+#[derive(Clone, PartialEq)]
 struct NodeInfo {
 	#[cfg(feature = "non_bitcoin_chain_hash_routing")]
 	channels: Vec<(u64, Sha256dHash)>,
@@ -168,6 +813,24 @@ struct NodeInfo {
 	announcement_message: Option<msgs::NodeAnnouncement>,
 }
 
+impl NodeInfo {
+	/// Gets the entry for a node we haven't received a node_announcement from, before any of its
+	/// channels are added.
+	fn unannounced() -> NodeInfo {
+		NodeInfo {
+			channels: Vec::new(),
+			lowest_inbound_channel_fee_base_msat: u32::max_value(),
+			lowest_inbound_channel_fee_proportional_millionths: u32::max_value(),
+			features: NodeFeatures::empty(),
+			last_update: None,
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: Vec::new(),
+			announcement_message: None,
+		}
+	}
+}
+
 impl std::fmt::Display for NodeInfo {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
 		write!(f, "features: {}, last_update: {:?}, lowest_inbound_channel_fee_base_msat: {}, lowest_inbound_channel_fee_proportional_millionths: {}, channels: {:?}", log_bytes!(self.features.encode()), self.last_update, self.lowest_inbound_channel_fee_base_msat, self.lowest_inbound_channel_fee_proportional_millionths, &self.channels[..])?;
@@ -245,6 +908,44 @@ struct NetworkMap {
 
 	our_node_id: PublicKey,
 	nodes: BTreeMap<PublicKey, NodeInfo>,
+
+	// Local-only score boosts by short_channel_id, set via Router::set_channel_score_boost. These
+	// are kept separate from the channels map as they aren't part of the gossiped view.
+	channel_score_boosts: BTreeMap<u64, u64>,
+	// Experimental local-only inbound fees by (short_channel_id, charging node), as
+	// (base_msat, proportional_millionths). Set via Router::set_inbound_fee and not persisted.
+	inbound_fees: BTreeMap<(u64, PublicKey), (i32, i32)>,
+	// Local-only estimates of the probability that a payment over a channel succeeds, in parts per
+	// million, by short_channel_id. Set via Router::set_channel_success_probability and not
+	// persisted.
+	channel_success_probabilities: BTreeMap<u64, u32>,
+	// Tracking of how often channels' policies change, by short_channel_id, as the timestamp of
+	// the latest change and the number of changes made within CHANNEL_POLICY_CHANGE_WINDOW_SECS
+	// of each other up to it. Used to penalize volatile channels and not persisted.
+	channel_policy_changes: BTreeMap<u64, (u32, u32)>,
+
+	// Caps on the size of the above maps, set via Router::set_graph_size_limits and not persisted.
+	max_channels: Option<usize>,
+	max_nodes: Option<usize>,
+	// Cap on the number of channels any node other than ourselves may have, set via
+	// Router::set_max_channels_per_node and not persisted.
+	max_channels_per_node: Option<usize>,
+
+	// The genesis block hash of the only chain whose gossip we accept, as given to Router::new or
+	// in RouterReadArgs. Only None in tests, see Router::clear_chain_hash.
+	chain_hash: Option<Sha256dHash>,
+	// Whether channel_announcements are accepted without their UTXO being checked when our
+	// ChainWatchInterface's UTXO lookups are unavailable, set via
+	// Router::set_accept_unverified_channels and not persisted.
+	accept_unverified_channels: bool,
+	// Channels accepted that way, along with the chain hash and bitcoin keys needed to look up
+	// their UTXO later. They aren't routed over or relayed until Router::reverify_channels
+	// succeeds in checking them.
+	unverified_channels: BTreeMap<u64, (Sha256dHash, PublicKey, PublicKey)>,
+	// The number of blocks after their funding block after which channels without any
+	// channel_updates are pruned, set via Router::set_unupdated_channel_grace_blocks and not
+	// persisted.
+	unupdated_channel_grace_blocks: Option<u32>,
 }
 
 impl Writeable for NetworkMap {
@@ -260,17 +961,36 @@ impl Writeable for NetworkMap {
 			node_id.write(writer)?;
 			node_info.write(writer)?;
 		}
+		(self.channel_score_boosts.len() as u64).write(writer)?;
+		for (short_channel_id, boost_msat) in self.channel_score_boosts.iter() {
+			short_channel_id.write(writer)?;
+			boost_msat.write(writer)?;
+		}
+		(self.unverified_channels.len() as u64).write(writer)?;
+		for (chan_id, &(ref chain_hash, ref bitcoin_key_1, ref bitcoin_key_2)) in self.unverified_channels.iter() {
+			chan_id.write(writer)?;
+			chain_hash.write(writer)?;
+			bitcoin_key_1.write(writer)?;
+			bitcoin_key_2.write(writer)?;
+		}
 		Ok(())
 	}
 }
 
 impl Readable for NetworkMap {
 	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<NetworkMap, DecodeError> {
+		NetworkMap::read_versioned(reader, SERIALIZATION_VERSION)
+	}
+}
+
+impl NetworkMap {
+	/// Reads a NetworkMap as written by a Router of the given serialization version.
+	fn read_versioned<R: ::std::io::Read>(reader: &mut R, ver: u8) -> Result<NetworkMap, DecodeError> {
 		let channels_count: u64 = Readable::read(reader)?;
 		let mut channels = BTreeMap::new();
 		for _ in 0..channels_count {
 			let chan_id: u64 = Readable::read(reader)?;
-			let chan_info = Readable::read(reader)?;
+			let chan_info = ChannelInfo::read_versioned(reader, ver)?;
 			channels.insert(chan_id, chan_info);
 		}
 		let our_node_id = Readable::read(reader)?;
@@ -281,10 +1001,41 @@ impl Readable for NetworkMap {
 			let node_info = Readable::read(reader)?;
 			nodes.insert(node_id, node_info);
 		}
+		let mut channel_score_boosts = BTreeMap::new();
+		if ver >= 2 {
+			let boosts_count: u64 = Readable::read(reader)?;
+			for _ in 0..boosts_count {
+				let short_channel_id = Readable::read(reader)?;
+				let boost_msat = Readable::read(reader)?;
+				channel_score_boosts.insert(short_channel_id, boost_msat);
+			}
+		}
+		let mut unverified_channels = BTreeMap::new();
+		if ver >= 2 {
+			let unverified_count: u64 = Readable::read(reader)?;
+			for _ in 0..unverified_count {
+				let chan_id = Readable::read(reader)?;
+				let chain_hash = Readable::read(reader)?;
+				let bitcoin_key_1 = Readable::read(reader)?;
+				let bitcoin_key_2 = Readable::read(reader)?;
+				unverified_channels.insert(chan_id, (chain_hash, bitcoin_key_1, bitcoin_key_2));
+			}
+		}
 		Ok(NetworkMap {
 			channels,
 			our_node_id,
 			nodes,
+			channel_score_boosts,
+			inbound_fees: BTreeMap::new(),
+			channel_success_probabilities: BTreeMap::new(),
+			channel_policy_changes: BTreeMap::new(),
+			max_channels: None,
+			max_nodes: None,
+			max_channels_per_node: None,
+			chain_hash: None,
+			accept_unverified_channels: false,
+			unverified_channels,
+			unupdated_channel_grace_blocks: None,
 		})
 	}
 }
@@ -304,6 +1055,131 @@ impl std::fmt::Display for NetworkMap {
 }
 
 impl NetworkMap {
+	/// Returns false if we're for a chain other than the one with the given genesis block hash.
+	fn accepts_chain(&self, chain_hash: &Sha256dHash) -> bool {
+		match self.chain_hash {
+			Some(ref our_chain_hash) => our_chain_hash == chain_hash,
+			None => true,
+		}
+	}
+
+	/// Estimates the memory used by the map, see Router::estimated_memory_bytes.
+	fn estimated_memory_bytes(&self) -> usize {
+		let mut total = mem::size_of::<NetworkMap>();
+		for (chan_id, chan) in self.channels.iter() {
+			total += mem::size_of_val(chan_id) + mem::size_of::<ChannelInfo>() + chan.features.byte_count();
+			if let Some(ref msg) = chan.announcement_message {
+				total += msg.contents.features.byte_count() + msg.contents.excess_data.capacity();
+			}
+			for directional_info in [&chan.one_to_two, &chan.two_to_one].iter() {
+				if let Some(ref msg) = directional_info.last_update_message {
+					total += msg.contents.excess_data.capacity();
+				}
+			}
+		}
+		for (node_id, node) in self.nodes.iter() {
+			total += mem::size_of_val(node_id) + mem::size_of::<NodeInfo>() + node.features.byte_count();
+			total += mem::size_of_val(&node.channels[..]);
+			total += node.addresses.capacity() * mem::size_of::<NetAddress>();
+			if let Some(ref msg) = node.announcement_message {
+				total += msg.contents.features.byte_count() + msg.contents.addresses.capacity() * mem::size_of::<NetAddress>();
+				total += msg.contents.excess_address_data.capacity() + msg.contents.excess_data.capacity();
+			}
+		}
+		total += self.channel_score_boosts.len() * mem::size_of::<(u64, u64)>();
+		total += self.inbound_fees.len() * mem::size_of::<((u64, PublicKey), (i32, i32))>();
+		total += self.channel_success_probabilities.len() * mem::size_of::<(u64, u32)>();
+		total += self.channel_policy_changes.len() * mem::size_of::<(u64, (u32, u32))>();
+		total
+	}
+
+	/// Gets the timestamps of the channel_updates and node_announcements we've received.
+	fn update_timestamps(&self) -> Vec<u32> {
+		let mut res = Vec::with_capacity(self.channels.len() * 2 + self.nodes.len());
+		for chan in self.channels.values() {
+			res.push(chan.one_to_two.last_update);
+			res.push(chan.two_to_one.last_update);
+		}
+		res.extend(self.nodes.values().filter_map(|node| node.last_update));
+		res.retain(|timestamp| *timestamp != 0);
+		res
+	}
+
+	/// Notes that a channel_update with the given timestamp changed the policy of the channel with
+	/// the given short_channel_id.
+	fn record_policy_change(&mut self, short_channel_id: u64, timestamp: u32) {
+		let entry = self.channel_policy_changes.entry(short_channel_id).or_insert((timestamp, 0));
+		if timestamp.saturating_sub(entry.0) > CHANNEL_POLICY_CHANGE_WINDOW_SECS {
+			entry.1 = 0;
+		}
+		entry.0 = cmp::max(entry.0, timestamp);
+		entry.1 = entry.1.saturating_add(1);
+	}
+
+	/// Gets the penalty for routing over the channel with the given short_channel_id, given how
+	/// recently and often its policy changed, see RouteParameters::channel_policy_change_penalty_msat.
+	fn policy_change_penalty_msat(&self, short_channel_id: u64, params: &RouteParameters, now: u64) -> u64 {
+		if params.channel_policy_change_penalty_msat == 0 {
+			return 0;
+		}
+		match self.channel_policy_changes.get(&short_channel_id) {
+			Some(&(last_change, changes)) if last_change as u64 + CHANNEL_POLICY_CHANGE_WINDOW_SECS as u64 >= now => {
+				cmp::min(params.channel_policy_change_penalty_msat.saturating_mul(changes as u64), params.max_channel_policy_change_penalty_msat)
+			},
+			_ => 0,
+		}
+	}
+
+	/// Gets the set of nodes connected to node_id by some sequence of channels we know of,
+	/// including node_id itself, irrespective of the direction(s) in which those channels are
+	/// usable. Empty if we don't know of node_id.
+	fn connected_nodes<S: BuildHasher + Default>(&self, node_id: &PublicKey) -> HashSet<PublicKey, S> {
+		let mut visited = HashSet::with_hasher(S::default());
+		if !self.nodes.contains_key(node_id) {
+			return visited;
+		}
+		let mut queue = VecDeque::new();
+		visited.insert(node_id.clone());
+		queue.push_back(node_id.clone());
+		while let Some(cur_node_id) = queue.pop_front() {
+			for chan_id in self.nodes.get(&cur_node_id).unwrap().channels.iter() {
+				let chan = self.channels.get(chan_id).unwrap();
+				let peer_id = if chan.one_to_two.src_node_id == cur_node_id { &chan.two_to_one.src_node_id } else { &chan.one_to_two.src_node_id };
+				if visited.insert(peer_id.clone()) {
+					queue.push_back(peer_id.clone());
+				}
+			}
+		}
+		visited
+	}
+
+	/// Gets the number of channels on the shortest path between node_id and each node within
+	/// max_hops channels of it, irrespective of the directions in which those channels are
+	/// currently enabled.
+	fn hop_distances<S: BuildHasher + Default>(&self, node_id: &PublicKey, max_hops: u8) -> HashMap<PublicKey, u8, S> {
+		let mut distances = HashMap::with_hasher(S::default());
+		if !self.nodes.contains_key(node_id) {
+			return distances;
+		}
+		let mut queue = VecDeque::new();
+		distances.insert(node_id.clone(), 0);
+		queue.push_back((node_id.clone(), 0));
+		while let Some((cur_node_id, distance)) = queue.pop_front() {
+			if distance == max_hops {
+				continue;
+			}
+			for chan_id in self.nodes.get(&cur_node_id).unwrap().channels.iter() {
+				let chan = self.channels.get(chan_id).unwrap();
+				let peer_id = if chan.one_to_two.src_node_id == cur_node_id { &chan.two_to_one.src_node_id } else { &chan.one_to_two.src_node_id };
+				if !distances.contains_key(peer_id) {
+					distances.insert(peer_id.clone(), distance + 1);
+					queue.push_back((peer_id.clone(), distance + 1));
+				}
+			}
+		}
+		distances
+	}
+
 	#[cfg(feature = "non_bitcoin_chain_hash_routing")]
 	#[inline]
 	fn get_key(short_channel_id: u64, chain_hash: Sha256dHash) -> (u64, Sha256dHash) {
@@ -334,7 +1210,7 @@ pub struct RouteHint {
 	/// The node_id of the non-target end of the route
 	pub src_node_id: PublicKey,
 	/// The short_channel_id of this channel
-	pub short_channel_id: u64,
+	pub short_channel_id: ShortChannelId,
 	/// The static msat-denominated fee which must be paid to use this channel
 	pub fee_base_msat: u32,
 	/// The dynamic proportional fee which must be paid to use this channel, denominated in
@@ -344,222 +1220,820 @@ pub struct RouteHint {
 	pub cltv_expiry_delta: u16,
 	/// The minimum value, in msat, which must be relayed to the next hop.
 	pub htlc_minimum_msat: u64,
+	/// The maximum value, in msat, which may be relayed to the next hop, if known.
+	pub htlc_maximum_msat: Option<u64>,
 }
 
-/// Tracks a view of the network, receiving updates from peers and generating Routes to
-/// payment destinations.
-pub struct Router {
-	secp_ctx: Secp256k1<secp256k1::VerifyOnly>,
-	network_map: RwLock<NetworkMap>,
-	full_syncs_requested: AtomicUsize,
-	chain_monitor: Arc<ChainWatchInterface>,
-	logger: Arc<Logger>,
+/// The length of a single hop in the BOLT 11 `r` (routing information) field.
+const BOLT11_ROUTE_HOP_LEN: usize = 33 + 8 + 4 + 4 + 2;
+
+/// A reason RouteHint::from_bolt11_route_field couldn't convert an `r` field.
+#[derive(Debug)]
+pub enum RouteHintDecodeError {
+	/// The field was malformed.
+	Decode(DecodeError),
+	/// The field was valid but described a path of more than one hop, which can't be expressed as
+	/// a single RouteHint.
+	MultiHopRoute {
+		/// The number of hops in the field.
+		hop_count: usize,
+	},
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
-
-impl Writeable for Router {
-	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
-		writer.write_all(&[SERIALIZATION_VERSION; 1])?;
-		writer.write_all(&[MIN_SERIALIZATION_VERSION; 1])?;
-
-		let network = self.network_map.read().unwrap();
-		network.write(writer)?;
-		Ok(())
+impl From<DecodeError> for RouteHintDecodeError {
+	fn from(e: DecodeError) -> Self {
+		RouteHintDecodeError::Decode(e)
 	}
 }
 
-/// Arguments for the creation of a Router that are not deserialized.
-/// At a high-level, the process for deserializing a Router and resuming normal operation is:
-/// 1) Deserialize the Router by filling in this struct and calling <Router>::read(reaser, args).
-/// 2) Register the new Router with your ChainWatchInterface
-pub struct RouterReadArgs {
-	/// The ChainWatchInterface for use in the Router in the future.
+impl RouteHint {
+	/// Converts the data of a BOLT 11 invoice `r` (routing information) field, already converted
+	/// from bech32 to 8-bit bytes, into the RouteHint get_route expects in last_hops. Multiple `r`
+	/// fields should each be converted and the results collected.
 	///
-	/// No calls to the ChainWatchInterface will be made during deserialization.
-	pub chain_monitor: Arc<ChainWatchInterface>,
-	/// The Logger for use in the ChannelManager and which may be used to log information during
-	/// deserialization.
-	pub logger: Arc<Logger>,
-}
-
-impl ReadableArgs<RouterReadArgs> for Router {
-	fn read<R: ::std::io::Read>(reader: &mut R, args: RouterReadArgs) -> Result<Router, DecodeError> {
-		let _ver: u8 = Readable::read(reader)?;
-		let min_ver: u8 = Readable::read(reader)?;
-		if min_ver > SERIALIZATION_VERSION {
-			return Err(DecodeError::UnknownVersion);
+	/// The field describes a path of private channels towards the payee. As last_hops may only
+	/// contain channels which go directly to the payee, fields with more than one hop can't be
+	/// routed over and are rejected with RouteHintDecodeError::MultiHopRoute.
+	///
+	/// BOLT 11 does not carry HTLC limits, so htlc_minimum_msat is set to 0 and htlc_maximum_msat
+	/// to None.
+	pub fn from_bolt11_route_field(data: &[u8]) -> Result<RouteHint, RouteHintDecodeError> {
+		if data.is_empty() || data.len() % BOLT11_ROUTE_HOP_LEN != 0 {
+			return Err(RouteHintDecodeError::Decode(DecodeError::BadLengthDescriptor));
 		}
-		let network_map = Readable::read(reader)?;
-		Ok(Router {
-			secp_ctx: Secp256k1::verification_only(),
-			network_map: RwLock::new(network_map),
-			full_syncs_requested: AtomicUsize::new(0),
-			chain_monitor: args.chain_monitor,
-			logger: args.logger,
+		if data.len() != BOLT11_ROUTE_HOP_LEN {
+			return Err(RouteHintDecodeError::MultiHopRoute { hop_count: data.len() / BOLT11_ROUTE_HOP_LEN });
+		}
+		let mut reader = ::std::io::Cursor::new(data);
+		Ok(RouteHint {
+			src_node_id: Readable::read(&mut reader)?,
+			short_channel_id: Readable::read(&mut reader)?,
+			fee_base_msat: Readable::read(&mut reader)?,
+			fee_proportional_millionths: Readable::read(&mut reader)?,
+			cltv_expiry_delta: Readable::read(&mut reader)?,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
 		})
 	}
 }
 
-macro_rules! secp_verify_sig {
-	( $secp_ctx: expr, $msg: expr, $sig: expr, $pubkey: expr ) => {
-		match $secp_ctx.verify($msg, $sig, $pubkey) {
-			Ok(_) => {},
-			Err(_) => return Err(LightningError{err: "Invalid signature from remote node", action: ErrorAction::IgnoreError}),
-		}
-	};
+/// A summary of the Router's current view of the network, as returned by get_network_stats.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkStats {
+	/// The number of nodes we know of, including ourselves.
+	pub node_count: usize,
+	/// The number of channels we know of.
+	pub channel_count: usize,
+	/// The number of channels which may be used for routing in at least one direction.
+	pub usable_channel_count: usize,
+	/// The number of channels which may be used for routing in both directions.
+	pub bidirectional_channel_count: usize,
+	/// The number of channels we've received a channel_announcement but no channel_update for,
+	/// see Router::set_unupdated_channel_grace_blocks.
+	pub unupdated_channel_count: usize,
 }
 
-impl RoutingMessageHandler for Router {
+/// How up-to-date the Router's view of the network is, as returned by get_gossip_freshness.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GossipFreshness {
+	/// The timestamp of the newest channel_update we've received, if any.
+	pub newest_channel_update_timestamp: Option<u32>,
+	/// The number of channels we've received a channel_update for (in either direction) with a
+	/// timestamp within the last day.
+	pub recently_updated_channel_count: usize,
+}
 
-	fn handle_node_announcement(&self, msg: &msgs::NodeAnnouncement) -> Result<bool, LightningError> {
-		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &msg.contents.node_id);
+/// A reason Router::find_node_by_prefix couldn't find a single node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrefixMatchError {
+	/// No node we know of has a node_id with the given prefix.
+	NotFound,
+	/// More than one node we know of has a node_id with the given prefix.
+	Ambiguous {
+		/// The number of nodes whose node_id has the given prefix.
+		match_count: usize,
+	},
+}
 
-		let mut network = self.network_map.write().unwrap();
-		match network.nodes.get_mut(&msg.contents.node_id) {
-			None => Err(LightningError{err: "No existing channels for node_announcement", action: ErrorAction::IgnoreError}),
-			Some(node) => {
-				match node.last_update {
-					Some(last_update) => if last_update >= msg.contents.timestamp {
-						return Err(LightningError{err: "Update older than last processed update", action: ErrorAction::IgnoreError});
-					},
-					None => {},
-				}
+/// Details about a route search which failed to find a path, as filled in by
+/// get_route_with_diagnostics. Searches proceed from the target towards the payer, so these
+/// describe how far back from the target the search got.
+#[derive(Clone, Default)]
+pub struct RouteDiagnostics {
+	/// The nodes from which the search found a path to the target, sorted by node_id. If the
+	/// payer has no path to any of these, no route exists.
+	pub reachable_nodes: Vec<PublicKey>,
+	/// The node in reachable_nodes with the most hops along its path to the target, if any.
+	pub partial_route_source: Option<PublicKey>,
+	/// The hops from partial_route_source to the target, in the same format as Route::hops.
+	pub partial_route_hops: Vec<RouteHop>,
+	/// If the search skipped any channels because they could not carry the amount we'd have
+	/// needed to send over them, the one closest to being able to, along with its limit. Channels
+	/// which were too small are reported in preference to those which required too large an
+	/// amount. The LightningError returned by the search carries only a static message, so this
+	/// is the only place the limit is reported.
+	pub binding_amount_limit: Option<RouteAmountLimit>,
+}
 
-				node.features = msg.contents.features.clone();
-				node.last_update = Some(msg.contents.timestamp);
-				node.rgb = msg.contents.rgb;
-				node.alias = msg.contents.alias;
-				node.addresses = msg.contents.addresses.clone();
+/// Measurements of how much work a route search did, as filled in by get_route_with_metrics, eg
+/// to decide whether RouteParameters::max_nodes_visited or RouteParameters::deadline are needed
+/// on a given network graph.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteSearchMetrics {
+	/// The number of times the search explored the channels of a node, as limited by
+	/// RouteParameters::max_nodes_visited.
+	pub nodes_visited: usize,
+	/// The number of channels (including first_hops and last_hops) the search considered
+	/// extending a path over, counting each direction separately.
+	pub channels_relaxed: usize,
+	/// The wall-clock time the search took, including waiting for the network graph lock.
+	pub elapsed: Duration,
+}
 
-				let should_relay = msg.contents.excess_data.is_empty() && msg.contents.excess_address_data.is_empty();
-				node.announcement_message = if should_relay { Some(msg.clone()) } else { None };
-				Ok(should_relay)
-			}
+/// A channel limit which prevented a route search from using a channel, see
+/// RouteDiagnostics::binding_amount_limit.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RouteAmountLimit {
+	/// The amount exceeded the channel's htlc_maximum_msat (or its capacity if it doesn't
+	/// advertise one).
+	AmountExceedsCapacity {
+		/// The channel which was too small.
+		short_channel_id: ShortChannelId,
+		/// The largest amount which may be forwarded over the channel.
+		htlc_maximum_msat: u64,
+		/// The amount (including fees for any later hops) we'd have needed to forward over the
+		/// channel.
+		amount_msat: u64,
+	},
+	/// The amount was below the channel's htlc_minimum_msat.
+	AmountBelowMinimum {
+		/// The channel which required a larger amount.
+		short_channel_id: ShortChannelId,
+		/// The smallest amount which may be forwarded over the channel.
+		htlc_minimum_msat: u64,
+		/// The amount (including fees for any later hops) we'd have needed to forward over the
+		/// channel.
+		amount_msat: u64,
+	},
+}
+
+/// Optional parameters which tweak how get_route_with_params searches for a route.
+///
+/// Default::default() provides the same behavior as get_route.
+#[derive(Clone, Debug)]
+pub struct RouteParameters {
+	/// The current block height. The first HTLC of a route expires at this height plus the sum of
+	/// the cltv_expiry_delta of all hops, which is checked against max_cltv_expiry_height.
+	///
+	/// Default value: 0
+	pub current_height: u32,
+	/// The maximum absolute block height at which the first HTLC of a route may expire. Paths
+	/// which would lock our funds beyond this height are not considered. If no route within the
+	/// limit is found, the search is repeated with objective set to minimize the total CLTV expiry
+	/// delta, so that a costlier route within the limit is still found where the cheaper paths
+	/// through some node are too long.
+	///
+	/// Default value: u32::max_value(), ie no limit.
+	pub max_cltv_expiry_height: u32,
+	/// If set, channels whose htlc_maximum_msat is below the amount we'd need to send over them
+	/// are not skipped, but instead have this penalty added to their cost so that they are only
+	/// used as a last resort. This may be useful for speculative routing, as the channel may have
+	/// since been updated. The penalty does not change the fees paid along the route.
+	///
+	/// Default value: None, ie such channels are never used, as BOLT 7 expects.
+	pub htlc_maximum_penalty_msat: Option<u64>,
+	/// What the search optimizes for.
+	///
+	/// Default value: RouteObjective::LowestFee
+	pub objective: RouteObjective,
+	/// The short_channel_ids of channels which may not be used, including any of our first_hops
+	/// or channels from last_hops.
+	///
+	/// Default value: empty
+	pub excluded_channels: Vec<ShortChannelId>,
+	/// A penalty added to the cost of a channel for each time its policy changed in the
+	/// channel_updates we've received, as long as each change was within a day of the previous one
+	/// and the latest was within the last day. Channels whose fees or limits change often may be
+	/// less reliable. Only changes received since this Router was created or deserialized are
+	/// counted, and the penalty does not change the fees paid along the route.
+	///
+	/// Default value: 0, ie no penalty
+	pub channel_policy_change_penalty_msat: u64,
+	/// The maximum total penalty applied to any one channel due to
+	/// channel_policy_change_penalty_msat, so that avoiding volatile channels never outweighs a
+	/// fee difference larger than this.
+	///
+	/// Default value: 0
+	pub max_channel_policy_change_penalty_msat: u64,
+	/// Channels in our network graph whose on-chain capacity, as looked up when they were
+	/// announced, is below this many satoshis are not considered, regardless of their
+	/// htlc_maximum_msat. Small channels may be unreliable for large payments. Channels whose
+	/// capacity is unknown (eg because our ChainWatchInterface doesn't support UTXO lookups), our
+	/// first_hops and channels from last_hops are not affected.
+	///
+	/// Default value: 0, ie no minimum
+	pub min_channel_capacity_sat: u64,
+	/// A penalty added to the cost of channels in our network graph whose capacity is unknown (eg
+	/// because our ChainWatchInterface doesn't support UTXO lookups), as we can't tell whether they
+	/// are large enough to be reliable. Such channels are otherwise treated as being as large as any
+	/// channel opened without option_support_large_channel may be. The penalty does not change the
+	/// fees paid along the route, and does not apply to our own channels or last_hops.
+	///
+	/// Default value: 0, ie no penalty
+	pub unknown_capacity_penalty_msat: u64,
+	/// The maximum number of times the search may explore the channels of a node before giving up
+	/// with a "Route search budget exhausted" error, bounding the work we do for a route
+	/// irrespective of the size or shape of the network graph. The search starts from the
+	/// target's channels and ends upon reaching us, neither of which count towards the limit.
+	///
+	/// Default value: None, ie no limit
+	pub max_nodes_visited: Option<usize>,
+	/// Whether channels in first_hops which are not currently live (see ChannelDetails::is_live)
+	/// may be used, eg to plan a payment over a channel we expect to be usable again soon. Routes
+	/// over such channels can't be sent until they're live, see Route::is_speculative. Channels
+	/// without a short_channel_id still may not be included in first_hops.
+	///
+	/// Default value: false
+	pub include_non_live_first_hops: bool,
+	/// A penalty for leaving us over first hops with few HTLC slots to spare (see
+	/// ChannelDetails::outbound_htlc_slots), which is divided by the number of slots the channel
+	/// has left, eg to keep headroom for sending many payments at once. Like other penalties, it
+	/// does not change the fees paid along the route. First hops with no slots left are never
+	/// used.
+	///
+	/// Default value: 0, ie no penalty
+	pub htlc_slot_penalty_msat: u64,
+	/// Penalties for routing through specific nodes, as (node_id, penalty_msat) pairs. A node's
+	/// penalty is added to the cost of each channel it would forward over, so that routes through
+	/// it are only used if they're at least that much cheaper than those avoiding it, eg to limit
+	/// how much we pay some node in fees. Like other penalties, they do not change the fees paid
+	/// along the route. Penalties for the payer or the target have no effect.
+	///
+	/// Default value: empty, ie no penalties
+	pub node_penalties: Vec<(PublicKey, u64)>,
+	/// Nodes to prefer routing through, as (node_id, discount_msat) pairs, eg our own
+	/// infrastructure. As with Router::set_channel_score_boost, a node's discount is subtracted
+	/// from the cost of each channel it would forward over, though never by more than the fee it
+	/// charges for that channel, so a route through preferred nodes is only used over another if it
+	/// costs at most their discounts more. Discounts never change the fees paid along the route,
+	/// and discounts for the payer or the target have no effect.
+	///
+	/// Default value: empty, ie no preferred nodes
+	pub preferred_nodes: Vec<(PublicKey, u64)>,
+	/// Nodes our connection layer currently can't reach, which may not forward any part of the
+	/// route. Unlike node_penalties, this is not a preference but reflects the network-level state
+	/// of our connections, so it is expected to change between searches. The target and our own
+	/// node are never excluded, even if listed.
+	///
+	/// Default value: empty
+	pub unreachable_nodes: Vec<PublicKey>,
+	/// The short_channel_ids of channels we know to be temporarily unusable, eg our own channels
+	/// to a peer which just disconnected, which may not be used, including any of our first_hops
+	/// or channels from last_hops. Unlike excluded_channels, this reflects local state rather than
+	/// a decision to avoid the channels, so it is expected to change between searches and allows
+	/// skipping channels without waiting for a channel_update disabling them. It never changes our
+	/// view of the network.
+	///
+	/// Default value: empty
+	pub locally_disabled_channels: Vec<ShortChannelId>,
+	/// A safety margin, in blocks, added to the final CLTV delta the recipient requires (ie the
+	/// final_cltv passed to get_route) to tolerate blocks being found while the payment is in
+	/// flight. The final hop's cltv_expiry_delta in the resulting route includes it, and it counts
+	/// towards max_cltv_expiry_height like any other CLTV delta.
+	///
+	/// Default value: 0
+	pub final_cltv_safety_delta: u32,
+	/// If set, the search returns the first route it finds whose total fee is at most this many
+	/// msat, rather than continuing to look for the cheapest route, trading optimality for speed on
+	/// large network graphs. To find a route early, the search continues from any of the payer's
+	/// peers as soon as it finds a path from it to the target. Penalties, boosts and the objective
+	/// still guide which routes are found first, but the budget only applies to fees actually
+	/// paid. If no route within the budget is found, the cheapest route is returned as usual, which
+	/// then exceeds the budget.
+	///
+	/// Default value: None, ie the cheapest route is always returned
+	pub fee_budget_msat: Option<u64>,
+	/// The maximum total fee, in millionths of the value being sent, that a route may pay. Paths
+	/// are dropped as soon as their fees exceed it, so eg our channels to peers whose onward base
+	/// fees are large relative to a micropayment are never used, and if no path is cheap enough the
+	/// search fails as if there were no path at all. Unlike fee_budget_msat, this is a hard limit.
+	/// If no route within the limit is found, the search is repeated without any penalties,
+	/// preferred_nodes or objective (other than one minimizing the total CLTV expiry delta), so
+	/// that a cheaper route isn't missed because a costlier one was preferred. Local score boosts
+	/// (see Router::set_channel_score_boost) still apply then, so a route within the limit may be
+	/// missed where they make a costlier path preferred.
+	///
+	/// Default value: None, ie no limit
+	pub max_fee_proportional_millionths: Option<u32>,
+	/// How to pick among parallel channels, ie multiple channels from one node to the same next
+	/// node.
+	///
+	/// Default value: ParallelChannelPolicy::Cheapest
+	pub parallel_channel_policy: ParallelChannelPolicy,
+	/// If set, channels in our network graph whose latest channel_update for the direction we'd
+	/// use them in is more than this many seconds old are not considered, eg so that large
+	/// payments only use channels whose policies are known to be current. This only applies to
+	/// this search and doesn't remove anything from our view of the network. Our own channels and
+	/// channels from last_hops are not affected.
+	///
+	/// Default value: None, ie no limit
+	pub max_channel_update_age_secs: Option<u32>,
+	/// If set, the maximum share, in millionths, of a route's total fee that any one node on it
+	/// may charge, eg 500_000 so that no hop is paid more than the rest of the route combined. This
+	/// protects against nodes which attract routes with low fees elsewhere and then charge a large
+	/// proportional fee on their own channel. Where the cheapest route violates the limit, the
+	/// channel charging the largest share is excluded and the search is repeated, so the route
+	/// returned may be more expensive. Routes over which only one node charges a fee are never
+	/// affected.
+	///
+	/// Channels are excluded greedily and for the rest of the search, so a route over an excluded
+	/// channel which would have satisfied the limit (eg because other hops would then have charged
+	/// more) is never found. To bound the time taken, no route is returned if the limit is still
+	/// violated after 10 searches.
+	///
+	/// Default value: None, ie no limit
+	pub max_hop_fee_share_millionths: Option<u32>,
+	/// Whether routes found by the search are refined by replacing any run of hops with a single
+	/// channel directly between its ends, wherever that doesn't increase the total fee, eg where
+	/// penalties, boosts or the objective led the search to take a detour. Shortcuts must satisfy
+	/// the same restrictions as the rest of the route (eg excluded_channels,
+	/// max_channel_update_age_secs, max_hop_fee_share_millionths and HTLC limits, including any
+	/// learned liquidity), and are never taken over channels which can't carry the amount, even if
+	/// htlc_maximum_penalty_msat is set. They are taken regardless of any penalties on them. Only
+	/// applies to routes from us, and not when inbound fees are set via Router::set_inbound_fee.
+	///
+	/// Default value: false
+	pub shortcut_redundant_hops: bool,
+	/// A point in time after which the search stops, bounding how long we spend looking for a
+	/// route on large network graphs. The search finds routes from us before it is done improving
+	/// them, so once the deadline has passed the best route found so far is returned, which may
+	/// not be the cheapest one. If no route has been found yet, the search fails with a "Route
+	/// search deadline exceeded" error.
+	///
+	/// Default value: None, ie no deadline
+	pub deadline: Option<Instant>,
+}
+
+/// What a route search optimizes for, see RouteParameters::objective.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RouteObjective {
+	/// Find the route which pays the lowest total fee.
+	LowestFee,
+	/// Find the route which is most likely to succeed, given the estimates set via
+	/// Router::set_channel_success_probability, assuming channels fail independently. Of the
+	/// routes which are equally likely to succeed, the one which pays the lowest fee is used. Our
+	/// own channels are assumed to always succeed.
+	HighestSuccessProbability {
+		/// The probability, in parts per million, assumed for channels we have no estimate for.
+		default_success_probability_ppm: u32,
+	},
+	/// Find the route which minimizes fee_weight * total fee (in msat) plus cltv_weight * total
+	/// CLTV expiry delta (in blocks), trading off cost against how long our funds may be locked
+	/// up. Of the routes with equal weighted cost, the one which pays the lowest fee is used. A
+	/// fee_weight of 1 and a cltv_weight of 0 is equivalent to LowestFee, while a fee_weight of 0
+	/// finds the route with the lowest total CLTV expiry delta.
+	Weighted {
+		/// The weight given to each millisatoshi of fees.
+		fee_weight: u64,
+		/// The weight given to each block of CLTV expiry delta.
+		cltv_weight: u64,
+	},
+}
+
+/// How a route search picks among parallel channels in our network graph, see
+/// RouteParameters::parallel_channel_policy. Channels which aren't usable (eg because they're
+/// disabled or excluded) are never picked, and our first_hops and last_hops aren't affected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParallelChannelPolicy {
+	/// Consider all parallel channels, so that the cheapest one is used.
+	Cheapest,
+	/// Only consider the parallel channels with the highest on-chain (or estimated, see
+	/// Router::get_route_with_capacity_estimator) capacity, with those of unknown capacity ranked
+	/// lowest, eg as larger channels are more likely to have enough liquidity.
+	HighestCapacity,
+	/// Only consider the parallel channels whose policy in the direction we'd use them was updated
+	/// most recently, eg as their policies are least likely to be stale.
+	MostRecentlyUpdated,
+}
+
+impl Default for RouteParameters {
+	fn default() -> Self {
+		RouteParameters {
+			current_height: 0,
+			max_cltv_expiry_height: u32::max_value(),
+			htlc_maximum_penalty_msat: None,
+			objective: RouteObjective::LowestFee,
+			excluded_channels: Vec::new(),
+			channel_policy_change_penalty_msat: 0,
+			max_channel_policy_change_penalty_msat: 0,
+			min_channel_capacity_sat: 0,
+			unknown_capacity_penalty_msat: 0,
+			max_nodes_visited: None,
+			include_non_live_first_hops: false,
+			htlc_slot_penalty_msat: 0,
+			node_penalties: Vec::new(),
+			preferred_nodes: Vec::new(),
+			unreachable_nodes: Vec::new(),
+			locally_disabled_channels: Vec::new(),
+			final_cltv_safety_delta: 0,
+			fee_budget_msat: None,
+			max_fee_proportional_millionths: None,
+			parallel_channel_policy: ParallelChannelPolicy::Cheapest,
+			max_channel_update_age_secs: None,
+			max_hop_fee_share_millionths: None,
+			shortcut_redundant_hops: false,
+			deadline: None,
 		}
 	}
+}
 
-	fn handle_channel_announcement(&self, msg: &msgs::ChannelAnnouncement) -> Result<bool, LightningError> {
-		if msg.contents.node_id_1 == msg.contents.node_id_2 || msg.contents.bitcoin_key_1 == msg.contents.bitcoin_key_2 {
-			return Err(LightningError{err: "Channel announcement node had a channel with itself", action: ErrorAction::IgnoreError});
-		}
+/// The fees charged along a path, as returned by Router::get_path_fee_estimate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathFeeEstimate {
+	/// The sum of the base fees charged by each node along the path.
+	pub fee_base_msat: u64,
+	/// The sum of the proportional fees charged by each node along the path, in millionths of the
+	/// amount forwarded.
+	pub fee_proportional_millionths: u64,
+	/// The sum of the CLTV deltas required by each node along the path, excluding the final CLTV
+	/// delta required by the target.
+	pub cltv_expiry_delta: u32,
+	/// The number of hops in the path, including the hop to the target.
+	pub hop_count: usize,
+}
 
-		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.node_signature_1, &msg.contents.node_id_1);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.node_signature_2, &msg.contents.node_id_2);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.bitcoin_signature_1, &msg.contents.bitcoin_key_1);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.bitcoin_signature_2, &msg.contents.bitcoin_key_2);
+impl PathFeeEstimate {
+	/// Estimates the total fee, in msat, to send amount_msat along the path. As each node's
+	/// proportional fee also applies to the fees of later nodes, this slightly underestimates the
+	/// fees actually paid.
+	pub fn fee_msat(&self, amount_msat: u64) -> u64 {
+		self.fee_base_msat.saturating_add(amount_msat.saturating_mul(self.fee_proportional_millionths) / 1000000)
+	}
+}
 
-		let checked_utxo = match self.chain_monitor.get_chain_utxo(msg.contents.chain_hash, msg.contents.short_channel_id) {
-			Ok((script_pubkey, _value)) => {
-				let expected_script = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
-				                                    .push_slice(&msg.contents.bitcoin_key_1.serialize())
-				                                    .push_slice(&msg.contents.bitcoin_key_2.serialize())
-				                                    .push_opcode(opcodes::all::OP_PUSHNUM_2)
-				                                    .push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh();
-				if script_pubkey != expected_script {
-					return Err(LightningError{err: "Channel announcement keys didn't match on-chain script", action: ErrorAction::IgnoreError});
-				}
-				//TODO: Check if value is worth storing, use it to inform routing, and compare it
-				//to the new HTLC max field in channel_update
-				true
-			},
-			Err(ChainError::NotSupported) => {
-				// Tentatively accept, potentially exposing us to DoS attacks
-				false
-			},
-			Err(ChainError::NotWatched) => {
-				return Err(LightningError{err: "Channel announced on an unknown chain", action: ErrorAction::IgnoreError});
-			},
-			Err(ChainError::UnknownTx) => {
-				return Err(LightningError{err: "Channel announced without corresponding UTXO entry", action: ErrorAction::IgnoreError});
-			},
-		};
+/// Returned by split_value_over_first_hops if our channels cannot carry the full value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InsufficientCapacityError {
+	/// The total outbound capacity, in msat, of the usable channels provided.
+	pub available_msat: u64,
+	/// The amount, in msat, by which available_msat falls short of the value to be sent.
+	pub shortfall_msat: u64,
+}
 
-		let mut network_lock = self.network_map.write().unwrap();
-		let network = &mut *network_lock;
+/// Whether to send a payment over a single path or split it into a multi-path payment, as
+/// recommended by Router::recommend_payment_paths.
+#[derive(Clone, PartialEq)]
+pub enum PaymentPathRecommendation {
+	/// Send the payment over a single route.
+	SinglePath {
+		/// The route to send the payment over.
+		route: Route,
+		/// Why a single path is recommended.
+		reason: PaymentPathReason,
+	},
+	/// Split the payment, sending one share over each route, each of which leaves us over a
+	/// different channel.
+	MultiPath {
+		/// The routes to send the shares of the payment over, whose final hops' fee_msat sum to
+		/// the value of the payment. They're sorted by total fee, and then by length and the
+		/// short_channel_ids of their hops.
+		routes: Vec<Route>,
+		/// Why splitting the payment is recommended.
+		reason: PaymentPathReason,
+	},
+}
 
-		let should_relay = msg.contents.excess_data.is_empty();
+/// Why Router::recommend_payment_paths recommended a PaymentPathRecommendation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaymentPathReason {
+	/// None of our channels can carry the whole payment (including fees) by itself.
+	SinglePathInfeasible,
+	/// The payment (including fees) fits in one of our channels, so there's no need to split it.
+	SplitUnnecessary,
+	/// The payment cannot be split such that our channels can carry each share (including fees).
+	SplitInfeasible,
+	/// Both options are feasible, and this one pays the lowest total fees. A single path is
+	/// recommended if the fees are equal, as it requires fewer HTLCs.
+	LowerFee,
+}
 
-		let chan_info = ChannelInfo {
-				features: msg.contents.features.clone(),
-				one_to_two: DirectionalChannelInfo {
-					src_node_id: msg.contents.node_id_1.clone(),
-					last_update: 0,
-					enabled: false,
-					cltv_expiry_delta: u16::max_value(),
-					htlc_minimum_msat: u64::max_value(),
-					fee_base_msat: u32::max_value(),
-					fee_proportional_millionths: u32::max_value(),
-					last_update_message: None,
-				},
-				two_to_one: DirectionalChannelInfo {
-					src_node_id: msg.contents.node_id_2.clone(),
-					last_update: 0,
-					enabled: false,
-					cltv_expiry_delta: u16::max_value(),
-					htlc_minimum_msat: u64::max_value(),
-					fee_base_msat: u32::max_value(),
-					fee_proportional_millionths: u32::max_value(),
-					last_update_message: None,
-				},
-				announcement_message: if should_relay { Some(msg.clone()) } else { None },
-			};
+/// Returned by split_value_over_first_hops_randomized if value_msat cannot be split as required.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SplitValueError {
+	/// Our channels cannot carry the full value, as for split_value_over_first_hops.
+	InsufficientCapacity(InsufficientCapacityError),
+	/// The shares needed to carry the value cannot each be at least the minimum share amount, as
+	/// the value is too small or one of the channels needed is.
+	MinimumShareUnsatisfiable {
+		/// The number of shares needed to carry the value.
+		share_count: usize,
+	},
+}
 
-		match network.channels.entry(NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash)) {
-			BtreeEntry::Occupied(mut entry) => {
-				//TODO: because asking the blockchain if short_channel_id is valid is only optional
-				//in the blockchain API, we need to handle it smartly here, though it's unclear
-				//exactly how...
-				if checked_utxo {
-					// Either our UTXO provider is busted, there was a reorg, or the UTXO provider
-					// only sometimes returns results. In any case remove the previous entry. Note
-					// that the spec expects us to "blacklist" the node_ids involved, but we can't
-					// do that because
-					// a) we don't *require* a UTXO provider that always returns results.
-					// b) we don't track UTXOs of channels we know about and remove them if they
-					//    get reorg'd out.
-					// c) it's unclear how to do so without exposing ourselves to massive DoS risk.
-					Self::remove_channel_in_nodes(&mut network.nodes, &entry.get(), msg.contents.short_channel_id);
-					*entry.get_mut() = chan_info;
-				} else {
-					return Err(LightningError{err: "Already have knowledge of channel", action: ErrorAction::IgnoreError})
+/// Gets the (short_channel_id, outbound_capacity_msat) of the first hops which may carry a
+/// share of a payment in split_value_over_first_hops, largest-first.
+fn first_hops_by_capacity(first_hops: &[channelmanager::ChannelDetails]) -> Vec<(ShortChannelId, u64)> {
+	let mut channels: Vec<(ShortChannelId, u64)> = first_hops.iter()
+		.filter(|chan| chan.is_live && chan.outbound_capacity_msat > 0)
+		.filter_map(|chan| chan.short_channel_id.map(|scid| (ShortChannelId(scid), chan.outbound_capacity_msat)))
+		.collect();
+	sort_channels_by_capacity(&mut channels);
+	channels
+}
+
+/// Sorts (short_channel_id, capacity_msat) pairs largest-first, with ties broken by
+/// short_channel_id.
+fn sort_channels_by_capacity(channels: &mut Vec<(ShortChannelId, u64)>) {
+	channels.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+}
+
+/// Sorts routes cheapest-first, with ties broken by length and then by the short_channel_ids of
+/// their hops, so that the routes returned together from a search are always in the same order.
+fn sort_routes_by_fee(routes: &mut Vec<Route>) {
+	routes.sort_by(|a, b| {
+		a.total_fees_msat().cmp(&b.total_fees_msat()).then(a.hops.len().cmp(&b.hops.len()))
+			.then_with(|| a.hops.iter().map(|hop| hop.short_channel_id).cmp(b.hops.iter().map(|hop| hop.short_channel_id)))
+	});
+}
+
+/// Splits value_msat over the given (short_channel_id, capacity_msat) pairs, sorted as by
+/// sort_channels_by_capacity, as described in split_value_over_first_hops.
+fn split_value_over_channels(channels: Vec<(ShortChannelId, u64)>, value_msat: u64) -> Result<Vec<(ShortChannelId, u64)>, InsufficientCapacityError> {
+	let available_msat = channels.iter().fold(0u64, |total, chan| total.saturating_add(chan.1));
+	if available_msat < value_msat {
+		return Err(InsufficientCapacityError { available_msat, shortfall_msat: value_msat - available_msat });
+	}
+
+	let mut shares = Vec::new();
+	let mut remaining_msat = value_msat;
+	for (short_channel_id, capacity_msat) in channels {
+		if remaining_msat == 0 { break; }
+		let amount_msat = cmp::min(capacity_msat, remaining_msat);
+		shares.push((short_channel_id, amount_msat));
+		remaining_msat -= amount_msat;
+	}
+	Ok(shares)
+}
+
+/// Proposes a split of value_msat into multi-path payment shares, one per first hop, based only
+/// on the outbound capacity of our channels (eg from ChannelManager::list_usable_channels). Each
+/// share may then be routed separately. Channels are used largest-first (ties broken by
+/// short_channel_id), so that as few shares as possible are used, and channels which are not
+/// live or have no short_channel_id are ignored.
+///
+/// Returns (short_channel_id, amount_msat) pairs which sum to value_msat, or the shortfall if
+/// our channels cannot carry value_msat in total. Note that this does not consider fees, so
+/// callers may wish to leave some headroom.
+pub fn split_value_over_first_hops(first_hops: &[channelmanager::ChannelDetails], value_msat: u64) -> Result<Vec<(ShortChannelId, u64)>, InsufficientCapacityError> {
+	split_value_over_channels(first_hops_by_capacity(first_hops), value_msat)
+}
+
+/// Proposes a split of value_msat over the same channels as split_value_over_first_hops, but
+/// with randomized share amounts, so that the shares of a payment aren't as easily linked to one
+/// another (or to the capacity of our channels) by the amounts they carry. Each share is at
+/// least min_share_msat (and at least 1 msat) and at most its channel's outbound capacity, and
+/// the shares still sum to value_msat exactly.
+///
+/// random_u64 must return uniformly random values, eg from a CSPRNG. Values which would make some
+/// amounts more likely than others are discarded and random_u64 is called again. The first shares
+/// are picked first and may take up any amount which still lets the later ones satisfy their
+/// limits.
+pub fn split_value_over_first_hops_randomized<R: FnMut() -> u64>(first_hops: &[channelmanager::ChannelDetails], value_msat: u64, min_share_msat: u64, mut random_u64: R) -> Result<Vec<(ShortChannelId, u64)>, SplitValueError> {
+	let share_count = split_value_over_first_hops(first_hops, value_msat).map_err(SplitValueError::InsufficientCapacity)?.len();
+	let channels = &first_hops_by_capacity(first_hops)[..share_count];
+	let min_share_msat = cmp::max(min_share_msat, 1);
+	if (share_count as u64).saturating_mul(min_share_msat) > value_msat || channels.iter().any(|chan| chan.1 < min_share_msat) {
+		return Err(SplitValueError::MinimumShareUnsatisfiable { share_count });
+	}
+
+	// Each share leaves enough of the value for the later shares to be at least min_share_msat
+	// and at most the capacity of the later channels, which is always possible as all channels
+	// can carry min_share_msat.
+	let mut shares = Vec::with_capacity(share_count);
+	let mut remaining_msat = value_msat;
+	let mut later_capacity_msat = channels.iter().fold(0u64, |total, chan| total.saturating_add(chan.1));
+	for (idx, &(short_channel_id, capacity_msat)) in channels.iter().enumerate() {
+		later_capacity_msat -= capacity_msat;
+		let later_shares = (share_count - idx - 1) as u64;
+		let amount_msat = if later_shares == 0 {
+			remaining_msat
+		} else {
+			let lowest_msat = cmp::max(min_share_msat, remaining_msat.saturating_sub(later_capacity_msat));
+			let highest_msat = cmp::min(capacity_msat, remaining_msat - later_shares * min_share_msat);
+			// Only values below the largest multiple of the range are used, so that taking them
+			// modulo the range doesn't favor the lower amounts.
+			let range_msat = highest_msat - lowest_msat + 1;
+			let rejected_count = (u64::max_value() % range_msat + 1) % range_msat;
+			loop {
+				let random = random_u64();
+				if random <= u64::max_value() - rejected_count {
+					break lowest_msat + random % range_msat;
 				}
-			},
-			BtreeEntry::Vacant(entry) => {
-				entry.insert(chan_info);
 			}
 		};
+		shares.push((short_channel_id, amount_msat));
+		remaining_msat -= amount_msat;
+	}
+	Ok(shares)
+}
 
-		macro_rules! add_channel_to_node {
-			( $node_id: expr ) => {
-				match network.nodes.entry($node_id) {
-					BtreeEntry::Occupied(node_entry) => {
-						node_entry.into_mut().channels.push(NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash));
-					},
-					BtreeEntry::Vacant(node_entry) => {
-						node_entry.insert(NodeInfo {
-							channels: vec!(NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash)),
-							lowest_inbound_channel_fee_base_msat: u32::max_value(),
-							lowest_inbound_channel_fee_proportional_millionths: u32::max_value(),
-							features: NodeFeatures::empty(),
-							last_update: None,
-							rgb: [0; 3],
-							alias: [0; 32],
-							addresses: Vec::new(),
-							announcement_message: None,
-						});
-					}
-				}
-			};
+/// Tracks a view of the network, receiving updates from peers and generating Routes to
+/// payment destinations.
+///
+/// Router should be registered as a ChainListener with your ChainWatchInterface so that channels
+/// whose funding transactions are reorged out of the chain are forgotten.
+///
+/// The hasher used by Router's internal hash maps and sets may be set via its S parameter, eg
+/// for determinism while fuzzing or to use a faster (non-DoS-resistant) hasher on trusted
+/// devices. It defaults to the standard library's RandomState.
+pub struct Router<S = RandomState> {
+	secp_ctx: Secp256k1<secp256k1::VerifyOnly>,
+	network_map: RwLock<NetworkMap>,
+	full_syncs_requested: AtomicUsize,
+	// Set by the caller to stop batch operations early, see set_cancellation_flag
+	cancellation_flag: RwLock<Option<Arc<AtomicBool>>>,
+	chain_monitor: Arc<ChainWatchInterface>,
+	logger: Arc<Logger>,
+	hasher: PhantomData<S>,
+}
+
+// Version 2 added ChannelInfo::capacity_sats and DirectionalChannelInfo::htlc_maximum_msat, which
+// are read as None from version 1 maps, and the channel score boosts and unverified channels,
+// which are read as empty. Version 1 readers can't skip them, so we write a minimum version of 2.
+const SERIALIZATION_VERSION: u8 = 2;
+const MIN_SERIALIZATION_VERSION: u8 = 2;
+
+/// channel_updates with a timestamp more than this many seconds ahead of our clock are rejected,
+/// as otherwise a single such update would win every future freshness comparison, pinning the
+/// channel's policy.
+const MAX_CHANNEL_UPDATE_FUTURE_SECS: u64 = 60 * 60;
+
+/// The value of all satoshis which will ever exist, in millisatoshis. No route may send more.
+const MAX_VALUE_MSAT: u64 = 21_000_000 * 1_0000_0000 * 1000;
+
+/// The most hops an onion can route a payment over, and thus the most hops any route may have.
+const MAX_PATH_HOPS: usize = 20;
+
+/// The most searches made for a single route when enforcing
+/// RouteParameters::max_hop_fee_share_millionths, each excluding one more channel.
+const MAX_HOP_FEE_SHARE_SEARCHES: usize = 10;
+
+/// Changes to a channel's policy made within this many seconds of each other are counted towards
+/// its volatility, see RouteParameters::channel_policy_change_penalty_msat.
+const CHANNEL_POLICY_CHANGE_WINDOW_SECS: u32 = 24 * 60 * 60;
+
+/// Router::estimate_sync_message_count extrapolates from updates within this many seconds of our
+/// newest one.
+const SYNC_ESTIMATE_WINDOW_SECS: u32 = 24 * 60 * 60;
+
+/// Channels updated within this many seconds of the current time count towards
+/// GossipFreshness::recently_updated_channel_count.
+const GOSSIP_FRESHNESS_WINDOW_SECS: u32 = 24 * 60 * 60;
+
+/// The smallest htlc_minimum_msat we store for a channel. HTLCs for 0 msat are never accepted, so
+/// a channel_update advertising an htlc_minimum_msat of 0 is treated as advertising this instead.
+pub const MIN_HTLC_MINIMUM_MSAT: u64 = 1;
+
+impl<S> Writeable for Router<S> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		writer.write_all(&[SERIALIZATION_VERSION; 1])?;
+		writer.write_all(&[MIN_SERIALIZATION_VERSION; 1])?;
+
+		let network = self.network_map.read().unwrap();
+		network.write(writer)?;
+		Ok(())
+	}
+}
+
+/// Arguments for the creation of a Router that are not deserialized.
+/// At a high-level, the process for deserializing a Router and resuming normal operation is:
+/// 1) Deserialize the Router by filling in this struct and calling <Router>::read(reaser, args).
+/// 2) Register the new Router with your ChainWatchInterface
+pub struct RouterReadArgs {
+	/// The ChainWatchInterface for use in the Router in the future.
+	///
+	/// No calls to the ChainWatchInterface will be made during deserialization.
+	pub chain_monitor: Arc<ChainWatchInterface>,
+	/// The Logger for use in the ChannelManager and which may be used to log information during
+	/// deserialization.
+	pub logger: Arc<Logger>,
+	/// The genesis block hash of the chain whose gossip the Router accepts, as passed to
+	/// Router::new.
+	pub genesis_hash: Sha256dHash,
+}
+
+impl ReadableArgs<RouterReadArgs> for Router {
+	fn read<R: ::std::io::Read>(reader: &mut R, args: RouterReadArgs) -> Result<Router, DecodeError> {
+		Router::read_with_hasher(reader, args)
+	}
+}
+
+impl<S: BuildHasher + Default> Router<S> {
+	/// Deserializes a Router which uses S for its internal maps, as ReadableArgs::read does for
+	/// the default RandomState.
+	pub fn read_with_hasher<R: ::std::io::Read>(reader: &mut R, args: RouterReadArgs) -> Result<Router<S>, DecodeError> {
+		let ver: u8 = Readable::read(reader)?;
+		let min_ver: u8 = Readable::read(reader)?;
+		if min_ver > SERIALIZATION_VERSION {
+			return Err(DecodeError::UnknownVersion);
 		}
+		let mut network_map = NetworkMap::read_versioned(reader, ver)?;
+		network_map.chain_hash = Some(args.genesis_hash);
+		Ok(Router {
+			secp_ctx: Secp256k1::verification_only(),
+			network_map: RwLock::new(network_map),
+			full_syncs_requested: AtomicUsize::new(0),
+			cancellation_flag: RwLock::new(None),
+			chain_monitor: args.chain_monitor,
+			logger: args.logger,
+			hasher: PhantomData,
+		})
+	}
+}
 
-		add_channel_to_node!(msg.contents.node_id_1);
-		add_channel_to_node!(msg.contents.node_id_2);
+fn is_cancelled(cancellation_flag: &Option<Arc<AtomicBool>>) -> bool {
+	cancellation_flag.as_ref().map(|flag| flag.load(Ordering::Acquire)).unwrap_or(false)
+}
 
-		Ok(should_relay)
+/// Why a gossip message was rejected, as passed to the callback given to
+/// Router::handle_gossip_batch_from_peer, eg to score peers by how much invalid gossip they send.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GossipRejectionReason {
+	/// A signature on the message didn't verify.
+	InvalidSignature,
+	/// The message was no newer than what we already have, which honest peers may also send us
+	/// while gossip propagates.
+	Stale,
+	/// The channel's funding output doesn't exist or doesn't pay to the announced bitcoin keys.
+	UtxoMismatch,
+	/// The message was rejected for any other reason, which may not be the peer's fault (eg if we
+	/// couldn't look up the channel's funding output).
+	Other,
+}
+
+/// A gossip message we rejected, with the error we return for it and why, so that
+/// handle_gossip_batch_from_peer doesn't need to tell errors apart by their messages. reason is
+/// None for messages a batch didn't get to because it was cancelled, which isn't a rejection.
+struct GossipError {
+	error: LightningError,
+	reason: Option<GossipRejectionReason>,
+}
+
+impl GossipError {
+	fn new(err: &'static str, reason: GossipRejectionReason) -> GossipError {
+		GossipError { error: LightningError { err, action: ErrorAction::IgnoreError }, reason: Some(reason) }
+	}
+
+	fn cancelled() -> GossipError {
+		GossipError { error: LightningError { err: "Gossip batch was cancelled before processing this message", action: ErrorAction::IgnoreError }, reason: None }
+	}
+}
+
+impl From<GossipError> for LightningError {
+	fn from(e: GossipError) -> LightningError {
+		e.error
+	}
+}
+
+/// The number of messages Router::read_gossip_snapshot applies under a single lock.
+const GOSSIP_SNAPSHOT_BATCH_SIZE: usize = 1024;
+
+/// A gossip message to be applied by Router::handle_gossip_batch.
+#[derive(Clone)]
+pub enum GossipMessage {
+	/// A node_announcement, as for RoutingMessageHandler::handle_node_announcement.
+	NodeAnnouncement(msgs::NodeAnnouncement),
+	/// A channel_announcement, as for RoutingMessageHandler::handle_channel_announcement.
+	ChannelAnnouncement(msgs::ChannelAnnouncement),
+	/// A channel_update, as for RoutingMessageHandler::handle_channel_update.
+	ChannelUpdate(msgs::ChannelUpdate),
+}
+
+macro_rules! secp_verify_sig {
+	( $secp_ctx: expr, $msg: expr, $sig: expr, $pubkey: expr ) => {
+		match $secp_ctx.verify($msg, $sig, $pubkey) {
+			Ok(_) => {},
+			Err(_) => return Err(GossipError::new("Invalid signature from remote node", GossipRejectionReason::InvalidSignature)),
+		}
+	};
+}
+
+impl<S: BuildHasher + Default + Send + Sync> RoutingMessageHandler for Router<S> {
+
+	fn handle_node_announcement(&self, msg: &msgs::NodeAnnouncement) -> Result<bool, LightningError> {
+		self.verify_node_announcement(msg)?;
+		let mut network = self.network_map.write().unwrap();
+		Ok(Self::apply_node_announcement(&mut network, msg)?)
+	}
+
+	fn handle_channel_announcement(&self, msg: &msgs::ChannelAnnouncement) -> Result<bool, LightningError> {
+		let (chain_hash, accept_unverified) = {
+			let network = self.network_map.read().unwrap();
+			(network.chain_hash, network.accept_unverified_channels)
+		};
+		let (capacity_sats, unverified) = self.verify_channel_announcement(msg, chain_hash, accept_unverified)?;
+		let mut network = self.network_map.write().unwrap();
+		Ok(Self::apply_channel_announcement(&mut network, msg, capacity_sats, unverified)?)
 	}
 
 	fn handle_htlc_fail_channel_update(&self, update: &msgs::HTLCFailChannelUpdate) {
@@ -571,6 +2045,8 @@ impl RoutingMessageHandler for Router {
 				let mut network = self.network_map.write().unwrap();
 				if *is_permanent {
 					if let Some(chan) = network.channels.remove(short_channel_id) {
+						network.unverified_channels.remove(short_channel_id);
+						network.channel_policy_changes.remove(short_channel_id);
 						Self::remove_channel_in_nodes(&mut network.nodes, &chan, *short_channel_id);
 					}
 				} else {
@@ -592,85 +2068,16 @@ impl RoutingMessageHandler for Router {
 
 	fn handle_channel_update(&self, msg: &msgs::ChannelUpdate) -> Result<bool, LightningError> {
 		let mut network = self.network_map.write().unwrap();
-		let dest_node_id;
-		let chan_enabled = msg.contents.flags & (1 << 1) != (1 << 1);
-		let chan_was_enabled;
-
-		match network.channels.get_mut(&NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash)) {
-			None => return Err(LightningError{err: "Couldn't find channel for update", action: ErrorAction::IgnoreError}),
-			Some(channel) => {
-				macro_rules! maybe_update_channel_info {
-					( $target: expr) => {
-						if $target.last_update >= msg.contents.timestamp {
-							return Err(LightningError{err: "Update older than last processed update", action: ErrorAction::IgnoreError});
-						}
-						chan_was_enabled = $target.enabled;
-						$target.last_update = msg.contents.timestamp;
-						$target.enabled = chan_enabled;
-						$target.cltv_expiry_delta = msg.contents.cltv_expiry_delta;
-						$target.htlc_minimum_msat = msg.contents.htlc_minimum_msat;
-						$target.fee_base_msat = msg.contents.fee_base_msat;
-						$target.fee_proportional_millionths = msg.contents.fee_proportional_millionths;
-						$target.last_update_message = if msg.contents.excess_data.is_empty() {
-							Some(msg.clone())
-						} else {
-							None
-						};
-					}
-				}
-				let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
-				if msg.contents.flags & 1 == 1 {
-					dest_node_id = channel.one_to_two.src_node_id.clone();
-					secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &channel.two_to_one.src_node_id);
-					maybe_update_channel_info!(channel.two_to_one);
-				} else {
-					dest_node_id = channel.two_to_one.src_node_id.clone();
-					secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &channel.one_to_two.src_node_id);
-					maybe_update_channel_info!(channel.one_to_two);
-				}
-			}
-		}
-
-		if chan_enabled {
-			let node = network.nodes.get_mut(&dest_node_id).unwrap();
-			node.lowest_inbound_channel_fee_base_msat = cmp::min(node.lowest_inbound_channel_fee_base_msat, msg.contents.fee_base_msat);
-			node.lowest_inbound_channel_fee_proportional_millionths = cmp::min(node.lowest_inbound_channel_fee_proportional_millionths, msg.contents.fee_proportional_millionths);
-		} else if chan_was_enabled {
-			let mut lowest_inbound_channel_fee_base_msat = u32::max_value();
-			let mut lowest_inbound_channel_fee_proportional_millionths = u32::max_value();
-
-			{
-				let node = network.nodes.get(&dest_node_id).unwrap();
-
-				for chan_id in node.channels.iter() {
-					let chan = network.channels.get(chan_id).unwrap();
-					if chan.one_to_two.src_node_id == dest_node_id {
-						lowest_inbound_channel_fee_base_msat = cmp::min(lowest_inbound_channel_fee_base_msat, chan.two_to_one.fee_base_msat);
-						lowest_inbound_channel_fee_proportional_millionths = cmp::min(lowest_inbound_channel_fee_proportional_millionths, chan.two_to_one.fee_proportional_millionths);
-					} else {
-						lowest_inbound_channel_fee_base_msat = cmp::min(lowest_inbound_channel_fee_base_msat, chan.one_to_two.fee_base_msat);
-						lowest_inbound_channel_fee_proportional_millionths = cmp::min(lowest_inbound_channel_fee_proportional_millionths, chan.one_to_two.fee_proportional_millionths);
-					}
-				}
-			}
-
-			//TODO: satisfy the borrow-checker without a double-map-lookup :(
-			let mut_node = network.nodes.get_mut(&dest_node_id).unwrap();
-			mut_node.lowest_inbound_channel_fee_base_msat = lowest_inbound_channel_fee_base_msat;
-			mut_node.lowest_inbound_channel_fee_proportional_millionths = lowest_inbound_channel_fee_proportional_millionths;
-		}
-
-		Ok(msg.contents.excess_data.is_empty())
-	}
-
+		Ok(self.apply_channel_update(&mut network, msg)?)
+	}
 
 	fn get_next_channel_announcements(&self, starting_point: u64, batch_amount: u8) -> Vec<(msgs::ChannelAnnouncement, msgs::ChannelUpdate,msgs::ChannelUpdate)> {
 		let mut result = Vec::with_capacity(batch_amount as usize);
 		let network = self.network_map.read().unwrap();
 		let mut iter = network.channels.range(starting_point..);
 		while result.len() < batch_amount as usize {
-			if let Some((_, ref chan)) = iter.next() {
-				if chan.announcement_message.is_some() &&
+			if let Some((chan_id, ref chan)) = iter.next() {
+				if chan.announcement_message.is_some() && !network.unverified_channels.contains_key(chan_id) &&
 						chan.one_to_two.last_update_message.is_some() &&
 						chan.two_to_one.last_update_message.is_some() {
 					result.push((chan.announcement_message.clone().unwrap(),
@@ -721,16 +2128,99 @@ impl RoutingMessageHandler for Router {
 	}
 }
 
-#[derive(Eq, PartialEq)]
+impl<S: BuildHasher + Default + Send + Sync> ChainListener for Router<S> {
+	fn block_connected(&self, _header: &BlockHeader, height: u32, _txn_matched: &[&Transaction], _indexes_of_txn_matched: &[u32]) {
+		// Don't block route searches on every block unless pruning is enabled
+		if self.network_map.read().unwrap().unupdated_channel_grace_blocks.is_none() {
+			return;
+		}
+		let mut network_lock = self.network_map.write().unwrap();
+		let network = &mut *network_lock;
+		let grace_blocks = match network.unupdated_channel_grace_blocks {
+			Some(grace_blocks) => grace_blocks,
+			None => return,
+		};
+		let our_node_id = network.our_node_id.clone();
+		let unupdated_chans: Vec<_> = network.channels.iter().filter(|&(chan_id, chan)| {
+			!chan.has_channel_update() && chan.one_to_two.src_node_id != our_node_id && chan.two_to_one.src_node_id != our_node_id &&
+				scid_utils::block_from_scid(*NetworkMap::get_short_id(chan_id)).saturating_add(grace_blocks) <= height
+		}).map(|(chan_id, _)| chan_id.clone()).collect();
+		for chan_id in unupdated_chans {
+			let chan = network.channels.remove(&chan_id).unwrap();
+			network.unverified_channels.remove(&chan_id);
+			network.channel_policy_changes.remove(NetworkMap::get_short_id(&chan_id));
+			log_trace!(self, "Removing channel {} as we never received a channel_update for it", NetworkMap::get_short_id(&chan_id));
+			Self::remove_channel_in_nodes(&mut network.nodes, &chan, *NetworkMap::get_short_id(&chan_id));
+		}
+	}
+
+	fn block_disconnected(&self, _header: &BlockHeader, disconnected_height: u32) {
+		// The funding transaction's block height is encoded in the upper three bytes of the
+		// short_channel_id, so any channels funded in the disconnected block no longer exist.
+		let mut network_lock = self.network_map.write().unwrap();
+		let network = &mut *network_lock;
+		let reorged_chans: Vec<_> = network.channels.keys().filter(|chan_id| {
+			scid_utils::block_from_scid(*NetworkMap::get_short_id(chan_id)) >= disconnected_height
+		}).cloned().collect();
+		for chan_id in reorged_chans {
+			let chan = network.channels.remove(&chan_id).unwrap();
+			network.unverified_channels.remove(&chan_id);
+			network.channel_policy_changes.remove(NetworkMap::get_short_id(&chan_id));
+			log_trace!(self, "Removing channel {} as its funding transaction was reorged out", NetworkMap::get_short_id(&chan_id));
+			Self::remove_channel_in_nodes(&mut network.nodes, &chan, *NetworkMap::get_short_id(&chan_id));
+		}
+	}
+}
+
+#[derive(Clone, Eq, PartialEq)]
 struct RouteGraphNode {
 	pubkey: PublicKey,
 	lowest_fee_to_peer_through_node: u64,
 	lowest_fee_to_node: u64,
+	// The fees from this node to the target as counted when comparing paths, which is more than
+	// lowest_fee_to_node where inbound discounts were only partially counted, see add_entry.
+	fee_cost_to_node: u64,
+	total_cltv_delta: u32,
+	path_penalty_msat: u64,
+	path_boost_msat: u64,
+	path_failure_cost: u64,
+	// The number of hops from this node to the target, including the hop from it.
+	path_hops: usize,
+	// The cost compared before fees for the RouteParameters::objective in use.
+	objective_cost: u64,
+}
+
+/// The state of a route search, kept separately so that its allocations may be reused by
+/// consecutive searches, see Router::get_routes_batch. Each search clears it before starting.
+struct RouteSearchScratch<S> {
+	targets: BinaryHeap<RouteGraphNode>, //TODO: Do we care about switching to eg Fibbonaci heap?
+	// For each node: the lowest cost to the target through it (as its failure cost and then its
+	// fee-based cost), its lowest inbound fees (for the A* heuristic), the hop it takes towards
+	// the target, and the fee charged by the node that hop goes to (including any inbound fee).
+	dist: HashMap<PublicKey, ((u64, u64), u32, u32, RouteHop, u64), S>,
+	popped_nodes: HashSet<PublicKey, S>,
+	// Our usable first hops by the peer they go to, as (short_channel_id, peer features, HTLC
+	// slots left).
+	first_hop_targets: HashMap<PublicKey, (u64, InitFeatures, u16), S>,
+	// RouteParameters::excluded_channels, which may be long (see Router::k_shortest_paths).
+	excluded_channels: HashSet<ShortChannelId, S>,
+}
+
+impl<S: BuildHasher + Default> RouteSearchScratch<S> {
+	fn new() -> Self {
+		RouteSearchScratch {
+			targets: BinaryHeap::new(),
+			dist: HashMap::with_hasher(S::default()),
+			popped_nodes: HashSet::with_hasher(S::default()),
+			first_hop_targets: HashMap::with_hasher(S::default()),
+			excluded_channels: HashSet::with_hasher(S::default()),
+		}
+	}
 }
 
 impl cmp::Ord for RouteGraphNode {
 	fn cmp(&self, other: &RouteGraphNode) -> cmp::Ordering {
-		other.lowest_fee_to_peer_through_node.cmp(&self.lowest_fee_to_peer_through_node)
+		(other.objective_cost, other.lowest_fee_to_peer_through_node).cmp(&(self.objective_cost, self.lowest_fee_to_peer_through_node))
 			.then_with(|| other.pubkey.serialize().cmp(&self.pubkey.serialize()))
 	}
 }
@@ -741,39 +2231,112 @@ impl cmp::PartialOrd for RouteGraphNode {
 	}
 }
 
+/// Gets the largest amount which may be sent over a channel, given its advertised
+/// htlc_maximum_msat and its capacity. Older channel_updates don't include an htlc_maximum_msat,
+/// in which case we fall back to the capacity, or, if that is unknown, the largest channel which
+/// may be opened without option_support_large_channel.
+fn effective_htlc_maximum_msat(htlc_maximum_msat: Option<u64>, capacity_sats: Option<u64>) -> u64 {
+	let capacity_msat = capacity_sats.unwrap_or(MAX_FUNDING_SATOSHIS).saturating_mul(1000);
+	cmp::min(htlc_maximum_msat.unwrap_or(capacity_msat), capacity_msat)
+}
+
+/// Gets the current UNIX time in seconds, or 0 if the system clock is set before 1970.
+#[cfg(not(feature = "fuzztarget"))]
+fn now_secs() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+/// Fuzzers generate arbitrary timestamps, so don't depend on the system clock for them.
+#[cfg(feature = "fuzztarget")]
+fn now_secs() -> u64 {
+	0
+}
+
+/// Gets the P2WSH script_pubkey of the 2-of-2 multisig funding output a channel_announcement with
+/// the given bitcoin keys must correspond to.
+fn funding_script_pubkey(bitcoin_key_1: &PublicKey, bitcoin_key_2: &PublicKey) -> Script {
+	Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+	              .push_slice(&bitcoin_key_1.serialize())
+	              .push_slice(&bitcoin_key_2.serialize())
+	              .push_opcode(opcodes::all::OP_PUSHNUM_2)
+	              .push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh()
+}
+
+/// Checks that we could actually send over the given route, in that it has at most 20 hops and
+/// the per-hop payloads ChannelManager would build for it fit in the fixed-size onion packet.
+/// Payload sizes depend on each hop's onion format and on the amounts and expiries encoded, so
+/// this is checked against the completed route.
+fn route_fits_in_onion(route: &Route, current_height: u32) -> bool {
+	if route.hops.len() > MAX_PATH_HOPS {
+		return false;
+	}
+	match onion_utils::build_onion_payloads(route, current_height.saturating_add(1)) {
+		Ok((payloads, _, _)) => !onion_utils::route_size_insane(&payloads),
+		// Fee and CLTV overflows are left for ChannelManager to reject when sending
+		Err(_) => true,
+	}
+}
+
+/// Gets the cost of using a channel with the given success probability, in parts per million,
+/// when searching for the route most likely to succeed. The cost is -ln(probability), in
+/// millionths, so that summing costs along a path corresponds to multiplying probabilities.
+fn channel_failure_cost(success_probability_ppm: u32) -> u64 {
+	let success_probability_ppm = cmp::max(1, cmp::min(success_probability_ppm, 1_000_000));
+	((1_000_000f64 / success_probability_ppm as f64).ln() * 1_000_000f64) as u64
+}
+
 struct DummyDirectionalChannelInfo {
 	src_node_id: PublicKey,
 	cltv_expiry_delta: u32,
 	htlc_minimum_msat: u64,
+	htlc_maximum_msat: Option<u64>,
 	fee_base_msat: u32,
 	fee_proportional_millionths: u32,
 }
 
 impl Router {
-	/// Creates a new router with the given node_id to be used as the source for get_route()
-	pub fn new(our_pubkey: PublicKey, chain_monitor: Arc<ChainWatchInterface>, logger: Arc<Logger>) -> Router {
+	/// Creates a new router with the given node_id to be used as the source for get_route(). Only
+	/// channel_announcements and channel_updates for the chain with the given genesis block hash
+	/// are accepted, eg so that separate Routers for mainnet and testnet in one process can't be
+	/// polluted with each other's channels.
+	pub fn new(our_pubkey: PublicKey, genesis_hash: Sha256dHash, chain_monitor: Arc<ChainWatchInterface>, logger: Arc<Logger>) -> Router {
+		Self::new_with_hasher(our_pubkey, genesis_hash, chain_monitor, logger)
+	}
+}
+
+impl<S: BuildHasher + Default> Router<S> {
+	/// Creates a new router, as in Router::new, whose internal hash maps and sets use the hasher
+	/// S.
+	pub fn new_with_hasher(our_pubkey: PublicKey, genesis_hash: Sha256dHash, chain_monitor: Arc<ChainWatchInterface>, logger: Arc<Logger>) -> Router<S> {
+		Self::new_for_chain(our_pubkey, Some(genesis_hash), chain_monitor, logger)
+	}
+
+	/// Creates a new router which accepts gossip for the given chain, or any chain if None.
+	fn new_for_chain(our_pubkey: PublicKey, chain_hash: Option<Sha256dHash>, chain_monitor: Arc<ChainWatchInterface>, logger: Arc<Logger>) -> Router<S> {
 		let mut nodes = BTreeMap::new();
-		nodes.insert(our_pubkey.clone(), NodeInfo {
-			channels: Vec::new(),
-			lowest_inbound_channel_fee_base_msat: u32::max_value(),
-			lowest_inbound_channel_fee_proportional_millionths: u32::max_value(),
-			features: NodeFeatures::empty(),
-			last_update: None,
-			rgb: [0; 3],
-			alias: [0; 32],
-			addresses: Vec::new(),
-			announcement_message: None,
-		});
+		nodes.insert(our_pubkey.clone(), NodeInfo::unannounced());
 		Router {
 			secp_ctx: Secp256k1::verification_only(),
 			network_map: RwLock::new(NetworkMap {
 				channels: BTreeMap::new(),
 				our_node_id: our_pubkey,
 				nodes: nodes,
+				channel_score_boosts: BTreeMap::new(),
+				inbound_fees: BTreeMap::new(),
+				channel_success_probabilities: BTreeMap::new(),
+				channel_policy_changes: BTreeMap::new(),
+				max_channels: None,
+				max_nodes: None,
+				max_channels_per_node: None,
+				chain_hash,
+				accept_unverified_channels: false,
+				unverified_channels: BTreeMap::new(),
+				unupdated_channel_grace_blocks: None,
 			}),
 			full_syncs_requested: AtomicUsize::new(0),
+			cancellation_flag: RwLock::new(None),
 			chain_monitor,
 			logger,
+			hasher: PhantomData,
 		}
 	}
 
@@ -789,657 +2352,3208 @@ impl Router {
 		network.nodes.get(pubkey).map(|n| n.addresses.clone())
 	}
 
-	/// Marks a node as having failed a route. This will avoid re-using the node in routes for now,
-	/// with an exponential decay in node "badness". Note that there is deliberately no
-	/// mark_channel_bad as a node may simply lie and suggest that an upstream channel from it is
-	/// what failed the route and not the node itself. Instead, setting the blamed_upstream_node
-	/// boolean will reduce the penalty, returning the node to usability faster. If the node is
-	/// behaving correctly, it will disable the failing channel and we will use it again next time.
-	pub fn mark_node_bad(&self, _node_id: &PublicKey, _blamed_upstream_node: bool) {
-		unimplemented!();
+	/// Gets a copy of our view of the channel with the given short_channel_id, if we know of it.
+	pub fn get_channel_info(&self, short_channel_id: ShortChannelId) -> Option<ChannelInfo> {
+		let network = self.network_map.read().unwrap();
+		network.channels.get(&short_channel_id.0).cloned()
 	}
 
-	fn remove_channel_in_nodes(nodes: &mut BTreeMap<PublicKey, NodeInfo>, chan: &ChannelInfo, short_channel_id: u64) {
-		macro_rules! remove_from_node {
-			($node_id: expr) => {
-				if let BtreeEntry::Occupied(mut entry) = nodes.entry($node_id) {
-					entry.get_mut().channels.retain(|chan_id| {
-						short_channel_id != *NetworkMap::get_short_id(chan_id)
-					});
-					if entry.get().channels.is_empty() {
-						entry.remove_entry();
-					}
-				} else {
-					panic!("Had channel that pointed to unknown node (ie inconsistent network map)!");
-				}
-			}
-		}
-		remove_from_node!(chan.one_to_two.src_node_id);
-		remove_from_node!(chan.two_to_one.src_node_id);
+	/// Gets the total capacity, in satoshis, of the channels of the given node in our view of the
+	/// network, eg for a node operator to check that their channel_announcements have reached us.
+	/// Channels whose capacity is unknown (eg because our ChainWatchInterface doesn't support UTXO
+	/// lookups) are not included. Returns None if we don't know of the node.
+	pub fn node_total_capacity_sats(&self, node_id: &PublicKey) -> Option<u64> {
+		let network = self.network_map.read().unwrap();
+		let node = network.nodes.get(node_id)?;
+		Some(node.channels.iter().filter_map(|chan_id| network.channels.get(chan_id).and_then(|chan| chan.capacity_sats))
+			.fold(0u64, |total, capacity_sats| total.saturating_add(capacity_sats)))
 	}
 
-	/// Gets a route from us to the given target node.
-	///
-	/// Extra routing hops between known nodes and the target will be used if they are included in
-	/// last_hops.
-	///
-	/// If some channels aren't announced, it may be useful to fill in a first_hops with the
-	/// results from a local ChannelManager::list_usable_channels() call. If it is filled in, our
-	/// (this Router's) view of our local channels will be ignored, and only those in first_hops
-	/// will be used.
-	///
-	/// Panics if first_hops contains channels without short_channel_ids
-	/// (ChannelManager::list_usable_channels will never include such channels).
-	///
-	/// The fees on channels from us to next-hops are ignored (as they are assumed to all be
-	/// equal), however the enabled/disabled bit on such channels as well as the htlc_minimum_msat
-	/// *is* checked as they may change based on the receiving node.
-	pub fn get_route(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32) -> Result<Route, LightningError> {
-		// TODO: Obviously *only* using total fee cost sucks. We should consider weighting by
-		// uptime/success in using a node in the past.
+	/// Gets the node_id of the one node we know of (including ourselves) whose serialized
+	/// (compressed) node_id starts with the given bytes, eg to resolve a truncated node_id from a
+	/// QR code before routing to it. Fails if no node or more than one node matches.
+	pub fn find_node_by_prefix(&self, prefix: &[u8]) -> Result<PublicKey, PrefixMatchError> {
 		let network = self.network_map.read().unwrap();
-
-		if *target == network.our_node_id {
-			return Err(LightningError{err: "Cannot generate a route to ourselves", action: ErrorAction::IgnoreError});
+		let mut matches = network.nodes.keys().filter(|node_id| node_id.serialize().starts_with(prefix));
+		let found = match matches.next() {
+			Some(node_id) => *node_id,
+			None => return Err(PrefixMatchError::NotFound),
+		};
+		let other_matches = matches.count();
+		if other_matches != 0 {
+			return Err(PrefixMatchError::Ambiguous { match_count: other_matches + 1 });
 		}
+		Ok(found)
+	}
 
-		if final_value_msat > 21_000_000 * 1_0000_0000 * 1000 {
-			return Err(LightningError{err: "Cannot generate a route of more value than all existing satoshis", action: ErrorAction::IgnoreError});
+	/// Gets a summary of the current view of the network, eg to monitor the progress of gossip
+	/// sync.
+	pub fn get_network_stats(&self) -> NetworkStats {
+		let network = self.network_map.read().unwrap();
+		let mut stats = NetworkStats {
+			node_count: network.nodes.len(),
+			channel_count: network.channels.len(),
+			usable_channel_count: 0,
+			bidirectional_channel_count: 0,
+			unupdated_channel_count: 0,
+		};
+		for chan in network.channels.values() {
+			if chan.is_usable() {
+				stats.usable_channel_count += 1;
+			}
+			if chan.is_usable_in_both_directions() {
+				stats.bidirectional_channel_count += 1;
+			}
+			if !chan.has_channel_update() {
+				stats.unupdated_channel_count += 1;
+			}
 		}
+		stats
+	}
 
-		// We do a dest-to-source Dijkstra's sorting by each node's distance from the destination
-		// plus the minimum per-HTLC fee to get from it to another node (aka "shitty A*").
-		// TODO: There are a few tweaks we could do, including possibly pre-calculating more stuff
-		// to use as the A* heuristic beyond just the cost to get one node further than the current
-		// one.
-
-		let dummy_directional_info = DummyDirectionalChannelInfo { // used for first_hops routes
-			src_node_id: network.our_node_id.clone(),
-			cltv_expiry_delta: 0,
-			htlc_minimum_msat: 0,
-			fee_base_msat: 0,
-			fee_proportional_millionths: 0,
-		};
+	/// Gets the timestamp of the newest channel_update or node_announcement we've received, if
+	/// any.
+	pub fn newest_update_timestamp(&self) -> Option<u32> {
+		let network = self.network_map.read().unwrap();
+		network.update_timestamps().iter().cloned().max()
+	}
 
-		let mut targets = BinaryHeap::new(); //TODO: Do we care about switching to eg Fibbonaci heap?
-		let mut dist = HashMap::with_capacity(network.nodes.len());
+	/// Gets how recently the channels in our view of the network were updated, eg to alert when we
+	/// have stopped receiving gossip, as routes are then found using outdated fees and may fail.
+	/// On the live network, many channels are updated every day.
+	pub fn get_gossip_freshness(&self) -> GossipFreshness {
+		let now = now_secs();
+		let window_start = now.saturating_sub(GOSSIP_FRESHNESS_WINDOW_SECS as u64);
 
-		let mut first_hop_targets = HashMap::with_capacity(if first_hops.is_some() { first_hops.as_ref().unwrap().len() } else { 0 });
-		if let Some(hops) = first_hops {
-			for chan in hops {
-				let short_channel_id = chan.short_channel_id.expect("first_hops should be filled in with usable channels, not pending ones");
-				if chan.remote_network_id == *target {
-					return Ok(Route {
-						hops: vec![RouteHop {
-							pubkey: chan.remote_network_id,
-							node_features: NodeFeatures::with_known_relevant_init_flags(&chan.counterparty_features),
-							short_channel_id,
-							channel_features: ChannelFeatures::with_known_relevant_init_flags(&chan.counterparty_features),
-							fee_msat: final_value_msat,
-							cltv_expiry_delta: final_cltv,
-						}],
-					});
-				}
-				first_hop_targets.insert(chan.remote_network_id, (short_channel_id, chan.counterparty_features.clone()));
+		let network = self.network_map.read().unwrap();
+		let mut freshness = GossipFreshness {
+			newest_channel_update_timestamp: None,
+			recently_updated_channel_count: 0,
+		};
+		for chan in network.channels.values() {
+			let last_update = cmp::max(chan.one_to_two.last_update, chan.two_to_one.last_update);
+			if last_update == 0 {
+				// We've never received a channel_update for the channel
+				continue;
 			}
-			if first_hop_targets.is_empty() {
-				return Err(LightningError{err: "Cannot route when there are no outbound routes away from us", action: ErrorAction::IgnoreError});
+			if freshness.newest_channel_update_timestamp.map(|newest| last_update > newest).unwrap_or(true) {
+				freshness.newest_channel_update_timestamp = Some(last_update);
+			}
+			if last_update as u64 > window_start {
+				freshness.recently_updated_channel_count += 1;
 			}
 		}
+		freshness
+	}
 
-		macro_rules! add_entry {
-			// Adds entry which goes from the node pointed to by $directional_info to
-			// $dest_node_id over the channel with id $chan_id with fees described in
-			// $directional_info.
-			( $chan_id: expr, $dest_node_id: expr, $directional_info: expr, $chan_features: expr, $starting_fee_msat: expr ) => {
-				//TODO: Explore simply adding fee to hit htlc_minimum_msat
-				if $starting_fee_msat as u64 + final_value_msat >= $directional_info.htlc_minimum_msat {
-					let proportional_fee_millions = ($starting_fee_msat + final_value_msat).checked_mul($directional_info.fee_proportional_millionths as u64);
-					if let Some(new_fee) = proportional_fee_millions.and_then(|part| {
-							($directional_info.fee_base_msat as u64).checked_add(part / 1000000) })
-					{
-						let mut total_fee = $starting_fee_msat as u64;
-						let hm_entry = dist.entry(&$directional_info.src_node_id);
-						let old_entry = hm_entry.or_insert_with(|| {
-							let node = network.nodes.get(&$directional_info.src_node_id).unwrap();
-							(u64::max_value(),
-								node.lowest_inbound_channel_fee_base_msat,
-								node.lowest_inbound_channel_fee_proportional_millionths,
-								RouteHop {
-									pubkey: $dest_node_id.clone(),
-									node_features: NodeFeatures::empty(),
-									short_channel_id: 0,
-									channel_features: $chan_features.clone(),
-									fee_msat: 0,
-									cltv_expiry_delta: 0,
-							})
-						});
-						if $directional_info.src_node_id != network.our_node_id {
-							// Ignore new_fee for channel-from-us as we assume all channels-from-us
-							// will have the same effective-fee
-							total_fee += new_fee;
-							if let Some(fee_inc) = final_value_msat.checked_add(total_fee).and_then(|inc| { (old_entry.2 as u64).checked_mul(inc) }) {
-								total_fee += fee_inc / 1000000 + (old_entry.1 as u64);
-							} else {
-								// max_value means we'll always fail the old_entry.0 > total_fee check
-								total_fee = u64::max_value();
-							}
-						}
-						let new_graph_node = RouteGraphNode {
-							pubkey: $directional_info.src_node_id,
-							lowest_fee_to_peer_through_node: total_fee,
-							lowest_fee_to_node: $starting_fee_msat as u64 + new_fee,
-						};
-						if old_entry.0 > total_fee {
-							targets.push(new_graph_node);
-							old_entry.0 = total_fee;
-							old_entry.3 = RouteHop {
-								pubkey: $dest_node_id.clone(),
-								node_features: NodeFeatures::empty(),
-								short_channel_id: $chan_id.clone(),
-								channel_features: $chan_features.clone(),
-								fee_msat: new_fee, // This field is ignored on the last-hop anyway
-								cltv_expiry_delta: $directional_info.cltv_expiry_delta as u32,
-							}
-						}
-					}
-				}
-			};
+	/// Estimates how many gossip messages we'd have to fetch to catch up with a peer whose newest
+	/// channel_update or node_announcement has the given timestamp, eg to prefer peers which are
+	/// cheap to sync with.
+	///
+	/// This extrapolates the rate at which channels and nodes in our view of the network were
+	/// updated over the day before our newest update, and so is only a rough estimate. It never
+	/// exceeds the number of messages in a full sync of a graph the size of ours, including
+	/// channel_announcements. Returns None if we haven't received any updates to estimate from.
+	pub fn estimate_sync_message_count(&self, peer_newest_timestamp: u32) -> Option<u64> {
+		let network = self.network_map.read().unwrap();
+		let timestamps = network.update_timestamps();
+		let our_newest_timestamp = timestamps.iter().cloned().max()?;
+		if peer_newest_timestamp <= our_newest_timestamp {
+			return Some(0);
 		}
+		let window_start = our_newest_timestamp.saturating_sub(SYNC_ESTIMATE_WINDOW_SECS);
+		let recent_updates = timestamps.iter().filter(|timestamp| **timestamp > window_start).count() as u64;
+		let window_secs = cmp::max(1, our_newest_timestamp - window_start) as u64;
+		let secs_behind = (peer_newest_timestamp - our_newest_timestamp) as u64;
+		let full_sync_messages = network.channels.len() as u64 * 3 + network.nodes.len() as u64;
+		Some(cmp::min(recent_updates.saturating_mul(secs_behind) / window_secs, full_sync_messages))
+	}
 
-		macro_rules! add_entries_to_cheapest_to_target_node {
-			( $node: expr, $node_id: expr, $fee_to_target_msat: expr ) => {
-				if first_hops.is_some() {
-					if let Some(&(ref first_hop, ref features)) = first_hop_targets.get(&$node_id) {
-						add_entry!(first_hop, $node_id, dummy_directional_info, ChannelFeatures::with_known_relevant_init_flags(&features), $fee_to_target_msat);
-					}
-				}
+	/// Gets the short_channel_ids in the given list, eg as received from a peer in a
+	/// reply_channel_range, which we don't yet know of, sorted and without duplicates, so that
+	/// their announcements can be requested from the peer with a query_short_channel_ids.
+	///
+	/// Note that this library does not yet implement the gossip_queries messages themselves, so
+	/// the list must be decoded and the query sent by the caller.
+	pub fn get_missing_short_channel_ids(&self, short_channel_ids: &[ShortChannelId]) -> Vec<ShortChannelId> {
+		let network = self.network_map.read().unwrap();
+		let mut missing: Vec<ShortChannelId> = short_channel_ids.iter().filter(|scid| !network.channels.contains_key(&scid.0)).cloned().collect();
+		missing.sort_unstable();
+		missing.dedup();
+		missing
+	}
 
-				if !$node.features.requires_unknown_bits() {
-					for chan_id in $node.channels.iter() {
-						let chan = network.channels.get(chan_id).unwrap();
-						if !chan.features.requires_unknown_bits() {
-							if chan.one_to_two.src_node_id == *$node_id {
-								// ie $node is one, ie next hop in A* is two, via the two_to_one channel
-								if first_hops.is_none() || chan.two_to_one.src_node_id != network.our_node_id {
-									if chan.two_to_one.enabled {
-										add_entry!(chan_id, chan.one_to_two.src_node_id, chan.two_to_one, chan.features, $fee_to_target_msat);
-									}
-								}
-							} else {
-								if first_hops.is_none() || chan.one_to_two.src_node_id != network.our_node_id {
-									if chan.one_to_two.enabled {
-										add_entry!(chan_id, chan.two_to_one.src_node_id, chan.one_to_two, chan.features, $fee_to_target_msat);
-									}
-								}
-							}
-						}
-					}
+	/// Estimates the amount of memory, in bytes, used by our view of the network, including the
+	/// announcements we store for relaying. This is not exact (eg it ignores the overhead of the
+	/// maps we store nodes and channels in), but grows in proportion to the size of the graph, and
+	/// may be used to decide when to set_graph_size_limits.
+	pub fn estimated_memory_bytes(&self) -> usize {
+		self.network_map.read().unwrap().estimated_memory_bytes()
+	}
+
+	/// Gets the connected component of the network graph containing the given node, ie the set
+	/// of nodes (including node_id itself) which it shares some path of channels with,
+	/// irrespective of the directions in which those channels are currently enabled. Empty if we
+	/// don't know of the node.
+	pub fn component_containing(&self, node_id: &PublicKey) -> HashSet<PublicKey, S> {
+		let network = self.network_map.read().unwrap();
+		network.connected_nodes(node_id)
+	}
+
+	/// Creates a new Router, sharing our ChainWatchInterface and Logger, whose view of the network
+	/// only contains the channels which lie on some walk of at most max_hops channels from us to
+	/// one of the given targets (irrespective of the directions in which they're currently
+	/// enabled), along with their nodes, eg to hand a thin client just the part of the network it
+	/// needs to pay those targets. Walks may pass through a node more than once, so this may
+	/// include channels which no path of at most max_hops channels without loops uses. Targets we
+	/// don't know of are ignored. Which channels we haven't yet verified the UTXO of is copied, but
+	/// settings and other local-only state, such as that set via set_channel_score_boost, are not.
+	pub fn subgraph_toward(&self, targets: &[PublicKey], max_hops: u8) -> Router<S> {
+		let network = self.network_map.read().unwrap();
+		let subgraph = Self::new_for_chain(network.our_node_id.clone(), network.chain_hash, self.chain_monitor.clone(), self.logger.clone());
+		{
+			let mut sub_network = subgraph.network_map.write().unwrap();
+			let our_distances: HashMap<PublicKey, u8, S> = network.hop_distances(&network.our_node_id, max_hops);
+			let target_distances: Vec<HashMap<PublicKey, u8, S>> = targets.iter().map(|target| network.hop_distances(target, max_hops)).collect();
+			let path_len = |from: &PublicKey, to: &PublicKey, target_distances: &HashMap<PublicKey, u8, S>| {
+				match (our_distances.get(from), target_distances.get(to)) {
+					(Some(ours), Some(theirs)) => Some(*ours as u16 + 1 + *theirs as u16),
+					_ => None,
 				}
 			};
+			for (chan_id, chan) in network.channels.iter() {
+				let (node_one, node_two) = (&chan.one_to_two.src_node_id, &chan.two_to_one.src_node_id);
+				let on_path = target_distances.iter().any(|distances| {
+					path_len(node_one, node_two, distances).into_iter().chain(path_len(node_two, node_one, distances))
+						.any(|len| len <= max_hops as u16)
+				});
+				if !on_path {
+					continue;
+				}
+				sub_network.channels.insert(chan_id.clone(), chan.clone());
+				if let Some(unverified) = network.unverified_channels.get(chan_id) {
+					sub_network.unverified_channels.insert(chan_id.clone(), unverified.clone());
+				}
+				for node_id in [node_one, node_two].iter() {
+					let node = network.nodes.get(*node_id).unwrap();
+					sub_network.nodes.entry((*node_id).clone()).or_insert_with(|| NodeInfo { channels: Vec::new(), ..node.clone() })
+						.channels.push(chan_id.clone());
+				}
+			}
+			for target in targets.iter() {
+				if let Some(node) = network.nodes.get(target) {
+					sub_network.nodes.entry(target.clone()).or_insert_with(|| NodeInfo { channels: Vec::new(), ..node.clone() });
+				}
+			}
+			// We may no longer have a node entry for ourselves once our last channel is closed, in
+			// which case we keep the one the new Router started with.
+			if let Some(our_node) = network.nodes.get(&network.our_node_id) {
+				let sub_our_node = sub_network.nodes.get_mut(&network.our_node_id).unwrap();
+				let channels = mem::replace(&mut sub_our_node.channels, Vec::new());
+				*sub_our_node = NodeInfo { channels, ..our_node.clone() };
+			}
 		}
+		subgraph
+	}
 
-		match network.nodes.get(target) {
-			None => {},
-			Some(node) => {
-				add_entries_to_cheapest_to_target_node!(node, target, 0);
-			},
+	/// Gets the number of nodes we know of which are not in our own connected component (see
+	/// component_containing), and thus can never be routed to.
+	pub fn count_nodes_outside_our_component(&self) -> usize {
+		let network = self.network_map.read().unwrap();
+		network.nodes.len() - network.connected_nodes::<S>(&network.our_node_id).len()
+	}
+
+	/// Sets a local-only boost for the channel with the given short_channel_id, eg because we
+	/// have found it to be reliable. When picking a route, the boost is subtracted from the cost of
+	/// using the channel (in either direction), though never by more than the fee it charges, and
+	/// never changes the fees actually paid. Setting a boost of 0 removes it.
+	///
+	/// Boosts are never gossiped, but are persisted along with the rest of the Router, even if we
+	/// don't (yet) know of the channel. They are removed if the channel is evicted, see
+	/// set_graph_size_limits.
+	pub fn set_channel_score_boost(&self, short_channel_id: ShortChannelId, boost_msat: u64) {
+		let mut network = self.network_map.write().unwrap();
+		if boost_msat == 0 {
+			network.channel_score_boosts.remove(&short_channel_id.0);
+		} else {
+			network.channel_score_boosts.insert(short_channel_id.0, boost_msat);
 		}
+	}
 
-		for hop in last_hops.iter() {
-			if first_hops.is_none() || hop.src_node_id != network.our_node_id { // first_hop overrules last_hops
-				if network.nodes.get(&hop.src_node_id).is_some() {
-					if first_hops.is_some() {
-						if let Some(&(ref first_hop, ref features)) = first_hop_targets.get(&hop.src_node_id) {
-							// Currently there are no channel-context features defined, so we are a
-							// bit lazy here. In the future, we should pull them out via our
-							// ChannelManager, but there's no reason to waste the space until we
-							// need them.
-							add_entry!(first_hop, hop.src_node_id, dummy_directional_info, ChannelFeatures::with_known_relevant_init_flags(&features), 0);
-						}
-					}
-					// BOLT 11 doesn't allow inclusion of features for the last hop hints, which
-					// really sucks, cause we're gonna need that eventually.
-					add_entry!(hop.short_channel_id, target, hop, ChannelFeatures::empty(), 0);
-				}
-			}
+	/// Sets an (experimental) inbound fee which node_id charges for forwarding HTLCs it receives
+	/// over the channel with the given short_channel_id, in addition to the fee it advertises for
+	/// the channel it forwards over. Inbound fees may be negative, ie discounts, though the total
+	/// fee a node charges for any forward is never considered to be below zero. When comparing
+	/// paths, a discount is only counted up to the fee charged for the channel it applies to, so
+	/// that no path is cheaper than a path it extends, though routes returned pay the full
+	/// discount.
+	///
+	/// As inbound fees are not (yet) gossiped, they are only known locally and are not persisted.
+	/// Setting both values to 0 removes the inbound fee.
+	pub fn set_inbound_fee(&self, short_channel_id: ShortChannelId, node_id: &PublicKey, base_msat: i32, proportional_millionths: i32) {
+		let mut network = self.network_map.write().unwrap();
+		if base_msat == 0 && proportional_millionths == 0 {
+			network.inbound_fees.remove(&(short_channel_id.0, node_id.clone()));
+		} else {
+			network.inbound_fees.insert((short_channel_id.0, node_id.clone()), (base_msat, proportional_millionths));
 		}
+	}
 
-		while let Some(RouteGraphNode { pubkey, lowest_fee_to_node, .. }) = targets.pop() {
-			if pubkey == network.our_node_id {
-				let mut res = vec!(dist.remove(&network.our_node_id).unwrap().3);
-				loop {
-					if let Some(&(_, ref features)) = first_hop_targets.get(&res.last().unwrap().pubkey) {
-						res.last_mut().unwrap().node_features = NodeFeatures::with_known_relevant_init_flags(&features);
-					} else if let Some(node) = network.nodes.get(&res.last().unwrap().pubkey) {
-						res.last_mut().unwrap().node_features = node.features.clone();
-					} else {
-						// We should be able to fill in features for everything except the last
-						// hop, if the last hop was provided via a BOLT 11 invoice (though we
-						// should be able to extend it further as BOLT 11 does have feature
-						// flags for the last hop node itself).
-						assert!(res.last().unwrap().pubkey == *target);
-					}
-					if res.last().unwrap().pubkey == *target {
-						break;
-					}
+	/// Sets our estimate of the probability, in parts per million, that a payment sent over the
+	/// channel with the given short_channel_id succeeds, for use by
+	/// RouteObjective::HighestSuccessProbability. Estimates above 1_000_000 are treated as
+	/// 1_000_000. Estimates are only known locally and are not persisted. Passing None removes the
+	/// estimate.
+	pub fn set_channel_success_probability(&self, short_channel_id: ShortChannelId, success_probability_ppm: Option<u32>) {
+		let mut network = self.network_map.write().unwrap();
+		match success_probability_ppm {
+			Some(success_probability_ppm) => { network.channel_success_probabilities.insert(short_channel_id.0, success_probability_ppm); },
+			None => { network.channel_success_probabilities.remove(&short_channel_id.0); },
+		}
+	}
 
-					let new_entry = match dist.remove(&res.last().unwrap().pubkey) {
-						Some(hop) => hop.3,
-						None => return Err(LightningError{err: "Failed to find a non-fee-overflowing path to the given destination", action: ErrorAction::IgnoreError}),
-					};
-					res.last_mut().unwrap().fee_msat = new_entry.fee_msat;
-					res.last_mut().unwrap().cltv_expiry_delta = new_entry.cltv_expiry_delta;
-					res.push(new_entry);
-				}
-				res.last_mut().unwrap().fee_msat = final_value_msat;
-				res.last_mut().unwrap().cltv_expiry_delta = final_cltv;
-				let route = Route { hops: res };
-				log_trace!(self, "Got route: {}", log_route!(route));
-				return Ok(route);
-			}
+	/// Removes the restriction to the chain passed to Router::new, so that gossip for any chain is
+	/// accepted as long as our ChainWatchInterface accepts it, eg for test fixtures which mix
+	/// announcements from several chains in one graph. Only available in tests, as Routers are
+	/// otherwise always restricted to their chain.
+	#[cfg(any(test, feature = "fuzztarget"))]
+	pub fn clear_chain_hash(&self) {
+		self.network_map.write().unwrap().chain_hash = None;
+	}
 
-			match network.nodes.get(&pubkey) {
-				None => {},
-				Some(node) => {
-					add_entries_to_cheapest_to_target_node!(node, &pubkey, lowest_fee_to_node);
+	/// Caps the number of channels and/or nodes this Router retains, for use on devices which
+	/// cannot hold the full network graph in memory. Whenever a cap is exceeded, the channels (or
+	/// nodes) which were least-recently updated are forgotten, along with any channels of a
+	/// forgotten node and any nodes left without channels. Our own node, and channels to and nodes
+	/// of our direct peers, are never evicted. Anything set locally for an evicted channel (its
+	/// score boost, success probability and inbound fees) is forgotten along with it.
+	///
+	/// Entries are evicted in batches, down to 15/16ths of the cap (rounded up), so that many
+	/// entries may be added after each eviction before the graph has to be scanned again.
+	///
+	/// Limits are applied immediately, but are not persisted and should be set again after
+	/// deserializing a Router. Passing None removes the corresponding limit.
+	pub fn set_graph_size_limits(&self, max_channels: Option<usize>, max_nodes: Option<usize>) {
+		let mut network_lock = self.network_map.write().unwrap();
+		let network = &mut *network_lock;
+		network.max_channels = max_channels;
+		network.max_nodes = max_nodes;
+		Self::enforce_graph_size_limits(network, None);
+	}
+
+	/// Caps the number of channels any one node (other than ourselves) may have in our graph.
+	/// channel_announcements for new channels of a node which is already at the cap are rejected,
+	/// limiting the memory and route-finding time a single node can consume by announcing an
+	/// implausible number of channels. Channels already in the graph are not affected.
+	///
+	/// The cap is not persisted and should be set again after deserializing a Router. Passing None
+	/// removes it.
+	pub fn set_max_channels_per_node(&self, max_channels_per_node: Option<usize>) {
+		self.network_map.write().unwrap().max_channels_per_node = max_channels_per_node;
+	}
+
+	/// Sets how many blocks after their funding transaction confirmed channels are kept without
+	/// us receiving a channel_update for either direction of them. Such channels are never routed
+	/// over, and once the grace period has passed they're pruned as new blocks are connected,
+	/// except for our own channels. Passing None (the default) keeps them indefinitely, eg for a
+	/// complete view of the announced network.
+	///
+	/// This setting is not persisted and should be set again after deserializing a Router.
+	pub fn set_unupdated_channel_grace_blocks(&self, grace_blocks: Option<u32>) {
+		self.network_map.write().unwrap().unupdated_channel_grace_blocks = grace_blocks;
+	}
+
+	/// Sets a flag which, while set to true, makes long-running batch operations on our view of the
+	/// network stop early, eg so that they don't hold up shutting down. Operations check the flag
+	/// between messages or channels, so our view of the network is always left consistent, with
+	/// the work done so far applied:
+	///  * handle_gossip_batch (and handle_gossip_batch_from_peer) give an error for each message
+	///    it didn't get to, which is not passed to handle_gossip_batch_from_peer's callback,
+	///  * read_gossip_snapshot stops reading and returns the number of messages applied so far,
+	///  * reverify_channels stops looking up UTXOs and applies the results it has.
+	///
+	/// This setting is not persisted and should be set again after deserializing a Router.
+	pub fn set_cancellation_flag(&self, cancellation_flag: Option<Arc<AtomicBool>>) {
+		*self.cancellation_flag.write().unwrap() = cancellation_flag;
+	}
+
+	/// Sets whether channel_announcements are provisionally accepted when our ChainWatchInterface's
+	/// UTXO lookups return ChainError::Unavailable (eg because its backend is down), rather than
+	/// being rejected. Such channels are marked unverified: they are kept in our graph and receive
+	/// channel_updates, but are not routed over or relayed until reverify_channels succeeds in
+	/// looking up their UTXO.
+	///
+	/// This setting is not persisted and should be set again after deserializing a Router, though
+	/// channels which are still unverified remain so.
+	pub fn set_accept_unverified_channels(&self, accept_unverified_channels: bool) {
+		self.network_map.write().unwrap().accept_unverified_channels = accept_unverified_channels;
+	}
+
+	/// Retries the UTXO lookups of channels accepted without one (see
+	/// set_accept_unverified_channels), eg periodically or once our chain backend is reachable
+	/// again. Channels whose UTXO is found and matches their announcement become usable (as do
+	/// channels our ChainWatchInterface no longer supports lookups for, as with any other channel
+	/// in that case), while channels whose UTXO doesn't exist or doesn't match are removed.
+	///
+	/// Returns the number of channels which remain unverified because their lookup is still
+	/// unavailable, or because we were cancelled before looking them up (see
+	/// set_cancellation_flag).
+	pub fn reverify_channels(&self) -> usize {
+		let unverified: Vec<_> = self.network_map.read().unwrap().unverified_channels.iter()
+			.map(|(chan_id, &(chain_hash, bitcoin_key_1, bitcoin_key_2))| (*chan_id, chain_hash, bitcoin_key_1, bitcoin_key_2)).collect();
+		let cancellation_flag = self.cancellation_flag.read().unwrap().clone();
+		// UTXO lookups may be slow, so we don't hold our lock on the network graph for them
+		let mut results = Vec::with_capacity(unverified.len());
+		for (chan_id, chain_hash, bitcoin_key_1, bitcoin_key_2) in unverified {
+			if is_cancelled(&cancellation_flag) { break; }
+			let result = match self.chain_monitor.get_chain_utxo(chain_hash, *NetworkMap::get_short_id(&chan_id)) {
+				Ok((script_pubkey, value)) => {
+					if script_pubkey == funding_script_pubkey(&bitcoin_key_1, &bitcoin_key_2) { Ok(Some(value)) } else { Err(()) }
+				},
+				Err(ChainError::NotSupported) => Ok(None),
+				Err(ChainError::Unavailable) => continue,
+				Err(ChainError::NotWatched) | Err(ChainError::UnknownTx) => Err(()),
+			};
+			results.push((chan_id, bitcoin_key_1, bitcoin_key_2, result));
+		}
+
+		let mut network_lock = self.network_map.write().unwrap();
+		let network = &mut *network_lock;
+		for (chan_id, bitcoin_key_1, bitcoin_key_2, result) in results {
+			// The channel may have been re-announced or removed while we weren't holding the lock
+			match network.unverified_channels.get(&chan_id) {
+				Some(&(_, ref key_1, ref key_2)) if *key_1 == bitcoin_key_1 && *key_2 == bitcoin_key_2 => {},
+				_ => continue,
+			}
+			network.unverified_channels.remove(&chan_id);
+			match result {
+				Ok(capacity_sats) => {
+					if let Some(chan) = network.channels.get_mut(&chan_id) {
+						chan.capacity_sats = capacity_sats;
+					}
+				},
+				Err(()) => {
+					if let Some(chan) = network.channels.remove(&chan_id) {
+						network.channel_policy_changes.remove(NetworkMap::get_short_id(&chan_id));
+						log_trace!(self, "Removing channel {} as its UTXO didn't match its announcement", NetworkMap::get_short_id(&chan_id));
+						Self::remove_channel_in_nodes(&mut network.nodes, &chan, *NetworkMap::get_short_id(&chan_id));
+					}
 				},
 			}
 		}
+		network.unverified_channels.len()
+	}
 
-		Err(LightningError{err: "Failed to find a path to the given destination", action: ErrorAction::IgnoreError})
+	/// Applies a batch of gossip messages in order, as if each had been passed to the corresponding
+	/// RoutingMessageHandler method, but taking our lock on the network graph only once for the
+	/// whole batch, eg for bulk import during initial sync. Signatures on announcements and any
+	/// UTXO lookups are checked before the lock is taken, while channel_updates, which are checked
+	/// against the channels they update, are checked under the lock.
+	///
+	/// Returns the result for each message, as the RoutingMessageHandler methods would, in the
+	/// same order as messages.
+	pub fn handle_gossip_batch(&self, messages: &[GossipMessage]) -> Vec<Result<bool, LightningError>> {
+		self.apply_gossip_batch(messages).into_iter().map(|res| res.map_err(|e| e.error)).collect()
 	}
-}
 
-#[cfg(test)]
-mod tests {
-	use chain::chaininterface;
-	use ln::channelmanager;
-	use ln::router::{Router,NodeInfo,NetworkMap,ChannelInfo,DirectionalChannelInfo,RouteHint};
-	use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
-	use ln::msgs::{ErrorAction, LightningError, RoutingMessageHandler};
-	use util::test_utils;
-	use util::test_utils::TestVecWriter;
-	use util::logger::Logger;
-	use util::ser::{Writeable, Readable};
+	/// Applies a batch of gossip messages as in handle_gossip_batch, giving why each rejected
+	/// message was rejected.
+	fn apply_gossip_batch(&self, messages: &[GossipMessage]) -> Vec<Result<bool, GossipError>> {
+		let (chain_hash, accept_unverified) = {
+			let network = self.network_map.read().unwrap();
+			(network.chain_hash, network.accept_unverified_channels)
+		};
+		let cancellation_flag = self.cancellation_flag.read().unwrap().clone();
+		let mut verified = Vec::with_capacity(messages.len());
+		for message in messages.iter() {
+			if is_cancelled(&cancellation_flag) {
+				verified.push(Err(GossipError::cancelled()));
+				continue;
+			}
+			verified.push(match message {
+				&GossipMessage::NodeAnnouncement(ref msg) => self.verify_node_announcement(msg).map(|_| None),
+				&GossipMessage::ChannelAnnouncement(ref msg) => self.verify_channel_announcement(msg, chain_hash, accept_unverified).map(|res| Some(res)),
+				&GossipMessage::ChannelUpdate(_) => Ok(None),
+			});
+		}
 
-	use bitcoin_hashes::sha256d::Hash as Sha256dHash;
-	use bitcoin_hashes::Hash;
-	use bitcoin::network::constants::Network;
+		let mut network = self.network_map.write().unwrap();
+		let mut results = Vec::with_capacity(messages.len());
+		for (message, verified) in messages.iter().zip(verified.drain(..)) {
+			if is_cancelled(&cancellation_flag) {
+				results.push(Err(GossipError::cancelled()));
+				continue;
+			}
+			results.push(verified.and_then(|utxo_check| match message {
+				&GossipMessage::NodeAnnouncement(ref msg) => Self::apply_node_announcement(&mut network, msg),
+				&GossipMessage::ChannelAnnouncement(ref msg) => {
+					let (capacity_sats, unverified) = utxo_check.unwrap();
+					Self::apply_channel_announcement(&mut network, msg, capacity_sats, unverified)
+				},
+				&GossipMessage::ChannelUpdate(ref msg) => self.apply_channel_update(&mut network, msg),
+			}));
+		}
+		results
+	}
 
-	use hex;
+	/// Applies a batch of gossip messages received from the given peer, as in
+	/// handle_gossip_batch, calling on_rejected with the peer, the reason and the error for each
+	/// message which was rejected. peer may be any identifier the caller uses for its peers.
+	pub fn handle_gossip_batch_from_peer<P: ?Sized, F: FnMut(&P, GossipRejectionReason, &LightningError)>(&self, peer: &P, messages: &[GossipMessage], mut on_rejected: F) -> Vec<Result<bool, LightningError>> {
+		self.apply_gossip_batch(messages).into_iter().map(|res| res.map_err(|e| {
+			if let Some(reason) = e.reason {
+				on_rejected(peer, reason, &e.error);
+			}
+			e.error
+		})).collect()
+	}
 
-	use secp256k1::key::{PublicKey,SecretKey};
-	use secp256k1::All;
-	use secp256k1::Secp256k1;
+	/// Writes the gossip messages backing our view of the network to writer, one message at a time,
+	/// eg to distribute a snapshot of the graph to other nodes without building it in memory. Each
+	/// announced channel's channel_announcement is written followed by its latest channel_updates,
+	/// and then all node_announcements, so that a reader may apply them in order. Only the signed
+	/// messages are written, so recipients can check them as they would any other gossip, and our
+	/// local-only state (eg score boosts) is not included. See read_gossip_snapshot for reading it
+	/// back.
+	///
+	/// The snapshot starts with a u64 message count, followed by each message in its wire format
+	/// (as sent to peers, including its type) prefixed with its u16 length.
+	pub fn write_gossip_snapshot<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		let network = self.network_map.read().unwrap();
+		macro_rules! for_each_message {
+			($action: ident) => {
+				for (chan_id, chan) in network.channels.iter() {
+					if let Some(ref announcement) = chan.announcement_message {
+						if network.unverified_channels.contains_key(chan_id) { continue; }
+						$action!(announcement);
+						for directional_info in [&chan.one_to_two, &chan.two_to_one].iter() {
+							if let Some(ref update) = directional_info.last_update_message {
+								$action!(update);
+							}
+						}
+					}
+				}
+				for node in network.nodes.values() {
+					if let Some(ref announcement) = node.announcement_message {
+						$action!(announcement);
+					}
+				}
+			}
+		}
 
-	use std::sync::Arc;
+		// Messages too long to be sent to a peer can't be relayed, so are skipped
+		let mut message_count = 0u64;
+		macro_rules! count_message {
+			($msg: expr) => {
+				let mut len = LengthCalculatingWriter(0);
+				wire::write($msg, &mut len)?;
+				if len.0 <= u16::max_value() as usize { message_count += 1; }
+			}
+		}
+		for_each_message!(count_message);
+		message_count.write(writer)?;
+
+		macro_rules! write_message {
+			($msg: expr) => {
+				let mut len = LengthCalculatingWriter(0);
+				wire::write($msg, &mut len)?;
+				if len.0 <= u16::max_value() as usize {
+					(len.0 as u16).write(writer)?;
+					wire::write($msg, writer)?;
+				}
+			}
+		}
+		for_each_message!(write_message);
+		Ok(())
+	}
 
-	fn create_router() -> (Secp256k1<All>, PublicKey, Router) {
-		let secp_ctx = Secp256k1::new();
-		let our_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0101010101010101010101010101010101010101010101010101010101010101").unwrap()[..]).unwrap());
-		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
-		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
-		let router = Router::new(our_id, chain_monitor, Arc::clone(&logger));
-		(secp_ctx, our_id, router)
+	/// Reads a snapshot written by write_gossip_snapshot, one message at a time, applying each
+	/// message as if it had been received from a peer (in batches, as by handle_gossip_batch).
+	/// Individual messages which we reject (eg because they're older than what we already have)
+	/// are skipped, while a snapshot which can't be decoded fails, though any messages before the
+	/// failure remain applied. Reading stops early if cancelled, see set_cancellation_flag.
+	///
+	/// Returns the number of messages which were applied.
+	pub fn read_gossip_snapshot<R: ::std::io::Read>(&self, reader: &mut R) -> Result<usize, DecodeError> {
+		let message_count: u64 = Readable::read(reader)?;
+		let mut applied = 0;
+		let mut batch = Vec::with_capacity(cmp::min(message_count, GOSSIP_SNAPSHOT_BATCH_SIZE as u64) as usize);
+		let mut buf = Vec::new();
+		let cancellation_flag = self.cancellation_flag.read().unwrap().clone();
+		for i in 0..message_count {
+			if is_cancelled(&cancellation_flag) { break; }
+			let len: u16 = Readable::read(reader)?;
+			buf.resize(len as usize, 0);
+			reader.read_exact(&mut buf[..])?;
+			batch.push(match wire::read(&mut ::std::io::Cursor::new(&buf[..]))? {
+				wire::Message::ChannelAnnouncement(msg) => GossipMessage::ChannelAnnouncement(msg),
+				wire::Message::ChannelUpdate(msg) => GossipMessage::ChannelUpdate(msg),
+				wire::Message::NodeAnnouncement(msg) => GossipMessage::NodeAnnouncement(msg),
+				_ => return Err(DecodeError::InvalidValue),
+			});
+			if batch.len() == GOSSIP_SNAPSHOT_BATCH_SIZE || i == message_count - 1 {
+				applied += self.handle_gossip_batch(&batch).iter().filter(|res| res.is_ok()).count();
+				batch.clear();
+			}
+		}
+		Ok(applied)
 	}
 
-	#[test]
-	fn route_test() {
-		let (secp_ctx, our_id, router) = create_router();
+	/// Checks the signature on a node_announcement, which doesn't require our view of the network.
+	fn verify_node_announcement(&self, msg: &msgs::NodeAnnouncement) -> Result<(), GossipError> {
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
+		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &msg.contents.node_id);
+		Ok(())
+	}
 
-		// Build network from our_id to node8:
-		//
-		//        -1(1)2-  node1  -1(3)2-
-		//       /                       \
-		// our_id -1(12)2- node8 -1(13)2--- node3
-		//       \                       /
-		//        -1(2)2-  node2  -1(4)2-
-		//
-		//
-		// chan1  1-to-2: disabled
-		// chan1  2-to-1: enabled, 0 fee
-		//
-		// chan2  1-to-2: enabled, ignored fee
-		// chan2  2-to-1: enabled, 0 fee
-		//
-		// chan3  1-to-2: enabled, 0 fee
-		// chan3  2-to-1: enabled, 100 msat fee
-		//
-		// chan4  1-to-2: enabled, 100% fee
-		// chan4  2-to-1: enabled, 0 fee
-		//
-		// chan12 1-to-2: enabled, ignored fee
-		// chan12 2-to-1: enabled, 0 fee
-		//
-		// chan13 1-to-2: enabled, 200% fee
-		// chan13 2-to-1: enabled, 0 fee
-		//
-		//
-		//       -1(5)2- node4 -1(8)2--
-		//       |         2          |
-		//       |       (11)         |
-		//      /          1           \
-		// node3--1(6)2- node5 -1(9)2--- node7 (not in global route map)
-		//      \                      /
-		//       -1(7)2- node6 -1(10)2-
-		//
-		// chan5  1-to-2: enabled, 100 msat fee
-		// chan5  2-to-1: enabled, 0 fee
-		//
-		// chan6  1-to-2: enabled, 0 fee
-		// chan6  2-to-1: enabled, 0 fee
-		//
-		// chan7  1-to-2: enabled, 100% fee
-		// chan7  2-to-1: enabled, 0 fee
-		//
-		// chan8  1-to-2: enabled, variable fee (0 then 1000 msat)
-		// chan8  2-to-1: enabled, 0 fee
-		//
-		// chan9  1-to-2: enabled, 1001 msat fee
-		// chan9  2-to-1: enabled, 0 fee
-		//
-		// chan10 1-to-2: enabled, 0 fee
-		// chan10 2-to-1: enabled, 0 fee
-		//
-		// chan11 1-to-2: enabled, 0 fee
-		// chan11 2-to-1: enabled, 0 fee
+	/// Applies a node_announcement whose signature has been checked by verify_node_announcement.
+	fn apply_node_announcement(network: &mut NetworkMap, msg: &msgs::NodeAnnouncement) -> Result<bool, GossipError> {
+		match network.nodes.get_mut(&msg.contents.node_id) {
+			None => Err(GossipError::new("No existing channels for node_announcement", GossipRejectionReason::Other)),
+			Some(node) => {
+				match node.last_update {
+					Some(last_update) => if last_update >= msg.contents.timestamp {
+						return Err(GossipError::new("Update older than last processed update", GossipRejectionReason::Stale));
+					},
+					None => {},
+				}
 
-		let node1 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0202020202020202020202020202020202020202020202020202020202020202").unwrap()[..]).unwrap());
-		let node2 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0303030303030303030303030303030303030303030303030303030303030303").unwrap()[..]).unwrap());
-		let node3 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0404040404040404040404040404040404040404040404040404040404040404").unwrap()[..]).unwrap());
-		let node4 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0505050505050505050505050505050505050505050505050505050505050505").unwrap()[..]).unwrap());
-		let node5 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0606060606060606060606060606060606060606060606060606060606060606").unwrap()[..]).unwrap());
-		let node6 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0707070707070707070707070707070707070707070707070707070707070707").unwrap()[..]).unwrap());
-		let node7 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0808080808080808080808080808080808080808080808080808080808080808").unwrap()[..]).unwrap());
-		let node8 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0909090909090909090909090909090909090909090909090909090909090909").unwrap()[..]).unwrap());
+				node.features = msg.contents.features.clone();
+				node.last_update = Some(msg.contents.timestamp);
+				node.rgb = msg.contents.rgb;
+				node.alias = msg.contents.alias;
+				node.addresses = msg.contents.addresses.clone();
 
-		let zero_hash = Sha256dHash::hash(&[0; 32]);
+				let should_relay = msg.contents.excess_data.is_empty() && msg.contents.excess_address_data.is_empty();
+				node.announcement_message = if should_relay { Some(msg.clone()) } else { None };
+				Ok(should_relay)
+			}
+		}
+	}
 
-		macro_rules! id_to_feature_flags {
-			// Set the feature flags to the id'th odd (ie non-required) feature bit so that we can
-			// test for it later.
-			($id: expr) => { {
-				let idx = ($id - 1) * 2 + 1;
-				if idx > 8*3 {
-					vec![1 << (idx - 8*3), 0, 0, 0]
-				} else if idx > 8*2 {
-					vec![1 << (idx - 8*2), 0, 0]
-				} else if idx > 8*1 {
-					vec![1 << (idx - 8*1), 0]
-				} else {
-					vec![1 << idx]
-				}
-			} }
+	/// Checks a channel_announcement's signatures and, if our ChainWatchInterface supports it,
+	/// its funding output, returning the channel's capacity if known. chain_hash is the chain we
+	/// accept gossip for, see Router::new.
+	fn verify_channel_announcement(&self, msg: &msgs::ChannelAnnouncement, chain_hash: Option<Sha256dHash>, accept_unverified: bool) -> Result<(Option<u64>, bool), GossipError> {
+		if msg.contents.node_id_1 == msg.contents.node_id_2 {
+			return Err(GossipError::new("Channel announcement node had a channel with itself", GossipRejectionReason::Other));
+		}
+		if msg.contents.bitcoin_key_1 == msg.contents.bitcoin_key_2 {
+			return Err(GossipError::new("Channel announcement used the same bitcoin key for both nodes", GossipRejectionReason::Other));
+		}
+		if chain_hash.map(|chain_hash| chain_hash != msg.contents.chain_hash).unwrap_or(false) {
+			return Err(GossipError::new("Channel announcement was for a different chain", GossipRejectionReason::Other));
 		}
 
-		{
-			let mut network = router.network_map.write().unwrap();
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
+		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.node_signature_1, &msg.contents.node_id_1);
+		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.node_signature_2, &msg.contents.node_id_2);
+		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.bitcoin_signature_1, &msg.contents.bitcoin_key_1);
+		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.bitcoin_signature_2, &msg.contents.bitcoin_key_2);
 
-			network.nodes.insert(node1.clone(), NodeInfo {
-				channels: vec!(NetworkMap::get_key(1, zero_hash.clone()), NetworkMap::get_key(3, zero_hash.clone())),
-				lowest_inbound_channel_fee_base_msat: 100,
-				lowest_inbound_channel_fee_proportional_millionths: 0,
-				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(1)),
-				last_update: Some(1),
-				rgb: [0; 3],
-				alias: [0; 32],
-				addresses: Vec::new(),
-				announcement_message: None,
-			});
-			network.channels.insert(NetworkMap::get_key(1, zero_hash.clone()), ChannelInfo {
-				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(1)),
-				one_to_two: DirectionalChannelInfo {
-					src_node_id: our_id.clone(),
-					last_update: 0,
-					enabled: false,
-					cltv_expiry_delta: u16::max_value(), // This value should be ignored
-					htlc_minimum_msat: 0,
-					fee_base_msat: u32::max_value(), // This value should be ignored
-					fee_proportional_millionths: u32::max_value(), // This value should be ignored
-					last_update_message: None,
-				}, two_to_one: DirectionalChannelInfo {
-					src_node_id: node1.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: 0,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 0,
-					last_update_message: None,
-				},
-				announcement_message: None,
-			});
-			network.nodes.insert(node2.clone(), NodeInfo {
-				channels: vec!(NetworkMap::get_key(2, zero_hash.clone()), NetworkMap::get_key(4, zero_hash.clone())),
-				lowest_inbound_channel_fee_base_msat: 0,
-				lowest_inbound_channel_fee_proportional_millionths: 0,
-				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(2)),
-				last_update: Some(1),
-				rgb: [0; 3],
-				alias: [0; 32],
-				addresses: Vec::new(),
-				announcement_message: None,
-			});
-			network.channels.insert(NetworkMap::get_key(2, zero_hash.clone()), ChannelInfo {
-				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(2)),
-				one_to_two: DirectionalChannelInfo {
-					src_node_id: our_id.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: u16::max_value(), // This value should be ignored
-					htlc_minimum_msat: 0,
-					fee_base_msat: u32::max_value(), // This value should be ignored
-					fee_proportional_millionths: u32::max_value(), // This value should be ignored
-					last_update_message: None,
-				}, two_to_one: DirectionalChannelInfo {
-					src_node_id: node2.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: 0,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 0,
-					last_update_message: None,
-				},
-				announcement_message: None,
-			});
-			network.nodes.insert(node8.clone(), NodeInfo {
-				channels: vec!(NetworkMap::get_key(12, zero_hash.clone()), NetworkMap::get_key(13, zero_hash.clone())),
-				lowest_inbound_channel_fee_base_msat: 0,
-				lowest_inbound_channel_fee_proportional_millionths: 0,
-				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(8)),
-				last_update: Some(1),
-				rgb: [0; 3],
-				alias: [0; 32],
-				addresses: Vec::new(),
-				announcement_message: None,
-			});
-			network.channels.insert(NetworkMap::get_key(12, zero_hash.clone()), ChannelInfo {
-				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(12)),
-				one_to_two: DirectionalChannelInfo {
-					src_node_id: our_id.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: u16::max_value(), // This value should be ignored
-					htlc_minimum_msat: 0,
-					fee_base_msat: u32::max_value(), // This value should be ignored
-					fee_proportional_millionths: u32::max_value(), // This value should be ignored
-					last_update_message: None,
-				}, two_to_one: DirectionalChannelInfo {
-					src_node_id: node8.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: 0,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 0,
-					last_update_message: None,
-				},
-				announcement_message: None,
-			});
-			network.nodes.insert(node3.clone(), NodeInfo {
-				channels: vec!(
-					NetworkMap::get_key(3, zero_hash.clone()),
-					NetworkMap::get_key(4, zero_hash.clone()),
-					NetworkMap::get_key(13, zero_hash.clone()),
-					NetworkMap::get_key(5, zero_hash.clone()),
-					NetworkMap::get_key(6, zero_hash.clone()),
-					NetworkMap::get_key(7, zero_hash.clone())),
-				lowest_inbound_channel_fee_base_msat: 0,
-				lowest_inbound_channel_fee_proportional_millionths: 0,
-				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(3)),
-				last_update: Some(1),
-				rgb: [0; 3],
-				alias: [0; 32],
-				addresses: Vec::new(),
-				announcement_message: None,
-			});
-			network.channels.insert(NetworkMap::get_key(3, zero_hash.clone()), ChannelInfo {
-				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(3)),
-				one_to_two: DirectionalChannelInfo {
-					src_node_id: node1.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (3 << 8) | 1,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 0,
-					last_update_message: None,
-				}, two_to_one: DirectionalChannelInfo {
-					src_node_id: node3.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (3 << 8) | 2,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 100,
-					fee_proportional_millionths: 0,
-					last_update_message: None,
-				},
-				announcement_message: None,
-			});
-			network.channels.insert(NetworkMap::get_key(4, zero_hash.clone()), ChannelInfo {
-				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(4)),
-				one_to_two: DirectionalChannelInfo {
-					src_node_id: node2.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (4 << 8) | 1,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 1000000,
-					last_update_message: None,
-				}, two_to_one: DirectionalChannelInfo {
-					src_node_id: node3.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (4 << 8) | 2,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 0,
-					last_update_message: None,
-				},
-				announcement_message: None,
-			});
-			network.channels.insert(NetworkMap::get_key(13, zero_hash.clone()), ChannelInfo {
-				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(13)),
-				one_to_two: DirectionalChannelInfo {
-					src_node_id: node8.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (13 << 8) | 1,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 2000000,
-					last_update_message: None,
-				}, two_to_one: DirectionalChannelInfo {
-					src_node_id: node3.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (13 << 8) | 2,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 0,
-					last_update_message: None,
-				},
-				announcement_message: None,
-			});
-			network.nodes.insert(node4.clone(), NodeInfo {
-				channels: vec!(NetworkMap::get_key(5, zero_hash.clone()), NetworkMap::get_key(11, zero_hash.clone())),
-				lowest_inbound_channel_fee_base_msat: 0,
-				lowest_inbound_channel_fee_proportional_millionths: 0,
-				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(4)),
-				last_update: Some(1),
-				rgb: [0; 3],
-				alias: [0; 32],
-				addresses: Vec::new(),
-				announcement_message: None,
-			});
-			network.channels.insert(NetworkMap::get_key(5, zero_hash.clone()), ChannelInfo {
-				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(5)),
+		let capacity_sats = match self.chain_monitor.get_chain_utxo(msg.contents.chain_hash, msg.contents.short_channel_id) {
+			Ok((script_pubkey, value)) => {
+				if script_pubkey != funding_script_pubkey(&msg.contents.bitcoin_key_1, &msg.contents.bitcoin_key_2) {
+					return Err(GossipError::new("Channel announcement keys didn't match on-chain script", GossipRejectionReason::UtxoMismatch));
+				}
+				Some(value)
+			},
+			Err(ChainError::NotSupported) => {
+				// Tentatively accept, potentially exposing us to DoS attacks
+				None
+			},
+			Err(ChainError::Unavailable) => {
+				if accept_unverified {
+					return Ok((None, true));
+				}
+				return Err(GossipError::new("Channel announcement UTXO lookup is currently unavailable", GossipRejectionReason::Other));
+			},
+			Err(ChainError::NotWatched) => {
+				return Err(GossipError::new("Channel announced on an unknown chain", GossipRejectionReason::Other));
+			},
+			Err(ChainError::UnknownTx) => {
+				return Err(GossipError::new("Channel announced without corresponding UTXO entry", GossipRejectionReason::UtxoMismatch));
+			},
+		};
+		Ok((capacity_sats, false))
+	}
+
+	/// Applies a channel_announcement which has been checked by verify_channel_announcement,
+	/// tracking it as unverified if its UTXO couldn't be looked up.
+	fn apply_channel_announcement(network: &mut NetworkMap, msg: &msgs::ChannelAnnouncement, capacity_sats: Option<u64>, unverified: bool) -> Result<bool, GossipError> {
+		let should_relay = msg.contents.excess_data.is_empty();
+
+		let checked_utxo = capacity_sats.is_some();
+		let chan_info = ChannelInfo {
+				features: msg.contents.features.clone(),
+				capacity_sats,
 				one_to_two: DirectionalChannelInfo {
-					src_node_id: node3.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (5 << 8) | 1,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 100,
-					fee_proportional_millionths: 0,
-					last_update_message: None,
-				}, two_to_one: DirectionalChannelInfo {
-					src_node_id: node4.clone(),
+					src_node_id: msg.contents.node_id_1.clone(),
 					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (5 << 8) | 2,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 0,
+					enabled: false,
+					cltv_expiry_delta: u16::max_value(),
+					htlc_minimum_msat: u64::max_value(),
+					htlc_maximum_msat: None,
+					fee_base_msat: u32::max_value(),
+					fee_proportional_millionths: u32::max_value(),
 					last_update_message: None,
 				},
-				announcement_message: None,
-			});
-			network.nodes.insert(node5.clone(), NodeInfo {
-				channels: vec!(NetworkMap::get_key(6, zero_hash.clone()), NetworkMap::get_key(11, zero_hash.clone())),
-				lowest_inbound_channel_fee_base_msat: 0,
-				lowest_inbound_channel_fee_proportional_millionths: 0,
-				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(5)),
-				last_update: Some(1),
-				rgb: [0; 3],
-				alias: [0; 32],
-				addresses: Vec::new(),
-				announcement_message: None,
-			});
-			network.channels.insert(NetworkMap::get_key(6, zero_hash.clone()), ChannelInfo {
-				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(6)),
-				one_to_two: DirectionalChannelInfo {
-					src_node_id: node3.clone(),
-					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (6 << 8) | 1,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 0,
-					last_update_message: None,
-				}, two_to_one: DirectionalChannelInfo {
-					src_node_id: node5.clone(),
+				two_to_one: DirectionalChannelInfo {
+					src_node_id: msg.contents.node_id_2.clone(),
 					last_update: 0,
-					enabled: true,
-					cltv_expiry_delta: (6 << 8) | 2,
-					htlc_minimum_msat: 0,
-					fee_base_msat: 0,
-					fee_proportional_millionths: 0,
+					enabled: false,
+					cltv_expiry_delta: u16::max_value(),
+					htlc_minimum_msat: u64::max_value(),
+					htlc_maximum_msat: None,
+					fee_base_msat: u32::max_value(),
+					fee_proportional_millionths: u32::max_value(),
 					last_update_message: None,
 				},
-				announcement_message: None,
-			});
-			network.channels.insert(NetworkMap::get_key(11, zero_hash.clone()), ChannelInfo {
-				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(11)),
+				announcement_message: if should_relay { Some(msg.clone()) } else { None },
+			};
+
+		if let Some(max_channels_per_node) = network.max_channels_per_node {
+			if !network.channels.contains_key(&NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash)) {
+				for node_id in [&msg.contents.node_id_1, &msg.contents.node_id_2].iter() {
+					if **node_id == network.our_node_id { continue; }
+					if let Some(node) = network.nodes.get(*node_id) {
+						if node.channels.len() >= max_channels_per_node {
+							return Err(GossipError::new("Node already has the maximum number of channels", GossipRejectionReason::Other));
+						}
+					}
+				}
+			}
+		}
+
+		match network.channels.entry(NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash)) {
+			BtreeEntry::Occupied(mut entry) => {
+				//TODO: because asking the blockchain if short_channel_id is valid is only optional
+				//in the blockchain API, we need to handle it smartly here, though it's unclear
+				//exactly how...
+				if checked_utxo {
+					// Either our UTXO provider is busted, there was a reorg, or the UTXO provider
+					// only sometimes returns results. In any case remove the previous entry. Note
+					// that the spec expects us to "blacklist" the node_ids involved, but we can't
+					// do that because
+					// a) we don't *require* a UTXO provider that always returns results.
+					// b) we don't track UTXOs of channels we know about and remove them if they
+					//    get reorg'd out.
+					// c) it's unclear how to do so without exposing ourselves to massive DoS risk.
+					Self::remove_channel_in_nodes(&mut network.nodes, &entry.get(), msg.contents.short_channel_id);
+					*entry.get_mut() = chan_info;
+				} else {
+					return Err(GossipError::new("Already have knowledge of channel", GossipRejectionReason::Stale))
+				}
+			},
+			BtreeEntry::Vacant(entry) => {
+				entry.insert(chan_info);
+			}
+		};
+
+		macro_rules! add_channel_to_node {
+			( $node_id: expr ) => {
+				match network.nodes.entry($node_id) {
+					BtreeEntry::Occupied(node_entry) => {
+						node_entry.into_mut().channels.push(NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash));
+					},
+					BtreeEntry::Vacant(node_entry) => {
+						node_entry.insert(NodeInfo::unannounced()).channels.push(NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash));
+					}
+				}
+			};
+		}
+
+		add_channel_to_node!(msg.contents.node_id_1);
+		add_channel_to_node!(msg.contents.node_id_2);
+
+		let chan_id = NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash);
+		if unverified {
+			network.unverified_channels.insert(chan_id, (msg.contents.chain_hash, msg.contents.bitcoin_key_1, msg.contents.bitcoin_key_2));
+		} else {
+			network.unverified_channels.remove(&chan_id);
+		}
+
+		Self::enforce_graph_size_limits(network, Some(msg.contents.short_channel_id));
+
+		// We don't relay channels we haven't checked the UTXO of
+		Ok(should_relay && !unverified)
+	}
+
+	/// Checks and applies a channel_update, whose signature can only be checked against the
+	/// channel it updates.
+	fn apply_channel_update(&self, network: &mut NetworkMap, msg: &msgs::ChannelUpdate) -> Result<bool, GossipError> {
+		// Fuzzers generate arbitrary timestamps, so don't depend on the system clock for them
+		#[cfg(not(feature = "fuzztarget"))]
+		{
+			if msg.contents.timestamp as u64 > now_secs() + MAX_CHANNEL_UPDATE_FUTURE_SECS {
+				return Err(GossipError::new("channel_update timestamp is too far in the future", GossipRejectionReason::Other));
+			}
+		}
+
+		if !network.accepts_chain(&msg.contents.chain_hash) {
+			return Err(GossipError::new("channel_update was for a different chain", GossipRejectionReason::Other));
+		}
+		let dest_node_id;
+		let chan_enabled = msg.contents.flags & (1 << 1) != (1 << 1);
+		let chan_was_enabled;
+		let policy_changed;
+		// flags is message_flags followed by channel_flags. The low bit of message_flags indicates
+		// htlc_maximum_msat is present, which is serialized at the start of what we otherwise
+		// treat as excess data.
+		let message_flags = (msg.contents.flags >> 8) as u8;
+		let htlc_maximum_msat = if message_flags & 1 == 1 {
+			if msg.contents.excess_data.len() < 8 {
+				return Err(GossipError::new("channel_update had htlc_maximum_msat flag set but no htlc_maximum_msat", GossipRejectionReason::Other));
+			}
+			Some(byte_utils::slice_to_be64(&msg.contents.excess_data[0..8]))
+		} else { None };
+		// Only relay updates which don't contain data beyond the fields we understand
+		let known_data_len = if htlc_maximum_msat.is_some() { 8 } else { 0 };
+		let should_relay = msg.contents.excess_data.len() == known_data_len;
+		let htlc_minimum_msat = cmp::max(msg.contents.htlc_minimum_msat, MIN_HTLC_MINIMUM_MSAT);
+
+		match network.channels.get_mut(&NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash)) {
+			None => return Err(GossipError::new("Couldn't find channel for update", GossipRejectionReason::Other)),
+			Some(channel) => {
+				macro_rules! maybe_update_channel_info {
+					( $target: expr) => {
+						// Timestamps must strictly increase, so of two different updates with the same
+						// timestamp, whichever we received first is kept (and relayed), irrespective
+						// of their contents.
+						if $target.last_update >= msg.contents.timestamp {
+							return Err(GossipError::new("Update older than last processed update", GossipRejectionReason::Stale));
+						}
+						chan_was_enabled = $target.enabled;
+						// Updates which only refresh the timestamp, or are the first we've seen for
+						// this direction, don't count as changes.
+						policy_changed = $target.last_update != 0 && ($target.enabled != chan_enabled ||
+							$target.cltv_expiry_delta != msg.contents.cltv_expiry_delta ||
+							$target.htlc_minimum_msat != htlc_minimum_msat ||
+							$target.htlc_maximum_msat != htlc_maximum_msat ||
+							$target.fee_base_msat != msg.contents.fee_base_msat ||
+							$target.fee_proportional_millionths != msg.contents.fee_proportional_millionths);
+						$target.last_update = msg.contents.timestamp;
+						$target.enabled = chan_enabled;
+						$target.cltv_expiry_delta = msg.contents.cltv_expiry_delta;
+						$target.htlc_minimum_msat = htlc_minimum_msat;
+						$target.htlc_maximum_msat = htlc_maximum_msat;
+						$target.fee_base_msat = msg.contents.fee_base_msat;
+						$target.fee_proportional_millionths = msg.contents.fee_proportional_millionths;
+						$target.last_update_message = if should_relay {
+							Some(msg.clone())
+						} else {
+							None
+						};
+					}
+				}
+				let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
+				if msg.contents.flags & 1 == 1 {
+					dest_node_id = channel.one_to_two.src_node_id.clone();
+					secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &channel.two_to_one.src_node_id);
+					maybe_update_channel_info!(channel.two_to_one);
+				} else {
+					dest_node_id = channel.two_to_one.src_node_id.clone();
+					secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &channel.one_to_two.src_node_id);
+					maybe_update_channel_info!(channel.one_to_two);
+				}
+			}
+		}
+
+		if policy_changed {
+			network.record_policy_change(msg.contents.short_channel_id, msg.contents.timestamp);
+		}
+
+		if chan_enabled {
+			let node = network.nodes.get_mut(&dest_node_id).unwrap();
+			node.lowest_inbound_channel_fee_base_msat = cmp::min(node.lowest_inbound_channel_fee_base_msat, msg.contents.fee_base_msat);
+			node.lowest_inbound_channel_fee_proportional_millionths = cmp::min(node.lowest_inbound_channel_fee_proportional_millionths, msg.contents.fee_proportional_millionths);
+		} else if chan_was_enabled {
+			Self::update_lowest_inbound_fees(network, &dest_node_id);
+		}
+
+		Ok(should_relay)
+	}
+
+	/// Recomputes the given node's lowest_inbound_channel_fee_* from the channels it has, eg after
+	/// one of them was disabled or evicted. Does nothing if we no longer know of the node.
+	fn update_lowest_inbound_fees(network: &mut NetworkMap, node_id: &PublicKey) {
+		let mut lowest_inbound_channel_fee_base_msat = u32::max_value();
+		let mut lowest_inbound_channel_fee_proportional_millionths = u32::max_value();
+
+		{
+			let node = match network.nodes.get(node_id) {
+				Some(node) => node,
+				None => return,
+			};
+
+			for chan_id in node.channels.iter() {
+				let chan = network.channels.get(chan_id).unwrap();
+				if chan.one_to_two.src_node_id == *node_id {
+					lowest_inbound_channel_fee_base_msat = cmp::min(lowest_inbound_channel_fee_base_msat, chan.two_to_one.fee_base_msat);
+					lowest_inbound_channel_fee_proportional_millionths = cmp::min(lowest_inbound_channel_fee_proportional_millionths, chan.two_to_one.fee_proportional_millionths);
+				} else {
+					lowest_inbound_channel_fee_base_msat = cmp::min(lowest_inbound_channel_fee_base_msat, chan.one_to_two.fee_base_msat);
+					lowest_inbound_channel_fee_proportional_millionths = cmp::min(lowest_inbound_channel_fee_proportional_millionths, chan.one_to_two.fee_proportional_millionths);
+				}
+			}
+		}
+
+		//TODO: satisfy the borrow-checker without a double-map-lookup :(
+		let mut_node = network.nodes.get_mut(node_id).unwrap();
+		mut_node.lowest_inbound_channel_fee_base_msat = lowest_inbound_channel_fee_base_msat;
+		mut_node.lowest_inbound_channel_fee_proportional_millionths = lowest_inbound_channel_fee_proportional_millionths;
+	}
+
+	/// Evicts least-recently-updated channels and nodes until the network map fits within its size
+	/// limits. The channel with short_channel_id keep_short_channel_id (and its endpoints) is
+	/// never evicted, so that a freshly-announced channel survives until its updates arrive.
+	fn enforce_graph_size_limits(network: &mut NetworkMap, keep_short_channel_id: Option<u64>) {
+		let our_node_id = network.our_node_id.clone();
+		let is_protected_chan = |short_channel_id: u64, chan: &ChannelInfo| {
+			Some(short_channel_id) == keep_short_channel_id ||
+				chan.one_to_two.src_node_id == our_node_id || chan.two_to_one.src_node_id == our_node_id
+		};
+
+		// Finding the least-recently updated entries takes a scan of the whole map, so rather than
+		// scanning again for each entry over a cap, we sort once and evict down to 15/16ths of it,
+		// leaving room for the next 1/16th of insertions without another scan.
+		let eviction_target = |max: usize| max - max / 16;
+
+		// Removes a channel along with everything we were told about it locally (eg its score
+		// boost), and recomputes the lowest inbound fees of its remaining endpoints without it.
+		macro_rules! evict_channel {
+			( $chan_id: expr ) => {
+				let short_channel_id = *NetworkMap::get_short_id(&$chan_id);
+				if let Some(chan) = network.channels.remove(&$chan_id) {
+					network.unverified_channels.remove(&$chan_id);
+					network.channel_policy_changes.remove(&short_channel_id);
+					network.channel_score_boosts.remove(&short_channel_id);
+					network.channel_success_probabilities.remove(&short_channel_id);
+					network.inbound_fees.remove(&(short_channel_id, chan.one_to_two.src_node_id.clone()));
+					network.inbound_fees.remove(&(short_channel_id, chan.two_to_one.src_node_id.clone()));
+					Self::remove_channel_in_nodes(&mut network.nodes, &chan, short_channel_id);
+					Self::update_lowest_inbound_fees(network, &chan.one_to_two.src_node_id);
+					Self::update_lowest_inbound_fees(network, &chan.two_to_one.src_node_id);
+				}
+			}
+		}
+
+		if let Some(max_channels) = network.max_channels {
+			if network.channels.len() > max_channels {
+				let mut candidates: Vec<_> = network.channels.iter()
+					.filter(|&(chan_id, chan)| !is_protected_chan(*NetworkMap::get_short_id(chan_id), chan))
+					.map(|(chan_id, chan)| (cmp::max(chan.one_to_two.last_update, chan.two_to_one.last_update), chan_id.clone()))
+					.collect();
+				// Stable, so ties are still broken by the map's order
+				candidates.sort_by_key(|&(last_update, _)| last_update);
+				let evict_count = network.channels.len() - eviction_target(max_channels);
+				for (_, chan_id) in candidates.into_iter().take(evict_count) {
+					evict_channel!(chan_id);
+				}
+			}
+		}
+
+		if let Some(max_nodes) = network.max_nodes {
+			if network.nodes.len() > max_nodes {
+				let mut candidates: Vec<_> = {
+					let channels = &network.channels;
+					network.nodes.iter()
+						.filter(|&(node_id, node)| *node_id != our_node_id && !node.channels.iter().any(|chan_id| {
+							channels.get(chan_id).map(|chan| is_protected_chan(*NetworkMap::get_short_id(chan_id), chan)).unwrap_or(false)
+						}))
+						.map(|(node_id, node)| (node.last_update, node_id.clone()))
+						.collect()
+				};
+				candidates.sort_by_key(|&(last_update, _)| last_update);
+				let target_nodes = eviction_target(max_nodes);
+				for (_, node_id) in candidates {
+					if network.nodes.len() <= target_nodes {
+						break;
+					}
+					// Nodes may already be gone, if evicting another node removed their last channel
+					let chan_ids = match network.nodes.get(&node_id) {
+						Some(node) => node.channels.clone(),
+						None => continue,
+					};
+					// Removing the node's last channel removes the node itself.
+					for chan_id in chan_ids {
+						evict_channel!(chan_id);
+					}
+					network.nodes.remove(&node_id);
+				}
+			}
+		}
+	}
+
+	/// Marks a node as having failed a route. This will avoid re-using the node in routes for now,
+	/// with an exponential decay in node "badness". Note that there is deliberately no
+	/// mark_channel_bad as a node may simply lie and suggest that an upstream channel from it is
+	/// what failed the route and not the node itself. Instead, setting the blamed_upstream_node
+	/// boolean will reduce the penalty, returning the node to usability faster. If the node is
+	/// behaving correctly, it will disable the failing channel and we will use it again next time.
+	pub fn mark_node_bad(&self, _node_id: &PublicKey, _blamed_upstream_node: bool) {
+		unimplemented!();
+	}
+
+	fn remove_channel_in_nodes(nodes: &mut BTreeMap<PublicKey, NodeInfo>, chan: &ChannelInfo, short_channel_id: u64) {
+		macro_rules! remove_from_node {
+			($node_id: expr) => {
+				if let BtreeEntry::Occupied(mut entry) = nodes.entry($node_id) {
+					entry.get_mut().channels.retain(|chan_id| {
+						short_channel_id != *NetworkMap::get_short_id(chan_id)
+					});
+					if entry.get().channels.is_empty() {
+						entry.remove_entry();
+					}
+				} else {
+					panic!("Had channel that pointed to unknown node (ie inconsistent network map)!");
+				}
+			}
+		}
+		remove_from_node!(chan.one_to_two.src_node_id);
+		remove_from_node!(chan.two_to_one.src_node_id);
+	}
+
+	/// Gets a route from us to the given target node.
+	///
+	/// Extra routing hops between known nodes and the target will be used if they are included in
+	/// last_hops.
+	///
+	/// If some channels aren't announced, it may be useful to fill in a first_hops with the
+	/// results from a local ChannelManager::list_usable_channels() call. If it is filled in, our
+	/// (this Router's) view of our local channels will be ignored, and only those in first_hops
+	/// will be used. This holds for all of Router's route-finding methods which take first_hops:
+	/// the first hop of any route they return is then always one of first_hops. In particular, any
+	/// of our announced channels which also appears in first_hops is used as described there, and
+	/// whatever (possibly stale) policy the graph has for it is ignored.
+	///
+	/// Panics if first_hops contains channels without short_channel_ids
+	/// (ChannelManager::list_usable_channels will never include such channels).
+	///
+	/// The fees on channels from us to next-hops are ignored (as they are assumed to all be
+	/// equal), however the enabled/disabled bit on such channels as well as the htlc_minimum_msat
+	/// *is* checked as they may change based on the receiving node.
+	///
+	/// The final hop's cltv_expiry_delta is always final_cltv (ie the invoice's
+	/// min_final_cltv_expiry), and each earlier hop's is the cltv_expiry_delta of the channel its
+	/// node forwards over, including that of any last_hops hint used. Thus the first HTLC expires
+	/// at the current height plus the sum of all hops' cltv_expiry_delta.
+	///
+	/// Paths with more than 20 hops are never considered, and routes whose per-hop onion payloads
+	/// wouldn't fit in the onion packet are rejected here, rather than failing when the payment is
+	/// sent. As each node only keeps its cheapest path to the target, a shorter path may be missed
+	/// where a cheaper one through the same node was too long.
+	///
+	/// final_value_msat must be non-zero and no more than the value of all satoshis which will ever
+	/// exist, and paths which would require sending more than that, including fees, are not
+	/// considered.
+	pub fn get_route(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32) -> Result<Route, LightningError> {
+		self.get_route_with_params(target, first_hops, last_hops, final_value_msat, final_cltv, &RouteParameters::default())
+	}
+
+	/// Gets a route from us to the given target node, as in get_route, with the search tweaked by
+	/// the given RouteParameters.
+	pub fn get_route_with_params(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters) -> Result<Route, LightningError> {
+		let network = self.network_map.read().unwrap();
+		self.find_route(&network, &network.our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, params, false, None, None)
+	}
+
+	/// Gets a route from us to the given target node, as in get_route_with_capacity_estimator, but
+	/// with the capacity of announced channels limited to the upper bounds on their liquidity
+	/// learned in the given ChannelLiquidityStore.
+	pub fn get_route_with_learned_liquidity(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, liquidity: &ChannelLiquidityStore) -> Result<Route, LightningError> {
+		let now = now_secs();
+
+		let network = self.network_map.read().unwrap();
+		let capacity_estimator = |short_channel_id: u64, from_node_one: bool| {
+			let chan = network.channels.get(&short_channel_id)?;
+			let src_node_id = if from_node_one { &chan.one_to_two.src_node_id } else { &chan.two_to_one.src_node_id };
+			let max_msat = liquidity.liquidity_bounds_msat(ShortChannelId(short_channel_id), src_node_id, now)?.1?;
+			let max_sats = max_msat / 1000;
+			Some(chan.capacity_sats.map(|capacity_sats| cmp::min(capacity_sats, max_sats)).unwrap_or(max_sats))
+		};
+		self.find_route(&network, &network.our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, params, false, Some(&capacity_estimator), None)
+	}
+
+	/// Gets a route from us to the given target node, as in get_route_with_params, but with a
+	/// penalty for routing through each node given by node_cost_adjuster, eg to prefer nodes with
+	/// clearnet addresses over Tor-only ones for latency-sensitive payments.
+	///
+	/// node_cost_adjuster is called with each node in our view of the network and the addresses
+	/// from its latest node_announcement (empty if we haven't received one), and returns a penalty
+	/// in msat, which is capped at max_penalty_msat and then applied as if it were listed in
+	/// params.node_penalties.
+	pub fn get_route_with_node_cost_adjuster<A: Fn(&PublicKey, &[NetAddress]) -> u64>(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, max_penalty_msat: u64, node_cost_adjuster: A) -> Result<Route, LightningError> {
+		let network = self.network_map.read().unwrap();
+		let mut params = params.clone();
+		for (node_id, node) in network.nodes.iter() {
+			let penalty_msat = cmp::min(node_cost_adjuster(node_id, &node.addresses), max_penalty_msat);
+			if penalty_msat != 0 {
+				params.node_penalties.push((*node_id, penalty_msat));
+			}
+		}
+		self.find_route(&network, &network.our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, &params, false, None, None)
+	}
+
+	/// Gets a route from us to the given target node, as in get_route_with_params, but with the
+	/// capacity of announced channels given by capacity_estimator, eg to fold in locally-learned
+	/// estimates of channels' balances without changing our view of the network.
+	///
+	/// capacity_estimator is called with a channel's short_channel_id and whether we'd send over it
+	/// from its node_id_1 (rather than node_id_2), and returns the amount, in satoshis, we expect
+	/// may be sent in that direction. Where it returns None, the channel's on-chain value is used,
+	/// as in get_route. Either way, the channel's htlc_maximum_msat still applies.
+	pub fn get_route_with_capacity_estimator<C: Fn(ShortChannelId, bool) -> Option<u64>>(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, capacity_estimator: C) -> Result<Route, LightningError> {
+		let network = self.network_map.read().unwrap();
+		let capacity_estimator = |short_channel_id: u64, from_node_one: bool| capacity_estimator(ShortChannelId(short_channel_id), from_node_one);
+		self.find_route(&network, &network.our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, params, false, Some(&capacity_estimator), None)
+	}
+
+	/// Gets a route from us to the given target node, as in get_route_with_params, but with the
+	/// last-hop hints yielded by hint_source for the target rather than passed in directly. This
+	/// allows callers with very large sets of private endpoints to only materialize the hints
+	/// relevant to the target.
+	///
+	/// Note that the search walks backwards from the target, so it needs the target's hints before
+	/// it starts. hint_source is thus called exactly once, with the target, before the search (and
+	/// not at all if the target is us), rather than as the search nears the target.
+	pub fn get_route_with_hint_source<F: Fn(&PublicKey) -> Vec<RouteHint>>(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, hint_source: F, final_value_msat: u64, final_cltv: u32, params: &RouteParameters) -> Result<Route, LightningError> {
+		let network = self.network_map.read().unwrap();
+		let mut last_hops = Vec::new();
+		if *target != network.our_node_id {
+			last_hops = hint_source(target);
+		}
+		self.find_route(&network, &network.our_node_id, target, first_hops, &last_hops, final_value_msat, final_cltv, params, false, None, None)
+	}
+
+	/// Gets a route from us to the given target node, as in get_route_with_params. If the search
+	/// fails to find a path, diagnostics is filled in with information about how far the search
+	/// got, which may be useful for debugging.
+	pub fn get_route_with_diagnostics(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, diagnostics: &mut RouteDiagnostics) -> Result<Route, LightningError> {
+		let network = self.network_map.read().unwrap();
+		self.find_route(&network, &network.our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, params, false, None, Some(diagnostics))
+	}
+
+	/// Gets a route from us to the given target node, as in get_route_with_params, filling in
+	/// metrics with how much work the search did, whether or not it finds a route.
+	pub fn get_route_with_metrics(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, metrics: &mut RouteSearchMetrics) -> Result<Route, LightningError> {
+		let start = Instant::now();
+		*metrics = RouteSearchMetrics::default();
+		let network = self.network_map.read().unwrap();
+		let res = self.find_route_with_metrics(&network, &network.our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, params, false, None, None, metrics, &mut RouteSearchScratch::new());
+		metrics.elapsed = start.elapsed();
+		res
+	}
+
+	/// Gets a new route to the destination of previous_route, as in get_route_with_params, after a
+	/// payment over it failed at the channel with the given short_channel_id, which is not used
+	/// again. Rather than searching the whole graph again, the hops up to the node which forwarded
+	/// over the failed channel are kept and only a new path from that node to the destination is
+	/// searched for, with the fees and CLTV deltas of the kept hops updated to match. If that isn't
+	/// possible (eg the failed channel was our own, the new path would revisit a kept node, or a
+	/// kept channel can't carry the new amount), a full search is done instead.
+	///
+	/// As the kept hops are not reconsidered, the resulting route may be more expensive than the
+	/// one a full search would find.
+	pub fn get_route_avoiding_failed_channel(&self, previous_route: &Route, failed_short_channel_id: ShortChannelId, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters) -> Result<Route, LightningError> {
+		let network = self.network_map.read().unwrap();
+		let target = match previous_route.hops.last() {
+			Some(hop) => hop.pubkey,
+			None => return Err(LightningError{err: "Previous route didn't go anywhere", action: ErrorAction::IgnoreError}),
+		};
+		let mut params = params.clone();
+		if !params.excluded_channels.contains(&failed_short_channel_id) {
+			params.excluded_channels.push(failed_short_channel_id);
+		}
+		if let Some(route) = self.reroute_after_failure(&network, previous_route, failed_short_channel_id, first_hops, last_hops, final_value_msat, final_cltv, &params) {
+			log_trace!(self, "Got route by replacing the path after a failure: {}", log_route!(route));
+			return Ok(route);
+		}
+		self.find_route(&network, &network.our_node_id, &target, first_hops, last_hops, final_value_msat, final_cltv, &params, false, None, None)
+	}
+
+	/// Gets the cheapest route from us to the given target, as in get_route_with_params, which uses
+	/// at least one of the channels with the given short_channel_ids, eg because payments must go
+	/// through a partner's channels. Required channels must be in our network graph or first_hops,
+	/// rather than only in last_hops.
+	///
+	/// If the cheapest unconstrained route uses a required channel it is returned as-is.
+	/// Otherwise, for each direction of each required channel, the cheapest path from the
+	/// channel's far end to the target and the cheapest path from us to its near end (avoiding the
+	/// nodes of the former) are joined, as in k_shortest_paths, and the cheapest result is used.
+	/// As the path to each required channel is searched for the amount the channel carries, before
+	/// the fee its near end charges, the result may be slightly more expensive than the cheapest
+	/// possible route. Such joined routes are not found while inbound fees (see set_inbound_fee)
+	/// are set.
+	pub fn get_route_via_channels(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, required_short_channel_ids: &[ShortChannelId]) -> Result<Route, LightningError> {
+		let network = self.network_map.read().unwrap();
+		let our_node_id = network.our_node_id;
+		if let Ok(route) = self.find_route(&network, &our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, params, false, None, None) {
+			if route.hops.iter().any(|hop| required_short_channel_ids.contains(&hop.short_channel_id)) {
+				return Ok(route);
+			}
+		}
+
+		let mut best_route: Option<Route> = None;
+		for &short_channel_id in required_short_channel_ids {
+			if params.excluded_channels.contains(&short_channel_id) {
+				continue;
+			}
+			// The nodes each direction of the channel we may route over starts from
+			let mut src_node_ids = Vec::new();
+			if let Some(chan) = network.channels.get(&short_channel_id.0) {
+				src_node_ids.push(chan.one_to_two.src_node_id);
+				src_node_ids.push(chan.two_to_one.src_node_id);
+			}
+			if first_hops.map(|hops| hops.iter().any(|chan| chan.short_channel_id == Some(short_channel_id.0))).unwrap_or(false) {
+				src_node_ids.push(our_node_id);
+			}
+
+			for src_node_id in src_node_ids {
+				if src_node_id == *target {
+					continue;
+				}
+				let candidate = self.route_via_channel(&network, &src_node_id, short_channel_id, target, first_hops, last_hops, final_value_msat, final_cltv, params);
+				if let Some(candidate) = candidate {
+					let route_key = |route: &Route| (route.total_fees_msat(), route.hops.len(), route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>());
+					if best_route.as_ref().map(|best_route| route_key(&candidate) < route_key(best_route)).unwrap_or(true) {
+						best_route = Some(candidate);
+					}
+				}
+			}
+		}
+		match best_route {
+			Some(route) => {
+				log_trace!(self, "Got route through a required channel: {}", log_route!(route));
+				Ok(route)
+			},
+			None => Err(LightningError{err: "Failed to find a path to the given destination using any of the required channels", action: ErrorAction::IgnoreError}),
+		}
+	}
+
+	/// Builds a route for get_route_via_channels which leaves src_node_id over the channel with the
+	/// given short_channel_id, by searching for a path from src_node_id which may only start with
+	/// that channel and joining a path from us to src_node_id in front of it.
+	fn route_via_channel(&self, network: &NetworkMap, src_node_id: &PublicKey, short_channel_id: ShortChannelId, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters) -> Option<Route> {
+		let our_node_id = network.our_node_id;
+		let mut tail_exclusions: HashSet<ShortChannelId, S> = HashSet::with_hasher(S::default());
+		Self::exclude_node_channels(network, &mut tail_exclusions, first_hops, last_hops, src_node_id, Some(short_channel_id));
+		if *src_node_id == our_node_id {
+			let mut tail_params = params.clone();
+			tail_params.excluded_channels.extend(tail_exclusions.into_iter());
+			let route = self.find_route(network, &our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, &tail_params, false, None, None).ok()?;
+			return if route.hops[0].short_channel_id == short_channel_id { Some(route) } else { None };
+		}
+		// Don't let the path after the channel come back through us
+		Self::exclude_node_channels(network, &mut tail_exclusions, first_hops, last_hops, &our_node_id, None);
+		let mut tail_params = params.clone();
+		tail_params.excluded_channels.extend(tail_exclusions.into_iter());
+		let tail = self.find_route(network, src_node_id, target, None, last_hops, final_value_msat, final_cltv, &tail_params, false, None, None).ok()?;
+		if tail.hops[0].short_channel_id != short_channel_id {
+			return None;
+		}
+
+		let mut head_exclusions: HashSet<ShortChannelId, S> = HashSet::with_hasher(S::default());
+		for hop in tail.hops.iter() {
+			Self::exclude_node_channels(network, &mut head_exclusions, first_hops, last_hops, &hop.pubkey, None);
+		}
+		let mut head_params = params.clone();
+		head_params.excluded_channels.extend(head_exclusions.into_iter());
+		// The head ends at src_node_id rather than the target, so has no final CLTV delta
+		head_params.final_cltv_safety_delta = 0;
+		let tail_amount_msat = tail.hops.iter().fold(0u64, |total, hop| total.saturating_add(hop.fee_msat));
+		let head = self.find_route(network, &our_node_id, src_node_id, first_hops, &[], tail_amount_msat, 0, &head_params, false, None, None).ok()?;
+		Self::splice_route(network, &head.hops, tail, first_hops, params)
+	}
+
+	/// Adds the channels of the given node to excluded_channels, including those from first_hops
+	/// (if it's us) and last_hops, except for except_short_channel_id, so that a search excluding
+	/// them can't pass through the node or, if the search starts there, may only leave over that
+	/// one channel.
+	fn exclude_node_channels(network: &NetworkMap, excluded_channels: &mut HashSet<ShortChannelId, S>, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], node_id: &PublicKey, except_short_channel_id: Option<ShortChannelId>) {
+		let mut short_channel_ids = Vec::new();
+		if let Some(node) = network.nodes.get(node_id) {
+			short_channel_ids.extend(node.channels.iter().map(|chan_id| ShortChannelId(*NetworkMap::get_short_id(chan_id))));
+		}
+		if *node_id == network.our_node_id {
+			if let Some(hops) = first_hops {
+				short_channel_ids.extend(hops.iter().filter_map(|chan| chan.short_channel_id.map(ShortChannelId)));
+			}
+		}
+		short_channel_ids.extend(last_hops.iter().filter(|hint| hint.src_node_id == *node_id).map(|hint| hint.short_channel_id));
+		excluded_channels.extend(short_channel_ids.into_iter().filter(|short_channel_id| Some(*short_channel_id) != except_short_channel_id));
+	}
+
+	/// Gets routes for a batch of payments, given as (target, final_value_msat, final_cltv) tuples,
+	/// with other arguments as for get_route_with_params. The network graph is locked once for the
+	/// whole batch, so all routes are found on the same view of the network, and the searches for
+	/// all requests reuse the same allocations for their state (eg the per-node costs, the queue of
+	/// nodes to visit and the indexes of first hops and excluded channels), rather than each
+	/// allocating and growing its own. A request is searched again without its first hop if that
+	/// hop can't carry the route's fees too.
+	///
+	/// Requests are routed in order, and the amount (including fees) and HTLC slot each route uses
+	/// on its first hop are reserved, so that later requests in the batch don't over-allocate our
+	/// channels' outbound capacity (see ChannelDetails::outbound_capacity_msat and
+	/// ChannelDetails::outbound_htlc_slots). A request which fails doesn't reserve anything. Unlike
+	/// get_route, first hops which can't carry a payment and its fees are never used. Results are
+	/// returned in the same order as their requests, rather than sorted.
+	pub fn get_routes_batch(&self, requests: &[(PublicKey, u64, u32)], first_hops: &[channelmanager::ChannelDetails], last_hops: &[RouteHint], params: &RouteParameters) -> Vec<Result<Route, LightningError>> {
+		let network = self.network_map.read().unwrap();
+		let our_node_id = network.our_node_id;
+		let mut scratch = RouteSearchScratch::new();
+		// (short_channel_id, outbound_capacity_msat, outbound_htlc_slots) left for later requests
+		let mut first_hop_capacities: Vec<(ShortChannelId, u64, u16)> = first_hops.iter()
+			.filter_map(|chan| chan.short_channel_id.map(|scid| (ShortChannelId(scid), chan.outbound_capacity_msat, chan.outbound_htlc_slots)))
+			.collect();
+
+		requests.iter().map(|&(ref target, final_value_msat, final_cltv)| {
+			let mut request_params = params.clone();
+			request_params.excluded_channels.extend(first_hop_capacities.iter()
+				.filter(|&&(_, capacity_msat, htlc_slots)| capacity_msat < final_value_msat || htlc_slots == 0).map(|chan| chan.0));
+			loop {
+				let route = self.find_route_with_metrics(&network, &our_node_id, target, Some(first_hops), last_hops, final_value_msat, final_cltv, &request_params, false, None, None, &mut RouteSearchMetrics::default(), &mut scratch)?;
+				let amount_msat = route.hops.iter().fold(0u64, |total, hop| total.saturating_add(hop.fee_msat));
+				let first_hop = first_hop_capacities.iter_mut().find(|chan| chan.0 == route.hops[0].short_channel_id).unwrap();
+				if amount_msat > first_hop.1 {
+					// Fees took the amount over what the channel has left, so try without it
+					request_params.excluded_channels.push(first_hop.0);
+					continue;
+				}
+				first_hop.1 -= amount_msat;
+				first_hop.2 -= 1;
+				return Ok(route);
+			}
+		}).collect()
+	}
+
+	/// Recommends whether to send the given payment over a single path or to split it over several
+	/// of our channels as a multi-path payment, with arguments as for get_route_with_params, eg so
+	/// that wallets don't have to implement the comparison themselves. Unlike get_route, this
+	/// ensures each route's first hop has enough outbound capacity to carry the amount sent over it,
+	/// including fees.
+	///
+	/// The best single path is the cheapest one over any of first_hops which can carry the payment.
+	/// To split the payment, the outbound capacity of each channel is first reduced by the fees of
+	/// routing that much over it, and the value is then split as in split_value_over_first_hops, so
+	/// that as few shares as possible are used. Fails if neither option is feasible.
+	pub fn recommend_payment_paths(&self, target: &PublicKey, first_hops: &[channelmanager::ChannelDetails], last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters) -> Result<PaymentPathRecommendation, LightningError> {
+		let network = self.network_map.read().unwrap();
+		let our_node_id = network.our_node_id;
+		let channels = first_hops_by_capacity(first_hops);
+		// Finds the cheapest route for amount_msat which leaves us over the given channel, ignoring
+		// the channel's outbound capacity
+		let route_over = |short_channel_id: ShortChannelId, amount_msat: u64| {
+			let mut channel_params = params.clone();
+			channel_params.excluded_channels.extend(channels.iter().map(|chan| chan.0).filter(|other_short_channel_id| *other_short_channel_id != short_channel_id));
+			self.find_route(&network, &our_node_id, target, Some(first_hops), last_hops, amount_msat, final_cltv, &channel_params, false, None, None).ok()
+				.and_then(|route| if route.hops[0].short_channel_id == short_channel_id { Some(route) } else { None })
+		};
+		// As route_over, but only if the channel can carry the payment and fees
+		let capacity_of = |short_channel_id: ShortChannelId| channels.iter().find(|chan| chan.0 == short_channel_id).unwrap().1;
+		let route_within_capacity = |short_channel_id: ShortChannelId, amount_msat: u64| {
+			route_over(short_channel_id, amount_msat).and_then(|route| {
+				if amount_msat.saturating_add(route.total_fees_msat()) <= capacity_of(short_channel_id) { Some(route) } else { None }
+			})
+		};
+
+		let mut single_path: Option<Route> = None;
+		for &(short_channel_id, _) in channels.iter().filter(|chan| chan.1 >= final_value_msat) {
+			if let Some(route) = route_within_capacity(short_channel_id, final_value_msat) {
+				if single_path.as_ref().map(|best_route| route.total_fees_msat() < best_route.total_fees_msat()).unwrap_or(true) {
+					single_path = Some(route);
+				}
+			}
+		}
+
+		let mut usable_capacities: Vec<(ShortChannelId, u64)> = channels.iter().filter_map(|&(short_channel_id, capacity_msat)| {
+			route_over(short_channel_id, capacity_msat).map(|route| (short_channel_id, capacity_msat.saturating_sub(route.total_fees_msat())))
+		}).filter(|chan| chan.1 > 0).collect();
+		sort_channels_by_capacity(&mut usable_capacities);
+		let mut split_reason = PaymentPathReason::SplitInfeasible;
+		let mut multi_path: Option<Vec<Route>> = None;
+		if let Ok(shares) = split_value_over_channels(usable_capacities, final_value_msat) {
+			if shares.len() == 1 {
+				split_reason = PaymentPathReason::SplitUnnecessary;
+			} else {
+				multi_path = shares.iter().map(|&(short_channel_id, amount_msat)| route_within_capacity(short_channel_id, amount_msat)).collect();
+			}
+		}
+		if let Some(ref mut routes) = multi_path {
+			sort_routes_by_fee(routes);
+		}
+
+		let total_fees_msat = |routes: &[Route]| routes.iter().fold(0u64, |total, route| total.saturating_add(route.total_fees_msat()));
+		match (single_path, multi_path) {
+			(Some(route), Some(routes)) => {
+				if total_fees_msat(&routes) < route.total_fees_msat() {
+					Ok(PaymentPathRecommendation::MultiPath { routes, reason: PaymentPathReason::LowerFee })
+				} else {
+					Ok(PaymentPathRecommendation::SinglePath { route, reason: PaymentPathReason::LowerFee })
+				}
+			},
+			(Some(route), None) => Ok(PaymentPathRecommendation::SinglePath { route, reason: split_reason }),
+			(None, Some(routes)) => Ok(PaymentPathRecommendation::MultiPath { routes, reason: PaymentPathReason::SinglePathInfeasible }),
+			(None, None) => Err(LightningError{err: "Failed to find a path to the given destination which our channels can carry, even when splitting the payment", action: ErrorAction::IgnoreError}),
+		}
+	}
+
+	/// Gets a route from the given source node to us, eg to pre-negotiate liquidity for a payment
+	/// which will be returned to us. The resulting route is what the source node would use (ie its
+	/// first hop is a channel from the source) and its final hop is us, with final_cltv as its
+	/// CLTV delta.
+	///
+	/// Routing hints for our own private channels may be provided in last_hops, with us as the
+	/// implied target. As the route will not be sent by us, no first_hops may be provided and fees
+	/// on channels from the source are ignored.
+	pub fn get_route_to_us(&self, source: &PublicKey, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters) -> Result<Route, LightningError> {
+		let network = self.network_map.read().unwrap();
+		self.find_route(&network, source, &network.our_node_id, None, last_hops, final_value_msat, final_cltv, params, false, None, None)
+	}
+
+	/// Gets up to k of the cheapest distinct routes from us to the given target, with arguments as
+	/// for get_route_with_params, sorted by total fee (and then by length and the short_channel_ids
+	/// of their hops), eg to analyze how
+	/// diverse the paths to a node are. Uses Yen's algorithm: each route after the first is found
+	/// by keeping a prefix of a previous route and searching for a new path from its last node
+	/// which avoids the nodes of the prefix and the channels previous routes took next.
+	///
+	/// Each route independently satisfies the HTLC limits of its channels for final_value_msat.
+	/// Routes diverging from one another after our own channels are not found while inbound fees
+	/// (see set_inbound_fee) are set. Returns an error only if no route exists, and no routes at
+	/// all if k is 0.
+	pub fn k_shortest_paths(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, k: usize) -> Result<Vec<Route>, LightningError> {
+		if k == 0 {
+			return Ok(Vec::new());
+		}
+		let network = self.network_map.read().unwrap();
+		let mut routes = vec![self.find_route(&network, &network.our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, params, false, None, None)?];
+		let mut candidates: Vec<Route> = Vec::new();
+		let route_channels = |route: &Route| route.hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>();
+
+		while routes.len() < k {
+			let previous_route = routes.last().unwrap().clone();
+			for spur_idx in 0..previous_route.hops.len() {
+				let root_hops = &previous_route.hops[..spur_idx];
+				let root_channels: Vec<u64> = root_hops.iter().map(|hop| hop.short_channel_id.0).collect();
+				let mut spur_exclusions: HashSet<ShortChannelId, S> = HashSet::with_hasher(S::default());
+				// Avoid the channels previous routes with the same prefix took next...
+				for route in routes.iter() {
+					if route.hops.len() > spur_idx && route_channels(route)[..spur_idx] == root_channels[..] {
+						spur_exclusions.insert(route.hops[spur_idx].short_channel_id);
+					}
+				}
+				// ...and the nodes of the prefix, including us, other than the spur node itself
+				if spur_idx > 0 {
+					let root_nodes = root_hops[..spur_idx - 1].iter().map(|hop| &hop.pubkey).chain(Some(&network.our_node_id));
+					for node_id in root_nodes {
+						if let Some(node) = network.nodes.get(node_id) {
+							spur_exclusions.extend(node.channels.iter().map(|chan_id| ShortChannelId(*NetworkMap::get_short_id(chan_id))));
+						}
+						spur_exclusions.extend(last_hops.iter().filter(|hint| hint.src_node_id == *node_id).map(|hint| hint.short_channel_id));
+					}
+				}
+				let mut spur_params = params.clone();
+				spur_params.excluded_channels.extend(spur_exclusions.into_iter());
+
+				let candidate = if spur_idx == 0 {
+					self.find_route(&network, &network.our_node_id, target, first_hops, last_hops, final_value_msat, final_cltv, &spur_params, false, None, None).ok()
+				} else {
+					let spur_node = root_hops[spur_idx - 1].pubkey;
+					self.find_route(&network, &spur_node, target, None, last_hops, final_value_msat, final_cltv, &spur_params, false, None, None).ok()
+						.and_then(|spur_path| Self::splice_route(&network, root_hops, spur_path, first_hops, params))
+				};
+				if let Some(candidate) = candidate {
+					let candidate_channels = route_channels(&candidate);
+					if !routes.iter().chain(candidates.iter()).any(|route| route_channels(route) == candidate_channels) {
+						candidates.push(candidate);
+					}
+				}
+			}
+
+			let best_idx = match (0..candidates.len()).min_by_key(|idx| {
+				let route = &candidates[*idx];
+				(route.total_fees_msat(), route.hops.len(), route_channels(route))
+			}) {
+				Some(idx) => idx,
+				None => break,
+			};
+			routes.push(candidates.swap_remove(best_idx));
+		}
+		// The first route is the cheapest by our search's cost, which may include penalties
+		sort_routes_by_fee(&mut routes);
+		Ok(routes)
+	}
+
+	/// Gets the fees charged along the cheapest path from us to the given target for sending
+	/// nominal_value_msat, ignoring the htlc_minimum_msat and htlc_maximum_msat of channels, eg to
+	/// show a typical fee to reach some node before the amount of a payment is known. Only
+	/// announced channels (and their advertised fees) are considered.
+	pub fn get_path_fee_estimate(&self, target: &PublicKey, nominal_value_msat: u64) -> Result<PathFeeEstimate, LightningError> {
+		let network = self.network_map.read().unwrap();
+		let route = self.find_route(&network, &network.our_node_id, target, None, &[], nominal_value_msat, 0, &RouteParameters::default(), true, None, None)?;
+		let mut estimate = PathFeeEstimate {
+			fee_base_msat: 0,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: route.total_cltv_delta(),
+			hop_count: route.hops.len(),
+		};
+		// Each hop's fees are those of the channel it forwards over, ie the next hop's
+		for (prev_hop, hop) in route.hops.iter().zip(route.hops.iter().skip(1)) {
+			let chan = network.channels.get(&hop.short_channel_id.0).unwrap();
+			let directional_info = if chan.one_to_two.src_node_id == prev_hop.pubkey { &chan.one_to_two } else { &chan.two_to_one };
+			estimate.fee_base_msat += directional_info.fee_base_msat as u64;
+			estimate.fee_proportional_millionths += directional_info.fee_proportional_millionths as u64;
+		}
+		Ok(estimate)
+	}
+
+	/// Gets the highest fee, in msat, which our channel with the given short_channel_id could charge
+	/// for forwarding amount_msat while still being on the cheapest path from `from` to `to`, eg
+	/// to decide how far our fees must drop to undercut a competing route. The path through our
+	/// channel is taken to be the cheapest path from `from` to us, our channel, and the cheapest
+	/// path from its other end to `to`, and is compared against the cheapest route avoiding our
+	/// channel, with the search tweaked by the given RouteParameters.
+	///
+	/// Returns None if there is no such competing route, in which case any fee will do, and an
+	/// error if there's no path over our channel or it cannot be cheaper even if it were free.
+	/// Proportional fees charged before our channel on our own fee are not included, so the result
+	/// may be slightly above the true break-even fee. Fees are never paid on channels from the
+	/// sender, so `from` may not be us.
+	pub fn get_break_even_fee_msat(&self, from: &PublicKey, to: &PublicKey, short_channel_id: ShortChannelId, amount_msat: u64, params: &RouteParameters) -> Result<Option<u64>, LightningError> {
+		let network = self.network_map.read().unwrap();
+		if *from == network.our_node_id {
+			return Err(LightningError{err: "Fees are not paid on our channels for payments we send", action: ErrorAction::IgnoreError});
+		}
+		let peer_node_id = match network.channels.get(&short_channel_id.0) {
+			Some(chan) if chan.one_to_two.src_node_id == network.our_node_id => chan.two_to_one.src_node_id,
+			Some(chan) if chan.two_to_one.src_node_id == network.our_node_id => chan.one_to_two.src_node_id,
+			_ => return Err(LightningError{err: "Channel is not one of our announced channels", action: ErrorAction::IgnoreError}),
+		};
+		if *from == peer_node_id {
+			return Err(LightningError{err: "Payments from our peer never go over its channel with us", action: ErrorAction::IgnoreError});
+		}
+		let mut competing_params = params.clone();
+		competing_params.excluded_channels.push(short_channel_id);
+
+		// The route search doesn't bound the fee on the payer's own channel, which here is our
+		// peer's (see below), so our sums are checked
+		let overflow_err = || LightningError{err: "Fees on the path through our channel overflow", action: ErrorAction::IgnoreError};
+
+		// Walk back from `to`, adding up the fees due after our channel
+		let mut amount_after_us_msat = amount_msat;
+		if peer_node_id != *to {
+			let route = self.find_route(&network, &peer_node_id, to, None, &[], amount_msat, 0, &competing_params, false, None, None)?;
+			amount_after_us_msat = amount_after_us_msat.checked_add(route.total_fees_msat()).ok_or_else(overflow_err)?;
+			// find_route ignores the fee of the payer's own channel, which here is our peer's
+			let chan = network.channels.get(&route.hops[0].short_channel_id.0).unwrap();
+			let directional_info = if chan.one_to_two.src_node_id == peer_node_id { &chan.one_to_two } else { &chan.two_to_one };
+			let peer_fee_msat = amount_after_us_msat.checked_mul(directional_info.fee_proportional_millionths as u64)
+				.and_then(|proportional_fee| (proportional_fee / 1000000).checked_add(directional_info.fee_base_msat as u64))
+				.ok_or_else(overflow_err)?;
+			amount_after_us_msat = amount_after_us_msat.checked_add(peer_fee_msat).ok_or_else(overflow_err)?;
+		}
+		let fees_to_us_msat = self.find_route(&network, from, &network.our_node_id, None, &[], amount_after_us_msat, 0, &competing_params, false, None, None)?.total_fees_msat();
+		let amount_to_us_msat = amount_after_us_msat.checked_add(fees_to_us_msat).ok_or_else(overflow_err)?;
+
+		let competing_fee_msat = match self.find_route(&network, from, to, None, &[], amount_msat, 0, &competing_params, false, None, None) {
+			Ok(route) => route.total_fees_msat(),
+			Err(_) => return Ok(None),
+		};
+		// Costs which tie are broken by short_channel_id, so we have to be strictly cheaper
+		match competing_fee_msat.checked_sub(amount_to_us_msat - amount_msat + 1) {
+			Some(fee_msat) => Ok(Some(fee_msat)),
+			None => Err(LightningError{err: "Our channel cannot undercut the cheapest competing route even without a fee", action: ErrorAction::IgnoreError}),
+		}
+	}
+
+	/// Attempts to build a route for get_route_avoiding_failed_channel by keeping the hops of
+	/// previous_route before the failed channel and searching only for a new path from there.
+	fn reroute_after_failure(&self, network: &NetworkMap, previous_route: &Route, failed_short_channel_id: ShortChannelId, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters) -> Option<Route> {
+		// Inbound fees depend on the channel a hop is reached over, which we don't recalculate, and
+		// if our own channel failed there is no shorter search to do.
+		let failed_idx = previous_route.hops.iter().position(|hop| hop.short_channel_id == failed_short_channel_id)?;
+		if failed_idx == 0 || !network.inbound_fees.is_empty() {
+			return None;
+		}
+		let forwarding_node = previous_route.hops[failed_idx - 1].pubkey;
+		let target = previous_route.hops.last().unwrap().pubkey;
+		let new_path = self.find_route(network, &forwarding_node, &target, None, last_hops, final_value_msat, final_cltv, params, false, None, None).ok()?;
+		Self::splice_route(network, &previous_route.hops[..failed_idx], new_path, first_hops, params)
+	}
+
+	/// Joins root_hops, a path from us, with new_path, a route from the last node of root_hops,
+	/// updating the fees and CLTV deltas of root_hops to match what their nodes charge for
+	/// forwarding the amount new_path now requires. Fails if new_path revisits a node of root_hops
+	/// (or us), any kept channel can't carry the new amount, or inbound fees are in use, as they
+	/// depend on the channel a hop is reached over, which we don't recalculate. As in find_route,
+	/// if first_hops is set the first hop must be one of them, rather than a channel in our graph.
+	fn splice_route(network: &NetworkMap, root_hops: &[RouteHop], new_path: Route, first_hops: Option<&[channelmanager::ChannelDetails]>, params: &RouteParameters) -> Option<Route> {
+		if !network.inbound_fees.is_empty() {
+			return None;
+		}
+		let spur_idx = root_hops.len();
+		let mut hops = root_hops.to_vec();
+		for new_hop in new_path.hops.iter() {
+			if new_hop.pubkey == network.our_node_id || hops.iter().any(|hop| hop.pubkey == new_hop.pubkey) {
+				return None;
+			}
+		}
+		hops.extend(new_path.hops);
+
+		// Walk back from the last node of root_hops, filling in the fee and CLTV delta each kept
+		// node charges for the (possibly changed) amount it now forwards to the next hop.
+		let mut amount_msat = hops[spur_idx..].iter().fold(0u64, |total, hop| total.saturating_add(hop.fee_msat));
+		for idx in (0..spur_idx + 1).rev() {
+			if idx == 0 && first_hops.is_some() {
+				// first_hops aren't in our graph, so we only check that the channel is one of them
+				if !first_hops.unwrap().iter().any(|chan| chan.short_channel_id == Some(hops[0].short_channel_id.0) && chan.remote_network_id == hops[0].pubkey) {
+					return None;
+				}
+				break;
+			}
+			let (src_node_id, short_channel_id) = if idx == 0 {
+				(&network.our_node_id, hops[0].short_channel_id.0)
+			} else {
+				(&hops[idx - 1].pubkey, hops[idx].short_channel_id.0)
+			};
+			let chan = network.channels.get(&short_channel_id)?;
+			let directional_info = if chan.one_to_two.src_node_id == *src_node_id {
+				&chan.one_to_two
+			} else if chan.two_to_one.src_node_id == *src_node_id {
+				&chan.two_to_one
+			} else { return None; };
+			if !directional_info.enabled || amount_msat < directional_info.htlc_minimum_msat {
+				return None;
+			}
+			if params.htlc_maximum_penalty_msat.is_none() && amount_msat > effective_htlc_maximum_msat(directional_info.htlc_maximum_msat, chan.capacity_sats) {
+				return None;
+			}
+			if idx == 0 {
+				// As in find_route, fees on channels from us are ignored
+				break;
+			}
+			let fee_msat = amount_msat.checked_mul(directional_info.fee_proportional_millionths as u64)
+				.and_then(|part| (directional_info.fee_base_msat as u64).checked_add(part / 1000000))?;
+			hops[idx - 1].fee_msat = fee_msat;
+			hops[idx - 1].cltv_expiry_delta = directional_info.cltv_expiry_delta as u32;
+			amount_msat = amount_msat.checked_add(fee_msat)?;
+		}
+
+		let route = Route { hops };
+		if route.total_cltv_delta() > params.max_cltv_expiry_height.saturating_sub(params.current_height) {
+			return None;
+		}
+		if !route_fits_in_onion(&route, params.current_height) {
+			return None;
+		}
+		Some(route)
+	}
+
+	/// Gets params without any of the penalties, discounts or objective which make the search
+	/// prefer a path over another which pays less in fees, or None if there are none. An objective
+	/// minimizing the total CLTV delta is kept, see find_route_with_metrics.
+	fn fee_only_params(params: &RouteParameters) -> Option<RouteParameters> {
+		let keep_objective = params.objective == RouteObjective::LowestFee || params.objective == (RouteObjective::Weighted { fee_weight: 0, cltv_weight: 1 });
+		if keep_objective && params.htlc_maximum_penalty_msat.unwrap_or(0) == 0 && params.channel_policy_change_penalty_msat == 0 &&
+				params.unknown_capacity_penalty_msat == 0 && params.htlc_slot_penalty_msat == 0 &&
+				params.node_penalties.is_empty() && params.preferred_nodes.is_empty() {
+			return None;
+		}
+		let mut fee_params = params.clone();
+		if !keep_objective {
+			fee_params.objective = RouteObjective::LowestFee;
+		}
+		fee_params.htlc_maximum_penalty_msat = params.htlc_maximum_penalty_msat.map(|_| 0);
+		fee_params.channel_policy_change_penalty_msat = 0;
+		fee_params.unknown_capacity_penalty_msat = 0;
+		fee_params.htlc_slot_penalty_msat = 0;
+		fee_params.node_penalties = Vec::new();
+		fee_params.preferred_nodes = Vec::new();
+		Some(fee_params)
+	}
+
+	/// Gets the channel to exclude if the largest fee any node charges on route is more than
+	/// max_share millionths of its total fee, see RouteParameters::max_hop_fee_share_millionths.
+	fn hop_fee_share_violation(route: &Route, max_share: u32) -> Option<ShortChannelId> {
+		// hops[i].fee_msat is charged by hops[i].pubkey for forwarding over hops[i + 1]
+		let fees: Vec<(u64, ShortChannelId)> = route.hops.windows(2).map(|hops| (hops[0].fee_msat, hops[1].short_channel_id)).collect();
+		let total_fees_msat = fees.iter().fold(0u64, |total, fee| total.saturating_add(fee.0));
+		if fees.iter().filter(|fee| fee.0 != 0).count() < 2 {
+			return None;
+		}
+		let &(max_fee_msat, max_fee_short_channel_id) = fees.iter().max_by_key(|fee| fee.0).unwrap();
+		if max_fee_msat.saturating_mul(1_000_000) <= total_fees_msat.saturating_mul(max_share as u64) {
+			return None;
+		}
+		Some(max_fee_short_channel_id)
+	}
+
+	/// Repeatedly replaces the longest run of hops in route (from us) which can be skipped over a
+	/// single channel from the node before it to its last node without increasing the total fee,
+	/// as described at RouteParameters::shortcut_redundant_hops. Each shortcut removes at least one
+	/// hop, so this takes at most as many rounds as route has hops.
+	fn shortcut_redundant_hops(network: &NetworkMap, mut route: Route, first_hops: Option<&[channelmanager::ChannelDetails]>, params: &RouteParameters, ignore_htlc_limits: bool, capacity_estimator: Option<&Fn(u64, bool) -> Option<u64>>) -> Route {
+		let now = now_secs();
+
+		'shortcut: loop {
+			for start_idx in 0..route.hops.len() {
+				let src_node_id = if start_idx == 0 { &network.our_node_id } else { &route.hops[start_idx - 1].pubkey };
+				for end_idx in (start_idx + 1..route.hops.len()).rev() {
+					let dest_node_id = &route.hops[end_idx].pubkey;
+					// (short_channel_id, channel_features, htlc_maximum_msat) of each channel from
+					// src_node_id to dest_node_id, filtered as in find_route
+					let mut shortcuts = Vec::new();
+					if start_idx == 0 && first_hops.is_some() {
+						for chan in first_hops.unwrap() {
+							if chan.remote_network_id == *dest_node_id && (chan.is_live || params.include_non_live_first_hops) && chan.outbound_htlc_slots != 0 {
+								shortcuts.push((chan.short_channel_id.unwrap(), ChannelFeatures::with_known_relevant_init_flags(&chan.counterparty_features), effective_htlc_maximum_msat(None, None)));
+							}
+						}
+					} else if let Some(node) = network.nodes.get(src_node_id) {
+						for chan_id in node.channels.iter() {
+							let chan = network.channels.get(chan_id).unwrap();
+							let (directional_info, from_node_one) = if chan.one_to_two.src_node_id == *src_node_id && chan.two_to_one.src_node_id == *dest_node_id {
+								(&chan.one_to_two, true)
+							} else if chan.two_to_one.src_node_id == *src_node_id && chan.one_to_two.src_node_id == *dest_node_id {
+								(&chan.two_to_one, false)
+							} else { continue; };
+							let update_too_old = directional_info.src_node_id != network.our_node_id &&
+								params.max_channel_update_age_secs.map(|max_age_secs| (directional_info.last_update as u64) + (max_age_secs as u64) < now).unwrap_or(false);
+							if !chan.features.requires_unknown_bits() && !network.unverified_channels.contains_key(chan_id) && !update_too_old &&
+									chan.capacity_sats.map(|capacity_sats| capacity_sats >= params.min_channel_capacity_sat).unwrap_or(true) {
+								let short_channel_id = *NetworkMap::get_short_id(chan_id);
+								let capacity_sats = capacity_estimator.and_then(|estimator| estimator(short_channel_id, from_node_one)).or(chan.capacity_sats);
+								shortcuts.push((short_channel_id, chan.features.clone(), effective_htlc_maximum_msat(directional_info.htlc_maximum_msat, capacity_sats)));
+							}
+						}
+					}
+					for (short_channel_id, channel_features, htlc_maximum_msat) in shortcuts {
+						if params.excluded_channels.contains(&ShortChannelId(short_channel_id)) || params.locally_disabled_channels.contains(&ShortChannelId(short_channel_id)) {
+							continue;
+						}
+						let mut shortcut_hop = route.hops[end_idx].clone();
+						shortcut_hop.short_channel_id = ShortChannelId(short_channel_id);
+						shortcut_hop.channel_features = channel_features;
+						let mut tail_hops = vec![shortcut_hop];
+						tail_hops.extend_from_slice(&route.hops[end_idx + 1..]);
+						if let Some(shortcut_route) = Self::splice_route(network, &route.hops[..start_idx], Route { hops: tail_hops }, first_hops, params) {
+							// Unlike the search, we never swap in a channel which can't carry the amount
+							// (even if asked to penalize those instead), nor one which breaks the
+							// fee share limit the search may have routed around.
+							let shortcut_amount_msat = shortcut_route.hops[start_idx..].iter().fold(0u64, |total, hop| total.saturating_add(hop.fee_msat));
+							if !ignore_htlc_limits && shortcut_amount_msat > htlc_maximum_msat {
+								continue;
+							}
+							if params.max_hop_fee_share_millionths.and_then(|max_share| Self::hop_fee_share_violation(&shortcut_route, max_share)).is_some() {
+								continue;
+							}
+							if shortcut_route.total_fees_msat() <= route.total_fees_msat() {
+								route = shortcut_route;
+								continue 'shortcut;
+							}
+						}
+					}
+				}
+			}
+			return route;
+		}
+	}
+
+	/// Finds a route from payer to target in the given network map. first_hops must only be set if
+	/// payer is us. If diagnostics is set, it is filled in if the search fails to find a path.
+	fn find_route(&self, network: &NetworkMap, payer: &PublicKey, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, ignore_htlc_limits: bool, capacity_estimator: Option<&Fn(u64, bool) -> Option<u64>>, diagnostics: Option<&mut RouteDiagnostics>) -> Result<Route, LightningError> {
+		self.find_route_with_metrics(network, payer, target, first_hops, last_hops, final_value_msat, final_cltv, params, ignore_htlc_limits, capacity_estimator, diagnostics, &mut RouteSearchMetrics::default(), &mut RouteSearchScratch::new())
+	}
+
+	/// As find_route, but counts the work done in metrics (except for elapsed, which isn't
+	/// touched).
+	fn find_route_with_metrics(&self, network: &NetworkMap, payer: &PublicKey, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, ignore_htlc_limits: bool, capacity_estimator: Option<&Fn(u64, bool) -> Option<u64>>, diagnostics: Option<&mut RouteDiagnostics>, metrics: &mut RouteSearchMetrics, scratch: &mut RouteSearchScratch<S>) -> Result<Route, LightningError> {
+		// TODO: Obviously *only* using total fee cost sucks. We should consider weighting by
+		// uptime/success in using a node in the past.
+		debug_assert!(first_hops.is_none() || *payer == network.our_node_id);
+		if params.shortcut_redundant_hops && *payer == network.our_node_id {
+			let mut search_params = params.clone();
+			search_params.shortcut_redundant_hops = false;
+			let route = self.find_route_with_metrics(network, payer, target, first_hops, last_hops, final_value_msat, final_cltv, &search_params, ignore_htlc_limits, capacity_estimator, diagnostics, metrics, scratch)?;
+			return Ok(Self::shortcut_redundant_hops(network, route, first_hops, params, ignore_htlc_limits, capacity_estimator));
+		}
+		if let Some(max_share) = params.max_hop_fee_share_millionths {
+			let mut diagnostics = diagnostics;
+			let mut share_params = params.clone();
+			share_params.max_hop_fee_share_millionths = None;
+			for _ in 0..MAX_HOP_FEE_SHARE_SEARCHES {
+				let route = self.find_route_with_metrics(network, payer, target, first_hops, last_hops, final_value_msat, final_cltv, &share_params, ignore_htlc_limits, capacity_estimator, diagnostics.as_mut().map(|d| &mut **d), metrics, scratch)?;
+				match Self::hop_fee_share_violation(&route, max_share) {
+					Some(max_fee_short_channel_id) => share_params.excluded_channels.push(max_fee_short_channel_id),
+					None => return Ok(route),
+				}
+			}
+			return Err(LightningError{err: "Failed to find a path within max_hop_fee_share_millionths", action: ErrorAction::IgnoreError});
+		}
+		let final_cltv = final_cltv.saturating_add(params.final_cltv_safety_delta);
+
+		if *target == *payer {
+			return Err(LightningError{err: "Cannot generate a route to ourselves", action: ErrorAction::IgnoreError});
+		}
+
+		if final_value_msat == 0 {
+			return Err(LightningError{err: "Cannot generate a route for a payment of zero value", action: ErrorAction::IgnoreError});
+		}
+
+		if final_value_msat > MAX_VALUE_MSAT {
+			return Err(LightningError{err: "Cannot generate a route of more value than all existing satoshis", action: ErrorAction::IgnoreError});
+		}
+
+		let max_total_cltv_delta = params.max_cltv_expiry_height.saturating_sub(params.current_height);
+		if final_cltv > max_total_cltv_delta {
+			return Err(LightningError{err: "Final CLTV expiry is beyond the maximum allowed expiry height", action: ErrorAction::IgnoreError});
+		}
+
+		// We do a dest-to-source Dijkstra's sorting by each node's distance from the destination
+		// plus the minimum per-HTLC fee to get from it to another node (aka "shitty A*").
+		// TODO: There are a few tweaks we could do, including possibly pre-calculating more stuff
+		// to use as the A* heuristic beyond just the cost to get one node further than the current
+		// one.
+
+		let dummy_directional_info = DummyDirectionalChannelInfo { // used for first_hops routes
+			src_node_id: network.our_node_id.clone(),
+			cltv_expiry_delta: 0,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+			fee_base_msat: 0,
+			fee_proportional_millionths: 0,
+		};
+
+		// Only the policy change penalty and max_channel_update_age_secs depend on the time
+		let now = if params.channel_policy_change_penalty_msat != 0 || params.max_channel_update_age_secs.is_some() { now_secs() } else { 0 };
+
+		let channel_capacity_sats = |short_channel_id: u64, from_node_one: bool, capacity_sats: Option<u64>| {
+			capacity_estimator.and_then(|estimator| estimator(short_channel_id, from_node_one)).or(capacity_sats)
+		};
+		let unknown_capacity_penalty_msat = |src_node_id: &PublicKey, capacity_sats: Option<u64>| {
+			if capacity_sats.is_none() && *src_node_id != *payer { params.unknown_capacity_penalty_msat } else { 0 }
+		};
+
+		// Whether max_cltv_expiry_height or max_fee_proportional_millionths kept us from extending
+		// any path, see below.
+		let mut cltv_limited = false;
+		let mut fee_limited = false;
+
+		let max_total_fee_msat = params.max_fee_proportional_millionths
+			.map(|proportional_millionths| final_value_msat.saturating_mul(proportional_millionths as u64) / 1000000);
+
+		// node_penalties may list many nodes (see get_route_with_node_cost_adjuster), so index them
+		let mut node_penalties: HashMap<&PublicKey, u64, S> = HashMap::with_capacity_and_hasher(params.node_penalties.len(), S::default());
+		for &(ref node_id, penalty_msat) in params.node_penalties.iter() {
+			let total = node_penalties.entry(node_id).or_insert(0);
+			*total = total.saturating_add(penalty_msat);
+		}
+		let node_penalty_msat = |node_id: &PublicKey| node_penalties.get(node_id).cloned().unwrap_or(0);
+		scratch.excluded_channels.clear();
+		scratch.excluded_channels.extend(params.excluded_channels.iter().cloned());
+		// preferred_nodes is checked for each channel we consider, so index it too
+		let mut preferred_nodes: HashMap<&PublicKey, u64, S> = HashMap::with_capacity_and_hasher(params.preferred_nodes.len(), S::default());
+		for &(ref node_id, discount_msat) in params.preferred_nodes.iter() {
+			let total = preferred_nodes.entry(node_id).or_insert(0);
+			*total = total.saturating_add(discount_msat);
+		}
+		let node_discount_msat = |node_id: &PublicKey| preferred_nodes.get(node_id).cloned().unwrap_or(0);
+		// As is unreachable_nodes
+		let unreachable_nodes: HashSet<&PublicKey, S> = {
+			let mut unreachable_nodes = HashSet::with_capacity_and_hasher(params.unreachable_nodes.len(), S::default());
+			unreachable_nodes.extend(params.unreachable_nodes.iter());
+			unreachable_nodes
+		};
+		// And locally_disabled_channels
+		let locally_disabled_channels: HashSet<ShortChannelId, S> = {
+			let mut locally_disabled_channels = HashSet::with_capacity_and_hasher(params.locally_disabled_channels.len(), S::default());
+			locally_disabled_channels.extend(params.locally_disabled_channels.iter().cloned());
+			locally_disabled_channels
+		};
+
+		scratch.targets.clear();
+		scratch.dist.clear();
+		scratch.dist.reserve(network.nodes.len());
+		scratch.popped_nodes.clear();
+		scratch.first_hop_targets.clear();
+		if let Some(hops) = first_hops {
+			for chan in hops {
+				let short_channel_id = chan.short_channel_id.expect("first_hops should be filled in with usable channels, not pending ones");
+				if scratch.excluded_channels.contains(&ShortChannelId(short_channel_id)) || locally_disabled_channels.contains(&ShortChannelId(short_channel_id)) || (!chan.is_live && !params.include_non_live_first_hops) || chan.outbound_htlc_slots == 0 {
+					continue;
+				}
+				if chan.remote_network_id == *target {
+					return Ok(Route {
+						hops: vec![RouteHop {
+							pubkey: chan.remote_network_id,
+							node_features: NodeFeatures::with_known_relevant_init_flags(&chan.counterparty_features),
+							short_channel_id: ShortChannelId(short_channel_id),
+							channel_features: ChannelFeatures::with_known_relevant_init_flags(&chan.counterparty_features),
+							fee_msat: final_value_msat,
+							cltv_expiry_delta: final_cltv,
+						}],
+					});
+				}
+				scratch.first_hop_targets.insert(chan.remote_network_id, (short_channel_id, chan.counterparty_features.clone(), chan.outbound_htlc_slots));
+			}
+			if scratch.first_hop_targets.is_empty() {
+				return Err(LightningError{err: "Cannot route when there are no outbound routes away from us", action: ErrorAction::IgnoreError});
+			}
+		}
+
+		// With a fee budget, the payer's peers are visited as soon as we find a path from them,
+		// rather than in order of cost, so that we find a complete path as early as possible.
+		// Visiting a node early only adds more (possibly costlier) paths to the search, so we still
+		// find the cheapest route if none is within the budget.
+		let mut payer_peers: HashSet<PublicKey, S> = HashSet::with_hasher(S::default());
+		if params.fee_budget_msat.is_some() {
+			if first_hops.is_some() {
+				payer_peers.extend(scratch.first_hop_targets.keys().cloned());
+			} else if let Some(node) = network.nodes.get(payer) {
+				for chan_id in node.channels.iter() {
+					let chan = network.channels.get(chan_id).unwrap();
+					payer_peers.insert(if chan.one_to_two.src_node_id == *payer { chan.two_to_one.src_node_id } else { chan.one_to_two.src_node_id });
+				}
+			}
+		}
+		let mut payer_peers_to_visit = Vec::new();
+
+		// The channels closest to being able to carry the amount we needed to send over them, for
+		// RouteDiagnostics::binding_amount_limit.
+		let mut max_limited_channel: Option<(u64, u64, u64)> = None;
+		let mut min_limited_channel: Option<(u64, u64, u64)> = None;
+		macro_rules! note_amount_exceeds_capacity {
+			( $short_channel_id: expr, $htlc_maximum_msat: expr, $amount_msat: expr ) => {
+				if max_limited_channel.map(|(scid, max, _)| ($htlc_maximum_msat, scid) > (max, $short_channel_id)).unwrap_or(true) {
+					max_limited_channel = Some(($short_channel_id, $htlc_maximum_msat, $amount_msat));
+				}
+			}
+		}
+		macro_rules! note_amount_below_minimum {
+			( $short_channel_id: expr, $htlc_minimum_msat: expr, $amount_msat: expr ) => {
+				if min_limited_channel.map(|(scid, min, _)| ($htlc_minimum_msat, $short_channel_id) < (min, scid)).unwrap_or(true) {
+					min_limited_channel = Some(($short_channel_id, $htlc_minimum_msat, $amount_msat));
+				}
+			}
+		}
+
+		macro_rules! add_entry {
+			// Adds entry which goes from the node pointed to by $directional_info to
+			// $dest_node_id over the channel with id $chan_id with fees described in
+			// $directional_info. $starting_cltv_delta is the total CLTV delta required from
+			// $dest_node_id to the target, including final_cltv. $starting_penalty_msat is the
+			// sum of penalties (which are considered in addition to fees when picking a path but
+			// are not paid) along the path from $dest_node_id to the target, and
+			// $starting_boost_msat similarly the sum of local score boosts, which are subtracted.
+			// $starting_failure_cost is the sum of channel_failure_cost along the path, which is
+			// always 0 unless we're looking for the most reliable route. Depending on the
+			// objective, either it or a weighted sum of fees and CLTV delta is compared before
+			// fees. $capacity_sats is the channel's on-chain (or estimated, see
+			// channel_capacity_sats) value, used to cap the amount sent over it. $starting_hops is
+			// the number of hops from $dest_node_id to the target, as paths longer than
+			// MAX_PATH_HOPS can't be sent over. $starting_fee_cost_msat is the fee from
+			// $dest_node_id to the target as counted when comparing paths, which is at least
+			// $starting_fee_msat.
+			( $chan_id: expr, $dest_node_id: expr, $directional_info: expr, $chan_features: expr, $capacity_sats: expr, $starting_fee_msat: expr, $starting_fee_cost_msat: expr, $starting_cltv_delta: expr, $starting_penalty_msat: expr, $starting_boost_msat: expr, $starting_failure_cost: expr, $starting_hops: expr ) => {
+				metrics.channels_relaxed += 1;
+				let path_hops = ($starting_hops as usize) + 1;
+				// $dest_node_id may charge a (possibly negative) inbound fee for forwarding HTLCs
+				// received over $chan_id, which is added to the fee it charges for forwarding
+				// onward, though the sum is never allowed to go below zero.
+				let dest_node_id: &PublicKey = &$dest_node_id;
+				let dest_fee_msat = if path_hops > MAX_PATH_HOPS {
+					None
+				} else if *dest_node_id != *target {
+					let outbound_fee_msat = scratch.dist.get(dest_node_id).map(|entry| entry.3.fee_msat).unwrap_or(0);
+					match network.inbound_fees.get(&($chan_id.clone(), dest_node_id.clone())) {
+						Some(&(base_msat, proportional_millionths)) => {
+							(proportional_millionths as i64).checked_mul(($starting_fee_msat as u64 + final_value_msat) as i64)
+								.map(|part| cmp::max(0, outbound_fee_msat as i64 + base_msat as i64 + part / 1000000) as u64)
+						},
+						None => Some(outbound_fee_msat),
+					}.map(|dest_fee_msat| (outbound_fee_msat, dest_fee_msat))
+				} else { Some((0, 0)) };
+				if let Some((outbound_fee_msat, dest_fee_msat)) = dest_fee_msat {
+					let starting_fee_msat = ($starting_fee_msat as u64).saturating_sub(outbound_fee_msat) + dest_fee_msat;
+					// As with fees, the CLTV delta on channels-from-the-payer is not part of the route
+					let total_cltv_delta = if $directional_info.src_node_id != *payer {
+						($starting_cltv_delta as u32).checked_add($directional_info.cltv_expiry_delta as u32)
+					} else {
+						Some($starting_cltv_delta as u32)
+					};
+					// Channels which can't carry the amount are skipped, unless we were asked to
+					// penalize them instead (or to ignore limits entirely).
+					let htlc_maximum_msat = effective_htlc_maximum_msat($directional_info.htlc_maximum_msat, $capacity_sats);
+					let path_penalty_msat = if !ignore_htlc_limits && starting_fee_msat + final_value_msat > htlc_maximum_msat {
+						if params.htlc_maximum_penalty_msat.is_none() {
+							note_amount_exceeds_capacity!($chan_id.clone(), htlc_maximum_msat, starting_fee_msat + final_value_msat);
+						}
+						params.htlc_maximum_penalty_msat.map(|penalty| penalty.saturating_add($starting_penalty_msat))
+					} else {
+						Some($starting_penalty_msat)
+					};
+					let path_penalty_msat = if $directional_info.src_node_id != *payer {
+						path_penalty_msat.and_then(|penalty| if unreachable_nodes.contains(&$directional_info.src_node_id) { None } else { Some(penalty) }).map(|penalty| penalty.saturating_add(network.policy_change_penalty_msat($chan_id.clone(), params, now))
+							.saturating_add(node_penalty_msat(&$directional_info.src_node_id)))
+					} else {
+						path_penalty_msat
+					};
+					let total_cltv_delta = total_cltv_delta.and_then(|delta| if delta <= max_total_cltv_delta { Some(delta) } else { cltv_limited = true; None });
+					//TODO: Explore simply adding fee to hit htlc_minimum_msat
+					if let (Some(total_cltv_delta), Some(path_penalty_msat)) = (total_cltv_delta, path_penalty_msat) {
+						if ignore_htlc_limits || starting_fee_msat + final_value_msat >= $directional_info.htlc_minimum_msat {
+							let proportional_fee_millions = (starting_fee_msat + final_value_msat).checked_mul($directional_info.fee_proportional_millionths as u64);
+							// As with the value itself, paths which would require sending more than all
+							// existing satoshis are never considered, which also keeps all later fee
+							// math well clear of overflow.
+							if let Some(new_fee) = proportional_fee_millions.and_then(|part| {
+									($directional_info.fee_base_msat as u64).checked_add(part / 1000000) })
+								.and_then(|new_fee| {
+									let total_amount_msat = starting_fee_msat.checked_add(new_fee).and_then(|fee| fee.checked_add(final_value_msat));
+									// Fees on channels from the payer aren't paid, see below
+									let path_fee_msat = if $directional_info.src_node_id == *payer { Some(starting_fee_msat) } else { starting_fee_msat.checked_add(new_fee) };
+									if max_total_fee_msat.map(|max_fee_msat| path_fee_msat.map(|fee| fee > max_fee_msat).unwrap_or(true)).unwrap_or(false) {
+										fee_limited = true;
+										None
+									} else if $directional_info.src_node_id == *payer || total_amount_msat.map(|amount| amount <= MAX_VALUE_MSAT).unwrap_or(false) {
+										Some(new_fee)
+									} else { None }
+								})
+							{
+								// An inbound discount may leave a path paying less in fees than the path it
+								// extends, but the search requires that the cost of a path never decreases as
+								// it is extended. Thus a discount is only counted against the fee charged for
+								// the channel it applies to, much like boosts below.
+								let path_fee_msat = if $directional_info.src_node_id != *payer { starting_fee_msat + new_fee } else { starting_fee_msat };
+								let fee_cost_increase_msat = path_fee_msat.saturating_sub($starting_fee_msat as u64);
+								let fee_cost_msat = ($starting_fee_cost_msat as u64).saturating_add(fee_cost_increase_msat);
+								let mut total_fee = fee_cost_msat;
+								let hm_entry = scratch.dist.entry($directional_info.src_node_id.clone());
+								let old_entry = hm_entry.or_insert_with(|| {
+									let node = network.nodes.get(&$directional_info.src_node_id).unwrap();
+									((u64::max_value(), u64::max_value()),
+										node.lowest_inbound_channel_fee_base_msat,
+										node.lowest_inbound_channel_fee_proportional_millionths,
+										RouteHop {
+											pubkey: $dest_node_id.clone(),
+											node_features: NodeFeatures::empty(),
+											short_channel_id: ShortChannelId(0),
+											channel_features: $chan_features.clone(),
+											fee_msat: 0,
+											cltv_expiry_delta: 0,
+										}, 0)
+								});
+								if $directional_info.src_node_id != *payer {
+									// Ignore new_fee for channel-from-us as we assume all channels-from-us
+									// will have the same effective-fee
+									if let Some(fee_inc) = final_value_msat.checked_add(total_fee).and_then(|inc| { (old_entry.2 as u64).checked_mul(inc) }) {
+										total_fee += fee_inc / 1000000 + (old_entry.1 as u64);
+									} else {
+										// max_value means we'll always fail the total_fee < u64::max_value() check
+										total_fee = u64::max_value();
+									}
+								}
+								// A channel's boost (including its node's discount) may at most cancel out
+								// its own fee, so that the cost of a path never decreases as it is extended.
+								let path_boost_msat = if $directional_info.src_node_id != *payer {
+									let boost_msat = network.channel_score_boosts.get(&$chan_id.clone()).cloned().unwrap_or(0)
+										.saturating_add(node_discount_msat(&$directional_info.src_node_id));
+									($starting_boost_msat as u64).saturating_add(cmp::min(boost_msat, fee_cost_increase_msat))
+								} else {
+									$starting_boost_msat as u64
+								};
+								let total_fee = total_fee.saturating_add(path_penalty_msat).saturating_sub(path_boost_msat);
+								let path_failure_cost = match params.objective {
+									RouteObjective::HighestSuccessProbability { default_success_probability_ppm } if $directional_info.src_node_id != *payer => {
+										let success_probability_ppm = network.channel_success_probabilities.get(&$chan_id.clone()).cloned().unwrap_or(default_success_probability_ppm);
+										($starting_failure_cost as u64).saturating_add(channel_failure_cost(success_probability_ppm))
+									},
+									_ => $starting_failure_cost as u64,
+								};
+								let objective_cost = match params.objective {
+									RouteObjective::LowestFee => 0,
+									RouteObjective::HighestSuccessProbability { .. } => path_failure_cost,
+									RouteObjective::Weighted { fee_weight, cltv_weight } => {
+										fee_weight.saturating_mul(total_fee).saturating_add(cltv_weight.saturating_mul(total_cltv_delta as u64))
+									},
+								};
+								let new_graph_node = RouteGraphNode {
+									pubkey: $directional_info.src_node_id,
+									lowest_fee_to_peer_through_node: total_fee,
+									lowest_fee_to_node: starting_fee_msat + new_fee,
+									fee_cost_to_node: fee_cost_msat,
+									total_cltv_delta,
+									path_penalty_msat,
+									path_boost_msat,
+									path_failure_cost,
+									path_hops,
+									objective_cost,
+								};
+								// Break ties between equal-cost hops canonically, preferring hops directly to
+								// the target (so that we take a direct channel over an equally cheap longer
+								// path), and then by short_channel_id and node_id, so that identical inputs
+								// always result in an identical route, irrespective of the order in which we
+								// happen to visit channels. Nodes which were already popped may be on the path
+								// we're extending, so we leave them be to avoid creating loops.
+								let total_cost = (objective_cost, total_fee);
+								if total_fee < u64::max_value() && (old_entry.0 > total_cost || (old_entry.0 == total_cost && !scratch.popped_nodes.contains(&$directional_info.src_node_id) &&
+										($dest_node_id.clone() != *target, $chan_id.clone(), &$dest_node_id.serialize()[..]) <
+										(old_entry.3.pubkey != *target, old_entry.3.short_channel_id.0, &old_entry.3.pubkey.serialize()[..]))) {
+									if payer_peers.contains(&$directional_info.src_node_id) {
+										payer_peers_to_visit.push(new_graph_node.clone());
+									}
+									scratch.targets.push(new_graph_node);
+									old_entry.0 = total_cost;
+									old_entry.3 = RouteHop {
+										pubkey: $dest_node_id.clone(),
+										node_features: NodeFeatures::empty(),
+										short_channel_id: ShortChannelId($chan_id.clone()),
+										channel_features: $chan_features.clone(),
+										fee_msat: new_fee, // This field is ignored on the last-hop anyway
+										cltv_expiry_delta: $directional_info.cltv_expiry_delta as u32,
+									};
+									old_entry.4 = dest_fee_msat;
+								}
+							}
+						} else {
+							note_amount_below_minimum!($chan_id.clone(), $directional_info.htlc_minimum_msat, starting_fee_msat + final_value_msat);
+						}
+					}
+				}
+			};
+		}
+
+		macro_rules! add_entries_to_cheapest_to_target_node {
+			( $node: expr, $node_id: expr, $fee_to_target_msat: expr, $fee_cost_to_target_msat: expr, $cltv_to_target: expr, $penalty_to_target_msat: expr, $boost_to_target_msat: expr, $failure_cost_to_target: expr, $hops_to_target: expr ) => {
+				if first_hops.is_some() {
+					if let Some(&(ref first_hop, ref features, htlc_slots)) = scratch.first_hop_targets.get(&$node_id) {
+						let penalty_msat = ($penalty_to_target_msat as u64).saturating_add(params.htlc_slot_penalty_msat / htlc_slots as u64);
+						add_entry!(first_hop, $node_id, dummy_directional_info, ChannelFeatures::with_known_relevant_init_flags(&features), None, $fee_to_target_msat, $fee_cost_to_target_msat, $cltv_to_target, penalty_msat, $boost_to_target_msat, $failure_cost_to_target, $hops_to_target);
+					}
+				}
+
+				if !$node.features.requires_unknown_bits() {
+					// The usable channels towards $node, as (channel key, channel, the node $node is
+					// on the channel, the directional info towards $node, channel capacity).
+					let mut usable_channels = Vec::with_capacity($node.channels.len());
+					for chan_id in $node.channels.iter() {
+						let chan = network.channels.get(chan_id).unwrap();
+						if !chan.features.requires_unknown_bits() && !scratch.excluded_channels.contains(&ShortChannelId(*NetworkMap::get_short_id(chan_id))) &&
+								!locally_disabled_channels.contains(&ShortChannelId(*NetworkMap::get_short_id(chan_id))) &&
+								!network.unverified_channels.contains_key(chan_id) &&
+								chan.capacity_sats.map(|capacity_sats| capacity_sats >= params.min_channel_capacity_sat).unwrap_or(true) {
+							// ie if $node is one, next hop in A* is two, via the two_to_one channel
+							let (dest_node_id, directional_info, from_node_one) = if chan.one_to_two.src_node_id == *$node_id {
+								(&chan.one_to_two.src_node_id, &chan.two_to_one, false)
+							} else {
+								(&chan.two_to_one.src_node_id, &chan.one_to_two, true)
+							};
+							// Our own channels are only ever taken from first_hops, if given, as
+							// our local view of them is authoritative while gossip may be stale.
+							let update_too_old = directional_info.src_node_id != network.our_node_id &&
+								params.max_channel_update_age_secs.map(|max_age_secs| (directional_info.last_update as u64) + (max_age_secs as u64) < now).unwrap_or(false);
+							if (first_hops.is_none() || directional_info.src_node_id != network.our_node_id) && directional_info.enabled && !update_too_old {
+								let capacity_sats = channel_capacity_sats(*NetworkMap::get_short_id(chan_id), from_node_one, chan.capacity_sats);
+								usable_channels.push((chan_id, chan, dest_node_id, directional_info, capacity_sats));
+							}
+						}
+					}
+
+					// Unless we're picking the cheapest, only the channels preferred by
+					// params.parallel_channel_policy among those from each node are considered.
+					let parallel_channel_rank = |directional_info: &DirectionalChannelInfo, capacity_sats: Option<u64>| {
+						match params.parallel_channel_policy {
+							ParallelChannelPolicy::Cheapest => 0,
+							ParallelChannelPolicy::HighestCapacity => capacity_sats.map(|capacity_sats| capacity_sats.saturating_add(1)).unwrap_or(0),
+							ParallelChannelPolicy::MostRecentlyUpdated => directional_info.last_update as u64,
+						}
+					};
+					let best_parallel_ranks = if params.parallel_channel_policy != ParallelChannelPolicy::Cheapest {
+						let mut best_parallel_ranks: HashMap<&PublicKey, u64, S> = HashMap::with_capacity_and_hasher(usable_channels.len(), S::default());
+						for &(_, _, _, directional_info, capacity_sats) in usable_channels.iter() {
+							let rank = best_parallel_ranks.entry(&directional_info.src_node_id).or_insert(0);
+							*rank = cmp::max(*rank, parallel_channel_rank(directional_info, capacity_sats));
+						}
+						Some(best_parallel_ranks)
+					} else { None };
+
+					for &(chan_id, chan, dest_node_id, directional_info, capacity_sats) in usable_channels.iter() {
+						if best_parallel_ranks.as_ref().map(|best_parallel_ranks| best_parallel_ranks.get(&directional_info.src_node_id) == Some(&parallel_channel_rank(directional_info, capacity_sats))).unwrap_or(true) {
+							let penalty_msat = ($penalty_to_target_msat as u64).saturating_add(unknown_capacity_penalty_msat(&directional_info.src_node_id, capacity_sats));
+							add_entry!(chan_id, *dest_node_id, directional_info, chan.features, capacity_sats, $fee_to_target_msat, $fee_cost_to_target_msat, $cltv_to_target, penalty_msat, $boost_to_target_msat, $failure_cost_to_target, $hops_to_target);
+						}
+					}
+				}
+			};
+		}
+
+		match network.nodes.get(target) {
+			None => {},
+			Some(node) => {
+				add_entries_to_cheapest_to_target_node!(node, target, 0, 0, final_cltv, 0, 0, 0, 0);
+			},
+		}
+
+		for hop in last_hops.iter() {
+			if scratch.excluded_channels.contains(&hop.short_channel_id) || locally_disabled_channels.contains(&hop.short_channel_id) {
+				continue;
+			}
+			if first_hops.is_none() || hop.src_node_id != network.our_node_id { // first_hop overrules last_hops
+				if !ignore_htlc_limits && params.htlc_maximum_penalty_msat.is_none() && final_value_msat > effective_htlc_maximum_msat(hop.htlc_maximum_msat, None) {
+					note_amount_exceeds_capacity!(hop.short_channel_id.0, effective_htlc_maximum_msat(hop.htlc_maximum_msat, None), final_value_msat);
+					continue;
+				}
+				if network.nodes.get(&hop.src_node_id).is_some() {
+					// BOLT 11 doesn't allow inclusion of features for the last hop hints, which
+					// really sucks, cause we're gonna need that eventually.
+					// As with any other channel, the hint's fee and CLTV delta are carried onwards
+					// once src_node_id is visited, including over our first_hops to it, so we don't
+					// add a first_hops entry for src_node_id here.
+					add_entry!(hop.short_channel_id.0, target, hop, ChannelFeatures::empty(), None, 0, 0, final_cltv, 0, 0, 0, 0);
+				}
+			}
+		}
+
+		macro_rules! route_from_payer {
+			// Walks the hops from the payer to the target as currently recorded in dist, giving
+			// None if the path is broken.
+			() => { {
+				let (_, _, _, ref first_hop, mut hop_fee_msat) = *scratch.dist.get(payer).unwrap();
+				let mut res = vec!(first_hop.clone());
+				let mut complete = true;
+				loop {
+					if let Some(&(_, ref features, _)) = scratch.first_hop_targets.get(&res.last().unwrap().pubkey) {
+						res.last_mut().unwrap().node_features = NodeFeatures::with_known_relevant_init_flags(&features);
+					} else if let Some(node) = network.nodes.get(&res.last().unwrap().pubkey) {
+						res.last_mut().unwrap().node_features = node.features.clone();
+					} else {
+						// We should be able to fill in features for everything except the last
+						// hop, if the last hop was provided via a BOLT 11 invoice (though we
+						// should be able to extend it further as BOLT 11 does have feature
+						// flags for the last hop node itself).
+						assert!(res.last().unwrap().pubkey == *target);
+					}
+					if res.last().unwrap().pubkey == *target {
+						break;
+					}
+
+					let (new_entry, next_hop_fee_msat) = match scratch.dist.get(&res.last().unwrap().pubkey) {
+						Some(hop) if res.len() <= scratch.dist.len() => (hop.3.clone(), hop.4),
+						_ => { complete = false; break; },
+					};
+					// The fee charged by each node includes any inbound fee on the channel we
+					// reach it over.
+					res.last_mut().unwrap().fee_msat = hop_fee_msat;
+					hop_fee_msat = next_hop_fee_msat;
+					res.last_mut().unwrap().cltv_expiry_delta = new_entry.cltv_expiry_delta;
+					res.push(new_entry);
+				}
+				if complete {
+					res.last_mut().unwrap().fee_msat = final_value_msat;
+					res.last_mut().unwrap().cltv_expiry_delta = final_cltv;
+					Some(Route { hops: res })
+				} else { None }
+			} }
+		}
+
+		while let Some(RouteGraphNode { pubkey, lowest_fee_to_node, fee_cost_to_node, total_cltv_delta, path_penalty_msat, path_boost_msat, path_failure_cost, path_hops, .. }) = payer_peers_to_visit.pop().or_else(|| scratch.targets.pop()) {
+			if pubkey == *payer {
+				let route = match route_from_payer!() {
+					Some(route) => route,
+					None => return Err(LightningError{err: "Failed to find a non-fee-overflowing path to the given destination", action: ErrorAction::IgnoreError}),
+				};
+				if !route_fits_in_onion(&route, params.current_height) {
+					// Paths are never extended past MAX_PATH_HOPS, so this is only hit where the
+					// per-hop payloads are too large (eg with large TLV payloads). Each node only
+					// keeps its cheapest path to the target, so a shorter (but more expensive)
+					// path which would have fit may exist, though this is rare in practice.
+					return Err(LightningError{err: "Cheapest path to the given destination is too long to fit in an onion", action: ErrorAction::IgnoreError});
+				}
+				log_trace!(self, "Got route: {}", log_route!(route));
+				return Ok(route);
+			}
+
+			metrics.nodes_visited += 1;
+			if params.max_nodes_visited.map(|max_nodes_visited| metrics.nodes_visited > max_nodes_visited).unwrap_or(false) {
+				return Err(LightningError{err: "Route search budget exhausted", action: ErrorAction::IgnoreError});
+			}
+			if params.deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+				if scratch.dist.get(payer).map(|entry| entry.0 != (u64::max_value(), u64::max_value())).unwrap_or(false) {
+					if let Some(route) = route_from_payer!() {
+						if route_fits_in_onion(&route, params.current_height) {
+							log_trace!(self, "Got route before the search deadline: {}", log_route!(route));
+							return Ok(route);
+						}
+					}
+				}
+				return Err(LightningError{err: "Route search deadline exceeded", action: ErrorAction::IgnoreError});
+			}
+			scratch.popped_nodes.insert(pubkey);
+			match network.nodes.get(&pubkey) {
+				None => {},
+				Some(node) => {
+					add_entries_to_cheapest_to_target_node!(node, &pubkey, lowest_fee_to_node, fee_cost_to_node, total_cltv_delta, path_penalty_msat, path_boost_msat, path_failure_cost, path_hops);
+				},
+			}
+
+			// Any path we've found from the payer can be returned early if it's cheap enough, rather
+			// than waiting to pop the payer.
+			if let Some(fee_budget_msat) = params.fee_budget_msat {
+				if scratch.dist.get(payer).map(|entry| entry.0 != (u64::max_value(), u64::max_value())).unwrap_or(false) {
+					if let Some(route) = route_from_payer!() {
+						if route.total_fees_msat() <= fee_budget_msat && route_fits_in_onion(&route, params.current_height) {
+							log_trace!(self, "Got route within fee budget: {}", log_route!(route));
+							return Ok(route);
+						}
+					}
+				}
+			}
+		}
+
+		// Each node only keeps its cheapest path to the target, which may be too long for the path
+		// to be extended to us within max_cltv_expiry_height even where a costlier but shorter one
+		// would have fit. Keeping the shortest path at each node instead finds a path within the
+		// limit whenever there is one, so retry doing so.
+		let shortest_cltv_objective = RouteObjective::Weighted { fee_weight: 0, cltv_weight: 1 };
+		if cltv_limited && params.objective != shortest_cltv_objective {
+			let mut cltv_params = params.clone();
+			cltv_params.objective = shortest_cltv_objective;
+			cltv_params.final_cltv_safety_delta = 0;
+			return self.find_route_with_metrics(network, payer, target, first_hops, last_hops, final_value_msat, final_cltv, &cltv_params, ignore_htlc_limits, capacity_estimator, diagnostics, metrics, scratch);
+		}
+		// Similarly, where penalties, discounts or the objective made a node keep a path which pays
+		// more in fees than another, the former may exceed max_fee_proportional_millionths once
+		// extended while the latter wouldn't have, so retry comparing paths by their fees alone.
+		if fee_limited {
+			if let Some(mut fee_params) = Self::fee_only_params(params) {
+				fee_params.final_cltv_safety_delta = 0;
+				return self.find_route_with_metrics(network, payer, target, first_hops, last_hops, final_value_msat, final_cltv, &fee_params, ignore_htlc_limits, capacity_estimator, diagnostics, metrics, scratch);
+			}
+		}
+
+		if let Some(diagnostics) = diagnostics {
+			let mut reachable_nodes: Vec<PublicKey> = scratch.dist.iter().filter(|&(node_id, entry)| *node_id != *target && entry.0 != (u64::max_value(), u64::max_value())).map(|(node_id, _)| (*node_id).clone()).collect();
+			reachable_nodes.sort_unstable_by_key(|node_id| node_id.serialize());
+
+			// Walk the path from each reachable node to the target, as we would for a full route.
+			let path_to_target = |source: &PublicKey| {
+				let mut res = vec!(scratch.dist.get(source).unwrap().3.clone());
+				let mut hop_fee_msat = scratch.dist.get(source).unwrap().4;
+				while res.last().unwrap().pubkey != *target && res.len() <= scratch.dist.len() {
+					let (new_entry, next_hop_fee_msat) = match scratch.dist.get(&res.last().unwrap().pubkey) {
+						Some(hop) => (hop.3.clone(), hop.4),
+						None => break,
+					};
+					res.last_mut().unwrap().fee_msat = hop_fee_msat;
+					hop_fee_msat = next_hop_fee_msat;
+					res.last_mut().unwrap().cltv_expiry_delta = new_entry.cltv_expiry_delta;
+					res.push(new_entry);
+				}
+				for hop in res.iter_mut() {
+					if let Some(node) = network.nodes.get(&hop.pubkey) {
+						hop.node_features = node.features.clone();
+					}
+				}
+				res.last_mut().unwrap().fee_msat = final_value_msat;
+				res.last_mut().unwrap().cltv_expiry_delta = final_cltv;
+				res
+			};
+			let mut partial_route_source = None;
+			let mut partial_route_hops = Vec::new();
+			for node_id in reachable_nodes.iter() {
+				let hops = path_to_target(node_id);
+				if hops.len() > partial_route_hops.len() {
+					partial_route_source = Some(node_id.clone());
+					partial_route_hops = hops;
+				}
+			}
+
+			let binding_amount_limit = max_limited_channel.map(|(short_channel_id, htlc_maximum_msat, amount_msat)| {
+				RouteAmountLimit::AmountExceedsCapacity { short_channel_id: ShortChannelId(short_channel_id), htlc_maximum_msat, amount_msat }
+			}).or(min_limited_channel.map(|(short_channel_id, htlc_minimum_msat, amount_msat)| {
+				RouteAmountLimit::AmountBelowMinimum { short_channel_id: ShortChannelId(short_channel_id), htlc_minimum_msat, amount_msat }
+			}));
+
+			*diagnostics = RouteDiagnostics { reachable_nodes, partial_route_source, partial_route_hops, binding_amount_limit };
+		}
+
+		Err(LightningError{err: "Failed to find a path to the given destination", action: ErrorAction::IgnoreError})
+	}
+}
+
+/// Generates successive routes for retrying a single payment, avoiding all channels which failed
+/// on previous attempts. Each call to next_route searches our Router's current view of the network
+/// with the failed channels (reported via channel_failed) added to RouteParameters's
+/// excluded_channels, until either max_attempts routes have been returned or no route remains.
+///
+/// If no failed channel is reported between two calls to next_route, the same route may be
+/// returned again.
+pub struct RouteRetryGenerator<S = RandomState> {
+	router: Arc<Router<S>>,
+	payment_hash: channelmanager::PaymentHash,
+	target: PublicKey,
+	last_hops: Vec<RouteHint>,
+	final_value_msat: u64,
+	final_cltv: u32,
+	params: RouteParameters,
+	max_attempts: usize,
+	attempts: usize,
+}
+
+impl<S: BuildHasher + Default> RouteRetryGenerator<S> {
+	/// Creates a new generator for the payment with the given hash, which will generate at most
+	/// max_attempts routes to target, with arguments as for Router::get_route_with_params. Any
+	/// channels already in params.excluded_channels are never used.
+	pub fn new(router: Arc<Router<S>>, payment_hash: channelmanager::PaymentHash, target: PublicKey, last_hops: Vec<RouteHint>, final_value_msat: u64, final_cltv: u32, params: RouteParameters, max_attempts: usize) -> Self {
+		RouteRetryGenerator {
+			router,
+			payment_hash,
+			target,
+			last_hops,
+			final_value_msat,
+			final_cltv,
+			params,
+			max_attempts,
+			attempts: 0,
+		}
+	}
+
+	/// Gets the payment hash of the payment this generator is generating routes for.
+	pub fn payment_hash(&self) -> &channelmanager::PaymentHash {
+		&self.payment_hash
+	}
+
+	/// Gets the short_channel_ids of all channels which will not be used, in the order they were
+	/// excluded.
+	pub fn excluded_channels(&self) -> &[ShortChannelId] {
+		&self.params.excluded_channels
+	}
+
+	/// Records that the channel with the given short_channel_id failed the payment, so that it is
+	/// not used by any later route.
+	pub fn channel_failed(&mut self, short_channel_id: ShortChannelId) {
+		if !self.params.excluded_channels.contains(&short_channel_id) {
+			self.params.excluded_channels.push(short_channel_id);
+		}
+	}
+
+	/// Gets the next route to try, see Router::get_route for a description of first_hops, which
+	/// should generally reflect our channels at the time of each attempt. Fails if max_attempts
+	/// routes have already been returned or if no route avoiding all failed channels exists.
+	pub fn next_route(&mut self, first_hops: Option<&[channelmanager::ChannelDetails]>) -> Result<Route, LightningError> {
+		if self.attempts >= self.max_attempts {
+			return Err(LightningError{err: "Exhausted the maximum number of payment attempts", action: ErrorAction::IgnoreError});
+		}
+		let route = self.router.get_route_with_params(&self.target, first_hops, &self.last_hops, self.final_value_msat, self.final_cltv, &self.params)?;
+		self.attempts += 1;
+		Ok(route)
+	}
+}
+
+/// What we've learned about the liquidity available in one direction of a channel, as of
+/// last_updated.
+struct LiquidityBounds {
+	min_msat: u64,
+	max_msat: Option<u64>,
+	last_updated: u64,
+}
+
+impl LiquidityBounds {
+	/// Gets the bounds as of now_secs, relaxed by one halving of the minimum and one doubling of the
+	/// maximum for each half life elapsed since they were learned.
+	fn decayed(&self, half_life_secs: u64, now_secs: u64) -> (u64, Option<u64>) {
+		let half_lives = now_secs.saturating_sub(self.last_updated) / cmp::max(half_life_secs, 1);
+		if half_lives >= 64 {
+			return (0, None);
+		}
+		let max_msat = self.max_msat.and_then(|max_msat| max_msat.checked_mul(1 << half_lives));
+		(self.min_msat >> half_lives, max_msat)
+	}
+}
+
+/// Bounds on the liquidity available to forward payments over channels, learned from the results
+/// of our payment attempts: a channel which failed to forward an amount can't have had that
+/// much available, while one which forwarded it must have. As channels' balances change over
+/// time, the bounds decay, with the upper bound doubling and the lower bound halving every
+/// half_life_secs, until we no longer know anything about the channel.
+///
+/// Upper bounds are consulted by Router::get_route_with_learned_liquidity, so that channels which
+/// recently failed are only used for smaller amounts.
+///
+/// Timestamps are in seconds since the UNIX epoch.
+pub struct ChannelLiquidityStore {
+	// Keyed by short_channel_id and the node forwarding over the channel
+	bounds: HashMap<(ShortChannelId, PublicKey), LiquidityBounds>,
+	half_life_secs: u64,
+}
+
+impl ChannelLiquidityStore {
+	/// Creates a new store, knowing nothing about any channel, whose bounds decay with the given
+	/// half life.
+	pub fn new(half_life_secs: u64) -> Self {
+		ChannelLiquidityStore {
+			bounds: HashMap::new(),
+			half_life_secs,
+		}
+	}
+
+	fn decayed_bounds(&self, short_channel_id: ShortChannelId, src_node_id: &PublicKey, now_secs: u64) -> (u64, Option<u64>) {
+		self.bounds.get(&(short_channel_id, *src_node_id))
+			.map(|bounds| bounds.decayed(self.half_life_secs, now_secs)).unwrap_or((0, None))
+	}
+
+	fn set_bounds(&mut self, short_channel_id: ShortChannelId, src_node_id: &PublicKey, min_msat: u64, max_msat: Option<u64>, now_secs: u64) {
+		if min_msat == 0 && max_msat.is_none() {
+			self.bounds.remove(&(short_channel_id, *src_node_id));
+		} else {
+			self.bounds.insert((short_channel_id, *src_node_id), LiquidityBounds { min_msat, max_msat, last_updated: now_secs });
+		}
+	}
+
+	/// Records that src_node_id failed to forward amount_msat over the given channel due to a lack
+	/// of liquidity, so that less than amount_msat is available. Any lower bound we'd learned which
+	/// contradicts this is outdated and forgotten.
+	pub fn channel_failed(&mut self, short_channel_id: ShortChannelId, src_node_id: &PublicKey, amount_msat: u64, now_secs: u64) {
+		let (min_msat, max_msat) = self.decayed_bounds(short_channel_id, src_node_id, now_secs);
+		let new_max_msat = cmp::min(max_msat.unwrap_or(u64::max_value()), amount_msat.saturating_sub(1));
+		let new_min_msat = if min_msat > new_max_msat { 0 } else { min_msat };
+		self.set_bounds(short_channel_id, src_node_id, new_min_msat, Some(new_max_msat), now_secs);
+	}
+
+	/// Records that src_node_id forwarded amount_msat over the given channel, so that at least
+	/// amount_msat was available. Any upper bound we'd learned which contradicts this is outdated
+	/// and forgotten.
+	pub fn channel_succeeded(&mut self, short_channel_id: ShortChannelId, src_node_id: &PublicKey, amount_msat: u64, now_secs: u64) {
+		let (min_msat, max_msat) = self.decayed_bounds(short_channel_id, src_node_id, now_secs);
+		let new_min_msat = cmp::max(min_msat, amount_msat);
+		let new_max_msat = max_msat.and_then(|max_msat| if max_msat >= new_min_msat { Some(max_msat) } else { None });
+		self.set_bounds(short_channel_id, src_node_id, new_min_msat, new_max_msat, now_secs);
+	}
+
+	/// Records the result of a payment over the given route from payer which failed at the hop with
+	/// the given index due to a lack of liquidity, ie every hop before it forwarded the payment but
+	/// the channel of that hop could not.
+	pub fn payment_failed(&mut self, payer: &PublicKey, route: &Route, failed_hop_idx: usize, now_secs: u64) {
+		let mut amount_msat = route.hops.iter().fold(0u64, |total, hop| total.saturating_add(hop.fee_msat));
+		for (idx, hop) in route.hops.iter().enumerate().take(failed_hop_idx + 1) {
+			let src_node_id = if idx == 0 { payer } else { &route.hops[idx - 1].pubkey };
+			if idx == failed_hop_idx {
+				self.channel_failed(hop.short_channel_id, src_node_id, amount_msat, now_secs);
+			} else {
+				self.channel_succeeded(hop.short_channel_id, src_node_id, amount_msat, now_secs);
+			}
+			// hops[idx].fee_msat is paid to hops[idx].pubkey, so isn't forwarded any further
+			amount_msat = amount_msat.saturating_sub(hop.fee_msat);
+		}
+	}
+
+	/// Gets the (lower, upper) bounds on the liquidity, in msat, available for src_node_id to
+	/// forward over the given channel as of now_secs, if we've learned anything about it.
+	pub fn liquidity_bounds_msat(&self, short_channel_id: ShortChannelId, src_node_id: &PublicKey, now_secs: u64) -> Option<(u64, Option<u64>)> {
+		match self.decayed_bounds(short_channel_id, src_node_id, now_secs) {
+			(0, None) => None,
+			bounds => Some(bounds),
+		}
+	}
+
+	/// Removes everything we've learned about channels whose bounds have fully decayed as of
+	/// now_secs, bounding the memory used by the store.
+	pub fn remove_stale(&mut self, now_secs: u64) {
+		let half_life_secs = self.half_life_secs;
+		self.bounds.retain(|_, bounds| bounds.decayed(half_life_secs, now_secs) != (0, None));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chain::chaininterface;
+	use chain::chaininterface::{ChainError, ChainListener, ChainWatchInterface};
+	use ln::channel::MAX_FUNDING_SATOSHIS;
+	use ln::channelmanager;
+	use ln::channelmanager::PaymentHash;
+	use ln::router::{GossipMessage,Route,RouteHop,RouteValidationError,MppValidationError,ExportedRouteHop,RouteFingerprint,RouteCostBreakdown,RouteComparison,PaymentPathRecommendation,PaymentPathReason,Router,NodeInfo,NetworkMap,ChannelInfo,DirectionalChannelInfo,RouteHint,RouteHintDecodeError,RouteParameters,RouteObjective,ParallelChannelPolicy,RouteRetryGenerator,ChannelLiquidityStore,NetworkStats,RouteDiagnostics,RouteSearchMetrics,RouteAmountLimit,PrefixMatchError,GossipFreshness,GossipRejectionReason,MIN_HTLC_MINIMUM_MSAT,RoutingFees,PathFeeEstimate,InsufficientCapacityError,SplitValueError,split_value_over_first_hops,split_value_over_first_hops_randomized,funding_script_pubkey,RouterReadArgs,SERIALIZATION_VERSION};
+	use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
+	use ln::msgs::{DecodeError, ErrorAction, LightningError, RoutingMessageHandler, NetAddress};
+	use ln::msgs;
+	use ln::onion_utils;
+	use util::test_utils;
+	use util::test_utils::TestVecWriter;
+	use util::logger::Logger;
+	use util::errors::APIError;
+	use util::ser::{Writeable, Readable, ReadableArgs, Writer};
+	use util::byte_utils;
+	use util::scid_utils;
+	use util::scid_utils::ShortChannelId;
+
+	use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin_hashes::Hash;
+	use bitcoin::network::constants::Network;
+	use bitcoin::blockdata::block::{Block, BlockHeader};
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::blockdata::script::Script;
+	use bitcoin::blockdata::transaction::Transaction;
+	use bitcoin::util::hash::BitcoinHash;
+
+	use hex;
+
+	use secp256k1::key::{PublicKey,SecretKey};
+	use secp256k1::All;
+	use secp256k1::Secp256k1;
+
+	use std::collections::HashSet;
+	use std::mem;
+	use std::sync::{Arc, Mutex};
+	use std::sync::atomic::{AtomicBool, Ordering};
+	use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+	/// A ChainWatchInterface whose UTXO lookups return utxo (or UnknownTx if it's None) while
+	/// available is set, and ChainError::Unavailable otherwise.
+	struct UtxoLookupChainWatcher {
+		available: AtomicBool,
+		utxo: Mutex<Option<(Script, u64)>>,
+	}
+	impl ChainWatchInterface for UtxoLookupChainWatcher {
+		fn install_watch_tx(&self, _txid: &Sha256dHash, _script_pub_key: &Script) { }
+		fn install_watch_outpoint(&self, _outpoint: (Sha256dHash, u32), _out_script: &Script) { }
+		fn watch_all_txn(&self) { }
+		fn get_chain_utxo(&self, _genesis_hash: Sha256dHash, _unspent_tx_output_identifier: u64) -> Result<(Script, u64), ChainError> {
+			if !self.available.load(Ordering::Acquire) {
+				return Err(ChainError::Unavailable);
+			}
+			self.utxo.lock().unwrap().clone().ok_or(ChainError::UnknownTx)
+		}
+		fn filter_block<'a>(&self, _block: &'a Block) -> (Vec<&'a Transaction>, Vec<u32>) {
+			(Vec::new(), Vec::new())
+		}
+		fn reentered(&self) -> usize { 0 }
+	}
+
+	fn create_router() -> (Secp256k1<All>, PublicKey, Router) {
+		let secp_ctx = Secp256k1::new();
+		let our_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0101010101010101010101010101010101010101010101010101010101010101").unwrap()[..]).unwrap());
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::new(our_id, genesis_block(Network::Testnet).header.bitcoin_hash(), chain_monitor, Arc::clone(&logger));
+		(secp_ctx, our_id, router)
+	}
+
+	fn get_test_node_id(secp_ctx: &Secp256k1<All>, idx: u8) -> PublicKey {
+		PublicKey::from_secret_key(secp_ctx, &SecretKey::from_slice(&[idx; 32]).unwrap())
+	}
+
+	fn get_test_directional_info(src_node_id: &PublicKey, cltv_expiry_delta: u16, fee_base_msat: u32, fee_proportional_millionths: u32) -> DirectionalChannelInfo {
+		DirectionalChannelInfo {
+			src_node_id: src_node_id.clone(),
+			last_update: 0,
+			enabled: true,
+			cltv_expiry_delta,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+			fee_base_msat,
+			fee_proportional_millionths,
+			last_update_message: None,
+		}
+	}
+
+	fn get_test_first_hop(remote_network_id: &PublicKey, short_channel_id: u64, outbound_capacity_msat: u64) -> channelmanager::ChannelDetails {
+		channelmanager::ChannelDetails {
+			channel_id: [0; 32],
+			short_channel_id: Some(short_channel_id),
+			remote_network_id: remote_network_id.clone(),
+			counterparty_features: InitFeatures::from_le_bytes(vec![0b11]),
+			channel_value_satoshis: 0,
+			user_id: 0,
+			outbound_capacity_msat,
+			inbound_capacity_msat: 0,
+			is_live: true,
+			outbound_htlc_slots: 483,
+		}
+	}
+
+	/// Gets a route for 1000 msat to target, with a final CLTV delta of 42 and neither first nor
+	/// last hops.
+	fn get_test_route(router: &Router, target: &PublicKey, params: &RouteParameters) -> Result<Route, LightningError> {
+		router.get_route_with_params(target, None, &Vec::new(), 1000, 42, params)
+	}
+
+	/// Inserts a channel with the given directional policies, adding any missing nodes with zero
+	/// lowest-inbound fees.
+	fn add_test_channel<S>(router: &Router<S>, short_channel_id: u64, one_to_two: DirectionalChannelInfo, two_to_one: DirectionalChannelInfo) {
+		let mut network = router.network_map.write().unwrap();
+		let chan_key = NetworkMap::get_key(short_channel_id, Sha256dHash::hash(&[0; 32]));
+		for node_id in [one_to_two.src_node_id.clone(), two_to_one.src_node_id.clone()].iter() {
+			let node = network.nodes.entry(node_id.clone()).or_insert_with(|| NodeInfo {
+				lowest_inbound_channel_fee_base_msat: 0,
+				lowest_inbound_channel_fee_proportional_millionths: 0,
+				last_update: Some(1),
+				..NodeInfo::unannounced()
+			});
+			node.channels.push(chan_key.clone());
+		}
+		network.channels.insert(chan_key, ChannelInfo {
+			features: ChannelFeatures::empty(),
+			capacity_sats: None,
+			one_to_two,
+			two_to_one,
+			announcement_message: None,
+		});
+	}
+
+	/// Builds a testnet channel_announcement between the nodes whose secret keys are
+	/// [node_1_secret_idx; 32] and [node_2_secret_idx; 32], with bitcoin keys [4; 32] and [5; 32].
+	fn get_signed_channel_announcement(secp_ctx: &Secp256k1<All>, short_channel_id: u64, node_1_secret_idx: u8, node_2_secret_idx: u8) -> msgs::ChannelAnnouncement {
+		get_signed_channel_announcement_with_features(secp_ctx, short_channel_id, node_1_secret_idx, node_2_secret_idx, ChannelFeatures::empty())
+	}
+
+	fn get_signed_channel_announcement_with_features(secp_ctx: &Secp256k1<All>, short_channel_id: u64, node_1_secret_idx: u8, node_2_secret_idx: u8, features: ChannelFeatures) -> msgs::ChannelAnnouncement {
+		get_signed_channel_announcement_with_bitcoin_keys(secp_ctx, short_channel_id, node_1_secret_idx, node_2_secret_idx, 4, 5, features)
+	}
+
+	fn get_signed_channel_announcement_with_bitcoin_keys(secp_ctx: &Secp256k1<All>, short_channel_id: u64, node_1_secret_idx: u8, node_2_secret_idx: u8, bitcoin_key_1_secret_idx: u8, bitcoin_key_2_secret_idx: u8, features: ChannelFeatures) -> msgs::ChannelAnnouncement {
+		let unsigned_announcement = msgs::UnsignedChannelAnnouncement {
+			features,
+			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+			short_channel_id,
+			node_id_1: get_test_node_id(secp_ctx, node_1_secret_idx),
+			node_id_2: get_test_node_id(secp_ctx, node_2_secret_idx),
+			bitcoin_key_1: get_test_node_id(secp_ctx, bitcoin_key_1_secret_idx),
+			bitcoin_key_2: get_test_node_id(secp_ctx, bitcoin_key_2_secret_idx),
+			excess_data: Vec::new(),
+		};
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+		msgs::ChannelAnnouncement {
+			node_signature_1: secp_ctx.sign(&msg_hash, &SecretKey::from_slice(&[node_1_secret_idx; 32]).unwrap()),
+			node_signature_2: secp_ctx.sign(&msg_hash, &SecretKey::from_slice(&[node_2_secret_idx; 32]).unwrap()),
+			bitcoin_signature_1: secp_ctx.sign(&msg_hash, &SecretKey::from_slice(&[bitcoin_key_1_secret_idx; 32]).unwrap()),
+			bitcoin_signature_2: secp_ctx.sign(&msg_hash, &SecretKey::from_slice(&[bitcoin_key_2_secret_idx; 32]).unwrap()),
+			contents: unsigned_announcement,
+		}
+	}
+
+	/// Builds a channel_update for the given direction of a channel, signed by the node whose secret
+	/// key is [node_secret_idx; 32].
+	fn get_signed_channel_update(secp_ctx: &Secp256k1<All>, node_secret_idx: u8, short_channel_id: u64, flags: u16, timestamp: u32, fee_base_msat: u32, excess_data: Vec<u8>) -> msgs::ChannelUpdate {
+		let unsigned_msg = msgs::UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+			short_channel_id,
+			timestamp,
+			flags,
+			cltv_expiry_delta: 0,
+			htlc_minimum_msat: 0,
+			fee_base_msat,
+			fee_proportional_millionths: 0,
+			excess_data,
+		};
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_msg.encode()[..])[..]);
+		msgs::ChannelUpdate {
+			signature: secp_ctx.sign(&msg_hash, &SecretKey::from_slice(&[node_secret_idx; 32]).unwrap()),
+			contents: unsigned_msg,
+		}
+	}
+
+	#[test]
+	fn node_announcement_unknown_address_type_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_secret = SecretKey::from_slice(&[2; 32]).unwrap();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &node_secret);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_id, 0, 0, 0));
+
+		// An IPv4 address followed by an address of a type we don't (yet) understand
+		let unsigned_announcement = msgs::UnsignedNodeAnnouncement {
+			features: NodeFeatures::empty(),
+			timestamp: 42,
+			node_id,
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: vec![msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }],
+			excess_address_data: vec![0xfe, 1, 2, 3, 4],
+			excess_data: Vec::new(),
+		};
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+		let announcement = msgs::NodeAnnouncement {
+			signature: secp_ctx.sign(&msg_hash, &node_secret),
+			contents: unsigned_announcement,
+		};
+
+		// The unknown address is kept aside rather than failing the whole announcement...
+		let read_announcement: msgs::NodeAnnouncement = Readable::read(&mut ::std::io::Cursor::new(&announcement.encode())).unwrap();
+		assert!(read_announcement == announcement);
+
+		// ...so the node's known addresses are still learned, though we don't relay what we
+		// can't fully understand.
+		assert!(!router.handle_node_announcement(&read_announcement).unwrap());
+		let network = router.network_map.read().unwrap();
+		let node = network.nodes.get(&node_id).unwrap();
+		assert_eq!(node.addresses, vec![msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }]);
+		assert_eq!(node.last_update, Some(42));
+		assert!(node.announcement_message.is_none());
+	}
+
+	#[test]
+	fn node_announcement_onion_v3_address_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_secret = SecretKey::from_slice(&[2; 32]).unwrap();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &node_secret);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_id, 0, 0, 0));
+
+		let addresses = vec![
+			msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 },
+			msgs::NetAddress::OnionV3 { ed25519_pubkey: [42; 32], checksum: 0xbeef, version: 3, port: 9735 },
+		];
+		let unsigned_announcement = msgs::UnsignedNodeAnnouncement {
+			features: NodeFeatures::empty(),
+			timestamp: 42,
+			node_id,
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: addresses.clone(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+		let announcement = msgs::NodeAnnouncement {
+			signature: secp_ctx.sign(&msg_hash, &node_secret),
+			contents: unsigned_announcement,
+		};
+
+		// Tor v3 addresses are understood, so the announcement is relayed as-is
+		let read_announcement: msgs::NodeAnnouncement = Readable::read(&mut ::std::io::Cursor::new(&announcement.encode())).unwrap();
+		assert!(router.handle_node_announcement(&read_announcement).unwrap());
+		assert_eq!(router.get_addresses(&node_id).unwrap(), addresses);
+		assert!(router.network_map.read().unwrap().nodes.get(&node_id).unwrap().announcement_message.is_some());
+	}
+
+	#[test]
+	fn self_referential_channel_announcement_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node_secret = SecretKey::from_slice(&[2; 32]).unwrap();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &node_secret);
+		let bitcoin_key_1 = get_test_node_id(&secp_ctx, 3);
+		let bitcoin_key_2 = get_test_node_id(&secp_ctx, 4);
+
+		let mut unsigned_announcement = msgs::UnsignedChannelAnnouncement {
+			features: ChannelFeatures::empty(),
+			chain_hash: Sha256dHash::hash(&[0; 32]),
+			short_channel_id: 1,
+			node_id_1: node_id,
+			node_id_2: node_id,
+			bitcoin_key_1,
+			bitcoin_key_2,
+			excess_data: Vec::new(),
+		};
+		macro_rules! sign_announcement {
+			() => { {
+				let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+				msgs::ChannelAnnouncement {
+					node_signature_1: secp_ctx.sign(&msg_hash, &node_secret),
+					node_signature_2: secp_ctx.sign(&msg_hash, &node_secret),
+					bitcoin_signature_1: secp_ctx.sign(&msg_hash, &SecretKey::from_slice(&[3; 32]).unwrap()),
+					bitcoin_signature_2: secp_ctx.sign(&msg_hash, &SecretKey::from_slice(&[4; 32]).unwrap()),
+					contents: unsigned_announcement.clone(),
+				}
+			} }
+		}
+
+		match router.handle_channel_announcement(&sign_announcement!()) {
+			Err(LightningError{err, ..}) => assert_eq!(err, "Channel announcement node had a channel with itself"),
+			Ok(_) => panic!(),
+		}
+
+		unsigned_announcement.node_id_2 = get_test_node_id(&secp_ctx, 5);
+		unsigned_announcement.bitcoin_key_2 = bitcoin_key_1;
+		match router.handle_channel_announcement(&sign_announcement!()) {
+			Err(LightningError{err, ..}) => assert_eq!(err, "Channel announcement used the same bitcoin key for both nodes"),
+			Ok(_) => panic!(),
+		}
+
+		assert!(router.get_channel_info(ShortChannelId(1)).is_none());
+		let network = router.network_map.read().unwrap();
+		assert!(network.nodes.get(&node_id).is_none());
+	}
+
+	#[test]
+	fn route_test() {
+		let (secp_ctx, our_id, router) = create_router();
+
+		// Build network from our_id to node8:
+		//
+		//        -1(1)2-  node1  -1(3)2-
+		//       /                       \
+		// our_id -1(12)2- node8 -1(13)2--- node3
+		//       \                       /
+		//        -1(2)2-  node2  -1(4)2-
+		//
+		//
+		// chan1  1-to-2: disabled
+		// chan1  2-to-1: enabled, 0 fee
+		//
+		// chan2  1-to-2: enabled, ignored fee
+		// chan2  2-to-1: enabled, 0 fee
+		//
+		// chan3  1-to-2: enabled, 0 fee
+		// chan3  2-to-1: enabled, 100 msat fee
+		//
+		// chan4  1-to-2: enabled, 100% fee
+		// chan4  2-to-1: enabled, 0 fee
+		//
+		// chan12 1-to-2: enabled, ignored fee
+		// chan12 2-to-1: enabled, 0 fee
+		//
+		// chan13 1-to-2: enabled, 200% fee
+		// chan13 2-to-1: enabled, 0 fee
+		//
+		//
+		//       -1(5)2- node4 -1(8)2--
+		//       |         2          |
+		//       |       (11)         |
+		//      /          1           \
+		// node3--1(6)2- node5 -1(9)2--- node7 (not in global route map)
+		//      \                      /
+		//       -1(7)2- node6 -1(10)2-
+		//
+		// chan5  1-to-2: enabled, 100 msat fee
+		// chan5  2-to-1: enabled, 0 fee
+		//
+		// chan6  1-to-2: enabled, 0 fee
+		// chan6  2-to-1: enabled, 0 fee
+		//
+		// chan7  1-to-2: enabled, 100% fee
+		// chan7  2-to-1: enabled, 0 fee
+		//
+		// chan8  1-to-2: enabled, variable fee (0 then 1000 msat)
+		// chan8  2-to-1: enabled, 0 fee
+		//
+		// chan9  1-to-2: enabled, 1001 msat fee
+		// chan9  2-to-1: enabled, 0 fee
+		//
+		// chan10 1-to-2: enabled, 0 fee
+		// chan10 2-to-1: enabled, 0 fee
+		//
+		// chan11 1-to-2: enabled, 0 fee
+		// chan11 2-to-1: enabled, 0 fee
+
+		let node1 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0202020202020202020202020202020202020202020202020202020202020202").unwrap()[..]).unwrap());
+		let node2 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0303030303030303030303030303030303030303030303030303030303030303").unwrap()[..]).unwrap());
+		let node3 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0404040404040404040404040404040404040404040404040404040404040404").unwrap()[..]).unwrap());
+		let node4 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0505050505050505050505050505050505050505050505050505050505050505").unwrap()[..]).unwrap());
+		let node5 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0606060606060606060606060606060606060606060606060606060606060606").unwrap()[..]).unwrap());
+		let node6 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0707070707070707070707070707070707070707070707070707070707070707").unwrap()[..]).unwrap());
+		let node7 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0808080808080808080808080808080808080808080808080808080808080808").unwrap()[..]).unwrap());
+		let node8 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0909090909090909090909090909090909090909090909090909090909090909").unwrap()[..]).unwrap());
+
+		let zero_hash = Sha256dHash::hash(&[0; 32]);
+
+		macro_rules! id_to_feature_flags {
+			// Set the feature flags to the id'th odd (ie non-required) feature bit so that we can
+			// test for it later.
+			($id: expr) => { {
+				let idx = ($id - 1) * 2 + 1;
+				if idx > 8*3 {
+					vec![1 << (idx - 8*3), 0, 0, 0]
+				} else if idx > 8*2 {
+					vec![1 << (idx - 8*2), 0, 0]
+				} else if idx > 8*1 {
+					vec![1 << (idx - 8*1), 0]
+				} else {
+					vec![1 << idx]
+				}
+			} }
+		}
+
+		{
+			let mut network = router.network_map.write().unwrap();
+
+			network.nodes.insert(node1.clone(), NodeInfo {
+				channels: vec!(NetworkMap::get_key(1, zero_hash.clone()), NetworkMap::get_key(3, zero_hash.clone())),
+				lowest_inbound_channel_fee_base_msat: 100,
+				lowest_inbound_channel_fee_proportional_millionths: 0,
+				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(1)),
+				last_update: Some(1),
+				rgb: [0; 3],
+				alias: [0; 32],
+				addresses: Vec::new(),
+				announcement_message: None,
+			});
+			network.channels.insert(NetworkMap::get_key(1, zero_hash.clone()), ChannelInfo {
+				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(1)),
+				capacity_sats: None,
+				one_to_two: DirectionalChannelInfo {
+					src_node_id: our_id.clone(),
+					last_update: 0,
+					enabled: false,
+					cltv_expiry_delta: u16::max_value(), // This value should be ignored
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: u32::max_value(), // This value should be ignored
+					fee_proportional_millionths: u32::max_value(), // This value should be ignored
+					last_update_message: None,
+				}, two_to_one: DirectionalChannelInfo {
+					src_node_id: node1.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: 0,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				},
+				announcement_message: None,
+			});
+			network.nodes.insert(node2.clone(), NodeInfo {
+				channels: vec!(NetworkMap::get_key(2, zero_hash.clone()), NetworkMap::get_key(4, zero_hash.clone())),
+				lowest_inbound_channel_fee_base_msat: 0,
+				lowest_inbound_channel_fee_proportional_millionths: 0,
+				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(2)),
+				last_update: Some(1),
+				rgb: [0; 3],
+				alias: [0; 32],
+				addresses: Vec::new(),
+				announcement_message: None,
+			});
+			network.channels.insert(NetworkMap::get_key(2, zero_hash.clone()), ChannelInfo {
+				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(2)),
+				capacity_sats: None,
+				one_to_two: DirectionalChannelInfo {
+					src_node_id: our_id.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: u16::max_value(), // This value should be ignored
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: u32::max_value(), // This value should be ignored
+					fee_proportional_millionths: u32::max_value(), // This value should be ignored
+					last_update_message: None,
+				}, two_to_one: DirectionalChannelInfo {
+					src_node_id: node2.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: 0,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				},
+				announcement_message: None,
+			});
+			network.nodes.insert(node8.clone(), NodeInfo {
+				channels: vec!(NetworkMap::get_key(12, zero_hash.clone()), NetworkMap::get_key(13, zero_hash.clone())),
+				lowest_inbound_channel_fee_base_msat: 0,
+				lowest_inbound_channel_fee_proportional_millionths: 0,
+				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(8)),
+				last_update: Some(1),
+				rgb: [0; 3],
+				alias: [0; 32],
+				addresses: Vec::new(),
+				announcement_message: None,
+			});
+			network.channels.insert(NetworkMap::get_key(12, zero_hash.clone()), ChannelInfo {
+				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(12)),
+				capacity_sats: None,
+				one_to_two: DirectionalChannelInfo {
+					src_node_id: our_id.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: u16::max_value(), // This value should be ignored
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: u32::max_value(), // This value should be ignored
+					fee_proportional_millionths: u32::max_value(), // This value should be ignored
+					last_update_message: None,
+				}, two_to_one: DirectionalChannelInfo {
+					src_node_id: node8.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: 0,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				},
+				announcement_message: None,
+			});
+			network.nodes.insert(node3.clone(), NodeInfo {
+				channels: vec!(
+					NetworkMap::get_key(3, zero_hash.clone()),
+					NetworkMap::get_key(4, zero_hash.clone()),
+					NetworkMap::get_key(13, zero_hash.clone()),
+					NetworkMap::get_key(5, zero_hash.clone()),
+					NetworkMap::get_key(6, zero_hash.clone()),
+					NetworkMap::get_key(7, zero_hash.clone())),
+				lowest_inbound_channel_fee_base_msat: 0,
+				lowest_inbound_channel_fee_proportional_millionths: 0,
+				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(3)),
+				last_update: Some(1),
+				rgb: [0; 3],
+				alias: [0; 32],
+				addresses: Vec::new(),
+				announcement_message: None,
+			});
+			network.channels.insert(NetworkMap::get_key(3, zero_hash.clone()), ChannelInfo {
+				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(3)),
+				capacity_sats: None,
+				one_to_two: DirectionalChannelInfo {
+					src_node_id: node1.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (3 << 8) | 1,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				}, two_to_one: DirectionalChannelInfo {
+					src_node_id: node3.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (3 << 8) | 2,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 100,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				},
+				announcement_message: None,
+			});
+			network.channels.insert(NetworkMap::get_key(4, zero_hash.clone()), ChannelInfo {
+				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(4)),
+				capacity_sats: None,
+				one_to_two: DirectionalChannelInfo {
+					src_node_id: node2.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (4 << 8) | 1,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 1000000,
+					last_update_message: None,
+				}, two_to_one: DirectionalChannelInfo {
+					src_node_id: node3.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (4 << 8) | 2,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				},
+				announcement_message: None,
+			});
+			network.channels.insert(NetworkMap::get_key(13, zero_hash.clone()), ChannelInfo {
+				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(13)),
+				capacity_sats: None,
+				one_to_two: DirectionalChannelInfo {
+					src_node_id: node8.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (13 << 8) | 1,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 2000000,
+					last_update_message: None,
+				}, two_to_one: DirectionalChannelInfo {
+					src_node_id: node3.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (13 << 8) | 2,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				},
+				announcement_message: None,
+			});
+			network.nodes.insert(node4.clone(), NodeInfo {
+				channels: vec!(NetworkMap::get_key(5, zero_hash.clone()), NetworkMap::get_key(11, zero_hash.clone())),
+				lowest_inbound_channel_fee_base_msat: 0,
+				lowest_inbound_channel_fee_proportional_millionths: 0,
+				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(4)),
+				last_update: Some(1),
+				rgb: [0; 3],
+				alias: [0; 32],
+				addresses: Vec::new(),
+				announcement_message: None,
+			});
+			network.channels.insert(NetworkMap::get_key(5, zero_hash.clone()), ChannelInfo {
+				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(5)),
+				capacity_sats: None,
+				one_to_two: DirectionalChannelInfo {
+					src_node_id: node3.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (5 << 8) | 1,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 100,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				}, two_to_one: DirectionalChannelInfo {
+					src_node_id: node4.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (5 << 8) | 2,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				},
+				announcement_message: None,
+			});
+			network.nodes.insert(node5.clone(), NodeInfo {
+				channels: vec!(NetworkMap::get_key(6, zero_hash.clone()), NetworkMap::get_key(11, zero_hash.clone())),
+				lowest_inbound_channel_fee_base_msat: 0,
+				lowest_inbound_channel_fee_proportional_millionths: 0,
+				features: NodeFeatures::from_le_bytes(id_to_feature_flags!(5)),
+				last_update: Some(1),
+				rgb: [0; 3],
+				alias: [0; 32],
+				addresses: Vec::new(),
+				announcement_message: None,
+			});
+			network.channels.insert(NetworkMap::get_key(6, zero_hash.clone()), ChannelInfo {
+				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(6)),
+				capacity_sats: None,
+				one_to_two: DirectionalChannelInfo {
+					src_node_id: node3.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (6 << 8) | 1,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				}, two_to_one: DirectionalChannelInfo {
+					src_node_id: node5.clone(),
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta: (6 << 8) | 2,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+					fee_base_msat: 0,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				},
+				announcement_message: None,
+			});
+			network.channels.insert(NetworkMap::get_key(11, zero_hash.clone()), ChannelInfo {
+				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(11)),
+				capacity_sats: None,
 				one_to_two: DirectionalChannelInfo {
 					src_node_id: node5.clone(),
 					last_update: 0,
 					enabled: true,
 					cltv_expiry_delta: (11 << 8) | 1,
 					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
 					last_update_message: None,
@@ -1449,6 +5563,7 @@ mod tests {
 					enabled: true,
 					cltv_expiry_delta: (11 << 8) | 2,
 					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
 					last_update_message: None,
@@ -1468,12 +5583,14 @@ mod tests {
 			});
 			network.channels.insert(NetworkMap::get_key(7, zero_hash.clone()), ChannelInfo {
 				features: ChannelFeatures::from_le_bytes(id_to_feature_flags!(7)),
+				capacity_sats: None,
 				one_to_two: DirectionalChannelInfo {
 					src_node_id: node3.clone(),
 					last_update: 0,
 					enabled: true,
 					cltv_expiry_delta: (7 << 8) | 1,
 					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 1000000,
 					last_update_message: None,
@@ -1483,6 +5600,7 @@ mod tests {
 					enabled: true,
 					cltv_expiry_delta: (7 << 8) | 2,
 					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
 					last_update_message: None,
@@ -1491,365 +5609,3947 @@ mod tests {
 			});
 		}
 
-		{ // Simple route to 3 via 2
-			let route = router.get_route(&node3, None, &Vec::new(), 100, 42).unwrap();
-			assert_eq!(route.hops.len(), 2);
+		{ // Simple route to 3 via 2
+			let route = router.get_route(&node3, None, &Vec::new(), 100, 42).unwrap();
+			assert_eq!(route.hops.len(), 2);
+
+			assert_eq!(route.hops[0].pubkey, node2);
+			assert_eq!(route.hops[0].short_channel_id, 2);
+			assert_eq!(route.hops[0].fee_msat, 100);
+			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
+			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
+			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+
+			assert_eq!(route.hops[1].pubkey, node3);
+			assert_eq!(route.hops[1].short_channel_id, 4);
+			assert_eq!(route.hops[1].fee_msat, 100);
+			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
+			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+		}
+
+		{ // Disable channels 4 and 12 by requiring unknown feature bits
+			let mut network = router.network_map.write().unwrap();
+			network.channels.get_mut(&NetworkMap::get_key(4, zero_hash.clone())).unwrap().features.set_require_unknown_bits();
+			network.channels.get_mut(&NetworkMap::get_key(12, zero_hash.clone())).unwrap().features.set_require_unknown_bits();
+		}
+
+		{ // If all the channels require some features we don't understand, route should fail
+			if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route(&node3, None, &Vec::new(), 100, 42) {
+				assert_eq!(err, "Failed to find a path to the given destination");
+			} else { panic!(); }
+		}
+
+		{ // If we specify a channel to node8, that overrides our local channel view and that gets used
+			let our_chans = vec![get_test_first_hop(&node8, 42, 0)];
+			let route = router.get_route(&node3, Some(&our_chans), &Vec::new(), 100, 42).unwrap();
+			assert_eq!(route.hops.len(), 2);
+
+			assert_eq!(route.hops[0].pubkey, node8);
+			assert_eq!(route.hops[0].short_channel_id, 42);
+			assert_eq!(route.hops[0].fee_msat, 200);
+			assert_eq!(route.hops[0].cltv_expiry_delta, (13 << 8) | 1);
+			assert_eq!(route.hops[0].node_features.le_flags(), &vec![0b11]); // it should also override our view of their features
+			assert_eq!(route.hops[0].channel_features.le_flags(), &Vec::new()); // No feature flags will meet the relevant-to-channel conversion
+
+			assert_eq!(route.hops[1].pubkey, node3);
+			assert_eq!(route.hops[1].short_channel_id, 13);
+			assert_eq!(route.hops[1].fee_msat, 100);
+			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
+			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(13));
+		}
+
+		{ // Re-enable channels 4 and 12 by wiping the unknown feature bits
+			let mut network = router.network_map.write().unwrap();
+			network.channels.get_mut(&NetworkMap::get_key(4, zero_hash.clone())).unwrap().features.clear_require_unknown_bits();
+			network.channels.get_mut(&NetworkMap::get_key(12, zero_hash.clone())).unwrap().features.clear_require_unknown_bits();
+		}
+
+		{ // Disable nodes 1, 2, and 8 by requiring unknown feature bits
+			let mut network = router.network_map.write().unwrap();
+			network.nodes.get_mut(&node1).unwrap().features.set_require_unknown_bits();
+			network.nodes.get_mut(&node2).unwrap().features.set_require_unknown_bits();
+			network.nodes.get_mut(&node8).unwrap().features.set_require_unknown_bits();
+		}
+
+		{ // If all nodes require some features we don't understand, route should fail
+			if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route(&node3, None, &Vec::new(), 100, 42) {
+				assert_eq!(err, "Failed to find a path to the given destination");
+			} else { panic!(); }
+		}
+
+		{ // If we specify a channel to node8, that overrides our local channel view and that gets used
+			let our_chans = vec![get_test_first_hop(&node8, 42, 0)];
+			let route = router.get_route(&node3, Some(&our_chans), &Vec::new(), 100, 42).unwrap();
+			assert_eq!(route.hops.len(), 2);
+
+			assert_eq!(route.hops[0].pubkey, node8);
+			assert_eq!(route.hops[0].short_channel_id, 42);
+			assert_eq!(route.hops[0].fee_msat, 200);
+			assert_eq!(route.hops[0].cltv_expiry_delta, (13 << 8) | 1);
+			assert_eq!(route.hops[0].node_features.le_flags(), &vec![0b11]); // it should also override our view of their features
+			assert_eq!(route.hops[0].channel_features.le_flags(), &Vec::new()); // No feature flags will meet the relevant-to-channel conversion
+
+			assert_eq!(route.hops[1].pubkey, node3);
+			assert_eq!(route.hops[1].short_channel_id, 13);
+			assert_eq!(route.hops[1].fee_msat, 100);
+			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
+			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(13));
+		}
+
+		{ // Re-enable nodes 1, 2, and 8
+			let mut network = router.network_map.write().unwrap();
+			network.nodes.get_mut(&node1).unwrap().features.clear_require_unknown_bits();
+			network.nodes.get_mut(&node2).unwrap().features.clear_require_unknown_bits();
+			network.nodes.get_mut(&node8).unwrap().features.clear_require_unknown_bits();
+		}
+
+		// Note that we don't test disabling node 3 and failing to route to it, as we (somewhat
+		// naively) assume that the user checked the feature bits on the invoice, which override
+		// the node_announcement.
+
+		{ // Route to 1 via 2 and 3 because our channel to 1 is disabled
+			let route = router.get_route(&node1, None, &Vec::new(), 100, 42).unwrap();
+			assert_eq!(route.hops.len(), 3);
+
+			assert_eq!(route.hops[0].pubkey, node2);
+			assert_eq!(route.hops[0].short_channel_id, 2);
+			assert_eq!(route.hops[0].fee_msat, 200);
+			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
+			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
+			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+
+			assert_eq!(route.hops[1].pubkey, node3);
+			assert_eq!(route.hops[1].short_channel_id, 4);
+			assert_eq!(route.hops[1].fee_msat, 100);
+			assert_eq!(route.hops[1].cltv_expiry_delta, (3 << 8) | 2);
+			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
+			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+
+			assert_eq!(route.hops[2].pubkey, node1);
+			assert_eq!(route.hops[2].short_channel_id, 3);
+			assert_eq!(route.hops[2].fee_msat, 100);
+			assert_eq!(route.hops[2].cltv_expiry_delta, 42);
+			assert_eq!(route.hops[2].node_features.le_flags(), &id_to_feature_flags!(1));
+			assert_eq!(route.hops[2].channel_features.le_flags(), &id_to_feature_flags!(3));
+		}
+
+		{ // If we specify a channel to node8, that overrides our local channel view and that gets used
+			let our_chans = vec![get_test_first_hop(&node8, 42, 0)];
+			let route = router.get_route(&node3, Some(&our_chans), &Vec::new(), 100, 42).unwrap();
+			assert_eq!(route.hops.len(), 2);
+
+			assert_eq!(route.hops[0].pubkey, node8);
+			assert_eq!(route.hops[0].short_channel_id, 42);
+			assert_eq!(route.hops[0].fee_msat, 200);
+			assert_eq!(route.hops[0].cltv_expiry_delta, (13 << 8) | 1);
+			assert_eq!(route.hops[0].node_features.le_flags(), &vec![0b11]);
+			assert_eq!(route.hops[0].channel_features.le_flags(), &Vec::new()); // No feature flags will meet the relevant-to-channel conversion
+
+			assert_eq!(route.hops[1].pubkey, node3);
+			assert_eq!(route.hops[1].short_channel_id, 13);
+			assert_eq!(route.hops[1].fee_msat, 100);
+			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
+			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(13));
+		}
+
+		let mut last_hops = vec!(RouteHint {
+				src_node_id: node4.clone(),
+				short_channel_id: ShortChannelId(8),
+				fee_base_msat: 0,
+				fee_proportional_millionths: 0,
+				cltv_expiry_delta: (8 << 8) | 1,
+				htlc_minimum_msat: 0,
+				htlc_maximum_msat: None,
+			}, RouteHint {
+				src_node_id: node5.clone(),
+				short_channel_id: ShortChannelId(9),
+				fee_base_msat: 1001,
+				fee_proportional_millionths: 0,
+				cltv_expiry_delta: (9 << 8) | 1,
+				htlc_minimum_msat: 0,
+				htlc_maximum_msat: None,
+			}, RouteHint {
+				src_node_id: node6.clone(),
+				short_channel_id: ShortChannelId(10),
+				fee_base_msat: 0,
+				fee_proportional_millionths: 0,
+				cltv_expiry_delta: (10 << 8) | 1,
+				htlc_minimum_msat: 0,
+				htlc_maximum_msat: None,
+			});
+
+		{ // Simple test across 2, 3, 5, and 4 via a last_hop channel
+			let route = router.get_route(&node7, None, &last_hops, 100, 42).unwrap();
+			assert_eq!(route.hops.len(), 5);
+
+			assert_eq!(route.hops[0].pubkey, node2);
+			assert_eq!(route.hops[0].short_channel_id, 2);
+			assert_eq!(route.hops[0].fee_msat, 100);
+			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
+			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
+			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+
+			assert_eq!(route.hops[1].pubkey, node3);
+			assert_eq!(route.hops[1].short_channel_id, 4);
+			assert_eq!(route.hops[1].fee_msat, 0);
+			assert_eq!(route.hops[1].cltv_expiry_delta, (6 << 8) | 1);
+			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
+			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+
+			assert_eq!(route.hops[2].pubkey, node5);
+			assert_eq!(route.hops[2].short_channel_id, 6);
+			assert_eq!(route.hops[2].fee_msat, 0);
+			assert_eq!(route.hops[2].cltv_expiry_delta, (11 << 8) | 1);
+			assert_eq!(route.hops[2].node_features.le_flags(), &id_to_feature_flags!(5));
+			assert_eq!(route.hops[2].channel_features.le_flags(), &id_to_feature_flags!(6));
+
+			assert_eq!(route.hops[3].pubkey, node4);
+			assert_eq!(route.hops[3].short_channel_id, 11);
+			assert_eq!(route.hops[3].fee_msat, 0);
+			assert_eq!(route.hops[3].cltv_expiry_delta, (8 << 8) | 1);
+			// If we have a peer in the node map, we'll use their features here since we don't have
+			// a way of figuring out their features from the invoice:
+			assert_eq!(route.hops[3].node_features.le_flags(), &id_to_feature_flags!(4));
+			assert_eq!(route.hops[3].channel_features.le_flags(), &id_to_feature_flags!(11));
+
+			assert_eq!(route.hops[4].pubkey, node7);
+			assert_eq!(route.hops[4].short_channel_id, 8);
+			assert_eq!(route.hops[4].fee_msat, 100);
+			assert_eq!(route.hops[4].cltv_expiry_delta, 42);
+			assert_eq!(route.hops[4].node_features.le_flags(), &Vec::new()); // We dont pass flags in from invoices yet
+			assert_eq!(route.hops[4].channel_features.le_flags(), &Vec::new()); // We can't learn any flags from invoices, sadly
+		}
+
+		{ // Simple test with outbound channel to 4 to test that last_hops and first_hops connect
+			let our_chans = vec![get_test_first_hop(&node4, 42, 0)];
+			let route = router.get_route(&node7, Some(&our_chans), &last_hops, 100, 42).unwrap();
+			assert_eq!(route.hops.len(), 2);
+
+			assert_eq!(route.hops[0].pubkey, node4);
+			assert_eq!(route.hops[0].short_channel_id, 42);
+			assert_eq!(route.hops[0].fee_msat, 0);
+			assert_eq!(route.hops[0].cltv_expiry_delta, (8 << 8) | 1);
+			assert_eq!(route.hops[0].node_features.le_flags(), &vec![0b11]);
+			assert_eq!(route.hops[0].channel_features.le_flags(), &Vec::new()); // No feature flags will meet the relevant-to-channel conversion
+
+			assert_eq!(route.hops[1].pubkey, node7);
+			assert_eq!(route.hops[1].short_channel_id, 8);
+			assert_eq!(route.hops[1].fee_msat, 100);
+			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+			assert_eq!(route.hops[1].node_features.le_flags(), &Vec::new()); // We dont pass flags in from invoices yet
+			assert_eq!(route.hops[1].channel_features.le_flags(), &Vec::new()); // We can't learn any flags from invoices, sadly
+		}
+
+		last_hops[0].fee_base_msat = 1000;
+
+		{ // Revert to via 6 as the fee on 8 goes up
+			let route = router.get_route(&node7, None, &last_hops, 100, 42).unwrap();
+			assert_eq!(route.hops.len(), 4);
+
+			assert_eq!(route.hops[0].pubkey, node2);
+			assert_eq!(route.hops[0].short_channel_id, 2);
+			assert_eq!(route.hops[0].fee_msat, 200); // fee increased as its % of value transferred across node
+			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
+			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
+			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+
+			assert_eq!(route.hops[1].pubkey, node3);
+			assert_eq!(route.hops[1].short_channel_id, 4);
+			assert_eq!(route.hops[1].fee_msat, 100);
+			assert_eq!(route.hops[1].cltv_expiry_delta, (7 << 8) | 1);
+			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
+			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+
+			assert_eq!(route.hops[2].pubkey, node6);
+			assert_eq!(route.hops[2].short_channel_id, 7);
+			assert_eq!(route.hops[2].fee_msat, 0);
+			assert_eq!(route.hops[2].cltv_expiry_delta, (10 << 8) | 1);
+			// If we have a peer in the node map, we'll use their features here since we don't have
+			// a way of figuring out their features from the invoice:
+			assert_eq!(route.hops[2].node_features.le_flags(), &id_to_feature_flags!(6));
+			assert_eq!(route.hops[2].channel_features.le_flags(), &id_to_feature_flags!(7));
+
+			assert_eq!(route.hops[3].pubkey, node7);
+			assert_eq!(route.hops[3].short_channel_id, 10);
+			assert_eq!(route.hops[3].fee_msat, 100);
+			assert_eq!(route.hops[3].cltv_expiry_delta, 42);
+			assert_eq!(route.hops[3].node_features.le_flags(), &Vec::new()); // We dont pass flags in from invoices yet
+			assert_eq!(route.hops[3].channel_features.le_flags(), &Vec::new()); // We can't learn any flags from invoices, sadly
+		}
+
+		{ // ...but still use 8 for larger payments as 6 has a variable feerate
+			let route = router.get_route(&node7, None, &last_hops, 2000, 42).unwrap();
+			assert_eq!(route.hops.len(), 5);
+
+			assert_eq!(route.hops[0].pubkey, node2);
+			assert_eq!(route.hops[0].short_channel_id, 2);
+			assert_eq!(route.hops[0].fee_msat, 3000);
+			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
+			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
+			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+
+			assert_eq!(route.hops[1].pubkey, node3);
+			assert_eq!(route.hops[1].short_channel_id, 4);
+			assert_eq!(route.hops[1].fee_msat, 0);
+			assert_eq!(route.hops[1].cltv_expiry_delta, (6 << 8) | 1);
+			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
+			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+
+			assert_eq!(route.hops[2].pubkey, node5);
+			assert_eq!(route.hops[2].short_channel_id, 6);
+			assert_eq!(route.hops[2].fee_msat, 0);
+			assert_eq!(route.hops[2].cltv_expiry_delta, (11 << 8) | 1);
+			assert_eq!(route.hops[2].node_features.le_flags(), &id_to_feature_flags!(5));
+			assert_eq!(route.hops[2].channel_features.le_flags(), &id_to_feature_flags!(6));
+
+			assert_eq!(route.hops[3].pubkey, node4);
+			assert_eq!(route.hops[3].short_channel_id, 11);
+			assert_eq!(route.hops[3].fee_msat, 1000);
+			assert_eq!(route.hops[3].cltv_expiry_delta, (8 << 8) | 1);
+			// If we have a peer in the node map, we'll use their features here since we don't have
+			// a way of figuring out their features from the invoice:
+			assert_eq!(route.hops[3].node_features.le_flags(), &id_to_feature_flags!(4));
+			assert_eq!(route.hops[3].channel_features.le_flags(), &id_to_feature_flags!(11));
+
+			assert_eq!(route.hops[4].pubkey, node7);
+			assert_eq!(route.hops[4].short_channel_id, 8);
+			assert_eq!(route.hops[4].fee_msat, 2000);
+			assert_eq!(route.hops[4].cltv_expiry_delta, 42);
+			assert_eq!(route.hops[4].node_features.le_flags(), &Vec::new()); // We dont pass flags in from invoices yet
+			assert_eq!(route.hops[4].channel_features.le_flags(), &Vec::new()); // We can't learn any flags from invoices, sadly
+		}
+
+		{ // Test Router serialization/deserialization
+			let mut w = TestVecWriter(Vec::new());
+			let network = router.network_map.read().unwrap();
+			assert!(!network.channels.is_empty());
+			assert!(!network.nodes.is_empty());
+			network.write(&mut w).unwrap();
+			let mut read_network = <NetworkMap>::read(&mut ::std::io::Cursor::new(&w.0)).unwrap();
+			// The chain is given to Router::read rather than serialized
+			read_network.chain_hash = network.chain_hash;
+			assert!(read_network == *network);
+		}
+	}
+
+	#[test]
+	fn route_max_cltv_expiry_height_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node2 is free but has a long CLTV delta, whereas
+		// our_id -3- node3 -4- node2 costs 100 msat with a short one.
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 40, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node3, 10, 100, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		let mut params = RouteParameters::default();
+		params.current_height = 100;
+		params.max_cltv_expiry_height = 100 + 42 + 40;
+		let route = router.get_route_with_params(&node2, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].short_channel_id, 1);
+		assert_eq!(route.hops[0].cltv_expiry_delta, 40);
+		assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+
+		// One block less and we have to take the more expensive but shorter path
+		params.max_cltv_expiry_height -= 1;
+		let route = router.get_route_with_params(&node2, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].short_channel_id, 3);
+		assert_eq!(route.hops[0].fee_msat, 100);
+		assert_eq!(route.hops[0].cltv_expiry_delta, 10);
+
+		params.max_cltv_expiry_height = 100 + 42 + 9;
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route_with_params(&node2, None, &Vec::new(), 1000, 42, &params) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+
+		params.max_cltv_expiry_height = 100 + 41;
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route_with_params(&node2, None, &Vec::new(), 1000, 42, &params) {
+			assert_eq!(err, "Final CLTV expiry is beyond the maximum allowed expiry height");
+		} else { panic!(); }
+	}
+
+	#[test]
+	fn route_max_cltv_expiry_height_intermediate_hop_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		let target = get_test_node_id(&secp_ctx, 5);
+
+		// our_id -1- node1 -2- node2, from which -3- target is free but has a long CLTV delta,
+		// whereas -4- node3 -5- target costs 100 msat with a short one. The limit only binds once
+		// the path from node2 is extended over channel 2.
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 30, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node2, 40, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 5, 100, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&node3, 5, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+
+		let route = router.get_route(&target, None, &Vec::new(), 1000, 10).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 3]);
+		assert_eq!(route.total_cltv_delta(), 30 + 40 + 10);
+
+		let params = RouteParameters { max_cltv_expiry_height: 60, ..RouteParameters::default() };
+		let route = router.get_route_with_params(&target, None, &Vec::new(), 1000, 10, &params).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 4, 5]);
+		assert_eq!(route.total_cltv_delta(), 30 + 5 + 5 + 10);
+		assert_eq!(route.total_fees_msat(), 100);
+
+		let params = RouteParameters { max_cltv_expiry_height: 49, ..RouteParameters::default() };
+		assert!(router.get_route_with_params(&target, None, &Vec::new(), 1000, 10, &params).is_err());
+	}
+
+	#[test]
+	fn route_tie_break_test() {
+		// Equal-cost parallel channels should always resolve to the lowest short_channel_id,
+		// regardless of the order in which the channels were learned.
+		for reverse in [false, true].iter() {
+			let (secp_ctx, our_id, router) = create_router();
+			let node1 = get_test_node_id(&secp_ctx, 2);
+			let node2 = get_test_node_id(&secp_ctx, 3);
+
+			let mut chans = vec![
+				(1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0)),
+				(2, get_test_directional_info(&node1, 10, 100, 0), get_test_directional_info(&node2, 0, 0, 0)),
+				(3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0)),
+				(4, get_test_directional_info(&node1, 10, 100, 0), get_test_directional_info(&node2, 0, 0, 0)),
+			];
+			if *reverse { chans.reverse(); }
+			for (scid, one_to_two, two_to_one) in chans.drain(..) {
+				add_test_channel(&router, scid, one_to_two, two_to_one);
+			}
+
+			let route = router.get_route(&node2, None, &Vec::new(), 1000, 42).unwrap();
+			assert_eq!(route.hops.len(), 2);
+			assert_eq!(route.hops[0].short_channel_id, 1);
+			assert_eq!(route.hops[1].short_channel_id, 2);
+		}
+	}
+
+	#[test]
+	fn route_prefers_direct_channel_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+
+		// A free path via node1 ties with our direct channel to the target, despite its lower
+		// short_channel_ids, as does a free path from node2 with its direct channel.
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 6, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node2, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 7, get_test_directional_info(&node2, 0, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+		let route = router.get_route(&target, None, &Vec::new(), 1000, 42).unwrap();
+		assert_route_hops(&route, &[(target, 5, 1000, 42)]);
+
+		// ...but a longer path is taken if the direct channel can't carry the payment
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(5, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_maximum_msat = Some(500);
+		let route = router.get_route(&target, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2]);
+		let route = router.get_route(&target, None, &Vec::new(), 500, 42).unwrap();
+		assert_route_hops(&route, &[(target, 5, 500, 42)]);
+		let params = RouteParameters { excluded_channels: vec![ShortChannelId(5), ShortChannelId(1)], ..RouteParameters::default() };
+		let route = router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![6, 7]);
+
+		// A direct channel in first_hops is always used, without any intermediate hops
+		let our_chans = vec![get_test_first_hop(&node1, 4, 250_000_000), get_test_first_hop(&target, 42, 250_000_000)];
+		let route = router.get_route(&target, Some(&our_chans), &Vec::new(), 1000, 42).unwrap();
+		assert_route_hops(&route, &[(target, 42, 1000, 42)]);
+	}
+
+	#[test]
+	fn route_hint_from_bolt11_test() {
+		let secp_ctx = Secp256k1::new();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+
+		let mut data = Vec::new();
+		data.extend_from_slice(&node1.serialize());
+		data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 10, 0, 0, 0, 20, 0, 30]);
+		data.extend_from_slice(&node2.serialize());
+		data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 11, 0, 0, 0, 21, 0, 31]);
+
+		let hint = RouteHint::from_bolt11_route_field(&data[..51]).unwrap();
+		assert_eq!(hint.src_node_id, node1);
+		assert_eq!(hint.short_channel_id, 1);
+		assert_eq!(hint.fee_base_msat, 10);
+		assert_eq!(hint.fee_proportional_millionths, 20);
+		assert_eq!(hint.cltv_expiry_delta, 30);
+		assert_eq!(hint.htlc_minimum_msat, 0);
+		assert_eq!(hint.htlc_maximum_msat, None);
+
+		// A path of more than one hop is valid but can't be used in last_hops
+		match RouteHint::from_bolt11_route_field(&data) {
+			Err(RouteHintDecodeError::MultiHopRoute { hop_count: 2 }) => {},
+			_ => panic!(),
+		}
+
+		match RouteHint::from_bolt11_route_field(&data[..50]) {
+			Err(RouteHintDecodeError::Decode(DecodeError::BadLengthDescriptor)) => {},
+			_ => panic!(),
+		}
+		data[0] = 0;
+		match RouteHint::from_bolt11_route_field(&data[..51]) {
+			Err(RouteHintDecodeError::Decode(DecodeError::InvalidValue)) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn route_hint_htlc_maximum_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let target = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+
+		let last_hops = vec![RouteHint {
+			src_node_id: node1,
+			short_channel_id: ShortChannelId(2),
+			fee_base_msat: 0,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: 10,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: Some(1000),
+		}];
+		let route = router.get_route(&target, None, &last_hops, 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[1].short_channel_id, 2);
+
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route(&target, None, &last_hops, 1001, 42) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+	}
+
+	#[test]
+	fn used_route_hint_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node2, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+
+		let mut last_hops = vec![RouteHint {
+			src_node_id: node1,
+			short_channel_id: ShortChannelId(4),
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: 10,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}];
+
+		// The public path is cheaper than the hinted channel
+		let route = router.get_route(&target, None, &last_hops, 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 3);
+		assert!(route.used_route_hint(&last_hops).is_none());
+
+		last_hops[0].fee_base_msat = 0;
+		let route = router.get_route(&target, None, &last_hops, 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.used_route_hint(&last_hops).unwrap().short_channel_id, 4);
+		assert!(route.used_route_hint(&[]).is_none());
+
+		// A hint for the same channel from a different node isn't the one we used
+		last_hops[0].src_node_id = node2;
+		assert!(route.used_route_hint(&last_hops).is_none());
+	}
+
+	#[test]
+	fn non_live_first_hops_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		let first_hop = |short_channel_id: u64, remote_network_id: PublicKey, is_live: bool| channelmanager::ChannelDetails { is_live, ..get_test_first_hop(&remote_network_id, short_channel_id, 0) };
+		// Our channel to node_a, which is on the cheaper path, isn't live
+		let our_chans = vec![first_hop(42, node_b, true), first_hop(43, node_a, false)];
+
+		let route = router.get_route(&target, Some(&our_chans), &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 42);
+		assert!(!route.is_speculative(&our_chans));
+
+		let params = RouteParameters { include_non_live_first_hops: true, ..RouteParameters::default() };
+		let route = router.get_route_with_params(&target, Some(&our_chans), &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 43);
+		assert!(route.is_speculative(&our_chans));
+
+		// Non-live channels directly to the target are skipped too, unless asked for
+		let our_chans = vec![first_hop(44, target, false)];
+		assert!(router.get_route(&target, Some(&our_chans), &Vec::new(), 1000, 42).is_err());
+		let route = router.get_route_with_params(&target, Some(&our_chans), &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 44);
+		assert!(route.is_speculative(&our_chans));
+	}
+
+	#[test]
+	fn first_hop_htlc_slots_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 0, 200, 0), get_test_directional_info(&target, 0, 0, 0));
+		let first_hop = |short_channel_id: u64, remote_network_id: PublicKey, outbound_htlc_slots: u16| channelmanager::ChannelDetails { outbound_htlc_slots, ..get_test_first_hop(&remote_network_id, short_channel_id, 0) };
+
+		// Our channel to node_a is on the cheaper path but only has one HTLC slot left
+		let our_chans = vec![first_hop(42, node_a, 1), first_hop(43, node_b, 400)];
+		let route = router.get_route(&target, Some(&our_chans), &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 42);
+		assert_eq!(route.max_in_flight_htlcs(&our_chans), Some(1));
+
+		// 1000 / 1 outweighs the 100 msat saved, while 1000 / 400 rounds down to 2
+		let params = RouteParameters { htlc_slot_penalty_msat: 1000, ..RouteParameters::default() };
+		let route = router.get_route_with_params(&target, Some(&our_chans), &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 43);
+		assert_eq!(route.max_in_flight_htlcs(&our_chans), Some(400));
+		assert_eq!(route.total_fees_msat(), 200);
+
+		// Channels with no slots left are never used
+		let our_chans = vec![first_hop(42, node_a, 0), first_hop(43, node_b, 400)];
+		assert_eq!(router.get_route(&target, Some(&our_chans), &Vec::new(), 1000, 42).unwrap().hops[0].short_channel_id, 43);
+		assert!(router.get_route(&target, Some(&our_chans[..1]), &Vec::new(), 1000, 42).is_err());
+		assert_eq!(route.max_in_flight_htlcs(&our_chans[..1]), None);
+	}
+
+	#[test]
+	fn first_hop_channel_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		let first_hop = |short_channel_id: u64, remote_network_id: PublicKey, user_id: u64| channelmanager::ChannelDetails { user_id, ..get_test_first_hop(&remote_network_id, short_channel_id, 0) };
+		let our_chans = vec![first_hop(42, node_b, 1), first_hop(43, node_a, 2)];
+
+		let route = router.get_route(&target, Some(&our_chans), &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 43);
+		assert_eq!(route.first_hop_channel(&our_chans).unwrap().user_id, 2);
+
+		// A channel with the same short_channel_id to another node (or no channel at all) isn't used
+		assert!(route.first_hop_channel(&[first_hop(43, node_b, 3)]).is_none());
+		assert!(route.first_hop_channel(&[]).is_none());
+		assert!(Route { hops: Vec::new() }.first_hop_channel(&our_chans).is_none());
+	}
+
+	#[test]
+	fn directional_fees_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&node_a, 0, 1000, 10), get_test_directional_info(&node_b, 0, 0, 5000));
+
+		let (one_to_two, two_to_one) = router.get_channel_info(ShortChannelId(1)).unwrap().directional_fees();
+		assert_eq!(one_to_two, RoutingFees { fee_base_msat: 1000, fee_proportional_millionths: 10 });
+		assert_eq!(two_to_one, RoutingFees { fee_base_msat: 0, fee_proportional_millionths: 5000 });
+		assert_eq!(one_to_two.fee_msat(1_000_000), 1010);
+		assert_eq!(two_to_one.fee_msat(1_000_000), 5000);
+
+		// Directions without a channel_update yet have maximal fees
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 2, 2, 3)).unwrap());
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 2, 2, 0, 1, 100, Vec::new())).unwrap());
+		let (one_to_two, two_to_one) = router.get_channel_info(ShortChannelId(2)).unwrap().directional_fees();
+		assert_eq!(one_to_two, RoutingFees { fee_base_msat: 100, fee_proportional_millionths: 0 });
+		assert_eq!(two_to_one, RoutingFees { fee_base_msat: u32::max_value(), fee_proportional_millionths: u32::max_value() });
+	}
+
+	#[test]
+	fn effective_htlc_maximum_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		let mut oversized = get_test_directional_info(&node1, 0, 0, 0);
+		oversized.htlc_maximum_msat = Some(5_000_000);
+		let mut undersized = get_test_directional_info(&node2, 0, 0, 0);
+		undersized.htlc_maximum_msat = Some(500_000);
+		add_test_channel(&router, 2, oversized, undersized);
+
+		// With an unknown capacity, only the advertised maximum applies...
+		let chan = router.get_channel_info(ShortChannelId(2)).unwrap();
+		assert_eq!(chan.effective_htlc_maximum_msat(true), 5_000_000);
+		assert_eq!(chan.effective_htlc_maximum_msat(false), 500_000);
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().effective_htlc_maximum_msat(true), MAX_FUNDING_SATOSHIS * 1000);
+
+		// ...while an advertised maximum beyond the channel's capacity is clamped to it
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = Some(1000);
+		let chan = router.get_channel_info(ShortChannelId(2)).unwrap();
+		assert_eq!(chan.effective_htlc_maximum_msat(true), 1_000_000);
+		assert_eq!(chan.effective_htlc_maximum_msat(false), 500_000);
+		assert!(router.get_route(&node2, None, &Vec::new(), 1_000_000, 42).is_ok());
+		assert!(router.get_route(&node2, None, &Vec::new(), 1_000_001, 42).is_err());
+	}
+
+	#[test]
+	fn routable_range_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let mut limited = get_test_directional_info(&node1, 0, 0, 0);
+		limited.htlc_minimum_msat = 1000;
+		limited.htlc_maximum_msat = Some(5_000_000);
+		let mut disabled = get_test_directional_info(&node2, 0, 0, 0);
+		disabled.enabled = false;
+		add_test_channel(&router, 1, limited, disabled);
+
+		let chan = router.get_channel_info(ShortChannelId(1)).unwrap();
+		assert_eq!(chan.routable_range(true), Some((1000, 5_000_000)));
+		assert_eq!(chan.routable_range(false), None);
+
+		// The maximum is clamped to the channel's capacity, which may leave no routable amount
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(1, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = Some(1);
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().routable_range(true), Some((1000, 1000)));
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(1, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_minimum_msat = 1001;
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().routable_range(true), None);
+	}
+
+	// Writes a ChannelInfo as a version 1 Router did, without capacity_sats or htlc_maximum_msat
+	fn write_version_1_channel_info(chan: &ChannelInfo, w: &mut TestVecWriter) {
+		chan.features.write(w).unwrap();
+		for directional_info in [&chan.one_to_two, &chan.two_to_one].iter() {
+			directional_info.src_node_id.write(w).unwrap();
+			directional_info.last_update.write(w).unwrap();
+			directional_info.enabled.write(w).unwrap();
+			directional_info.cltv_expiry_delta.write(w).unwrap();
+			directional_info.htlc_minimum_msat.write(w).unwrap();
+			directional_info.fee_base_msat.write(w).unwrap();
+			directional_info.fee_proportional_millionths.write(w).unwrap();
+			directional_info.last_update_message.write(w).unwrap();
+		}
+		chan.announcement_message.write(w).unwrap();
+	}
+
+	#[test]
+	fn channel_info_version_1_read_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let mut one_to_two = get_test_directional_info(&our_id, 10, 100, 1);
+		one_to_two.htlc_maximum_msat = Some(5_000_000);
+		add_test_channel(&router, 1, one_to_two, get_test_directional_info(&node1, 20, 200, 2));
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(1, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = Some(5000);
+		let mut chan = router.get_channel_info(ShortChannelId(1)).unwrap();
+
+		let mut w = TestVecWriter(Vec::new());
+		write_version_1_channel_info(&chan, &mut w);
+		let read_chan = ChannelInfo::read_versioned(&mut ::std::io::Cursor::new(&w.0), 1).unwrap();
+		chan.capacity_sats = None;
+		chan.one_to_two.htlc_maximum_msat = None;
+		assert!(read_chan == chan);
+
+		// Current channels round-trip, even when read through the versioned reader
+		let chan = router.get_channel_info(ShortChannelId(1)).unwrap();
+		assert!(ChannelInfo::read_versioned(&mut ::std::io::Cursor::new(&chan.encode()), SERIALIZATION_VERSION).unwrap() == chan);
+	}
+
+	#[test]
+	fn router_version_1_read_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 100, 1), get_test_directional_info(&node1, 20, 200, 2));
+
+		// Version 1 Routers wrote no score boosts or unverified channels after the nodes
+		let mut w = TestVecWriter(Vec::new());
+		{
+			let network = router.network_map.read().unwrap();
+			w.write_all(&[1, 1]).unwrap();
+			(network.channels.len() as u64).write(&mut w).unwrap();
+			for (chan_id, chan) in network.channels.iter() {
+				chan_id.write(&mut w).unwrap();
+				write_version_1_channel_info(chan, &mut w);
+			}
+			network.our_node_id.write(&mut w).unwrap();
+			(network.nodes.len() as u64).write(&mut w).unwrap();
+			for (node_id, node) in network.nodes.iter() {
+				node_id.write(&mut w).unwrap();
+				node.write(&mut w).unwrap();
+			}
+		}
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let read_args = || RouterReadArgs {
+			chain_monitor: Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger))),
+			logger: Arc::clone(&logger),
+			genesis_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+		};
+		let read_router = Router::read(&mut ::std::io::Cursor::new(&w.0), read_args()).unwrap();
+		assert!(*read_router.network_map.read().unwrap() == *router.network_map.read().unwrap());
+		assert_eq!(read_router.get_route(&node1, None, &Vec::new(), 1000, 42).unwrap().hops.len(), 1);
+
+		// We no longer write version 1, and version 1 readers are told so
+		let encoded = router.encode();
+		assert_eq!(&encoded[..2], &[2, 2]);
+		assert!(Router::read(&mut ::std::io::Cursor::new(&encoded), read_args()).unwrap().network_map.read().unwrap().channels.len() == 1);
+	}
+
+	#[test]
+	fn channel_usable_stats_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		let mut half_known = get_test_directional_info(&node2, 0, 0, 0);
+		half_known.enabled = false;
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), half_known);
+		let mut disabled = get_test_directional_info(&node1, 0, 0, 0);
+		disabled.enabled = false;
+		disabled.last_update = 1;
+		let mut disabled_too = get_test_directional_info(&our_id, 0, 0, 0);
+		disabled_too.enabled = false;
+		disabled_too.last_update = 1;
+		add_test_channel(&router, 3, disabled_too, disabled);
+
+		assert!(router.get_channel_info(ShortChannelId(1)).unwrap().is_usable_in_both_directions());
+		assert!(router.get_channel_info(ShortChannelId(2)).unwrap().is_usable());
+		assert!(!router.get_channel_info(ShortChannelId(2)).unwrap().is_usable_in_both_directions());
+		assert!(!router.get_channel_info(ShortChannelId(3)).unwrap().is_usable());
+		assert!(router.get_channel_info(ShortChannelId(4)).is_none());
+
+		assert_eq!(router.get_network_stats(), NetworkStats {
+			node_count: 3,
+			channel_count: 3,
+			usable_channel_count: 2,
+			bidirectional_channel_count: 1,
+			unupdated_channel_count: 0,
+		});
+	}
+
+	#[test]
+	fn get_missing_short_channel_ids_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		assert!(router.get_missing_short_channel_ids(&[]).is_empty());
+		assert_eq!(router.get_missing_short_channel_ids(&[ShortChannelId(3), ShortChannelId(1), ShortChannelId(2), ShortChannelId(1)]), vec![ShortChannelId(1), ShortChannelId(2), ShortChannelId(3)]);
+
+		add_test_channel(&router, 2, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		assert_eq!(router.get_missing_short_channel_ids(&[ShortChannelId(3), ShortChannelId(1), ShortChannelId(2), ShortChannelId(1)]), vec![ShortChannelId(1), ShortChannelId(3)]);
+		assert!(router.get_missing_short_channel_ids(&[ShortChannelId(2)]).is_empty());
+	}
+
+	#[test]
+	fn estimate_sync_message_count_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		assert_eq!(router.newest_update_timestamp(), None);
+		assert_eq!(router.estimate_sync_message_count(1000), None);
+
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node2, 0, 0, 0), get_test_directional_info(&node3, 0, 0, 0));
+		let now = 1_000_000;
+		{
+			let mut network = router.network_map.write().unwrap();
+			for node in network.nodes.values_mut() {
+				node.last_update = None;
+			}
+			network.nodes.get_mut(&node1).unwrap().last_update = Some(now - 10);
+			// Five updates in the day up to now, and one older update which isn't extrapolated from
+			network.channels.get_mut(&1).unwrap().one_to_two.last_update = now;
+			network.channels.get_mut(&1).unwrap().two_to_one.last_update = now - 100_000;
+			network.channels.get_mut(&2).unwrap().one_to_two.last_update = now - 3600;
+			network.channels.get_mut(&2).unwrap().two_to_one.last_update = now - 3600;
+			network.channels.get_mut(&3).unwrap().two_to_one.last_update = now - 7200;
+		}
+		assert_eq!(router.newest_update_timestamp(), Some(now));
+
+		assert_eq!(router.estimate_sync_message_count(now - 5), Some(0));
+		assert_eq!(router.estimate_sync_message_count(now), Some(0));
+		assert_eq!(router.estimate_sync_message_count(now + 24 * 60 * 60 / 5), Some(1));
+		assert_eq!(router.estimate_sync_message_count(now + 24 * 60 * 60), Some(5));
+		// Capped at 3 messages per channel and one per node
+		assert_eq!(router.estimate_sync_message_count(now + 10 * 24 * 60 * 60), Some(3 * 3 + 4));
+	}
+
+	#[test]
+	fn handle_gossip_batch_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node1_secret = SecretKey::from_slice(&[2; 32]).unwrap();
+		let node1 = PublicKey::from_secret_key(&secp_ctx, &node1_secret);
+
+		let channel_announcement = get_signed_channel_announcement(&secp_ctx, 1, 2, 3);
+		let mut bad_announcement = channel_announcement.clone();
+		bad_announcement.contents.short_channel_id = 2;
+
+		let unsigned_node_announcement = msgs::UnsignedNodeAnnouncement {
+			features: NodeFeatures::empty(),
+			timestamp: 42,
+			node_id: node1,
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: Vec::new(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_node_announcement.encode()[..])[..]);
+		let node_announcement = msgs::NodeAnnouncement {
+			signature: secp_ctx.sign(&msg_hash, &node1_secret),
+			contents: unsigned_node_announcement,
+		};
+
+		// Messages which depend on earlier ones in the batch see their effects
+		let results = router.handle_gossip_batch(&[
+			GossipMessage::NodeAnnouncement(node_announcement.clone()),
+			GossipMessage::ChannelAnnouncement(channel_announcement),
+			GossipMessage::ChannelUpdate(get_signed_channel_update(&secp_ctx, 2, 1, 0, 1, 0, Vec::new())),
+			GossipMessage::ChannelUpdate(get_signed_channel_update(&secp_ctx, 2, 1, 0, 1, 0, Vec::new())),
+			GossipMessage::ChannelAnnouncement(bad_announcement),
+			GossipMessage::NodeAnnouncement(node_announcement),
+		]);
+		let results: Vec<Result<bool, &str>> = results.into_iter().map(|res| res.map_err(|e| e.err)).collect();
+		assert_eq!(results, vec![
+			Err("No existing channels for node_announcement"),
+			Ok(true),
+			Ok(true),
+			Err("Update older than last processed update"),
+			Err("Invalid signature from remote node"),
+			Ok(true),
+		]);
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.last_update, 1);
+		assert!(router.get_channel_info(ShortChannelId(2)).is_none());
+		assert_eq!(router.network_map.read().unwrap().nodes.get(&node1).unwrap().last_update, Some(42));
+	}
+
+	#[test]
+	fn gossip_rejection_callback_test() {
+		let secp_ctx = Secp256k1::new();
+		let our_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[1; 32]).unwrap());
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(UtxoLookupChainWatcher { available: AtomicBool::new(true), utxo: Mutex::new(Some((Script::new(), 1000))) });
+		let router = <Router>::new(our_id, genesis_block(Network::Testnet).header.bitcoin_hash(), chain_monitor.clone(), Arc::clone(&logger));
+		let mut rejections = Vec::new();
+
+		// The funding output doesn't pay to the announced keys
+		let results = router.handle_gossip_batch_from_peer("alice", &[GossipMessage::ChannelAnnouncement(get_signed_channel_announcement(&secp_ctx, 1, 2, 3))],
+			|peer, reason, _| rejections.push((peer.to_owned(), reason)));
+		assert!(results[0].is_err());
+		assert_eq!(rejections, vec![("alice".to_owned(), GossipRejectionReason::UtxoMismatch)]);
+
+		*chain_monitor.utxo.lock().unwrap() = Some((funding_script_pubkey(&get_test_node_id(&secp_ctx, 4), &get_test_node_id(&secp_ctx, 5)), 1000));
+		rejections.clear();
+		let results = router.handle_gossip_batch_from_peer("bob", &[
+			GossipMessage::ChannelAnnouncement(get_signed_channel_announcement(&secp_ctx, 1, 2, 3)),
+			// Signed by neither of the channel's nodes
+			GossipMessage::ChannelUpdate(get_signed_channel_update(&secp_ctx, 6, 1, 0, 1, 0, Vec::new())),
+			GossipMessage::ChannelUpdate(get_signed_channel_update(&secp_ctx, 2, 1, 0, 1, 0, Vec::new())),
+			GossipMessage::ChannelUpdate(get_signed_channel_update(&secp_ctx, 2, 1, 0, 1, 0, Vec::new())),
+			GossipMessage::ChannelUpdate(get_signed_channel_update(&secp_ctx, 2, 9, 0, 1, 0, Vec::new())),
+		], |peer, reason, _| rejections.push((peer.to_owned(), reason)));
+		assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 2);
+		assert_eq!(rejections, vec![
+			("bob".to_owned(), GossipRejectionReason::InvalidSignature),
+			("bob".to_owned(), GossipRejectionReason::Stale),
+			("bob".to_owned(), GossipRejectionReason::Other),
+		]);
+	}
+
+	#[test]
+	fn gossip_batch_cancellation_test() {
+		let (secp_ctx, _, router) = create_router();
+		let messages = [
+			GossipMessage::ChannelAnnouncement(get_signed_channel_announcement(&secp_ctx, 1, 2, 3)),
+			GossipMessage::ChannelUpdate(get_signed_channel_update(&secp_ctx, 2, 1, 0, 1, 0, Vec::new())),
+		];
+		let cancellation_flag = Arc::new(AtomicBool::new(true));
+		router.set_cancellation_flag(Some(Arc::clone(&cancellation_flag)));
+
+		// Cancelled messages aren't applied, and aren't blamed on the peer
+		let mut rejections = Vec::new();
+		let results = router.handle_gossip_batch_from_peer("alice", &messages, |peer, reason, _| rejections.push((peer.to_owned(), reason)));
+		let results: Vec<Result<bool, &str>> = results.into_iter().map(|res| res.map_err(|e| e.err)).collect();
+		assert_eq!(results, vec![Err("Gossip batch was cancelled before processing this message"); 2]);
+		assert!(rejections.is_empty());
+		assert!(router.get_channel_info(ShortChannelId(1)).is_none());
+
+		cancellation_flag.store(false, Ordering::Release);
+		assert!(router.handle_gossip_batch(&messages).iter().all(|res| res.is_ok()));
+		let mut snapshot = Vec::new();
+		router.write_gossip_snapshot(&mut snapshot).unwrap();
+
+		// A cancelled snapshot read applies nothing, even if it's valid
+		let (_, _, new_router) = create_router();
+		new_router.set_cancellation_flag(Some(Arc::clone(&cancellation_flag)));
+		cancellation_flag.store(true, Ordering::Release);
+		assert_eq!(new_router.read_gossip_snapshot(&mut ::std::io::Cursor::new(&snapshot[..])).unwrap(), 0);
+		assert!(new_router.get_channel_info(ShortChannelId(1)).is_none());
+		new_router.set_cancellation_flag(None);
+		assert_eq!(new_router.read_gossip_snapshot(&mut ::std::io::Cursor::new(&snapshot[..])).unwrap(), 2);
+	}
+
+	#[test]
+	fn channel_announcement_features_test() {
+		let (secp_ctx, _, router) = create_router();
+
+		// Both an unknown even (required) and an unknown odd (optional) bit are kept as-is
+		let flags = vec![0, 0, 0, 0b0100_0001];
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement_with_features(&secp_ctx, 1, 2, 3, ChannelFeatures::from_le_bytes(flags.clone()))).unwrap());
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 2, 2, 3)).unwrap());
+		let chan = router.get_channel_info(ShortChannelId(1)).unwrap();
+		assert_eq!(chan.features().le_flags(), &flags);
+		assert!(chan.features().requires_unknown_bits());
+		assert!(router.get_channel_info(ShortChannelId(2)).unwrap().features().le_flags().is_empty());
+
+		let mut snapshot = Vec::new();
+		router.write_gossip_snapshot(&mut snapshot).unwrap();
+		let (_, _, new_router) = create_router();
+		assert_eq!(new_router.read_gossip_snapshot(&mut ::std::io::Cursor::new(&snapshot[..])).unwrap(), 2);
+		assert_eq!(new_router.get_channel_info(ShortChannelId(1)).unwrap().features().le_flags(), &flags);
+	}
+
+	#[test]
+	fn gossip_snapshot_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 1, 2, 3)).unwrap());
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 2, 3, 6)).unwrap());
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 2, 1, 0, 1, 100, Vec::new())).unwrap());
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 3, 1, 1, 2, 0, Vec::new())).unwrap());
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 6, 2, 1, 3, 0, Vec::new())).unwrap());
+		let unsigned_node_announcement = msgs::UnsignedNodeAnnouncement {
+			features: NodeFeatures::empty(),
+			timestamp: 42,
+			node_id: node1,
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: Vec::new(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_node_announcement.encode()[..])[..]);
+		assert!(router.handle_node_announcement(&msgs::NodeAnnouncement {
+			signature: secp_ctx.sign(&msg_hash, &SecretKey::from_slice(&[2; 32]).unwrap()),
+			contents: unsigned_node_announcement,
+		}).unwrap());
+
+		// Any io::Write may be written to directly
+		let mut snapshot = Vec::new();
+		router.write_gossip_snapshot(&mut snapshot).unwrap();
+		assert_eq!(&snapshot[..8], &[0, 0, 0, 0, 0, 0, 0, 6]);
+
+		let (_, _, new_router) = create_router();
+		assert_eq!(new_router.read_gossip_snapshot(&mut ::std::io::Cursor::new(&snapshot[..])).unwrap(), 6);
+		for short_channel_id in 1..3 {
+			let (chan, new_chan) = (router.get_channel_info(ShortChannelId(short_channel_id)).unwrap(), new_router.get_channel_info(ShortChannelId(short_channel_id)).unwrap());
+			assert!(chan.one_to_two == new_chan.one_to_two);
+			assert!(chan.two_to_one == new_chan.two_to_one);
+		}
+		assert!(new_router.network_map.read().unwrap().nodes.get(&node1).unwrap().announcement_message.is_some());
+
+		// Reading it again applies nothing new, while a truncated snapshot fails
+		assert_eq!(new_router.read_gossip_snapshot(&mut ::std::io::Cursor::new(&snapshot[..])).unwrap(), 0);
+		let (_, _, new_router) = create_router();
+		assert!(new_router.read_gossip_snapshot(&mut ::std::io::Cursor::new(&snapshot[..snapshot.len() - 1])).is_err());
+	}
+
+	/// Checks each hop of the given route against its expected (pubkey, short_channel_id, fee_msat,
+	/// cltv_expiry_delta).
+	fn assert_route_hops(route: &Route, expected_hops: &[(PublicKey, u64, u64, u32)]) {
+		assert_eq!(route.hops.len(), expected_hops.len());
+		for (hop, &(pubkey, short_channel_id, fee_msat, cltv_expiry_delta)) in route.hops.iter().zip(expected_hops.iter()) {
+			assert_eq!(hop.pubkey, pubkey);
+			assert_eq!(hop.short_channel_id, short_channel_id);
+			assert_eq!(hop.fee_msat, fee_msat);
+			assert_eq!(hop.cltv_expiry_delta, cltv_expiry_delta);
+		}
+	}
+
+	#[test]
+	fn htlc_minimum_normalization_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		let update = |node_secret_idx: u8, short_channel_id: u64, htlc_minimum_msat: u64, fee_base_msat: u32| {
+			let unsigned_msg = msgs::UnsignedChannelUpdate {
+				chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+				short_channel_id,
+				timestamp: 1,
+				flags: 0,
+				cltv_expiry_delta: 0,
+				htlc_minimum_msat,
+				fee_base_msat,
+				fee_proportional_millionths: 0,
+				excess_data: Vec::new(),
+			};
+			let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_msg.encode()[..])[..]);
+			router.handle_channel_update(&msgs::ChannelUpdate {
+				signature: secp_ctx.sign(&msg_hash, &SecretKey::from_slice(&[node_secret_idx; 32]).unwrap()),
+				contents: unsigned_msg,
+			}).unwrap();
+		};
+
+		// A zero minimum is stored as MIN_HTLC_MINIMUM_MSAT...
+		update(3, 4, 0, 100);
+		assert_eq!(router.get_channel_info(ShortChannelId(4)).unwrap().one_to_two.htlc_minimum_msat, MIN_HTLC_MINIMUM_MSAT);
+
+		// ...while an unusually high one makes small payments route around the cheaper channel
+		update(2, 2, 1_000_000, 0);
+		assert_eq!(router.get_channel_info(ShortChannelId(2)).unwrap().one_to_two.htlc_minimum_msat, 1_000_000);
+		assert_eq!(router.get_route(&target, None, &Vec::new(), 1000, 42).unwrap().hops[0].short_channel_id, 3);
+		assert_eq!(router.get_route(&target, None, &Vec::new(), 1_000_000, 42).unwrap().hops[0].short_channel_id, 1);
+	}
+
+	#[test]
+	fn route_node_penalties_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 300, 0), get_test_directional_info(&target, 0, 0, 0));
+		let route_via = |node_penalties: Vec<(PublicKey, u64)>| {
+			let params = RouteParameters { node_penalties, ..RouteParameters::default() };
+			let route = router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap();
+			(route.hops[0].pubkey, route.total_fees_msat())
+		};
+
+		// node_a is still used while it's cheaper even with its penalty...
+		assert_eq!(route_via(Vec::new()), (node_a, 100));
+		assert_eq!(route_via(vec![(node_a, 150)]), (node_a, 100));
+		// ...but not once the penalty outweighs its savings
+		assert_eq!(route_via(vec![(node_a, 250)]), (node_b, 300));
+		assert_eq!(route_via(vec![(node_a, 150), (node_a, 100)]), (node_b, 300));
+		// Penalties on the endpoints of every route don't change anything
+		assert_eq!(route_via(vec![(target, 1000), (our_id, 1000)]), (node_a, 100));
+	}
+
+	#[test]
+	fn channel_liquidity_store_test() {
+		let secp_ctx = Secp256k1::new();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let mut store = ChannelLiquidityStore::new(100);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1000), None);
+
+		store.channel_failed(ShortChannelId(1), &node_a, 10_000, 1000);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1000), Some((0, Some(9_999))));
+		// Bounds are per-direction
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_b, 1000), None);
+		store.channel_succeeded(ShortChannelId(1), &node_a, 5000, 1000);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1000), Some((5000, Some(9_999))));
+		store.channel_failed(ShortChannelId(1), &node_a, 8000, 1000);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1000), Some((5000, Some(7_999))));
+
+		// The bounds decay every half life...
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1099), Some((5000, Some(7_999))));
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1100), Some((2500, Some(15_998))));
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1250), Some((1250, Some(31_996))));
+		// ...and new results are applied to the decayed bounds
+		store.channel_succeeded(ShortChannelId(1), &node_a, 20_000, 1250);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1250), Some((20_000, Some(31_996))));
+		// Contradicted bounds are forgotten, as the channel's balance must have changed
+		store.channel_failed(ShortChannelId(1), &node_a, 15_000, 1250);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1250), Some((0, Some(14_999))));
+		store.channel_succeeded(ShortChannelId(1), &node_a, 15_000, 1250);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1250), Some((15_000, None)));
+
+		// Eventually, we know nothing about the channel
+		store.remove_stale(1250 + 13 * 100);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1250 + 13 * 100), Some((1, None)));
+		assert_eq!(store.bounds.len(), 1);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &node_a, 1250 + 14 * 100), None);
+		store.remove_stale(1250 + 14 * 100);
+		assert!(store.bounds.is_empty());
+	}
+
+	#[test]
+	fn route_learned_liquidity_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 300, 0), get_test_directional_info(&target, 0, 0, 0));
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		let mut store = ChannelLiquidityStore::new(3600);
+		let route_via = |store: &ChannelLiquidityStore, final_value_msat: u64| {
+			let route = router.get_route_with_learned_liquidity(&target, None, &Vec::new(), final_value_msat, 42, &RouteParameters::default(), store).unwrap();
+			route.hops[0].short_channel_id
+		};
+		assert_eq!(route_via(&store, 100_000), 1);
+
+		// The payment failed at node_a, which couldn't forward 100_000 msat over channel 2
+		let route = router.get_route(&target, None, &Vec::new(), 100_000, 42).unwrap();
+		store.payment_failed(&our_id, &route, 1, now);
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(1), &our_id, now), Some((100_100, None)));
+		assert_eq!(store.liquidity_bounds_msat(ShortChannelId(2), &node_a, now), Some((0, Some(99_999))));
+		assert_eq!(route_via(&store, 100_000), 3);
+		// Smaller payments may still use channel 2
+		assert_eq!(route_via(&store, 99_000), 1);
+	}
+
+	#[test]
+	fn route_node_cost_adjuster_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 300, 0), get_test_directional_info(&target, 0, 0, 0));
+		{
+			let mut network = router.network_map.write().unwrap();
+			network.nodes.get_mut(&node_a).unwrap().addresses = vec![NetAddress::OnionV2 { addr: [0; 10], port: 9735 }];
+			network.nodes.get_mut(&node_b).unwrap().addresses = vec![NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }, NetAddress::OnionV2 { addr: [0; 10], port: 9735 }];
+		}
+		// Penalize nodes which are only reachable over Tor
+		let tor_only_penalty = |_: &PublicKey, addresses: &[NetAddress]| {
+			if addresses.iter().all(|addr| match *addr { NetAddress::OnionV2 { .. } | NetAddress::OnionV3 { .. } => true, _ => false }) { 1000 } else { 0 }
+		};
+		let route_via = |max_penalty_msat: u64| {
+			let route = router.get_route_with_node_cost_adjuster(&target, None, &Vec::new(), 1000, 42, &RouteParameters::default(), max_penalty_msat, &tor_only_penalty).unwrap();
+			(route.hops[0].pubkey, route.total_fees_msat())
+		};
+
+		assert_eq!(route_via(1000), (node_b, 300));
+		// The penalty is capped, so node_a is still used if it's cheap enough
+		assert_eq!(route_via(150), (node_a, 100));
+		assert_eq!(route_via(0), (node_a, 100));
+	}
+
+	#[test]
+	fn route_unreachable_nodes_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 300, 0), get_test_directional_info(&target, 0, 0, 0));
+		let route_avoiding = |unreachable_nodes: Vec<PublicKey>| {
+			let params = RouteParameters { unreachable_nodes, ..RouteParameters::default() };
+			router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params)
+				.map(|route| route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>())
+		};
+
+		assert_eq!(route_avoiding(Vec::new()).unwrap(), vec![1, 2]);
+		assert_eq!(route_avoiding(vec![node_a]).unwrap(), vec![3, 4]);
+		assert_eq!(route_avoiding(vec![node_b]).unwrap(), vec![1, 2]);
+		assert_eq!(route_avoiding(vec![target, our_id]).unwrap(), vec![1, 2]);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = route_avoiding(vec![node_a, node_b]) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+
+		// Our channel to an unreachable peer isn't used either
+		let our_chans = vec![get_test_first_hop(&node_a, 42, 250_000_000)];
+		let params = RouteParameters { unreachable_nodes: vec![node_a], ..RouteParameters::default() };
+		assert!(router.get_route_with_params(&target, Some(&our_chans), &Vec::new(), 1000, 42, &params).is_err());
+	}
+
+	#[test]
+	fn final_cltv_safety_delta_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let target = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 0, 0), get_test_directional_info(&node1, 10, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 20, 100, 0), get_test_directional_info(&target, 20, 0, 0));
+
+		let params = RouteParameters { final_cltv_safety_delta: 3, ..RouteParameters::default() };
+		let route = router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_route_hops(&route, &[(node1, 1, 100, 20), (target, 2, 1000, 45)]);
+		assert_eq!(route.total_cltv_delta(), 65);
+
+		// The margin counts towards the maximum expiry height
+		let params = RouteParameters { final_cltv_safety_delta: 3, max_cltv_expiry_height: 64, ..RouteParameters::default() };
+		assert!(router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).is_err());
+		let params = RouteParameters { final_cltv_safety_delta: 3, max_cltv_expiry_height: 43, ..RouteParameters::default() };
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params) {
+			assert_eq!(err, "Final CLTV expiry is beyond the maximum allowed expiry height");
+		} else { panic!(); }
+	}
+
+	#[test]
+	fn route_fee_budget_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let node_c = get_test_node_id(&secp_ctx, 4);
+		let node_d = get_test_node_id(&secp_ctx, 5);
+		let target = get_test_node_id(&secp_ctx, 6);
+
+		// our_id -1- node_a -2- target is found first, but our_id -3- node_b -4- node_c -5- node_d
+		// -6- target is cheaper
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 300, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 50, 0), get_test_directional_info(&node_c, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&node_c, 0, 50, 0), get_test_directional_info(&node_d, 0, 0, 0));
+		add_test_channel(&router, 6, get_test_directional_info(&node_d, 0, 50, 0), get_test_directional_info(&target, 0, 0, 0));
+		let route_within = |fee_budget_msat: Option<u64>| {
+			let params = RouteParameters { fee_budget_msat, ..RouteParameters::default() };
+			let route = router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap();
+			(route.hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>(), route.total_fees_msat())
+		};
+
+		assert_eq!(route_within(None), (vec![3, 4, 5, 6], 150));
+		assert_eq!(route_within(Some(300)), (vec![1, 2], 300));
+		assert_eq!(route_within(Some(200)), (vec![3, 4, 5, 6], 150));
+		// Without a route within the budget, we still get the cheapest one
+		assert_eq!(route_within(Some(100)), (vec![3, 4, 5, 6], 150));
+
+		// The same goes for routes over our first_hops
+		let our_chans = vec![get_test_first_hop(&node_a, 11, 0), get_test_first_hop(&node_b, 13, 0)];
+		for &(fee_budget_msat, ref expected_short_channel_ids) in [(None, vec![13, 4, 5, 6]), (Some(1000), vec![11, 2])].iter() {
+			let params = RouteParameters { fee_budget_msat, ..RouteParameters::default() };
+			let route = router.get_route_with_params(&target, Some(&our_chans), &Vec::new(), 1000, 42, &params).unwrap();
+			assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), *expected_short_channel_ids);
+		}
+	}
+
+	#[test]
+	fn route_shortcut_redundant_hops_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 10, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 0, 10, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_a, 0, 15, 0), get_test_directional_info(&target, 0, 0, 0));
+		// Boosting channels 2 and 3 makes the search take a detour through node_b
+		router.set_channel_score_boost(ShortChannelId(2), 10);
+		router.set_channel_score_boost(ShortChannelId(3), 10);
+		let route_over = |shortcut_redundant_hops: bool, excluded_channels: Vec<ShortChannelId>| {
+			let params = RouteParameters { shortcut_redundant_hops, excluded_channels, ..RouteParameters::default() };
+			let route = router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap();
+			(route.hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>(), route.total_fees_msat())
+		};
+
+		assert_eq!(route_over(false, Vec::new()), (vec![1, 2, 3], 20));
+		assert_eq!(route_over(true, Vec::new()), (vec![1, 4], 15));
+		// Shortcuts over excluded channels aren't taken
+		assert_eq!(route_over(true, vec![ShortChannelId(4)]), (vec![1, 2, 3], 20));
+
+		// Nor are shortcuts which would cost more
+		router.network_map.write().unwrap().channels.get_mut(&4).unwrap().one_to_two.fee_base_msat = 21;
+		assert_eq!(route_over(true, Vec::new()), (vec![1, 2, 3], 20));
+		router.network_map.write().unwrap().channels.get_mut(&4).unwrap().one_to_two.fee_base_msat = 15;
+
+		// Nor those over channels the search wouldn't use, as their last update is too old...
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		{
+			let mut network = router.network_map.write().unwrap();
+			network.channels.get_mut(&2).unwrap().one_to_two.last_update = now as u32;
+			network.channels.get_mut(&3).unwrap().one_to_two.last_update = now as u32;
+		}
+		let params = RouteParameters { shortcut_redundant_hops: true, max_channel_update_age_secs: Some(3600), ..RouteParameters::default() };
+		assert_eq!(router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap().hops[1].short_channel_id, 2);
+
+		// ...or can't carry the amount, even where the search would only penalize it...
+		router.network_map.write().unwrap().channels.get_mut(&4).unwrap().one_to_two.htlc_maximum_msat = Some(999);
+		let params = RouteParameters { shortcut_redundant_hops: true, htlc_maximum_penalty_msat: Some(1_000_000), ..RouteParameters::default() };
+		assert_eq!(router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap().hops[1].short_channel_id, 2);
+		router.network_map.write().unwrap().channels.get_mut(&4).unwrap().one_to_two.htlc_maximum_msat = None;
+
+		// ...including as far as we've learned
+		let mut store = ChannelLiquidityStore::new(3600);
+		store.channel_failed(ShortChannelId(4), &node_a, 1000, now);
+		let params = RouteParameters { shortcut_redundant_hops: true, ..RouteParameters::default() };
+		assert_eq!(router.get_route_with_learned_liquidity(&target, None, &Vec::new(), 1000, 42, &params, &store).unwrap().hops[1].short_channel_id, 2);
+		assert_eq!(router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap().hops[1].short_channel_id, 4);
+
+		// Nor are shortcuts which break the fee share limit the search kept to. Here, the search
+		// takes our_id -1- node_a -2- node_b -3- node_c -6- target, paying 10 msat each to node_a,
+		// node_b and node_c, while skipping node_b would leave node_a charging 19 of 29 msat.
+		let (_, _, router) = create_router();
+		let node_c = get_test_node_id(&secp_ctx, 5);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 10, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 0, 10, 0), get_test_directional_info(&node_c, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_a, 0, 19, 0), get_test_directional_info(&node_c, 0, 0, 0));
+		add_test_channel(&router, 6, get_test_directional_info(&node_c, 0, 10, 0), get_test_directional_info(&target, 0, 0, 0));
+		router.set_channel_score_boost(ShortChannelId(2), 10);
+		router.set_channel_score_boost(ShortChannelId(3), 10);
+		let route_over = |max_hop_fee_share_millionths: Option<u32>| {
+			let params = RouteParameters { shortcut_redundant_hops: true, max_hop_fee_share_millionths, ..RouteParameters::default() };
+			router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap().hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>()
+		};
+		assert_eq!(route_over(None), vec![1, 4, 6]);
+		assert_eq!(route_over(Some(600_000)), vec![1, 2, 3, 6]);
+	}
+
+	#[test]
+	fn route_max_hop_fee_share_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let node_c = get_test_node_id(&secp_ctx, 4);
+		let node_d = get_test_node_id(&secp_ctx, 5);
+		let target = get_test_node_id(&secp_ctx, 6);
+		// The cheapest route pays 10 msat to node_a and 90 msat to node_b
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 10, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 0, 90, 0), get_test_directional_info(&target, 0, 0, 0));
+		// The alternative pays 60 msat each to node_c and node_d
+		add_test_channel(&router, 4, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_c, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&node_c, 0, 60, 0), get_test_directional_info(&node_d, 0, 0, 0));
+		add_test_channel(&router, 6, get_test_directional_info(&node_d, 0, 60, 0), get_test_directional_info(&target, 0, 0, 0));
+		let route_over = |max_hop_fee_share_millionths: Option<u32>| {
+			let params = RouteParameters { max_hop_fee_share_millionths, ..RouteParameters::default() };
+			router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).map(|route| route.hops[0].short_channel_id)
+		};
+
+		assert_eq!(route_over(None).unwrap(), 1);
+		assert_eq!(route_over(Some(900_000)).unwrap(), 1);
+		assert_eq!(route_over(Some(899_999)).unwrap(), 4);
+		assert_eq!(route_over(Some(500_000)).unwrap(), 4);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = route_over(Some(499_999)) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+
+		// Each violating route excludes one more channel, so with nine channels like channel 3 the
+		// route via node_c and node_d is found on the tenth search...
+		for short_channel_id in 10..18 {
+			add_test_channel(&router, short_channel_id, get_test_directional_info(&node_b, 0, 90, 0), get_test_directional_info(&target, 0, 0, 0));
+		}
+		assert_eq!(route_over(Some(899_999)).unwrap(), 4);
+		// ...but with ten we give up before finding it.
+		add_test_channel(&router, 18, get_test_directional_info(&node_b, 0, 90, 0), get_test_directional_info(&target, 0, 0, 0));
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = route_over(Some(899_999)) {
+			assert_eq!(err, "Failed to find a path within max_hop_fee_share_millionths");
+		} else { panic!(); }
+
+		// A route over which only one node charges a fee is never affected
+		add_test_channel(&router, 7, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		assert_eq!(route_over(Some(1)).unwrap(), 7);
+	}
+
+	#[test]
+	fn route_max_fee_proportional_millionths_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 1000, 0), get_test_directional_info(&target, 0, 0, 0));
+		let route_over = |final_value_msat: u64, max_fee_proportional_millionths: Option<u32>| {
+			let params = RouteParameters { max_fee_proportional_millionths, ..RouteParameters::default() };
+			router.get_route_with_params(&target, None, &Vec::new(), final_value_msat, 42, &params).map(|route| route.hops[0].short_channel_id)
+		};
+
+		// A 100 msat fee is 10% of 1000 msat
+		assert_eq!(route_over(1000, None).unwrap(), 1);
+		assert_eq!(route_over(1000, Some(100_000)).unwrap(), 1);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = route_over(1000, Some(99_999)) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+		assert_eq!(route_over(10_000, Some(10_000)).unwrap(), 1);
+
+		// With channel 2 excluded, only node_b's larger fee is left
+		let params = RouteParameters { max_fee_proportional_millionths: Some(100_000), excluded_channels: vec![ShortChannelId(2)], ..RouteParameters::default() };
+		assert!(router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).is_err());
+		assert!(router.get_route_with_params(&target, None, &Vec::new(), 10_000, 42, &params).is_ok());
+	}
+
+	#[test]
+	fn route_max_fee_proportional_millionths_penalty_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_x = get_test_node_id(&secp_ctx, 3);
+		let node_y = get_test_node_id(&secp_ctx, 4);
+		let node_z = get_test_node_id(&secp_ctx, 5);
+		let target = get_test_node_id(&secp_ctx, 6);
+
+		// our_id -1- node_a -2- node_x, from which the target is reached for 200 msat via node_z or
+		// for 50 msat via node_y, which is penalized.
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&node_x, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_x, 0, 0, 0), get_test_directional_info(&node_z, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_z, 0, 200, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&node_x, 0, 0, 0), get_test_directional_info(&node_y, 0, 0, 0));
+		add_test_channel(&router, 6, get_test_directional_info(&node_y, 0, 50, 0), get_test_directional_info(&target, 0, 0, 0));
+
+		let mut params = RouteParameters { node_penalties: vec![(node_y, 1000)], ..RouteParameters::default() };
+		let route = router.get_route_with_params(&target, None, &Vec::new(), 1_000_000, 42, &params).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+		assert_eq!(route.total_fees_msat(), 300);
+
+		// node_x keeps the path via node_z, which exceeds the limit once extended to node_a, so the
+		// penalized path via node_y is only found without the penalty.
+		params.max_fee_proportional_millionths = Some(250);
+		let route = router.get_route_with_params(&target, None, &Vec::new(), 1_000_000, 42, &params).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 5, 6]);
+		assert_eq!(route.total_fees_msat(), 150);
+
+		params.max_fee_proportional_millionths = Some(149);
+		assert!(router.get_route_with_params(&target, None, &Vec::new(), 1_000_000, 42, &params).is_err());
+	}
+
+	#[test]
+	fn route_parallel_channel_policy_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let target = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+
+		// Three parallel channels from node1 to target: the cheapest, the largest and the most
+		// recently updated
+		for &(short_channel_id, fee_base_msat, capacity_sats, last_update) in [(2, 100, Some(10), 1), (3, 200, Some(1000), 2), (4, 300, None, 3)].iter() {
+			let mut directional_info = get_test_directional_info(&node1, 0, fee_base_msat, 0);
+			directional_info.last_update = last_update;
+			add_test_channel(&router, short_channel_id, directional_info, get_test_directional_info(&target, 0, 0, 0));
+			router.network_map.write().unwrap().channels.get_mut(&short_channel_id).unwrap().capacity_sats = capacity_sats;
+		}
+		let route_over = |parallel_channel_policy: ParallelChannelPolicy, excluded_channels: Vec<ShortChannelId>| {
+			let params = RouteParameters { parallel_channel_policy, excluded_channels, ..RouteParameters::default() };
+			let route = router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap();
+			assert_eq!(route.hops.len(), 2);
+			(route.hops[1].short_channel_id.0, route.total_fees_msat())
+		};
+
+		assert_eq!(route_over(ParallelChannelPolicy::Cheapest, Vec::new()), (2, 100));
+		assert_eq!(route_over(ParallelChannelPolicy::HighestCapacity, Vec::new()), (3, 200));
+		assert_eq!(route_over(ParallelChannelPolicy::MostRecentlyUpdated, Vec::new()), (4, 300));
+		// Unusable channels aren't picked
+		assert_eq!(route_over(ParallelChannelPolicy::HighestCapacity, vec![ShortChannelId(3)]), (2, 100));
+		router.network_map.write().unwrap().channels.get_mut(&4).unwrap().one_to_two.enabled = false;
+		assert_eq!(route_over(ParallelChannelPolicy::MostRecentlyUpdated, Vec::new()), (3, 200));
+		// Ties fall back to the cheapest
+		router.network_map.write().unwrap().channels.get_mut(&2).unwrap().capacity_sats = Some(1000);
+		assert_eq!(route_over(ParallelChannelPolicy::HighestCapacity, Vec::new()), (2, 100));
+	}
+
+	#[test]
+	fn route_search_metrics_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node2, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		let last_hops = vec![RouteHint {
+			src_node_id: node1,
+			short_channel_id: ShortChannelId(4),
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: 0,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}];
+
+		// The hint and channel 3 are relaxed first. We then visit node2 (relaxing channels 2 and 3,
+		// the latter back towards target), target (relaxing channel 3) and node1 (relaxing channels
+		// 1 and 2) before reaching ourselves.
+		let mut metrics = RouteSearchMetrics { nodes_visited: 42, ..RouteSearchMetrics::default() };
+		let route = router.get_route_with_metrics(&target, None, &last_hops, 1000, 42, &RouteParameters::default(), &mut metrics).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 3]);
+		assert_eq!(metrics.nodes_visited, 3);
+		assert_eq!(metrics.channels_relaxed, 7);
+
+		// Metrics are filled in even if the search fails
+		let params = RouteParameters { max_nodes_visited: Some(1), ..RouteParameters::default() };
+		assert!(router.get_route_with_metrics(&target, None, &last_hops, 1000, 42, &params, &mut metrics).is_err());
+		assert_eq!(metrics.nodes_visited, 2);
+		assert_eq!(metrics.channels_relaxed, 4);
+	}
+
+	#[test]
+	fn route_max_channel_update_age_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+
+		// Both our channels were last updated long ago, as was channel 2 from node_a, the cheaper
+		// way onwards to target, while channel 4 from node_b was updated a minute ago
+		let updated_at = |src_node_id: &PublicKey, fee_base_msat: u32, last_update: u32| {
+			let mut directional_info = get_test_directional_info(src_node_id, 0, fee_base_msat, 0);
+			directional_info.last_update = last_update;
+			directional_info
+		};
+		add_test_channel(&router, 1, updated_at(&our_id, 0, 1), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, updated_at(&node_a, 100, now - 7200), updated_at(&target, 0, now));
+		add_test_channel(&router, 3, updated_at(&our_id, 0, 1), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, updated_at(&node_b, 200, now - 60), updated_at(&target, 0, now));
+		let route_over = |max_channel_update_age_secs: Option<u32>| {
+			let params = RouteParameters { max_channel_update_age_secs, ..RouteParameters::default() };
+			router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params)
+				.map(|route| route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>())
+		};
+
+		assert_eq!(route_over(None).unwrap(), vec![1, 2]);
+		assert_eq!(route_over(Some(86400)).unwrap(), vec![1, 2]);
+		assert_eq!(route_over(Some(3600)).unwrap(), vec![3, 4]);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = route_over(Some(10)) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+	}
+
+	#[test]
+	fn route_preferred_nodes_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 150, 0), get_test_directional_info(&target, 0, 0, 0));
+		let route_via = |preferred_nodes: Vec<(PublicKey, u64)>| {
+			let params = RouteParameters { preferred_nodes, ..RouteParameters::default() };
+			let route = router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap();
+			(route.hops[0].pubkey, route.total_fees_msat())
+		};
+
+		assert_eq!(route_via(Vec::new()), (node_a, 100));
+		// node_b is only preferred if it costs at most its discount more...
+		assert_eq!(route_via(vec![(node_b, 40)]), (node_a, 100));
+		assert_eq!(route_via(vec![(node_b, 60)]), (node_b, 150));
+		assert_eq!(route_via(vec![(node_b, 30), (node_b, 30)]), (node_b, 150));
+		// ...and a discount can't exceed the fee node_b charges
+		assert_eq!(route_via(vec![(node_a, 1000), (node_b, 1_000_000)]), (node_a, 100));
+		// Discounts on the endpoints of every route don't change anything
+		assert_eq!(route_via(vec![(target, 1000), (our_id, 1000)]), (node_a, 100));
+	}
+
+	#[test]
+	fn route_max_nodes_visited_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let node_c = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 0, 0, 0), get_test_directional_info(&node_c, 0, 0, 0));
+
+		// Reaching us from node_c requires exploring the channels of node_b and then node_a
+		let params = RouteParameters { max_nodes_visited: Some(2), ..RouteParameters::default() };
+		assert_eq!(router.get_route_with_params(&node_c, None, &Vec::new(), 1000, 42, &params).unwrap().hops.len(), 3);
+		let params = RouteParameters { max_nodes_visited: Some(1), ..RouteParameters::default() };
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route_with_params(&node_c, None, &Vec::new(), 1000, 42, &params) {
+			assert_eq!(err, "Route search budget exhausted");
+		} else { panic!(); }
+		let params = RouteParameters { max_nodes_visited: Some(0), ..RouteParameters::default() };
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route_with_params(&node_b, None, &Vec::new(), 1000, 42, &params) {
+			assert_eq!(err, "Route search budget exhausted");
+		} else { panic!(); }
+	}
+
+	#[test]
+	fn route_deadline_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+
+		let params = RouteParameters { deadline: Some(Instant::now() + Duration::from_secs(3600)), ..RouteParameters::default() };
+		assert_eq!(router.get_route_with_params(&node_b, None, &Vec::new(), 1000, 42, &params).unwrap().hops.len(), 2);
+		let params = RouteParameters { deadline: Some(Instant::now()), ..RouteParameters::default() };
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route_with_params(&node_b, None, &Vec::new(), 1000, 42, &params) {
+			assert_eq!(err, "Route search deadline exceeded");
+		} else { panic!(); }
+
+		// With a (penalized) hint from us directly to node_b, a route from us is known before the
+		// search visits node_a, and is returned though the route via node_a is cheaper
+		let last_hops = vec![RouteHint {
+			src_node_id: our_id,
+			short_channel_id: ShortChannelId(3),
+			fee_base_msat: 0,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: 0,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: Some(1),
+		}];
+		let mut params = RouteParameters { htlc_maximum_penalty_msat: Some(1000), ..RouteParameters::default() };
+		let route_over = |params: &RouteParameters| {
+			router.get_route_with_params(&node_b, None, &last_hops, 1000, 42, params).unwrap().hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>()
+		};
+		assert_eq!(route_over(&params), vec![1, 2]);
+		params.deadline = Some(Instant::now());
+		assert_eq!(route_over(&params), vec![3]);
+	}
+
+	#[test]
+	fn route_fee_vector_single_hop_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		// Our own fees and CLTV delta are never paid, so the only hop carries exactly the final
+		// value and CLTV.
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 1000, 1000), get_test_directional_info(&node_a, 10, 1000, 1000));
+
+		let route = router.get_route(&node_a, None, &Vec::new(), 10_000, 42).unwrap();
+		assert_route_hops(&route, &[(node_a, 1, 10_000, 42)]);
+	}
+
+	#[test]
+	fn route_fee_vector_multi_hop_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let node_c = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 5000, 5000), get_test_directional_info(&node_a, 10, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 20, 100, 1000), get_test_directional_info(&node_b, 20, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 30, 200, 2000), get_test_directional_info(&node_c, 30, 0, 0));
+
+		// node_b charges 200 + 1_000_000 * 2000 / 1_000_000 = 2200 to forward over channel 3.
+		// node_a charges 100 + 1_002_200 * 1000 / 1_000_000 = 100 + 1002 = 1102 (rounded down) to
+		// forward over channel 2. Each hop's CLTV delta is that of the channel after it.
+		let route = router.get_route(&node_c, None, &Vec::new(), 1_000_000, 42).unwrap();
+		assert_route_hops(&route, &[(node_a, 1, 1102, 20), (node_b, 2, 2200, 30), (node_c, 3, 1_000_000, 42)]);
+		assert_eq!(route.total_fees_msat(), 1102 + 2200);
+	}
+
+	#[test]
+	fn route_fee_vector_hint_terminated_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let node_d = get_test_node_id(&secp_ctx, 5);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 0, 0), get_test_directional_info(&node_a, 10, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 20, 100, 1000), get_test_directional_info(&node_b, 20, 0, 0));
+		let last_hops = vec![RouteHint {
+			src_node_id: node_b,
+			short_channel_id: ShortChannelId(8),
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 100,
+			cltv_expiry_delta: 40,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}];
+
+		// node_b charges the hint's 1000 + 500_000 * 100 / 1_000_000 = 1050 to forward to node_d.
+		// node_a charges 100 + 501_050 * 1000 / 1_000_000 = 100 + 501 = 601 (rounded down).
+		let route = router.get_route(&node_d, None, &last_hops, 500_000, 42).unwrap();
+		assert_route_hops(&route, &[(node_a, 1, 601, 20), (node_b, 2, 1050, 40), (node_d, 8, 500_000, 42)]);
+	}
+
+	#[test]
+	fn chain_hash_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1_secret = SecretKey::from_slice(&[2; 32]).unwrap();
+		let testnet_hash = genesis_block(Network::Testnet).header.bitcoin_hash();
+		let mainnet_hash = genesis_block(Network::Bitcoin).header.bitcoin_hash();
+		let announcement = get_signed_channel_announcement(&secp_ctx, 1, 2, 3);
+
+		// A mainnet graph rejects testnet gossip, even though our (testnet) chain monitor would
+		// accept it, including once deserialized
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let mainnet_router = Router::new(our_id, mainnet_hash, chain_monitor.clone(), Arc::clone(&logger));
+		let read_mainnet_router = Router::read(&mut ::std::io::Cursor::new(&mainnet_router.encode()), RouterReadArgs {
+			chain_monitor,
+			logger: Arc::clone(&logger),
+			genesis_hash: mainnet_hash,
+		}).unwrap();
+		for mainnet_router in [mainnet_router, read_mainnet_router].iter() {
+			match mainnet_router.handle_channel_announcement(&announcement) {
+				Err(LightningError{err, ..}) => assert_eq!(err, "Channel announcement was for a different chain"),
+				Ok(_) => panic!(),
+			}
+			assert!(mainnet_router.get_channel_info(ShortChannelId(1)).is_none());
+		}
+
+		assert!(router.handle_channel_announcement(&announcement).unwrap());
+		assert!(router.get_channel_info(ShortChannelId(1)).is_some());
+
+		// channel_updates for a channel with the same short_channel_id on another chain are
+		// rejected too, rather than overwriting this chain's channel
+		let mut unsigned_update = get_signed_channel_update(&secp_ctx, 2, 1, 0, 1, 0, Vec::new()).contents;
+		unsigned_update.chain_hash = mainnet_hash;
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_update.encode()[..])[..]);
+		let update = msgs::ChannelUpdate {
+			signature: secp_ctx.sign(&msg_hash, &node1_secret),
+			contents: unsigned_update.clone(),
+		};
+		match router.handle_channel_update(&update) {
+			Err(LightningError{err, ..}) => assert_eq!(err, "channel_update was for a different chain"),
+			Ok(_) => panic!(),
+		}
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.last_update, 0);
+
+		unsigned_update.chain_hash = testnet_hash;
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&unsigned_update.encode()[..])[..]);
+		let update = msgs::ChannelUpdate {
+			signature: secp_ctx.sign(&msg_hash, &node1_secret),
+			contents: unsigned_update,
+		};
+		assert!(router.handle_channel_update(&update).unwrap());
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.last_update, 1);
+
+		// Clearing the restriction accepts gossip for other chains again
+		router.network_map.write().unwrap().chain_hash = Some(mainnet_hash);
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 2, 2, 3)).is_err());
+		router.clear_chain_hash();
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 2, 2, 3)).unwrap());
+		assert!(router.get_channel_info(ShortChannelId(2)).is_some());
+	}
+
+	#[test]
+	fn channel_update_message_flags_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		// Without the message_flags bit, the max is not parsed out of (and so can't be relayed with)
+		// excess data
+		let update = get_signed_channel_update(&secp_ctx, 2, 1, 0, 1, 0, vec![0, 0, 0, 0, 0, 0, 0x01, 0xf4]);
+		assert!(!router.handle_channel_update(&update).unwrap());
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.htlc_maximum_msat, None);
+
+		// With the bit set, but no room for the field, the update is rejected
+		let update = get_signed_channel_update(&secp_ctx, 2, 1, 1 << 8, 2, 0, vec![0, 0, 0x01, 0xf4]);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.handle_channel_update(&update) {
+			assert_eq!(err, "channel_update had htlc_maximum_msat flag set but no htlc_maximum_msat");
+		} else { panic!(); }
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.last_update, 1);
+
+		// Data beyond the max is still stored, but not relayed
+		let update = get_signed_channel_update(&secp_ctx, 2, 1, 1 << 8, 3, 0, vec![0, 0, 0, 0, 0, 0, 0x01, 0xf4, 42]);
+		assert!(!router.handle_channel_update(&update).unwrap());
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.htlc_maximum_msat, Some(500));
+		assert!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.last_update_message.is_none());
+
+		let update = get_signed_channel_update(&secp_ctx, 2, 1, 1 << 8, 4, 0, vec![0, 0, 0, 0, 0, 0, 0x03, 0xe8]);
+		assert!(router.handle_channel_update(&update).unwrap());
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.htlc_maximum_msat, Some(1000));
+		assert!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.last_update_message.is_some());
+	}
+
+	#[test]
+	fn route_capacity_fallback_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node2 is free, our_id -3- node3 -4- node2 costs 100 msat
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node3, 0, 100, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		// Without an htlc_maximum_msat, channel 2 is limited by its capacity...
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = Some(1);
+		let route = router.get_route(&node2, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 2);
+		let route = router.get_route(&node2, None, &Vec::new(), 1001, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 4);
+
+		// ...and an htlc_maximum_msat cannot exceed the capacity
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_maximum_msat = Some(1_000_000);
+		let route = router.get_route(&node2, None, &Vec::new(), 1001, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 4);
+
+		// Without either, it is limited to the largest non-wumbo channel
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = None;
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_maximum_msat = None;
+		let route = router.get_route(&node2, None, &Vec::new(), (1 << 24) * 1000, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 2);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route(&node2, None, &Vec::new(), (1 << 24) * 1000 + 1, 42) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+	}
+
+	#[test]
+	#[cfg(not(feature = "fuzztarget"))]
+	fn channel_update_future_timestamp_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+		let update = get_signed_channel_update(&secp_ctx, 2, 1, 0, now + 2 * 60 * 60, 0, Vec::new());
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.handle_channel_update(&update) {
+			assert_eq!(err, "channel_update timestamp is too far in the future");
+		} else { panic!(); }
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.last_update, 0);
+
+		// Small amounts of clock skew are tolerated
+		let update = get_signed_channel_update(&secp_ctx, 2, 1, 0, now + 10 * 60, 0, Vec::new());
+		assert!(router.handle_channel_update(&update).unwrap());
+	}
+
+	#[test]
+	fn gossip_freshness_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 1, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node2, 0, 0, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node3, 0, 0, 0));
+		assert_eq!(router.get_gossip_freshness(), GossipFreshness {
+			newest_channel_update_timestamp: None,
+			recently_updated_channel_count: 0,
+		});
+
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 2, 1, 0, now - 2 * 24 * 60 * 60, 0, Vec::new())).unwrap());
+		assert_eq!(router.get_gossip_freshness(), GossipFreshness {
+			newest_channel_update_timestamp: Some(now - 2 * 24 * 60 * 60),
+			recently_updated_channel_count: 0,
+		});
+
+		// Either direction of a channel being updated counts, but only once per channel
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 3, 1, 1, now - 60 * 60, 0, Vec::new())).unwrap());
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 3, 2, 0, now - 2 * 60 * 60, 0, Vec::new())).unwrap());
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 4, 2, 1, now - 3 * 60 * 60, 0, Vec::new())).unwrap());
+		assert_eq!(router.get_gossip_freshness(), GossipFreshness {
+			newest_channel_update_timestamp: Some(now - 60 * 60),
+			recently_updated_channel_count: 2,
+		});
+	}
+
+	#[test]
+	fn channel_update_equal_timestamp_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		let first_update = get_signed_channel_update(&secp_ctx, 2, 1, 0, 42, 100, Vec::new());
+		assert!(router.handle_channel_update(&first_update).unwrap());
+		let racing_update = get_signed_channel_update(&secp_ctx, 2, 1, 0, 42, 200, Vec::new());
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.handle_channel_update(&racing_update) {
+			assert_eq!(err, "Update older than last processed update");
+		} else { panic!(); }
+		let chan = router.get_channel_info(ShortChannelId(1)).unwrap();
+		assert_eq!(chan.one_to_two.fee_base_msat, 100);
+		assert!(chan.one_to_two.last_update_message == Some(first_update));
+
+		// The other direction's timestamps are tracked separately
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 3, 1, 1, 42, 300, Vec::new())).unwrap());
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().two_to_one.fee_base_msat, 300);
+	}
+
+	#[test]
+	fn route_htlc_maximum_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node2 is free, our_id -3- node3 -4- node2 costs 100 msat
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node3, 0, 100, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		// Limit node1 -> node2 to 500 msat via a channel_update with htlc_maximum_msat set
+		let update = get_signed_channel_update(&secp_ctx, 2, 2, 1 << 8, 1, 0, vec![0, 0, 0, 0, 0, 0, 0x01, 0xf4]);
+		assert!(router.handle_channel_update(&update).unwrap());
+		assert_eq!(router.get_channel_info(ShortChannelId(2)).unwrap().one_to_two.htlc_maximum_msat, Some(500));
+
+		let route = router.get_route(&node2, None, &Vec::new(), 500, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 2);
+		let route = router.get_route(&node2, None, &Vec::new(), 501, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 4);
+
+		// With a penalty, the over-maximum channel is only used if its cost beats the alternative
+		let mut params = RouteParameters::default();
+		params.htlc_maximum_penalty_msat = Some(1000);
+		let route = router.get_route_with_params(&node2, None, &Vec::new(), 501, 42, &params).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 4);
+		params.htlc_maximum_penalty_msat = Some(99);
+		let route = router.get_route_with_params(&node2, None, &Vec::new(), 501, 42, &params).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 2);
+		assert_eq!(route.hops[0].fee_msat, 0);
+
+		// ...or when it is the last resort
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(4, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.enabled = false;
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route(&node2, None, &Vec::new(), 501, 42) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+		params.htlc_maximum_penalty_msat = Some(1_000_000);
+		let route = router.get_route_with_params(&node2, None, &Vec::new(), 501, 42, &params).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 2);
+	}
+
+	#[test]
+	fn route_to_us_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+
+		// node2 -2- node1 -1- our_id, where fees are only charged towards us.
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 1000, 0), get_test_directional_info(&node1, 20, 100, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 30, 1000, 0), get_test_directional_info(&node2, 40, 100, 0));
+
+		let route = router.get_route_to_us(&node2, &Vec::new(), 1000, 42, &RouteParameters::default()).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].pubkey, node1);
+		assert_eq!(route.hops[0].short_channel_id, 2);
+		assert_eq!(route.hops[0].fee_msat, 100);
+		assert_eq!(route.hops[0].cltv_expiry_delta, 20);
+		assert_eq!(route.hops[1].pubkey, our_id);
+		assert_eq!(route.hops[1].short_channel_id, 1);
+		assert_eq!(route.hops[1].fee_msat, 1000);
+		assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+
+		// A hint for a private channel of ours may be used as the final hop
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		add_test_channel(&router, 3, get_test_directional_info(&node3, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		let last_hops = vec![RouteHint {
+			src_node_id: node3.clone(),
+			short_channel_id: ShortChannelId(4),
+			fee_base_msat: 10,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: 5,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}];
+		let route = router.get_route_to_us(&node2, &last_hops, 1000, 42, &RouteParameters::default()).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].pubkey, node3);
+		assert_eq!(route.hops[0].fee_msat, 10);
+		assert_eq!(route.hops[1].pubkey, our_id);
+		assert_eq!(route.hops[1].short_channel_id, 4);
+
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route_to_us(&our_id, &Vec::new(), 1000, 42, &RouteParameters::default()) {
+			assert_eq!(err, "Cannot generate a route to ourselves");
+		} else { panic!(); }
+	}
+
+	#[test]
+	fn route_variable_length_onion_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+
+		// our_id -1- node1 -2- node2, where only node2 advertises TLV onion support
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 0, 0), get_test_directional_info(&node1, 10, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 10, 0, 0), get_test_directional_info(&node2, 10, 0, 0));
+		router.network_map.write().unwrap().nodes.get_mut(&node2).unwrap().features = NodeFeatures::supported();
+
+		let route = router.get_route(&node2, None, &Vec::new(), 100, 42).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert!(!route.hops[0].supports_variable_length_onion());
+		assert!(route.hops[1].supports_variable_length_onion());
+
+		let (onion_payloads, _, _) = onion_utils::build_onion_payloads(&route, 0).unwrap();
+		match onion_payloads[0].format {
+			msgs::OnionHopDataFormat::Legacy { short_channel_id } => assert_eq!(short_channel_id, 2),
+			_ => panic!(),
+		}
+		match onion_payloads[1].format {
+			msgs::OnionHopDataFormat::FinalNode => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn unverified_channels_test() {
+		let secp_ctx = Secp256k1::new();
+		let our_id = get_test_node_id(&secp_ctx, 1);
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(UtxoLookupChainWatcher { available: AtomicBool::new(false), utxo: Mutex::new(None) });
+		let router = <Router>::new(our_id, genesis_block(Network::Testnet).header.bitcoin_hash(), chain_monitor.clone(), Arc::clone(&logger));
+		add_test_channel(&router, 10, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+
+		let get_announcement = |short_channel_id: u64, bitcoin_key_1_idx: u8, bitcoin_key_2_idx: u8| {
+			get_signed_channel_announcement_with_bitcoin_keys(&secp_ctx, short_channel_id, 2, 3, bitcoin_key_1_idx, bitcoin_key_2_idx, ChannelFeatures::empty())
+		};
+
+		// By default, gossip we can't look up the UTXO of is dropped
+		match router.handle_channel_announcement(&get_announcement(1, 10, 11)) {
+			Err(LightningError{err, ..}) => assert_eq!(err, "Channel announcement UTXO lookup is currently unavailable"),
+			Ok(_) => panic!(),
+		}
+		assert!(router.get_channel_info(ShortChannelId(1)).is_none());
+
+		// Once enabled, it's accepted (but not relayed) and tracked, but not routed over
+		router.set_accept_unverified_channels(true);
+		assert!(!router.handle_channel_announcement(&get_announcement(1, 10, 11)).unwrap());
+		assert!(!router.handle_channel_announcement(&get_announcement(2, 12, 13)).unwrap());
+		assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 2, 1, 0, 1, 0, Vec::new())).unwrap());
+		assert!(router.get_channel_info(ShortChannelId(1)).unwrap().one_to_two.enabled);
+		assert!(router.get_route(&node2, None, &Vec::new(), 1000, 42).is_err());
+		assert_eq!(router.reverify_channels(), 2);
+
+		// Unverified channels remain so across serialization
+		let mut w = TestVecWriter(Vec::new());
+		router.network_map.read().unwrap().write(&mut w).unwrap();
+		let network: NetworkMap = Readable::read(&mut ::std::io::Cursor::new(&w.0)).unwrap();
+		assert_eq!(network.unverified_channels.len(), 2);
+
+		// Once lookups are back, channels which check out are promoted and others removed
+		chain_monitor.available.store(true, Ordering::Release);
+		*chain_monitor.utxo.lock().unwrap() = Some((funding_script_pubkey(&get_test_node_id(&secp_ctx, 10), &get_test_node_id(&secp_ctx, 11)), 5000));
+		// ...unless we've been cancelled
+		let cancellation_flag = Arc::new(AtomicBool::new(true));
+		router.set_cancellation_flag(Some(Arc::clone(&cancellation_flag)));
+		assert_eq!(router.reverify_channels(), 2);
+		cancellation_flag.store(false, Ordering::Release);
+		assert_eq!(router.reverify_channels(), 0);
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().capacity_sats, Some(5000));
+		assert!(router.get_channel_info(ShortChannelId(2)).is_none());
+		let route = router.get_route(&node2, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 1);
+
+		// Unverified channels stop being tracked once they're removed
+		chain_monitor.available.store(false, Ordering::Release);
+		assert!(!router.handle_channel_announcement(&get_announcement(3, 12, 13)).unwrap());
+		assert!(!router.handle_channel_announcement(&get_announcement(4, 14, 15)).unwrap());
+		assert_eq!(router.network_map.read().unwrap().unverified_channels.len(), 2);
+		router.handle_htlc_fail_channel_update(&msgs::HTLCFailChannelUpdate::ChannelClosed { short_channel_id: 3, is_permanent: true });
+		assert_eq!(router.network_map.read().unwrap().unverified_channels.len(), 1);
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		router.block_disconnected(&header, 0);
+		assert!(router.get_channel_info(ShortChannelId(4)).is_none());
+		assert!(router.network_map.read().unwrap().unverified_channels.is_empty());
+	}
+
+	#[test]
+	fn htlc_maximum_capacity_clamping_test() {
+		let secp_ctx = Secp256k1::new();
+		let our_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[1; 32]).unwrap());
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let funding_script = funding_script_pubkey(&get_test_node_id(&secp_ctx, 4), &get_test_node_id(&secp_ctx, 5));
+		let chain_monitor = Arc::new(UtxoLookupChainWatcher { available: AtomicBool::new(true), utxo: Mutex::new(Some((funding_script, 1000))) });
+		let router = <Router>::new(our_id, genesis_block(Network::Testnet).header.bitcoin_hash(), chain_monitor, Arc::clone(&logger));
+		add_test_channel(&router, 10, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+
+		// The channel's capacity is learned from its funding output when it's announced
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 1, 2, 3)).unwrap());
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().capacity_sats(), Some(1000));
+		let update_with_maximum = |timestamp: u32, htlc_maximum_msat: u64| {
+			let mut excess_data = Vec::new();
+			excess_data.extend_from_slice(&byte_utils::be64_to_array(htlc_maximum_msat));
+			assert!(router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 2, 1, 1 << 8, timestamp, 0, excess_data)).unwrap());
+		};
+
+		// An htlc_maximum_msat above the capacity is clamped to it...
+		update_with_maximum(1, 5_000_000);
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().effective_htlc_maximum_msat(true), 1_000_000);
+		assert!(router.get_route(&node2, None, &Vec::new(), 1_000_000, 42).is_ok());
+		assert!(router.get_route(&node2, None, &Vec::new(), 1_000_001, 42).is_err());
+
+		// ...while one below it is honored
+		update_with_maximum(2, 500_000);
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().effective_htlc_maximum_msat(true), 500_000);
+		assert!(router.get_route(&node2, None, &Vec::new(), 500_000, 42).is_ok());
+		assert!(router.get_route(&node2, None, &Vec::new(), 500_001, 42).is_err());
+
+		// One exactly at the capacity is allowed through in full
+		update_with_maximum(3, 1_000_000);
+		assert_eq!(router.get_channel_info(ShortChannelId(1)).unwrap().effective_htlc_maximum_msat(true), 1_000_000);
+		assert!(router.get_route(&node2, None, &Vec::new(), 1_000_000, 42).is_ok());
+	}
+
+	#[test]
+	fn find_node_by_prefix_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		for node_id in [our_id, node1, node2].iter() {
+			assert_eq!(router.find_node_by_prefix(&node_id.serialize()[..]), Ok(*node_id));
+			assert_eq!(router.find_node_by_prefix(&node_id.serialize()[..8]), Ok(*node_id));
+		}
+
+		// All node_ids start with 02 or 03
+		let even_nodes: Vec<_> = [our_id, node1, node2].iter().cloned().filter(|node_id| node_id.serialize()[0] == 2).collect();
+		let expected = match even_nodes.len() {
+			0 => Err(PrefixMatchError::NotFound),
+			1 => Ok(even_nodes[0]),
+			match_count => Err(PrefixMatchError::Ambiguous { match_count }),
+		};
+		assert_eq!(router.find_node_by_prefix(&[2]), expected);
+		assert_eq!(router.find_node_by_prefix(&[]), Err(PrefixMatchError::Ambiguous { match_count: 3 }));
 
-			assert_eq!(route.hops[0].pubkey, node2);
-			assert_eq!(route.hops[0].short_channel_id, 2);
-			assert_eq!(route.hops[0].fee_msat, 100);
-			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
-			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
-			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+		let mut unknown = get_test_node_id(&secp_ctx, 4).serialize();
+		assert_eq!(router.find_node_by_prefix(&unknown[..]), Err(PrefixMatchError::NotFound));
+		unknown[0] = 4;
+		assert_eq!(router.find_node_by_prefix(&unknown[..1]), Err(PrefixMatchError::NotFound));
+	}
 
-			assert_eq!(route.hops[1].pubkey, node3);
-			assert_eq!(route.hops[1].short_channel_id, 4);
-			assert_eq!(route.hops[1].fee_msat, 100);
-			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
-			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
-			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+	#[test]
+	fn max_channels_per_node_test() {
+		let (secp_ctx, _, router) = create_router();
+
+		router.set_max_channels_per_node(Some(2));
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 1, 2, 3)).unwrap());
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 2, 4, 2)).unwrap());
+
+		// Node 2 is at the cap, regardless of which side of the new channel it is on
+		for &(node1_idx, node2_idx) in [(2, 5), (5, 2)].iter() {
+			match router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 3, node1_idx, node2_idx)) {
+				Err(LightningError{err, ..}) => assert_eq!(err, "Node already has the maximum number of channels"),
+				Ok(_) => panic!(),
+			}
 		}
+		assert!(router.get_channel_info(ShortChannelId(3)).is_none());
 
-		{ // Disable channels 4 and 12 by requiring unknown feature bits
+		// Re-announcing a channel we already have isn't subject to the cap
+		match router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 1, 2, 3)) {
+			Err(LightningError{err, ..}) => assert_eq!(err, "Already have knowledge of channel"),
+			Ok(_) => panic!(),
+		}
+
+		// Our own node is never capped
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 4, 1, 3)).unwrap());
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 5, 1, 4)).unwrap());
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 6, 1, 5)).unwrap());
+
+		router.set_max_channels_per_node(None);
+		assert!(router.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 3, 2, 5)).unwrap());
+	}
+
+	#[test]
+	fn graph_size_limits_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		let node4 = get_test_node_id(&secp_ctx, 5);
+
+		// our_id -1- node1 -2- node2 -3- node3 -4- node4
+		let updated_info = |src_node_id: &PublicKey, last_update: u32| {
+			let mut info = get_test_directional_info(src_node_id, 0, 0, 0);
+			info.last_update = last_update;
+			info
+		};
+		let mut our_info = updated_info(&our_id, 0);
+		our_info.fee_base_msat = 100;
+		our_info.fee_proportional_millionths = 10;
+		add_test_channel(&router, 1, our_info, updated_info(&node1, 0));
+		add_test_channel(&router, 2, updated_info(&node1, 5), updated_info(&node2, 0));
+		add_test_channel(&router, 3, updated_info(&node2, 3), updated_info(&node3, 0));
+		add_test_channel(&router, 4, updated_info(&node3, 0), updated_info(&node4, 10));
+		router.set_channel_score_boost(ShortChannelId(2), 10);
+		router.set_channel_success_probability(ShortChannelId(2), Some(500_000));
+		router.set_inbound_fee(ShortChannelId(2), &node1, -10, 0);
+		router.set_channel_score_boost(ShortChannelId(4), 10);
+
+		// Our own channel is kept despite being the least-recently updated
+		router.set_graph_size_limits(Some(2), None);
+		assert!(router.get_channel_info(ShortChannelId(1)).is_some());
+		assert!(router.get_channel_info(ShortChannelId(2)).is_none());
+		assert!(router.get_channel_info(ShortChannelId(3)).is_none());
+		assert!(router.get_channel_info(ShortChannelId(4)).is_some());
+		{
+			let network = router.network_map.read().unwrap();
+			assert!(network.nodes.get(&node2).is_none());
+			assert_eq!(network.nodes.get(&node1).unwrap().channels, vec![1]);
+			assert_eq!(network.nodes.get(&node3).unwrap().channels, vec![4]);
+			assert_eq!(network.nodes.len(), 4);
+
+			// node1's cheapest inbound channel is now channel 1
+			assert_eq!(network.nodes.get(&node1).unwrap().lowest_inbound_channel_fee_base_msat, 100);
+			assert_eq!(network.nodes.get(&node1).unwrap().lowest_inbound_channel_fee_proportional_millionths, 10);
+			// What we set locally for channel 2 went with it, but not that for channel 4
+			assert!(network.channel_score_boosts.get(&2).is_none());
+			assert!(network.channel_success_probabilities.get(&2).is_none());
+			assert!(network.inbound_fees.is_empty());
+			assert!(network.channel_score_boosts.get(&4).is_some());
+		}
+
+		// Evicting node3 takes channel 4, and then the now channel-less node4, with it, while our
+		// direct peer is kept
+		router.network_map.write().unwrap().nodes.get_mut(&node4).unwrap().last_update = Some(5);
+		router.set_graph_size_limits(None, Some(3));
+		{
+			let network = router.network_map.read().unwrap();
+			assert_eq!(network.channels.len(), 1);
+			assert_eq!(network.nodes.len(), 2);
+			assert!(network.nodes.get(&our_id).is_some());
+			assert!(network.nodes.get(&node1).is_some());
+		}
+
+		// Larger caps are enforced in batches, evicting the oldest channels down to 15/16ths, ie 30
+		router.set_graph_size_limits(None, None);
+		for short_channel_id in 10..42 {
+			add_test_channel(&router, short_channel_id, updated_info(&node2, short_channel_id as u32), updated_info(&node3, 0));
+		}
+		router.set_graph_size_limits(Some(32), None);
+		assert_eq!(router.network_map.read().unwrap().channels.len(), 30);
+		assert!(router.get_channel_info(ShortChannelId(1)).is_some());
+		assert!(router.get_channel_info(ShortChannelId(12)).is_none());
+		assert!(router.get_channel_info(ShortChannelId(13)).is_some());
+	}
+
+	#[test]
+	fn block_disconnected_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+
+		let scid_at_height = |height: u64| scid_utils::scid_from_parts(height, 1, 0).unwrap();
+		add_test_channel(&router, scid_at_height(100), get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, scid_at_height(101), get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		router.block_disconnected(&header, 102);
+		assert_eq!(router.get_network_stats().channel_count, 2);
+
+		router.block_disconnected(&header, 101);
+		assert!(router.get_channel_info(ShortChannelId(scid_at_height(100))).is_some());
+		assert!(router.get_channel_info(ShortChannelId(scid_at_height(101))).is_none());
+		let network = router.network_map.read().unwrap();
+		assert!(network.nodes.get(&node2).is_none());
+		assert_eq!(network.nodes.get(&node1).unwrap().channels, vec![scid_at_height(100)]);
+	}
+
+	#[test]
+	fn unupdated_channels_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		let scid_at_height = |height: u64| scid_utils::scid_from_parts(height, 1, 0).unwrap();
+		let vout_scid_at_height = |height: u64| scid_utils::scid_from_parts(height, 1, 1).unwrap();
+		add_test_channel(&router, scid_at_height(100), get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		let announced_only_chan = |router: &Router, short_channel_id: u64, node_a: &PublicKey, node_b: &PublicKey| {
+			let (mut one_to_two, mut two_to_one) = (get_test_directional_info(node_a, 0, 0, 0), get_test_directional_info(node_b, 0, 0, 0));
+			one_to_two.enabled = false;
+			two_to_one.enabled = false;
+			add_test_channel(router, short_channel_id, one_to_two, two_to_one);
+		};
+		announced_only_chan(&router, scid_at_height(101), &node1, &node2);
+		announced_only_chan(&router, scid_at_height(110), &node1, &node3);
+		announced_only_chan(&router, vout_scid_at_height(100), &our_id, &node2);
+		assert!(!router.get_channel_info(ShortChannelId(scid_at_height(101))).unwrap().has_channel_update());
+		assert_eq!(router.get_network_stats().unupdated_channel_count, 3);
+		// Channels without updates are never routed over
+		assert!(router.get_route(&node2, None, &Vec::new(), 1000, 42).is_err());
+
+		// By default, such channels are kept around indefinitely
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		router.block_connected(&header, 1000, &[], &[]);
+		assert_eq!(router.get_network_stats().channel_count, 4);
+
+		router.set_unupdated_channel_grace_blocks(Some(6));
+		router.block_connected(&header, 106, &[], &[]);
+		assert_eq!(router.get_network_stats().channel_count, 4);
+		router.block_connected(&header, 107, &[], &[]);
+		assert!(router.get_channel_info(ShortChannelId(scid_at_height(101))).is_none());
+		assert!(router.get_channel_info(ShortChannelId(scid_at_height(110))).is_some());
+		assert!(router.network_map.read().unwrap().nodes.get(&node2).is_some());
+
+		// A channel_update saves a channel from being pruned, while our own channels are kept
+		{
 			let mut network = router.network_map.write().unwrap();
-			network.channels.get_mut(&NetworkMap::get_key(4, zero_hash.clone())).unwrap().features.set_require_unknown_bits();
-			network.channels.get_mut(&NetworkMap::get_key(12, zero_hash.clone())).unwrap().features.set_require_unknown_bits();
+			let chan = network.channels.get_mut(&NetworkMap::get_key(scid_at_height(110), Sha256dHash::hash(&[0; 32]))).unwrap();
+			chan.one_to_two.enabled = true;
+			chan.one_to_two.last_update = 1;
 		}
+		router.block_connected(&header, 200, &[], &[]);
+		assert!(router.get_channel_info(ShortChannelId(scid_at_height(110))).unwrap().has_channel_update());
+		assert!(router.get_channel_info(ShortChannelId(vout_scid_at_height(100))).is_some());
+		assert_eq!(router.get_network_stats(), NetworkStats {
+			node_count: 4,
+			channel_count: 3,
+			usable_channel_count: 2,
+			bidirectional_channel_count: 1,
+			unupdated_channel_count: 1,
+		});
+	}
 
-		{ // If all the channels require some features we don't understand, route should fail
-			if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route(&node3, None, &Vec::new(), 100, 42) {
-				assert_eq!(err, "Failed to find a path to the given destination");
-			} else { panic!(); }
+	#[test]
+	fn route_binding_amount_limit_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node2 and our_id -3- node3 -4- node2, where 2 and 4 are too small
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node3, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_maximum_msat = Some(500);
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(4, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_maximum_msat = Some(800);
+
+		let mut diagnostics = RouteDiagnostics::default();
+		assert!(router.get_route_with_diagnostics(&node2, None, &Vec::new(), 1000, 42, &RouteParameters::default(), &mut diagnostics).is_err());
+		assert_eq!(diagnostics.binding_amount_limit, Some(RouteAmountLimit::AmountExceedsCapacity { short_channel_id: ShortChannelId(4), htlc_maximum_msat: 800, amount_msat: 1000 }));
+
+		// Without any too-small channels, a too-large minimum is reported
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_maximum_msat = None;
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(4, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_maximum_msat = None;
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_minimum_msat = 2000;
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(4, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_minimum_msat = 3000;
+		let mut diagnostics = RouteDiagnostics::default();
+		assert!(router.get_route_with_diagnostics(&node2, None, &Vec::new(), 1000, 42, &RouteParameters::default(), &mut diagnostics).is_err());
+		assert_eq!(diagnostics.binding_amount_limit, Some(RouteAmountLimit::AmountBelowMinimum { short_channel_id: ShortChannelId(2), htlc_minimum_msat: 2000, amount_msat: 1000 }));
+	}
+
+	#[test]
+	fn route_inbound_fee_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		let node4 = get_test_node_id(&secp_ctx, 5);
+
+		// our_id -1- node1 -2- node2 -3- node3 costs 200 msat, our_id -4- node4 -5- node3 costs 150
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node2, 0, 100, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node4, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&node4, 0, 150, 0), get_test_directional_info(&node3, 0, 0, 0));
+
+		let route = router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].pubkey, node4);
+
+		// A positive inbound fee makes the cheaper path more expensive
+		router.set_inbound_fee(ShortChannelId(4), &node4, 100, 0);
+		let route = router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 3);
+		assert_eq!(route.hops[0].pubkey, node1);
+
+		// A discount is taken from the discounting node's fee, but never below zero
+		router.set_inbound_fee(ShortChannelId(4), &node4, 0, 0);
+		router.set_inbound_fee(ShortChannelId(2), &node2, -1000, 0);
+		let route = router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 3);
+		assert_eq!(route.hops[0].pubkey, node1);
+		assert_eq!(route.hops[0].fee_msat, 100);
+		assert_eq!(route.hops[1].pubkey, node2);
+		assert_eq!(route.hops[1].fee_msat, 0);
+		assert_eq!(route.hops[2].fee_msat, 1000);
+
+		router.set_inbound_fee(ShortChannelId(2), &node2, -40, 0);
+		let route = router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].pubkey, node4);
+		router.set_inbound_fee(ShortChannelId(2), &node2, -60, 0);
+		let route = router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].pubkey, node1);
+		assert_eq!(route.hops[1].fee_msat, 40);
+	}
+
+	#[test]
+	fn route_inbound_discount_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_c = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node_c -2- target costs 50 msat, our_id -1- node_c -3- node_b -4- target costs
+		// 100 msat, less node_b's discount for HTLCs received over channel 3
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_c, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_c, 0, 50, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_c, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		router.set_inbound_fee(ShortChannelId(3), &node_b, -100, 0);
+
+		// The discount only counts against node_c's (zero) fee for channel 3, so that no path costs
+		// less than the path it extends, and the route via node_b costs 100 msat in the search.
+		let route = router.get_route(&target, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>(), vec![1, 2]);
+		assert_eq!(route.total_fees_msat(), 50);
+
+		// Without channel 2, the route via node_b is found, with the discount applied to its fees
+		let params = RouteParameters { excluded_channels: vec![ShortChannelId(2)], ..RouteParameters::default() };
+		let route = router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>(), vec![1, 3, 4]);
+		assert_eq!(route.total_fees_msat(), 0);
+	}
+
+	#[test]
+	fn route_hint_cltv_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node2, with node2 -3- node3 only known via a hint
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 0, 0), get_test_directional_info(&node1, 10, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 20, 0, 0), get_test_directional_info(&node2, 20, 0, 0));
+		let last_hops = vec![RouteHint {
+			src_node_id: node2,
+			short_channel_id: ShortChannelId(3),
+			fee_base_msat: 100,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: 30,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}];
+
+		// Each hop's delta is the one required by the node it goes to, ending with the final CLTV
+		let route = router.get_route(&node3, None, &last_hops, 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 3);
+		assert_eq!(route.hops.iter().map(|hop| hop.cltv_expiry_delta).collect::<Vec<_>>(), vec![20, 30, 42]);
+		assert_eq!(route.hops[1].fee_msat, 100);
+
+		// The same holds if the hint starts at one of our first_hops
+		let our_chans = vec![get_test_first_hop(&node2, 42, 0)];
+		let route = router.get_route(&node3, Some(&our_chans), &last_hops, 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].short_channel_id, 42);
+		assert_eq!(route.hops[0].fee_msat, 100);
+		assert_eq!(route.hops[0].cltv_expiry_delta, 30);
+		assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+
+		// The hint's delta counts towards the maximum total CLTV delta
+		let params = RouteParameters { current_height: 100, max_cltv_expiry_height: 100 + 20 + 30 + 41, ..RouteParameters::default() };
+		assert!(router.get_route_with_params(&node3, None, &last_hops, 1000, 42, &params).is_err());
+		assert!(router.get_route_with_params(&node3, Some(&our_chans), &last_hops, 1000, 42, &params).is_ok());
+		let params = RouteParameters { current_height: 100, max_cltv_expiry_height: 100 + 30 + 41, ..RouteParameters::default() };
+		assert!(router.get_route_with_params(&node3, Some(&our_chans), &last_hops, 1000, 42, &params).is_err());
+	}
+
+	#[test]
+	fn route_weighted_objective_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node3 costs 100 msat and 144 blocks, while
+		// our_id -3- node2 -4- node3 costs 1000 msat and 6 blocks
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 144, 100, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 6, 1000, 0), get_test_directional_info(&node3, 0, 0, 0));
+
+		let route_via = |fee_weight, cltv_weight| {
+			get_test_route(&router, &node3, &RouteParameters { objective: RouteObjective::Weighted { fee_weight, cltv_weight }, ..RouteParameters::default() }).unwrap().hops[0].pubkey
+		};
+
+		// Pure fee matches the default objective
+		assert_eq!(router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap().hops[0].pubkey, node1);
+		assert_eq!(route_via(1, 0), node1);
+
+		// Pure CLTV picks the shorter expiry regardless of fees
+		assert_eq!(route_via(0, 1), node2);
+
+		// 100 + 186 * 6 < 1000 + 48 * 6, but 100 + 186 * 7 > 1000 + 48 * 7
+		assert_eq!(route_via(1, 6), node1);
+		assert_eq!(route_via(1, 7), node2);
+
+		// With no weight on either, ties are broken by fee
+		assert_eq!(route_via(0, 0), node1);
+	}
+
+	#[test]
+	fn route_success_probability_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		let node4 = get_test_node_id(&secp_ctx, 5);
+
+		// our_id -1- node1 -2- node3 costs 100 msat, our_id -3- node2 -4- node4 -5- node3 costs 200
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 0, 100, 0), get_test_directional_info(&node4, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&node4, 0, 100, 0), get_test_directional_info(&node3, 0, 0, 0));
+
+		let mut params = RouteParameters::default();
+		params.objective = RouteObjective::HighestSuccessProbability { default_success_probability_ppm: 500_000 };
+
+		// With no estimates, the route with fewer hops is more likely to succeed
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].pubkey, node1);
+
+		// 0.9 * 0.9 beats 0.5 (our own channels don't count)...
+		router.set_channel_success_probability(ShortChannelId(4), Some(900_000));
+		router.set_channel_success_probability(ShortChannelId(5), Some(900_000));
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops.len(), 3);
+		assert_eq!(route.hops[0].pubkey, node2);
+		// ...though fees are still all that matter for the default objective
+		let route = router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].pubkey, node1);
+
+		// 0.9 * 0.9 doesn't beat 0.9
+		router.set_channel_success_probability(ShortChannelId(2), Some(900_000));
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].pubkey, node1);
+
+		// Routes which are equally likely to succeed are picked between by fee
+		router.set_channel_success_probability(ShortChannelId(2), Some(810_000));
+		router.set_channel_success_probability(ShortChannelId(4), Some(1_000_000));
+		router.set_channel_success_probability(ShortChannelId(5), Some(810_000));
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].pubkey, node1);
+		router.set_channel_success_probability(ShortChannelId(5), Some(820_000));
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].pubkey, node2);
+
+		router.set_channel_success_probability(ShortChannelId(2), None);
+		assert!(router.network_map.read().unwrap().channel_success_probabilities.get(&2).is_none());
+	}
+
+	#[test]
+	fn route_policy_change_penalty_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node3, with a more expensive node1 -3- node2 -4- node3 alternative
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 5, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node1, 0, 10, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 0, 10, 0), get_test_directional_info(&node3, 0, 0, 0));
+
+		// node1 flips channel 2's fee back and forth, with a heartbeat in between. The first update
+		// we see for a direction isn't a change.
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+		for &(timestamp, fee_base_msat) in [(now - 40, 5), (now - 30, 6), (now - 20, 6), (now - 10, 5)].iter() {
+			router.handle_channel_update(&get_signed_channel_update(&secp_ctx, 2, 2, 0, timestamp, fee_base_msat, Vec::new())).unwrap();
 		}
+		assert_eq!(router.network_map.read().unwrap().channel_policy_changes.get(&2), Some(&(now - 10, 2)));
 
-		{ // If we specify a channel to node8, that overrides our local channel view and that gets used
-			let our_chans = vec![channelmanager::ChannelDetails {
-				channel_id: [0; 32],
-				short_channel_id: Some(42),
-				remote_network_id: node8.clone(),
-				counterparty_features: InitFeatures::from_le_bytes(vec![0b11]),
-				channel_value_satoshis: 0,
-				user_id: 0,
-				outbound_capacity_msat: 0,
-				inbound_capacity_msat: 0,
-				is_live: true,
-			}];
-			let route = router.get_route(&node3, Some(&our_chans), &Vec::new(), 100, 42).unwrap();
-			assert_eq!(route.hops.len(), 2);
+		let get_route_scids = |params: &RouteParameters| {
+			router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, params).unwrap()
+				.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>()
+		};
+		assert_eq!(get_route_scids(&RouteParameters::default()), vec![1, 2]);
 
-			assert_eq!(route.hops[0].pubkey, node8);
-			assert_eq!(route.hops[0].short_channel_id, 42);
-			assert_eq!(route.hops[0].fee_msat, 200);
-			assert_eq!(route.hops[0].cltv_expiry_delta, (13 << 8) | 1);
-			assert_eq!(route.hops[0].node_features.le_flags(), &vec![0b11]); // it should also override our view of their features
-			assert_eq!(route.hops[0].channel_features.le_flags(), &Vec::new()); // No feature flags will meet the relevant-to-channel conversion
+		// A penalty of 8 msat per change outweighs the 15 msat we save by using channel 2...
+		let params = RouteParameters { channel_policy_change_penalty_msat: 8, max_channel_policy_change_penalty_msat: 100, ..RouteParameters::default() };
+		assert_eq!(get_route_scids(&params), vec![1, 3, 4]);
 
-			assert_eq!(route.hops[1].pubkey, node3);
-			assert_eq!(route.hops[1].short_channel_id, 13);
-			assert_eq!(route.hops[1].fee_msat, 100);
-			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
-			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
-			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(13));
+		// ...but not when capped below that.
+		let params = RouteParameters { channel_policy_change_penalty_msat: 8, max_channel_policy_change_penalty_msat: 14, ..RouteParameters::default() };
+		assert_eq!(get_route_scids(&params), vec![1, 2]);
+
+		// Once changes stop for a day, the count starts over.
+		let timestamp = now - 10 + 24 * 60 * 60 + 1;
+		router.network_map.write().unwrap().record_policy_change(2, timestamp);
+		assert_eq!(router.network_map.read().unwrap().channel_policy_changes.get(&2), Some(&(timestamp, 1)));
+
+		// Closing the channel forgets its changes
+		router.handle_htlc_fail_channel_update(&msgs::HTLCFailChannelUpdate::ChannelClosed { short_channel_id: 2, is_permanent: true });
+		assert!(router.network_map.read().unwrap().channel_policy_changes.get(&2).is_none());
+	}
+
+	#[test]
+	fn route_capacity_estimator_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node3, with a more expensive node1 -3- node2 -4- node3 alternative
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 10, 0, 0), get_test_directional_info(&node3, 10, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node1, 10, 0, 0), get_test_directional_info(&node2, 10, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 10, 0, 0), get_test_directional_info(&node3, 10, 0, 0));
+
+		let route = router.get_route_with_capacity_estimator(&node3, None, &Vec::new(), 100_000, 42, &RouteParameters::default(), |_, _| None).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2]);
+
+		// An estimate for the other direction of channel 2 doesn't matter...
+		let route = router.get_route_with_capacity_estimator(&node3, None, &Vec::new(), 100_000, 42, &RouteParameters::default(),
+			|short_channel_id, from_node_one| if short_channel_id == 2 && !from_node_one { Some(0) } else { None }).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2]);
+
+		// ...but if we expect node1 can't send 100 sat over it, we route around it.
+		let route = router.get_route_with_capacity_estimator(&node3, None, &Vec::new(), 100_000, 42, &RouteParameters::default(),
+			|short_channel_id, from_node_one| if short_channel_id == 2 && from_node_one { Some(99) } else { None }).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 3, 4]);
+
+		// Our view of the network is unchanged
+		let route = router.get_route(&node3, None, &Vec::new(), 100_000, 42).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2]);
+	}
+
+	#[test]
+	fn route_excluded_channels_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node3 is cheaper than our_id -3- node2 -4- node3
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 0, 200, 0), get_test_directional_info(&node3, 0, 0, 0));
+
+		// Excluding our own channel to node1 avoids it for that search only
+		let mut params = RouteParameters::default();
+		params.excluded_channels = vec![ShortChannelId(1)];
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 3);
+		assert!(router.get_channel_info(ShortChannelId(1)).unwrap().is_usable());
+		let route = router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 1);
+
+		params.excluded_channels = vec![ShortChannelId(1), ShortChannelId(3)];
+		assert!(router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).is_err());
+		params.excluded_channels = vec![ShortChannelId(4)];
+		assert!(router.get_route_with_params(&node2, None, &Vec::new(), 1000, 42, &params).is_ok());
+	}
+
+	#[test]
+	fn route_locally_disabled_channels_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		let target = get_test_node_id(&secp_ctx, 5);
+
+		// our_id -1- node1 -2- node3 is cheaper than our_id -3- node2 -4- node3
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 0, 200, 0), get_test_directional_info(&node3, 0, 0, 0));
+
+		let route_scids = |route: &Route| route.hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>();
+		let params = RouteParameters { locally_disabled_channels: vec![ShortChannelId(1)], ..RouteParameters::default() };
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route_scids(&route), vec![3, 4]);
+		assert!(router.get_channel_info(ShortChannelId(1)).unwrap().is_usable());
+		let route = router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route_scids(&route), vec![1, 2]);
+
+		// Our first_hops are skipped too, as are channels from last_hops
+		let our_chans = vec![get_test_first_hop(&node1, 5, 250_000_000), get_test_first_hop(&node2, 6, 250_000_000)];
+		let params = RouteParameters { locally_disabled_channels: vec![ShortChannelId(5)], ..RouteParameters::default() };
+		let route = router.get_route_with_params(&node3, Some(&our_chans), &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route_scids(&route), vec![6, 4]);
+		let last_hops = vec![RouteHint {
+			src_node_id: node3,
+			short_channel_id: ShortChannelId(7),
+			fee_base_msat: 0,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: 0,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}];
+		assert!(router.get_route(&target, None, &last_hops, 1000, 42).is_ok());
+		let params = RouteParameters { locally_disabled_channels: vec![ShortChannelId(7)], ..RouteParameters::default() };
+		assert!(router.get_route_with_params(&target, None, &last_hops, 1000, 42, &params).is_err());
+
+		// They combine with excluded_channels
+		let params = RouteParameters { excluded_channels: vec![ShortChannelId(3)], locally_disabled_channels: vec![ShortChannelId(1)], ..RouteParameters::default() };
+		assert!(router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).is_err());
+	}
+
+	#[test]
+	fn route_min_channel_capacity_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node3 is cheaper than our_id -3- node2 -4- node3
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 0, 200, 0), get_test_directional_info(&node3, 0, 0, 0));
+		{
+			let mut network = router.network_map.write().unwrap();
+			network.channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = Some(10_000);
+			network.channels.get_mut(&NetworkMap::get_key(4, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = Some(1_000_000);
 		}
 
-		{ // Re-enable channels 4 and 12 by wiping the unknown feature bits
+		let mut params = RouteParameters::default();
+		params.min_channel_capacity_sat = 10_000;
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 2);
+
+		// Channel 2 is too small, even though we'd only send a small amount over it, while channels
+		// of unknown capacity are still used
+		params.min_channel_capacity_sat = 10_001;
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 3);
+		assert_eq!(route.hops[1].short_channel_id, 4);
+
+		params.min_channel_capacity_sat = 1_000_001;
+		assert!(router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).is_err());
+	}
+
+	#[test]
+	fn node_total_capacity_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node2, 0, 0, 0), get_test_directional_info(&our_id, 0, 0, 0));
+		{
 			let mut network = router.network_map.write().unwrap();
-			network.channels.get_mut(&NetworkMap::get_key(4, zero_hash.clone())).unwrap().features.clear_require_unknown_bits();
-			network.channels.get_mut(&NetworkMap::get_key(12, zero_hash.clone())).unwrap().features.clear_require_unknown_bits();
+			network.channels.get_mut(&NetworkMap::get_key(1, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = Some(10_000);
+			network.channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = Some(250_000);
 		}
 
-		{ // Disable nodes 1, 2, and 8 by requiring unknown feature bits
+		// Channel 3's capacity is unknown, so doesn't count towards either of its nodes' totals
+		assert_eq!(router.node_total_capacity_sats(&our_id), Some(10_000));
+		assert_eq!(router.node_total_capacity_sats(&node1), Some(260_000));
+		assert_eq!(router.node_total_capacity_sats(&node2), Some(250_000));
+		assert_eq!(router.node_total_capacity_sats(&node3), None);
+	}
+
+	#[test]
+	fn break_even_fee_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let sender = get_test_node_id(&secp_ctx, 2);
+		let peer = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+		let competitor = get_test_node_id(&secp_ctx, 5);
+		let behind_peer = get_test_node_id(&secp_ctx, 6);
+
+		add_test_channel(&router, 1, get_test_directional_info(&sender, 0, 0, 0), get_test_directional_info(&our_id, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&our_id, 0, 5000, 0), get_test_directional_info(&peer, 0, 0, 0));
+		let mut target_to_peer = get_test_directional_info(&target, 0, 0, 0);
+		target_to_peer.enabled = false;
+		add_test_channel(&router, 3, get_test_directional_info(&peer, 0, 100, 1000), target_to_peer);
+		add_test_channel(&router, 4, get_test_directional_info(&sender, 0, 0, 0), get_test_directional_info(&competitor, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&competitor, 0, 1000, 2000), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 6, get_test_directional_info(&peer, 0, 0, 0), get_test_directional_info(&behind_peer, 0, 0, 0));
+		{
+			// add_test_channel doesn't update the lowest inbound fees of our existing node, which the
+			// search uses to estimate the remaining cost of paths through us
 			let mut network = router.network_map.write().unwrap();
-			network.nodes.get_mut(&node1).unwrap().features.set_require_unknown_bits();
-			network.nodes.get_mut(&node2).unwrap().features.set_require_unknown_bits();
-			network.nodes.get_mut(&node8).unwrap().features.set_require_unknown_bits();
+			let our_node = network.nodes.get_mut(&our_id).unwrap();
+			our_node.lowest_inbound_channel_fee_base_msat = 0;
+			our_node.lowest_inbound_channel_fee_proportional_millionths = 0;
 		}
+		let params = RouteParameters::default();
 
-		{ // If all nodes require some features we don't understand, route should fail
-			if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route(&node3, None, &Vec::new(), 100, 42) {
-				assert_eq!(err, "Failed to find a path to the given destination");
+		// The competitor charges 1000 + 2000 to forward 1_000_000 msat, while our peer charges 100 +
+		// 1000, leaving us 3000 - 1100 - 1 to be strictly cheaper.
+		assert_eq!(router.get_break_even_fee_msat(&sender, &target, ShortChannelId(2), 1_000_000, &params).unwrap(), Some(1899));
+		let route_via = |our_fee_base_msat: u32| {
+			router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.fee_base_msat = our_fee_base_msat;
+			let network = router.network_map.read().unwrap();
+			router.find_route(&network, &sender, &target, None, &[], 1_000_000, 0, &params, false, None, None).unwrap().hops[0].short_channel_id
+		};
+		assert_eq!(route_via(1899), 1);
+		assert_eq!(route_via(1901), 4);
+
+		// No competing route reaches a node only we can get to
+		assert_eq!(router.get_break_even_fee_msat(&sender, &behind_peer, ShortChannelId(2), 1_000_000, &params).unwrap(), None);
+
+		// Nor can we undercut a competitor whose fee is below our peer's
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(5, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.fee_proportional_millionths = 0;
+		assert!(router.get_break_even_fee_msat(&sender, &target, ShortChannelId(2), 1_000_000, &params).is_err());
+
+		assert!(router.get_break_even_fee_msat(&our_id, &target, ShortChannelId(2), 1_000_000, &params).is_err());
+		assert!(router.get_break_even_fee_msat(&peer, &target, ShortChannelId(2), 1_000_000, &params).is_err());
+		assert!(router.get_break_even_fee_msat(&sender, &target, ShortChannelId(5), 1_000_000, &params).is_err());
+
+		// An arbitrarily large fee on our peer's channel fails rather than overflowing
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(3, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.fee_proportional_millionths = u32::max_value();
+		assert!(router.get_break_even_fee_msat(&sender, &target, ShortChannelId(2), 1_000_000, &params).is_err());
+		assert!(router.get_break_even_fee_msat(&sender, &target, ShortChannelId(2), 5_000_000_000, &params).is_err());
+	}
+
+	#[test]
+	fn first_hops_override_graph_policy_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let target = get_test_node_id(&secp_ctx, 3);
+
+		// The graph's view of our channel 1 is disabled and has an unmeetable htlc_minimum_msat
+		let mut stale_policy = get_test_directional_info(&our_id, 1000, 0, 0);
+		stale_policy.enabled = false;
+		stale_policy.htlc_minimum_msat = 1_000_000_000;
+		add_test_channel(&router, 1, stale_policy, get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 20, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		assert!(router.get_route(&target, None, &Vec::new(), 1000, 42).is_err());
+
+		let our_chans = vec![get_test_first_hop(&node_a, 1, 0)];
+		let route = router.get_route(&target, Some(&our_chans), &Vec::new(), 1000, 42).unwrap();
+		assert_route_hops(&route, &[(node_a, 1, 100, 20), (target, 2, 1000, 42)]);
+
+		// A first_hops channel which isn't in the graph at all is used just the same
+		let mut our_chans = our_chans;
+		our_chans[0].short_channel_id = Some(7);
+		let route = router.get_route(&target, Some(&our_chans), &Vec::new(), 1000, 42).unwrap();
+		assert_route_hops(&route, &[(node_a, 7, 100, 20), (target, 2, 1000, 42)]);
+	}
+
+	#[test]
+	fn first_hops_only_starting_channels_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+
+		// Our public channel 1 to node_a is cheaper than our private channel 42 to node_b
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_a, 0, 200, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 300, 0), get_test_directional_info(&target, 0, 0, 0));
+		let our_chans = vec![get_test_first_hop(&node_b, 42, 0)];
+		let params = RouteParameters::default();
+
+		let route = router.get_route(&target, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 1);
+		let route = router.get_route(&target, Some(&our_chans), &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 42);
+
+		// Replacing the path after a failure doesn't keep a first hop which isn't in first_hops...
+		let previous_route = router.get_route(&target, None, &Vec::new(), 1000, 42).unwrap();
+		let route = router.get_route_avoiding_failed_channel(&previous_route, ShortChannelId(2), None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 3]);
+		let route = router.get_route_avoiding_failed_channel(&previous_route, ShortChannelId(2), Some(&our_chans), &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![42, 4]);
+
+		// ...while one which is may be kept
+		let previous_route = Route::from_hops(vec![RouteHop::new(node_a, ShortChannelId(42), 100, 0), RouteHop::new(target, ShortChannelId(2), 1000, 42)], 1000, 42).unwrap();
+		let our_chans_to_a = vec![get_test_first_hop(&node_a, 42, 0)];
+		let route = router.get_route_avoiding_failed_channel(&previous_route, ShortChannelId(2), Some(&our_chans_to_a), &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![42, 3]);
+
+		let routes = router.k_shortest_paths(&target, Some(&our_chans), &Vec::new(), 1000, 42, &params, 10).unwrap();
+		assert_eq!(routes.len(), 1);
+		assert_eq!(routes[0].hops[0].short_channel_id, 42);
+	}
+
+	#[test]
+	fn k_shortest_paths_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let node_c = get_test_node_id(&secp_ctx, 4);
+		let target = get_test_node_id(&secp_ctx, 5);
+
+		// our_id -1- node_a -2- target costs 100 msat, our_id -3- node_b -4- target 200,
+		// our_id -1- node_a -5- node_b -4- target 210 and our_id -6- node_c -7- target 300
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		let mut chan_2 = get_test_directional_info(&node_a, 0, 100, 0);
+		chan_2.htlc_maximum_msat = Some(1500);
+		add_test_channel(&router, 2, chan_2, get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 200, 0), get_test_directional_info(&target, 0, 0, 0));
+		let mut chan_5_from_b = get_test_directional_info(&node_b, 0, 0, 0);
+		chan_5_from_b.enabled = false;
+		add_test_channel(&router, 5, get_test_directional_info(&node_a, 0, 10, 0), chan_5_from_b);
+		add_test_channel(&router, 6, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_c, 0, 0, 0));
+		add_test_channel(&router, 7, get_test_directional_info(&node_c, 0, 300, 0), get_test_directional_info(&target, 0, 0, 0));
+
+		let params = RouteParameters::default();
+		let channels = |routes: &Vec<Route>| routes.iter().map(|route| route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>()).collect::<Vec<_>>();
+
+		let routes = router.k_shortest_paths(&target, None, &Vec::new(), 1000, 42, &params, 3).unwrap();
+		assert_eq!(channels(&routes), vec![vec![1, 2], vec![3, 4], vec![1, 5, 4]]);
+		assert_eq!(routes.iter().map(|route| route.total_fees_msat()).collect::<Vec<_>>(), vec![100, 200, 210]);
+		assert!(routes[0] == router.get_route(&target, None, &Vec::new(), 1000, 42).unwrap());
+		for route in routes.iter() {
+			assert_eq!(route.validate_against(&router, None, &[]), Ok(()));
+		}
+
+		// We return fewer routes if there aren't k, and each must be able to carry the amount
+		let routes = router.k_shortest_paths(&target, None, &Vec::new(), 1000, 42, &params, 10).unwrap();
+		assert_eq!(channels(&routes), vec![vec![1, 2], vec![3, 4], vec![1, 5, 4], vec![6, 7]]);
+		let routes = router.k_shortest_paths(&target, None, &Vec::new(), 2000, 42, &params, 10).unwrap();
+		assert_eq!(channels(&routes), vec![vec![3, 4], vec![1, 5, 4], vec![6, 7]]);
+		for route in routes.iter() {
+			assert_eq!(route.validate_against(&router, None, &[]), Ok(()));
+		}
+
+		assert!(router.k_shortest_paths(&get_test_node_id(&secp_ctx, 6), None, &Vec::new(), 1000, 42, &params, 3).is_err());
+		assert!(router.k_shortest_paths(&target, None, &Vec::new(), 1000, 42, &params, 0).unwrap().is_empty());
+	}
+
+	#[test]
+	fn k_shortest_paths_order_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node_a -2- target and our_id -3- node_b -4- target both cost 100 msat, but a
+		// penalty on node_a makes us find the route via node_b first
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		let params = RouteParameters { node_penalties: vec![(node_a, 50)], ..RouteParameters::default() };
+		assert_eq!(router.get_route_with_params(&target, None, &Vec::new(), 1000, 42, &params).unwrap().hops[0].short_channel_id, 3);
+
+		// Routes of equal fee and length are returned in order of their short_channel_ids
+		let routes = router.k_shortest_paths(&target, None, &Vec::new(), 1000, 42, &params, 2).unwrap();
+		assert_eq!(routes.iter().map(|route| route.hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>()).collect::<Vec<_>>(), vec![vec![1, 2], vec![3, 4]]);
+	}
+
+	#[test]
+	fn route_via_channels_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node_a -2- target is cheaper than our_id -3- node_b -4- target, with a cheap
+		// channel 5 between node_a and node_b
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 100, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 200, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&node_a, 0, 10, 0), get_test_directional_info(&node_b, 0, 20, 0));
+		let route_channels = |required_short_channel_ids: &[ShortChannelId]| {
+			let route = router.get_route_via_channels(&target, None, &Vec::new(), 1000, 42, &RouteParameters::default(), required_short_channel_ids).unwrap();
+			(route.hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>(), route.total_fees_msat())
+		};
+
+		// The cheapest route already uses one of the required channels
+		assert_eq!(route_channels(&[ShortChannelId(2), ShortChannelId(4)]), (vec![1, 2], 100));
+		assert_eq!(route_channels(&[ShortChannelId(4)]), (vec![3, 4], 200));
+		// Going via node_b and then back over channel 5 to node_a is cheaper than channel 4
+		assert_eq!(route_channels(&[ShortChannelId(3)]), (vec![3, 5, 2], 120));
+		// Channel 5 is cheapest to use from node_b to node_a
+		assert_eq!(route_channels(&[ShortChannelId(5)]), (vec![3, 5, 2], 120));
+		let route = router.get_route_via_channels(&target, None, &Vec::new(), 1000, 42, &RouteParameters::default(), &[ShortChannelId(5)]).unwrap();
+		assert_route_hops(&route, &[(node_b, 3, 20, 0), (node_a, 5, 100, 0), (target, 2, 1000, 42)]);
+
+		// With first_hops, our own graph channels are replaced
+		let our_chans = vec![get_test_first_hop(&node_b, 42, 250_000_000)];
+		let route = router.get_route_via_channels(&target, Some(&our_chans), &Vec::new(), 1000, 42, &RouteParameters::default(), &[ShortChannelId(42)]).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![42, 5, 2]);
+		assert!(router.get_route_via_channels(&target, Some(&our_chans), &Vec::new(), 1000, 42, &RouteParameters::default(), &[ShortChannelId(1)]).is_err());
+
+		for required_short_channel_ids in [&[][..], &[ShortChannelId(99)][..]].iter() {
+			if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route_via_channels(&target, None, &Vec::new(), 1000, 42, &RouteParameters::default(), required_short_channel_ids) {
+				assert_eq!(err, "Failed to find a path to the given destination using any of the required channels");
 			} else { panic!(); }
 		}
+		let params = RouteParameters { excluded_channels: vec![ShortChannelId(5)], ..RouteParameters::default() };
+		assert!(router.get_route_via_channels(&target, None, &Vec::new(), 1000, 42, &params, &[ShortChannelId(5)]).is_err());
+	}
 
-		{ // If we specify a channel to node8, that overrides our local channel view and that gets used
-			let our_chans = vec![channelmanager::ChannelDetails {
-				channel_id: [0; 32],
-				short_channel_id: Some(42),
-				remote_network_id: node8.clone(),
-				counterparty_features: InitFeatures::from_le_bytes(vec![0b11]),
-				channel_value_satoshis: 0,
-				user_id: 0,
-				outbound_capacity_msat: 0,
-				inbound_capacity_msat: 0,
-				is_live: true,
-			}];
-			let route = router.get_route(&node3, Some(&our_chans), &Vec::new(), 100, 42).unwrap();
-			assert_eq!(route.hops.len(), 2);
+	#[test]
+	fn recommend_payment_paths_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+
+		// We reach target via node_a for 10 msat or via node_b for 20 msat
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 10, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 20, 0), get_test_directional_info(&target, 0, 0, 0));
+		let our_chans = vec![get_test_first_hop(&node_a, 1, 6000), get_test_first_hop(&node_b, 3, 4000)];
+		let recommend = |final_value_msat: u64| router.recommend_payment_paths(&target, &our_chans, &Vec::new(), final_value_msat, 42, &RouteParameters::default());
+
+		if let Ok(PaymentPathRecommendation::SinglePath { route, reason }) = recommend(1000) {
+			assert_route_hops(&route, &[(node_a, 1, 10, 0), (target, 2, 1000, 42)]);
+			assert_eq!(reason, PaymentPathReason::SplitUnnecessary);
+		} else { panic!(); }
+		// The payment and fees only just fit in channel 1
+		if let Ok(PaymentPathRecommendation::SinglePath { route, reason }) = recommend(5990) {
+			assert_route_hops(&route, &[(node_a, 1, 10, 0), (target, 2, 5990, 42)]);
+			assert_eq!(reason, PaymentPathReason::SplitUnnecessary);
+		} else { panic!(); }
+
+		// Channel 1 can carry 5990 msat after fees, and channel 3 the rest
+		if let Ok(PaymentPathRecommendation::MultiPath { routes, reason }) = recommend(5991) {
+			assert_eq!(routes.len(), 2);
+			assert_route_hops(&routes[0], &[(node_a, 1, 10, 0), (target, 2, 5990, 42)]);
+			assert_route_hops(&routes[1], &[(node_b, 3, 20, 0), (target, 4, 1, 42)]);
+			assert_eq!(reason, PaymentPathReason::SinglePathInfeasible);
+		} else { panic!(); }
+		if let Ok(PaymentPathRecommendation::MultiPath { routes, .. }) = recommend(9970) {
+			assert_route_hops(&routes[1], &[(node_b, 3, 20, 0), (target, 4, 3980, 42)]);
+		} else { panic!(); }
+
+		for final_value_msat in [9971, 20000].iter() {
+			if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = recommend(*final_value_msat) {
+				assert_eq!(err, "Failed to find a path to the given destination which our channels can carry, even when splitting the payment");
+			} else { panic!(); }
+		}
 
-			assert_eq!(route.hops[0].pubkey, node8);
-			assert_eq!(route.hops[0].short_channel_id, 42);
-			assert_eq!(route.hops[0].fee_msat, 200);
-			assert_eq!(route.hops[0].cltv_expiry_delta, (13 << 8) | 1);
-			assert_eq!(route.hops[0].node_features.le_flags(), &vec![0b11]); // it should also override our view of their features
-			assert_eq!(route.hops[0].channel_features.le_flags(), &Vec::new()); // No feature flags will meet the relevant-to-channel conversion
+		// Without a path onwards from node_b, the payment can't be split
+		let params = RouteParameters { excluded_channels: vec![ShortChannelId(4)], ..RouteParameters::default() };
+		if let Ok(PaymentPathRecommendation::SinglePath { reason, .. }) = router.recommend_payment_paths(&target, &our_chans, &Vec::new(), 5000, 42, &params) {
+			assert_eq!(reason, PaymentPathReason::SplitUnnecessary);
+		} else { panic!(); }
+		assert!(router.recommend_payment_paths(&target, &our_chans, &Vec::new(), 5991, 42, &params).is_err());
+		assert!(router.recommend_payment_paths(&target, &[], &Vec::new(), 1000, 42, &RouteParameters::default()).is_err());
+
+		// The largest share goes over channel 3, but the cheaper route over channel 1 comes first
+		let our_chans = vec![get_test_first_hop(&node_a, 1, 4000), get_test_first_hop(&node_b, 3, 6000)];
+		if let Ok(PaymentPathRecommendation::MultiPath { routes, .. }) = router.recommend_payment_paths(&target, &our_chans, &Vec::new(), 9000, 42, &RouteParameters::default()) {
+			assert_eq!(routes.len(), 2);
+			assert_route_hops(&routes[0], &[(node_a, 1, 10, 0), (target, 2, 3020, 42)]);
+			assert_route_hops(&routes[1], &[(node_b, 3, 20, 0), (target, 4, 5980, 42)]);
+		} else { panic!(); }
+	}
 
-			assert_eq!(route.hops[1].pubkey, node3);
-			assert_eq!(route.hops[1].short_channel_id, 13);
-			assert_eq!(route.hops[1].fee_msat, 100);
-			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
-			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
-			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(13));
+	#[test]
+	fn get_routes_batch_test() {
+		let (secp_ctx, _, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+
+		// We reach target via node_a for 10 msat or via node_b for 20 msat
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 10, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_b, 0, 20, 0), get_test_directional_info(&target, 0, 0, 0));
+		let first_hop = |short_channel_id: u64, remote_network_id: PublicKey, outbound_capacity_msat: u64, outbound_htlc_slots: u16| channelmanager::ChannelDetails { outbound_htlc_slots, ..get_test_first_hop(&remote_network_id, short_channel_id, outbound_capacity_msat) };
+		let our_chans = vec![first_hop(1, node_a, 5000, 483), first_hop(3, node_b, 10000, 483)];
+		let first_hop_scids = |routes: &[Result<Route, LightningError>]| {
+			routes.iter().map(|route| route.as_ref().ok().map(|route| route.hops[0].short_channel_id.0)).collect::<Vec<_>>()
+		};
+
+		// Channel 1 is cheaper, but after the first payment only has 1990 msat left, so the next two
+		// go over channel 3, leaving 1960 msat there
+		let requests = [(target, 3000, 42), (target, 3000, 42), (target, 5000, 42), (target, 1980, 42), (target, 1940, 42)];
+		let routes = router.get_routes_batch(&requests, &our_chans, &Vec::new(), &RouteParameters::default());
+		assert_eq!(first_hop_scids(&routes), vec![Some(1), Some(3), Some(3), Some(1), Some(3)]);
+		assert_route_hops(routes[0].as_ref().ok().unwrap(), &[(node_a, 1, 10, 0), (target, 2, 3000, 42)]);
+		assert_route_hops(routes[2].as_ref().ok().unwrap(), &[(node_b, 3, 20, 0), (target, 4, 5000, 42)]);
+
+		// 4990 msat fits channel 1 but its fee doesn't, while a failed request reserves nothing
+		let routes = router.get_routes_batch(&[(target, 4991, 42), (target, 20000, 42), (target, 4990, 42), (target, 9980, 42)], &our_chans, &Vec::new(), &RouteParameters::default());
+		assert_eq!(first_hop_scids(&routes), vec![Some(3), None, Some(1), None]);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = routes[1] {
+			assert_eq!(err, "Cannot route when there are no outbound routes away from us");
+		} else { panic!(); }
+
+		// Searches reuse each other's state, but with enough capacity each route is the same as if
+		// it had been searched for on its own
+		let large_chans = vec![first_hop(1, node_a, 1_000_000, 483), first_hop(3, node_b, 1_000_000, 483)];
+		let requests = [(target, 3000, 42), (node_b, 1000, 42), (target, 5000, 42), (node_a, 2000, 42)];
+		let routes = router.get_routes_batch(&requests, &large_chans, &Vec::new(), &RouteParameters::default());
+		for (route, &(ref request_target, value_msat, final_cltv)) in routes.iter().zip(requests.iter()) {
+			assert!(*route.as_ref().unwrap() == router.get_route(request_target, Some(&large_chans), &Vec::new(), value_msat, final_cltv).unwrap());
 		}
 
-		{ // Re-enable nodes 1, 2, and 8
-			let mut network = router.network_map.write().unwrap();
-			network.nodes.get_mut(&node1).unwrap().features.clear_require_unknown_bits();
-			network.nodes.get_mut(&node2).unwrap().features.clear_require_unknown_bits();
-			network.nodes.get_mut(&node8).unwrap().features.clear_require_unknown_bits();
+		// An HTLC slot is reserved on the first hop of each route
+		let our_chans = vec![first_hop(1, node_a, 5000, 1), first_hop(3, node_b, 10000, 1)];
+		let routes = router.get_routes_batch(&[(target, 10, 42), (target, 10, 42), (target, 10, 42)], &our_chans, &Vec::new(), &RouteParameters::default());
+		assert_eq!(first_hop_scids(&routes), vec![Some(1), Some(3), None]);
+	}
+
+	#[test]
+	fn route_unknown_capacity_penalty_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node3 is cheaper than our_id -3- node2 -4- node3, but channel 2's
+		// capacity is unknown (as is that of our own channels, which don't matter)
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 0, 200, 0), get_test_directional_info(&node3, 0, 0, 0));
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(4, Sha256dHash::hash(&[0; 32]))).unwrap().capacity_sats = Some(1_000_000);
+
+		let mut params = RouteParameters { unknown_capacity_penalty_msat: 100, ..RouteParameters::default() };
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 1);
+
+		params.unknown_capacity_penalty_msat = 101;
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 3);
+		assert_eq!(route.total_fees_msat(), 200);
+
+		// Unknown-capacity channels are still used if they're all we have
+		params.excluded_channels = vec![ShortChannelId(4)];
+		let route = router.get_route_with_params(&node3, None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 2);
+		assert_eq!(route.total_fees_msat(), 100);
+	}
+
+	#[test]
+	fn route_avoiding_failed_channel_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let node_c = get_test_node_id(&secp_ctx, 4);
+		let node_d = get_test_node_id(&secp_ctx, 5);
+		let target = get_test_node_id(&secp_ctx, 6);
+
+		// our_id -1- node_a -2- node_b -3- target, with alternatives node_b -8- node_c -5- target,
+		// node_a -4- node_c and our_id -6- node_d -7- target
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 10, 0, 100_000), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 15, 10, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_a, 0, 500, 0), get_test_directional_info(&node_c, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&node_c, 30, 30, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 6, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_d, 0, 0, 0));
+		add_test_channel(&router, 7, get_test_directional_info(&node_d, 0, 1000, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 8, get_test_directional_info(&node_b, 20, 20, 0), get_test_directional_info(&node_c, 0, 0, 0));
+
+		let params = RouteParameters::default();
+		let route = router.get_route(&target, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 3]);
+		assert_eq!(route.hops[0].fee_msat, 101);
+
+		// node_b -3- target failed, so we only search from node_b again and node_a's fee is updated
+		// for the larger amount it forwards
+		let new_route = router.get_route_avoiding_failed_channel(&route, ShortChannelId(3), None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(new_route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 8, 5]);
+		assert_eq!(new_route.hops.iter().map(|hop| hop.fee_msat).collect::<Vec<_>>(), vec![105, 20, 30, 1000]);
+		assert_eq!(new_route.hops.iter().map(|hop| hop.cltv_expiry_delta).collect::<Vec<_>>(), vec![10, 20, 30, 42]);
+		assert!(Route::from_hops(new_route.hops.clone(), 1000, 42).is_ok());
+
+		// If a kept channel can no longer carry the amount, we fall back to a full search
+		router.network_map.write().unwrap().channels.get_mut(&2).unwrap().one_to_two.htlc_maximum_msat = Some(1040);
+		let new_route = router.get_route_avoiding_failed_channel(&route, ShortChannelId(3), None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(new_route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 4, 5]);
+		router.network_map.write().unwrap().channels.get_mut(&2).unwrap().one_to_two.htlc_maximum_msat = None;
+
+		// As we do if our own channel failed
+		let new_route = router.get_route_avoiding_failed_channel(&route, ShortChannelId(1), None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(new_route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![6, 7]);
+
+		// Or if the new path from the forwarding node would go back through a kept node
+		router.network_map.write().unwrap().channels.get_mut(&8).unwrap().one_to_two.enabled = false;
+		let new_route = router.get_route_avoiding_failed_channel(&route, ShortChannelId(3), None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(new_route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 4, 5]);
+
+		// Channels which aren't on the previous route are simply avoided
+		let new_route = router.get_route_avoiding_failed_channel(&route, ShortChannelId(7), None, &Vec::new(), 1000, 42, &params).unwrap();
+		assert_eq!(new_route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 3]);
+	}
+
+	#[test]
+	fn path_fee_estimate_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node2 -3- node3, where channel 2 can only carry 500 msat
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 1000, 1000), get_test_directional_info(&node1, 0, 0, 0));
+		let mut small_info = get_test_directional_info(&node1, 20, 100, 1000);
+		small_info.htlc_maximum_msat = Some(500);
+		add_test_channel(&router, 2, small_info, get_test_directional_info(&node2, 0, 0, 0));
+		let mut large_info = get_test_directional_info(&node2, 30, 200, 2000);
+		large_info.htlc_minimum_msat = 1_000_000;
+		add_test_channel(&router, 3, large_info, get_test_directional_info(&node3, 0, 0, 0));
+
+		assert!(router.get_route(&node3, None, &Vec::new(), 1000, 42).is_err());
+		let estimate = router.get_path_fee_estimate(&node3, 1000).unwrap();
+		assert_eq!(estimate, PathFeeEstimate {
+			fee_base_msat: 300,
+			fee_proportional_millionths: 3000,
+			cltv_expiry_delta: 50,
+			hop_count: 3,
+		});
+		assert_eq!(estimate.fee_msat(1_000_000), 300 + 3000);
+
+		assert!(router.get_path_fee_estimate(&get_test_node_id(&secp_ctx, 5), 1000).is_err());
+	}
+
+	#[test]
+	fn route_retry_generator_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		let node4 = get_test_node_id(&secp_ctx, 5);
+
+		// our_id -1- node1 -2- node4, our_id -3- node2 -4- node4 and our_id -5- node3 -6- node4, in
+		// order of increasing fee
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node4, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 0, 200, 0), get_test_directional_info(&node4, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 6, get_test_directional_info(&node3, 0, 300, 0), get_test_directional_info(&node4, 0, 0, 0));
+		let router = Arc::new(router);
+
+		let payment_hash = PaymentHash([42; 32]);
+		let mut generator = RouteRetryGenerator::new(Arc::clone(&router), payment_hash, node4, Vec::new(), 1000, 42, RouteParameters::default(), 2);
+		assert_eq!(*generator.payment_hash(), payment_hash);
+
+		// Without any failures we keep getting the cheapest route
+		assert_eq!(generator.next_route(None).unwrap().hops[0].short_channel_id, 1);
+		generator.channel_failed(ShortChannelId(2));
+		generator.channel_failed(ShortChannelId(2));
+		assert_eq!(generator.excluded_channels(), &[2]);
+		assert_eq!(generator.next_route(None).unwrap().hops[0].short_channel_id, 3);
+		generator.channel_failed(ShortChannelId(4));
+		match generator.next_route(None) {
+			Err(LightningError{err, ..}) => assert_eq!(err, "Exhausted the maximum number of payment attempts"),
+			Ok(_) => panic!(),
 		}
 
-		// Note that we don't test disabling node 3 and failing to route to it, as we (somewhat
-		// naively) assume that the user checked the feature bits on the invoice, which override
-		// the node_announcement.
+		// With more attempts available we instead run out of routes
+		let mut params = RouteParameters::default();
+		params.excluded_channels = vec![ShortChannelId(4)];
+		let mut generator = RouteRetryGenerator::new(Arc::clone(&router), payment_hash, node4, Vec::new(), 1000, 42, params, 10);
+		assert_eq!(generator.next_route(None).unwrap().hops[0].short_channel_id, 1);
+		generator.channel_failed(ShortChannelId(1));
+		assert_eq!(generator.next_route(None).unwrap().hops[0].short_channel_id, 5);
+		generator.channel_failed(ShortChannelId(6));
+		assert!(generator.excluded_channels() == &[4, 1, 6]);
+		match generator.next_route(None) {
+			Err(LightningError{err, ..}) => assert_eq!(err, "Failed to find a path to the given destination"),
+			Ok(_) => panic!(),
+		}
 
-		{ // Route to 1 via 2 and 3 because our channel to 1 is disabled
-			let route = router.get_route(&node1, None, &Vec::new(), 100, 42).unwrap();
-			assert_eq!(route.hops.len(), 3);
+		// Excluded first_hops and last_hops are skipped as well
+		let our_chans = vec![get_test_first_hop(&node4, 42, 0), get_test_first_hop(&node2, 43, 0)];
+		let last_hops = vec![RouteHint {
+			src_node_id: node2,
+			short_channel_id: ShortChannelId(7),
+			fee_base_msat: 0,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: 0,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}];
+		let target = get_test_node_id(&secp_ctx, 6);
+		let mut generator = RouteRetryGenerator::new(Arc::clone(&router), payment_hash, target, last_hops, 1000, 42, RouteParameters::default(), 10);
+		assert_eq!(generator.next_route(Some(&our_chans)).unwrap().hops[0].short_channel_id, 43);
+		generator.channel_failed(ShortChannelId(7));
+		assert!(generator.next_route(Some(&our_chans)).is_err());
+		let mut generator = RouteRetryGenerator::new(Arc::clone(&router), payment_hash, node4, Vec::new(), 1000, 42, RouteParameters::default(), 10);
+		assert_eq!(generator.next_route(Some(&our_chans)).unwrap().hops[0].short_channel_id, 42);
+		generator.channel_failed(ShortChannelId(42));
+		assert_eq!(generator.next_route(Some(&our_chans)).unwrap().hops[0].short_channel_id, 43);
+	}
 
-			assert_eq!(route.hops[0].pubkey, node2);
-			assert_eq!(route.hops[0].short_channel_id, 2);
-			assert_eq!(route.hops[0].fee_msat, 200);
-			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
-			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
-			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+	#[test]
+	fn route_hint_source_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// node2 is only reachable via a private channel from node1
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		let hint_source = |target: &PublicKey| {
+			if *target == node2 {
+				vec![RouteHint {
+					src_node_id: node1.clone(),
+					short_channel_id: ShortChannelId(2),
+					fee_base_msat: 10,
+					fee_proportional_millionths: 0,
+					cltv_expiry_delta: 5,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: None,
+				}]
+			} else { Vec::new() }
+		};
 
-			assert_eq!(route.hops[1].pubkey, node3);
-			assert_eq!(route.hops[1].short_channel_id, 4);
-			assert_eq!(route.hops[1].fee_msat, 100);
-			assert_eq!(route.hops[1].cltv_expiry_delta, (3 << 8) | 2);
-			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
-			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+		let route = router.get_route_with_hint_source(&node2, None, &hint_source, 1000, 42, &RouteParameters::default()).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].pubkey, node1);
+		assert_eq!(route.hops[0].fee_msat, 10);
+		assert_eq!(route.hops[1].pubkey, node2);
+		assert_eq!(route.hops[1].short_channel_id, 2);
 
-			assert_eq!(route.hops[2].pubkey, node1);
-			assert_eq!(route.hops[2].short_channel_id, 3);
-			assert_eq!(route.hops[2].fee_msat, 100);
-			assert_eq!(route.hops[2].cltv_expiry_delta, 42);
-			assert_eq!(route.hops[2].node_features.le_flags(), &id_to_feature_flags!(1));
-			assert_eq!(route.hops[2].channel_features.le_flags(), &id_to_feature_flags!(3));
-		}
+		assert!(router.get_route_with_hint_source(&node3, None, &hint_source, 1000, 42, &RouteParameters::default()).is_err());
 
-		{ // If we specify a channel to node8, that overrides our local channel view and that gets used
-			let our_chans = vec![channelmanager::ChannelDetails {
-				channel_id: [0; 32],
-				short_channel_id: Some(42),
-				remote_network_id: node8.clone(),
-				counterparty_features: InitFeatures::from_le_bytes(vec![0b11]),
-				channel_value_satoshis: 0,
-				user_id: 0,
-				outbound_capacity_msat: 0,
-				inbound_capacity_msat: 0,
-				is_live: true,
-			}];
-			let route = router.get_route(&node3, Some(&our_chans), &Vec::new(), 100, 42).unwrap();
-			assert_eq!(route.hops.len(), 2);
+		// The source is consulted once, for the target only
+		let queried = ::std::cell::RefCell::new(Vec::new());
+		let recording_source = |target: &PublicKey| {
+			queried.borrow_mut().push(target.clone());
+			hint_source(target)
+		};
+		assert!(router.get_route_with_hint_source(&node2, None, &recording_source, 1000, 42, &RouteParameters::default()).is_ok());
+		assert!(router.get_route_with_hint_source(&our_id, None, &recording_source, 1000, 42, &RouteParameters::default()).is_err());
+		assert_eq!(*queried.borrow(), vec![node2]);
+	}
 
-			assert_eq!(route.hops[0].pubkey, node8);
-			assert_eq!(route.hops[0].short_channel_id, 42);
-			assert_eq!(route.hops[0].fee_msat, 200);
-			assert_eq!(route.hops[0].cltv_expiry_delta, (13 << 8) | 1);
-			assert_eq!(route.hops[0].node_features.le_flags(), &vec![0b11]);
-			assert_eq!(route.hops[0].channel_features.le_flags(), &Vec::new()); // No feature flags will meet the relevant-to-channel conversion
+	#[test]
+	fn route_diagnostics_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		let node4 = get_test_node_id(&secp_ctx, 5);
+
+		// our_id -1- node1, with node2 -2- node3 -3- node4 disconnected from us
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node2, 10, 100, 0), get_test_directional_info(&node3, 10, 100, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node3, 20, 200, 0), get_test_directional_info(&node4, 20, 200, 0));
+
+		let mut diagnostics = RouteDiagnostics::default();
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = router.get_route_with_diagnostics(&node4, None, &Vec::new(), 1000, 42, &RouteParameters::default(), &mut diagnostics) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+
+		let mut expected_nodes = vec![node2, node3];
+		expected_nodes.sort_unstable_by_key(|node_id| node_id.serialize());
+		assert_eq!(diagnostics.reachable_nodes, expected_nodes);
+		assert_eq!(diagnostics.partial_route_source, Some(node2));
+		assert_eq!(diagnostics.partial_route_hops.len(), 2);
+		assert_eq!(diagnostics.partial_route_hops[0].pubkey, node3);
+		assert_eq!(diagnostics.partial_route_hops[0].short_channel_id, 2);
+		assert_eq!(diagnostics.partial_route_hops[0].fee_msat, 200);
+		assert_eq!(diagnostics.partial_route_hops[0].cltv_expiry_delta, 20);
+		assert_eq!(diagnostics.partial_route_hops[1].pubkey, node4);
+		assert_eq!(diagnostics.partial_route_hops[1].short_channel_id, 3);
+		assert_eq!(diagnostics.partial_route_hops[1].fee_msat, 1000);
+		assert_eq!(diagnostics.partial_route_hops[1].cltv_expiry_delta, 42);
+
+		// Successful searches leave the diagnostics untouched
+		let mut diagnostics = RouteDiagnostics::default();
+		router.get_route_with_diagnostics(&node1, None, &Vec::new(), 1000, 42, &RouteParameters::default(), &mut diagnostics).unwrap();
+		assert!(diagnostics.reachable_nodes.is_empty());
+		assert!(diagnostics.partial_route_source.is_none());
+	}
 
-			assert_eq!(route.hops[1].pubkey, node3);
-			assert_eq!(route.hops[1].short_channel_id, 13);
-			assert_eq!(route.hops[1].fee_msat, 100);
-			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
-			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
-			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(13));
+	#[test]
+	fn route_from_hops_test() {
+		let secp_ctx = Secp256k1::new();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+
+		let route = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(1), 100, 20), RouteHop::new(node2, ShortChannelId(2), 1000, 42)], 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].pubkey, node1);
+		assert_eq!(route.hops[0].fee_msat, 100);
+		assert_eq!(route.hops[1].short_channel_id, 2);
+		assert_eq!(route.hops[1].node_features, NodeFeatures::empty());
+
+		macro_rules! expect_route_err {
+			($hops: expr, $value: expr, $cltv: expr, $expected_err: expr) => {
+				if let Err(APIError::RouteError{err}) = Route::from_hops($hops, $value, $cltv) {
+					assert_eq!(err, $expected_err);
+				} else { panic!(); }
+			}
 		}
+		expect_route_err!(Vec::new(), 1000, 42, "Route didn't go anywhere/had bogus size");
+		expect_route_err!(vec![RouteHop::new(node1, ShortChannelId(1), 100, 20), RouteHop::new(node2, ShortChannelId(2), 999, 42)], 1000, 42, "Final hop fee must be the value delivered to the destination");
+		expect_route_err!(vec![RouteHop::new(node1, ShortChannelId(1), 100, 20), RouteHop::new(node2, ShortChannelId(2), 1000, 41)], 1000, 42, "Final hop CLTV delta must be the final CLTV delta");
+		expect_route_err!(vec![RouteHop::new(node1, ShortChannelId(1), 100, 20), RouteHop::new(node1, ShortChannelId(2), 1000, 42)], 1000, 42, "Route went from a node back to itself");
+		expect_route_err!(vec![RouteHop::new(node1, ShortChannelId(1), u64::max_value(), 20), RouteHop::new(node2, ShortChannelId(2), 1000, 42)], 1000, 42, "Channel fees overflowed?!");
+		expect_route_err!(vec![RouteHop::new(node1, ShortChannelId(1), 100, u32::max_value()), RouteHop::new(node2, ShortChannelId(2), 1000, 42)], 1000, 42, "Channel CLTV overflowed?!");
+	}
 
-		let mut last_hops = vec!(RouteHint {
-				src_node_id: node4.clone(),
-				short_channel_id: 8,
-				fee_base_msat: 0,
-				fee_proportional_millionths: 0,
-				cltv_expiry_delta: (8 << 8) | 1,
-				htlc_minimum_msat: 0,
-			}, RouteHint {
-				src_node_id: node5.clone(),
-				short_channel_id: 9,
-				fee_base_msat: 1001,
-				fee_proportional_millionths: 0,
-				cltv_expiry_delta: (9 << 8) | 1,
-				htlc_minimum_msat: 0,
-			}, RouteHint {
-				src_node_id: node6.clone(),
-				short_channel_id: 10,
-				fee_base_msat: 0,
-				fee_proportional_millionths: 0,
-				cltv_expiry_delta: (10 << 8) | 1,
-				htlc_minimum_msat: 0,
-			});
+	#[test]
+	fn channel_score_boost_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// our_id -1- node1 -2- node2 costs 100 msat, our_id -3- node3 -4- node2 costs 150 msat
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node3, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node3, 0, 150, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		let route = router.get_route(&node2, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 2);
+
+		// A boost makes the more expensive path preferable, but doesn't change the fee paid
+		router.set_channel_score_boost(ShortChannelId(4), 60);
+		let route = router.get_route(&node2, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 4);
+		assert_eq!(route.hops[0].fee_msat, 150);
+
+		// Boosts are bounded by the channel's fee, so an enormous boost is no better than one which
+		// cancels out the fee
+		router.set_channel_score_boost(ShortChannelId(4), u64::max_value());
+		router.set_channel_score_boost(ShortChannelId(2), 100);
+		let route = router.get_route(&node2, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops[1].short_channel_id, 2);
+
+		// Boosts survive serialization
+		let mut w = TestVecWriter(Vec::new());
+		router.network_map.read().unwrap().write(&mut w).unwrap();
+		let network: NetworkMap = Readable::read(&mut ::std::io::Cursor::new(&w.0)).unwrap();
+		assert_eq!(network.channel_score_boosts.get(&4), Some(&u64::max_value()));
+		assert_eq!(network.channel_score_boosts.get(&2), Some(&100));
+
+		router.set_channel_score_boost(ShortChannelId(2), 0);
+		assert!(router.network_map.read().unwrap().channel_score_boosts.get(&2).is_none());
+	}
 
-		{ // Simple test across 2, 3, 5, and 4 via a last_hop channel
-			let route = router.get_route(&node7, None, &last_hops, 100, 42).unwrap();
-			assert_eq!(route.hops.len(), 5);
+	#[test]
+	fn estimated_memory_bytes_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let empty_bytes = router.estimated_memory_bytes();
+		assert!(empty_bytes > 0);
+
+		let mut prev_bytes = empty_bytes;
+		let mut chan_bytes = Vec::new();
+		for idx in 2..12 {
+			let node_id = get_test_node_id(&secp_ctx, idx);
+			add_test_channel(&router, idx as u64, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_id, 0, 0, 0));
+			let bytes = router.estimated_memory_bytes();
+			chan_bytes.push(bytes - prev_bytes);
+			prev_bytes = bytes;
+		}
+		// Each channel (and its new node) takes the same space, so the estimate scales linearly with
+		// the graph
+		assert!(chan_bytes.iter().all(|bytes| *bytes == chan_bytes[0]));
+		assert!(chan_bytes[0] > mem::size_of::<ChannelInfo>() + mem::size_of::<NodeInfo>());
+		assert_eq!(prev_bytes - empty_bytes, 10 * chan_bytes[0]);
 
-			assert_eq!(route.hops[0].pubkey, node2);
-			assert_eq!(route.hops[0].short_channel_id, 2);
-			assert_eq!(route.hops[0].fee_msat, 100);
-			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
-			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
-			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+		// Stored addresses are counted as well
+		{
+			let mut network = router.network_map.write().unwrap();
+			let node = network.nodes.get_mut(&get_test_node_id(&secp_ctx, 2)).unwrap();
+			node.addresses.push(msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 });
+		}
+		assert!(router.estimated_memory_bytes() > prev_bytes);
+	}
 
-			assert_eq!(route.hops[1].pubkey, node3);
-			assert_eq!(route.hops[1].short_channel_id, 4);
-			assert_eq!(route.hops[1].fee_msat, 0);
-			assert_eq!(route.hops[1].cltv_expiry_delta, (6 << 8) | 1);
-			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
-			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+	#[test]
+	fn connected_components_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+		let node4 = get_test_node_id(&secp_ctx, 5);
+
+		// our_id -1- node1 -2- node2, with node3 -3- node4 disconnected from us
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		let mut disabled_info = get_test_directional_info(&node1, 0, 0, 0);
+		disabled_info.enabled = false;
+		add_test_channel(&router, 2, disabled_info, get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node3, 0, 0, 0), get_test_directional_info(&node4, 0, 0, 0));
+
+		let our_component = router.component_containing(&our_id);
+		assert_eq!(our_component.len(), 3);
+		assert!(our_component.contains(&our_id) && our_component.contains(&node1) && our_component.contains(&node2));
+		assert_eq!(router.component_containing(&node4), router.component_containing(&node3));
+		assert_eq!(router.component_containing(&node4).len(), 2);
+		assert!(router.component_containing(&get_test_node_id(&secp_ctx, 6)).is_empty());
+		assert_eq!(router.count_nodes_outside_our_component(), 2);
+	}
 
-			assert_eq!(route.hops[2].pubkey, node5);
-			assert_eq!(route.hops[2].short_channel_id, 6);
-			assert_eq!(route.hops[2].fee_msat, 0);
-			assert_eq!(route.hops[2].cltv_expiry_delta, (11 << 8) | 1);
-			assert_eq!(route.hops[2].node_features.le_flags(), &id_to_feature_flags!(5));
-			assert_eq!(route.hops[2].channel_features.le_flags(), &id_to_feature_flags!(6));
+	#[test]
+	fn subgraph_toward_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node_a = get_test_node_id(&secp_ctx, 2);
+		let node_b = get_test_node_id(&secp_ctx, 3);
+		let node_c = get_test_node_id(&secp_ctx, 4);
+		let node_d = get_test_node_id(&secp_ctx, 5);
+		let target = get_test_node_id(&secp_ctx, 6);
+
+		// our_id -1- node_a -2- node_b -3- target -5- node_d, with node_a -4- node_c
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_a, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node_a, 0, 10, 0), get_test_directional_info(&node_b, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node_b, 0, 10, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node_a, 0, 0, 0), get_test_directional_info(&node_c, 0, 0, 0));
+		add_test_channel(&router, 5, get_test_directional_info(&target, 0, 0, 0), get_test_directional_info(&node_d, 0, 0, 0));
+		let channels_toward = |targets: &[PublicKey], max_hops: u8| {
+			let subgraph = router.subgraph_toward(targets, max_hops);
+			let network = subgraph.network_map.read().unwrap();
+			for node in network.nodes.values() {
+				assert!(node.channels.iter().all(|chan_id| network.channels.contains_key(chan_id)));
+			}
+			network.channels.keys().map(|chan_id| *NetworkMap::get_short_id(chan_id)).collect::<Vec<_>>()
+		};
 
-			assert_eq!(route.hops[3].pubkey, node4);
-			assert_eq!(route.hops[3].short_channel_id, 11);
-			assert_eq!(route.hops[3].fee_msat, 0);
-			assert_eq!(route.hops[3].cltv_expiry_delta, (8 << 8) | 1);
-			// If we have a peer in the node map, we'll use their features here since we don't have
-			// a way of figuring out their features from the invoice:
-			assert_eq!(route.hops[3].node_features.le_flags(), &id_to_feature_flags!(4));
-			assert_eq!(route.hops[3].channel_features.le_flags(), &id_to_feature_flags!(11));
+		assert_eq!(channels_toward(&[target], 3), vec![1, 2, 3]);
+		assert!(channels_toward(&[target], 2).is_empty());
+		// node_c's channel only lies on longer paths, which loop back through node_a
+		assert_eq!(channels_toward(&[target], 5), vec![1, 2, 3, 4, 5]);
+		assert_eq!(channels_toward(&[node_c, node_d], 4), vec![1, 2, 3, 4, 5]);
+		assert_eq!(channels_toward(&[node_c, get_test_node_id(&secp_ctx, 7)], 2), vec![1, 4]);
+
+		// The subgraph can be routed over just like the full graph
+		let subgraph = router.subgraph_toward(&[target], 3);
+		assert_eq!(subgraph.network_map.read().unwrap().nodes.len(), 4);
+		let route = subgraph.get_route(&target, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 3]);
+		assert!(subgraph.get_route(&node_c, None, &Vec::new(), 1000, 42).is_err());
+
+		// Channels we haven't verified stay unverified, and so unroutable, in the subgraph
+		let chan_key = NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]));
+		router.network_map.write().unwrap().unverified_channels.insert(chan_key.clone(), (Sha256dHash::hash(&[0; 32]), node_a.clone(), node_b.clone()));
+		let subgraph = router.subgraph_toward(&[target], 3);
+		assert!(subgraph.network_map.read().unwrap().unverified_channels.contains_key(&chan_key));
+		assert!(subgraph.get_route(&target, None, &Vec::new(), 1000, 42).is_err());
+
+		// Closing our only channel removes our own node, which the subgraph still has
+		router.handle_htlc_fail_channel_update(&msgs::HTLCFailChannelUpdate::ChannelClosed { short_channel_id: 1, is_permanent: true });
+		assert!(router.network_map.read().unwrap().nodes.get(&our_id).is_none());
+		assert!(channels_toward(&[target], 3).is_empty());
+		assert!(router.subgraph_toward(&[target], 3).network_map.read().unwrap().nodes.get(&our_id).is_some());
+	}
 
-			assert_eq!(route.hops[4].pubkey, node7);
-			assert_eq!(route.hops[4].short_channel_id, 8);
-			assert_eq!(route.hops[4].fee_msat, 100);
-			assert_eq!(route.hops[4].cltv_expiry_delta, 42);
-			assert_eq!(route.hops[4].node_features.le_flags(), &Vec::new()); // We dont pass flags in from invoices yet
-			assert_eq!(route.hops[4].channel_features.le_flags(), &Vec::new()); // We can't learn any flags from invoices, sadly
+	#[test]
+	fn route_value_limits_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 1000, 0), get_test_directional_info(&node2, 0, 0, 0));
+
+		macro_rules! expect_route_err {
+			($value_msat: expr, $params: expr, $expected_err: expr) => {
+				match router.get_route_with_params(&node2, None, &Vec::new(), $value_msat, 42, &$params) {
+					Err(LightningError{err, ..}) => assert_eq!(err, $expected_err),
+					Ok(_) => panic!(),
+				}
+			}
 		}
+		expect_route_err!(0, RouteParameters::default(), "Cannot generate a route for a payment of zero value");
+		expect_route_err!(u64::max_value(), RouteParameters::default(), "Cannot generate a route of more value than all existing satoshis");
+		expect_route_err!(21_000_000 * 1_0000_0000 * 1000 + 1, RouteParameters::default(), "Cannot generate a route of more value than all existing satoshis");
+
+		// Even ignoring capacity, we can't route all satoshis if node1 charges a fee for it...
+		let params = RouteParameters { htlc_maximum_penalty_msat: Some(0), ..RouteParameters::default() };
+		expect_route_err!(21_000_000 * 1_0000_0000 * 1000, params, "Failed to find a path to the given destination");
+		// ...but we can route all but node1's fee.
+		let route = router.get_route_with_params(&node2, None, &Vec::new(), 21_000_000 * 1_0000_0000 * 1000 - 1000, 42, &params).unwrap();
+		assert_eq!(route.total_fees_msat(), 1000);
+	}
 
-		{ // Simple test with outbound channel to 4 to test that last_hops and first_hops connect
-			let our_chans = vec![channelmanager::ChannelDetails {
-				channel_id: [0; 32],
-				short_channel_id: Some(42),
-				remote_network_id: node4.clone(),
-				counterparty_features: InitFeatures::from_le_bytes(vec![0b11]),
-				channel_value_satoshis: 0,
-				user_id: 0,
-				outbound_capacity_msat: 0,
-				inbound_capacity_msat: 0,
-				is_live: true,
-			}];
-			let route = router.get_route(&node7, Some(&our_chans), &last_hops, 100, 42).unwrap();
-			assert_eq!(route.hops.len(), 2);
+	#[test]
+	fn route_onion_size_test() {
+		let (secp_ctx, our_id, router) = create_router();
 
-			assert_eq!(route.hops[0].pubkey, node4);
-			assert_eq!(route.hops[0].short_channel_id, 42);
-			assert_eq!(route.hops[0].fee_msat, 0);
-			assert_eq!(route.hops[0].cltv_expiry_delta, (8 << 8) | 1);
-			assert_eq!(route.hops[0].node_features.le_flags(), &vec![0b11]);
-			assert_eq!(route.hops[0].channel_features.le_flags(), &Vec::new()); // No feature flags will meet the relevant-to-channel conversion
+		// A chain of 21 nodes, none of which support variable-length onions, so that each hop
+		// takes a full 65 bytes of the onion
+		let mut prev_node = our_id;
+		let mut nodes = Vec::new();
+		for i in 0..21 {
+			let node = get_test_node_id(&secp_ctx, i + 2);
+			add_test_channel(&router, i as u64 + 1, get_test_directional_info(&prev_node, 0, 0, 0), get_test_directional_info(&node, 0, 0, 0));
+			nodes.push(node);
+			prev_node = node;
+		}
 
-			assert_eq!(route.hops[1].pubkey, node7);
-			assert_eq!(route.hops[1].short_channel_id, 8);
-			assert_eq!(route.hops[1].fee_msat, 100);
-			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
-			assert_eq!(route.hops[1].node_features.le_flags(), &Vec::new()); // We dont pass flags in from invoices yet
-			assert_eq!(route.hops[1].channel_features.le_flags(), &Vec::new()); // We can't learn any flags from invoices, sadly
+		let route = router.get_route(&nodes[19], None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 20);
+
+		// Paths are never extended past 20 hops, so there is no path to nodes[20]...
+		match router.get_route(&nodes[20], None, &Vec::new(), 1000, 42) {
+			Err(LightningError{err, ..}) => assert_eq!(err, "Failed to find a path to the given destination"),
+			Ok(_) => panic!(),
 		}
 
-		last_hops[0].fee_base_msat = 1000;
+		// ...and a shorter path is used over a cheaper one which is too long.
+		let node_x = get_test_node_id(&secp_ctx, 30);
+		add_test_channel(&router, 100, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node_x, 0, 0, 0));
+		add_test_channel(&router, 101, get_test_directional_info(&node_x, 0, 1000, 0), get_test_directional_info(&nodes[20], 0, 0, 0));
+		let route = router.get_route(&nodes[20], None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id.0).collect::<Vec<_>>(), vec![100, 101]);
+	}
 
-		{ // Revert to via 6 as the fee on 8 goes up
-			let route = router.get_route(&node7, None, &last_hops, 100, 42).unwrap();
-			assert_eq!(route.hops.len(), 4);
+	#[test]
+	fn route_totals_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 0, 0), get_test_directional_info(&node1, 10, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 20, 100, 0), get_test_directional_info(&node2, 20, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node2, 30, 0, 1000), get_test_directional_info(&node3, 30, 0, 0));
+
+		let route = router.get_route(&node3, None, &Vec::new(), 10000, 42).unwrap();
+		assert_eq!(route.hops.len(), 3);
+		assert_eq!(route.total_fees_msat(), route.hops[0].fee_msat + route.hops[1].fee_msat);
+		assert_eq!(route.total_fees_msat(), 100 + 10);
+		assert_eq!(route.total_cltv_delta(), 20 + 30 + 42);
+
+		// A direct route pays no fees
+		let route = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(1), 1000, 42)], 1000, 42).unwrap();
+		assert_eq!(route.total_fees_msat(), 0);
+		assert_eq!(route.total_cltv_delta(), 42);
+	}
 
-			assert_eq!(route.hops[0].pubkey, node2);
-			assert_eq!(route.hops[0].short_channel_id, 2);
-			assert_eq!(route.hops[0].fee_msat, 200); // fee increased as its % of value transferred across node
-			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
-			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
-			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+	#[test]
+	fn route_cost_breakdown_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		// Our own channel's policy charges a fee, which we never pay
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 500, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 100, 0), get_test_directional_info(&node2, 0, 0, 0));
+		add_test_channel(&router, 3, get_test_directional_info(&node2, 0, 10, 0), get_test_directional_info(&node3, 0, 0, 0));
+
+		let route = router.get_route(&node3, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.cost_breakdown(), RouteCostBreakdown {
+			final_value_msat: 1000,
+			fees_paid_msat: vec![(node1, 100), (node2, 10)],
+			total_fees_msat: 110,
+			amount_sent_msat: 1110,
+		});
 
-			assert_eq!(route.hops[1].pubkey, node3);
-			assert_eq!(route.hops[1].short_channel_id, 4);
-			assert_eq!(route.hops[1].fee_msat, 100);
-			assert_eq!(route.hops[1].cltv_expiry_delta, (7 << 8) | 1);
-			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
-			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+		let route = router.get_route(&node1, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.cost_breakdown(), RouteCostBreakdown {
+			final_value_msat: 1000,
+			fees_paid_msat: Vec::new(),
+			total_fees_msat: 0,
+			amount_sent_msat: 1000,
+		});
+	}
 
-			assert_eq!(route.hops[2].pubkey, node6);
-			assert_eq!(route.hops[2].short_channel_id, 7);
-			assert_eq!(route.hops[2].fee_msat, 0);
-			assert_eq!(route.hops[2].cltv_expiry_delta, (10 << 8) | 1);
-			// If we have a peer in the node map, we'll use their features here since we don't have
-			// a way of figuring out their features from the invoice:
-			assert_eq!(route.hops[2].node_features.le_flags(), &id_to_feature_flags!(6));
-			assert_eq!(route.hops[2].channel_features.le_flags(), &id_to_feature_flags!(7));
+	#[test]
+	fn route_compare_test() {
+		let secp_ctx = Secp256k1::new();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		let route_a = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(1), 100, 20), RouteHop::new(node2, ShortChannelId(2), 50, 30), RouteHop::new(node3, ShortChannelId(3), 1000, 42)], 1000, 42).unwrap();
+		let route_b = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(1), 300, 10), RouteHop::new(node3, ShortChannelId(4), 1000, 42)], 1000, 42).unwrap();
+		assert!(route_a.compare(&route_b) == RouteComparison {
+			fee_delta_msat: 150,
+			cltv_delta: -40,
+			shared_short_channel_ids: vec![ShortChannelId(1)],
+			short_channel_ids_only_in_self: vec![ShortChannelId(2), ShortChannelId(3)],
+			short_channel_ids_only_in_other: vec![ShortChannelId(4)],
+		});
+		assert!(route_b.compare(&route_a) == RouteComparison {
+			fee_delta_msat: -150,
+			cltv_delta: 40,
+			shared_short_channel_ids: vec![ShortChannelId(1)],
+			short_channel_ids_only_in_self: vec![ShortChannelId(4)],
+			short_channel_ids_only_in_other: vec![ShortChannelId(2), ShortChannelId(3)],
+		});
+		assert!(route_a.compare(&route_a) == RouteComparison {
+			fee_delta_msat: 0,
+			cltv_delta: 0,
+			shared_short_channel_ids: vec![ShortChannelId(1), ShortChannelId(2), ShortChannelId(3)],
+			short_channel_ids_only_in_self: Vec::new(),
+			short_channel_ids_only_in_other: Vec::new(),
+		});
 
-			assert_eq!(route.hops[3].pubkey, node7);
-			assert_eq!(route.hops[3].short_channel_id, 10);
-			assert_eq!(route.hops[3].fee_msat, 100);
-			assert_eq!(route.hops[3].cltv_expiry_delta, 42);
-			assert_eq!(route.hops[3].node_features.le_flags(), &Vec::new()); // We dont pass flags in from invoices yet
-			assert_eq!(route.hops[3].channel_features.le_flags(), &Vec::new()); // We can't learn any flags from invoices, sadly
-		}
+		// Fee deltas saturate
+		let expensive_route = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(1), u64::max_value() - 1000, 20), RouteHop::new(node3, ShortChannelId(3), 1000, 42)], 1000, 42).unwrap();
+		assert_eq!(route_a.compare(&expensive_route).fee_delta_msat, i64::max_value());
+		assert_eq!(expensive_route.compare(&route_a).fee_delta_msat, -i64::max_value());
+	}
 
-		{ // ...but still use 8 for larger payments as 6 has a variable feerate
-			let route = router.get_route(&node7, None, &last_hops, 2000, 42).unwrap();
-			assert_eq!(route.hops.len(), 5);
+	#[test]
+	fn route_validate_against_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 100, 0), get_test_directional_info(&node1, 0, 0, 0));
+		let mut chan2_info = get_test_directional_info(&node1, 20, 100, 1000);
+		chan2_info.htlc_minimum_msat = 500;
+		chan2_info.htlc_maximum_msat = Some(5000);
+		add_test_channel(&router, 2, chan2_info, get_test_directional_info(&node2, 0, 0, 0));
+		let last_hops = vec![RouteHint {
+			src_node_id: node2,
+			short_channel_id: ShortChannelId(3),
+			fee_base_msat: 10,
+			fee_proportional_millionths: 0,
+			cltv_expiry_delta: 30,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}];
 
-			assert_eq!(route.hops[0].pubkey, node2);
-			assert_eq!(route.hops[0].short_channel_id, 2);
-			assert_eq!(route.hops[0].fee_msat, 3000);
-			assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
-			assert_eq!(route.hops[0].node_features.le_flags(), &id_to_feature_flags!(2));
-			assert_eq!(route.hops[0].channel_features.le_flags(), &id_to_feature_flags!(2));
+		let route = router.get_route(&node3, None, &last_hops, 1000, 42).unwrap();
+		assert_eq!(route.validate_against(&router, None, &last_hops), Ok(()));
 
-			assert_eq!(route.hops[1].pubkey, node3);
-			assert_eq!(route.hops[1].short_channel_id, 4);
-			assert_eq!(route.hops[1].fee_msat, 0);
-			assert_eq!(route.hops[1].cltv_expiry_delta, (6 << 8) | 1);
-			assert_eq!(route.hops[1].node_features.le_flags(), &id_to_feature_flags!(3));
-			assert_eq!(route.hops[1].channel_features.le_flags(), &id_to_feature_flags!(4));
+		// Channel 3 is only known from the hint
+		assert_eq!(route.validate_against(&router, None, &[]), Err(RouteValidationError::UnknownChannel { hop_idx: 2, short_channel_id: ShortChannelId(3) }));
 
-			assert_eq!(route.hops[2].pubkey, node5);
-			assert_eq!(route.hops[2].short_channel_id, 6);
-			assert_eq!(route.hops[2].fee_msat, 0);
-			assert_eq!(route.hops[2].cltv_expiry_delta, (11 << 8) | 1);
-			assert_eq!(route.hops[2].node_features.le_flags(), &id_to_feature_flags!(5));
-			assert_eq!(route.hops[2].channel_features.le_flags(), &id_to_feature_flags!(6));
+		macro_rules! expect_err {
+			($hops: expr, $expected_err: expr) => {
+				assert_eq!(Route { hops: $hops }.validate_against(&router, None, &last_hops), Err($expected_err));
+			}
+		}
+		let hops = || vec![RouteHop::new(node1, ShortChannelId(1), 101, 20), RouteHop::new(node2, ShortChannelId(2), 10, 30), RouteHop::new(node3, ShortChannelId(3), 1000, 42)];
+		assert_eq!(Route { hops: hops() }.validate_against(&router, None, &last_hops), Ok(()));
+
+		let mut underpaying_hops = hops();
+		underpaying_hops[0].fee_msat = 100;
+		expect_err!(underpaying_hops, RouteValidationError::InsufficientFee { hop_idx: 0, required_fee_msat: 101 });
+		let mut short_cltv_hops = hops();
+		short_cltv_hops[1].cltv_expiry_delta = 29;
+		expect_err!(short_cltv_hops, RouteValidationError::InsufficientCltvExpiryDelta { hop_idx: 1, required_cltv_expiry_delta: 30 });
+		let mut small_hops = hops();
+		small_hops[2].fee_msat = 400;
+		expect_err!(small_hops, RouteValidationError::AmountBelowMinimum { hop_idx: 1, short_channel_id: ShortChannelId(2), htlc_minimum_msat: 500 });
+		let mut large_hops = hops();
+		large_hops[1].fee_msat = 4010;
+		large_hops[0].fee_msat = 1000;
+		expect_err!(large_hops, RouteValidationError::AmountExceedsMaximum { hop_idx: 1, short_channel_id: ShortChannelId(2), htlc_maximum_msat: 5000 });
+		// Channel 2 doesn't go from us to node2
+		expect_err!(vec![RouteHop::new(node2, ShortChannelId(2), 1000, 42)], RouteValidationError::UnknownChannel { hop_idx: 0, short_channel_id: ShortChannelId(2) });
+
+		router.network_map.write().unwrap().channels.get_mut(&2).unwrap().one_to_two.enabled = false;
+		expect_err!(hops(), RouteValidationError::ChannelDisabled { hop_idx: 1, short_channel_id: ShortChannelId(2) });
+	}
 
-			assert_eq!(route.hops[3].pubkey, node4);
-			assert_eq!(route.hops[3].short_channel_id, 11);
-			assert_eq!(route.hops[3].fee_msat, 1000);
-			assert_eq!(route.hops[3].cltv_expiry_delta, (8 << 8) | 1);
-			// If we have a peer in the node map, we'll use their features here since we don't have
-			// a way of figuring out their features from the invoice:
-			assert_eq!(route.hops[3].node_features.le_flags(), &id_to_feature_flags!(4));
-			assert_eq!(route.hops[3].channel_features.le_flags(), &id_to_feature_flags!(11));
+	#[test]
+	fn route_validate_mpp_test() {
+		let (secp_ctx, our_id, router) = create_router();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let target = get_test_node_id(&secp_ctx, 4);
+
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 0, 0, 0), get_test_directional_info(&node1, 0, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 0, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+		add_test_channel(&router, 4, get_test_directional_info(&node2, 0, 0, 0), get_test_directional_info(&target, 0, 0, 0));
+		router.network_map.write().unwrap().channels.get_mut(&2).unwrap().capacity_sats = Some(5);
+		let our_chans = vec![get_test_first_hop(&node1, 11, 3000), get_test_first_hop(&node2, 13, 10000)];
+		let part = |first_short_channel_id: u64, short_channel_id: u64, amount_msat: u64| {
+			let first_hop_node = if short_channel_id == 4 { node2 } else { node1 };
+			Route { hops: vec![RouteHop::new(first_hop_node, ShortChannelId(first_short_channel_id), 0, 0), RouteHop::new(target, ShortChannelId(short_channel_id), amount_msat, 42)] }
+		};
+		let validate = |parts: &[Route], total_value_msat: u64| Route::validate_mpp(parts, &router, Some(&our_chans), &[], total_value_msat);
+
+		assert_eq!(validate(&[part(11, 2, 3000), part(13, 4, 2000)], 5000), Ok(()));
+		assert_eq!(validate(&[part(11, 2, 3000), part(13, 4, 2000)], 6000), Err(MppValidationError::IncorrectTotal { paid_msat: 5000 }));
+		// Each part fits in channel 11, but not both together
+		assert_eq!(validate(&[part(11, 2, 3000), part(11, 2, 1000)], 4000),
+			Err(MppValidationError::ChannelOversubscribed { short_channel_id: ShortChannelId(11), amount_msat: 4000, capacity_msat: 3000 }));
+		// The same goes for channel 2's on-chain capacity, while our graph channel 1's is unknown
+		assert_eq!(Route::validate_mpp(&[part(1, 2, 3000), part(1, 2, 2000)], &router, None, &[], 5000), Ok(()));
+		assert_eq!(Route::validate_mpp(&[part(1, 2, 3000), part(1, 2, 2001)], &router, None, &[], 5001),
+			Err(MppValidationError::ChannelOversubscribed { short_channel_id: ShortChannelId(2), amount_msat: 5001, capacity_msat: 5000 }));
+
+		let node2_part = Route { hops: vec![RouteHop::new(node2, ShortChannelId(13), 1000, 42)] };
+		assert_eq!(validate(&[part(11, 2, 3000), node2_part], 4000), Err(MppValidationError::TargetMismatch { part_idx: 1 }));
+		assert_eq!(validate(&[part(13, 4, 2000), part(11, 99, 1000)], 3000),
+			Err(MppValidationError::InvalidPart { part_idx: 1, error: Some(RouteValidationError::UnknownChannel { hop_idx: 1, short_channel_id: ShortChannelId(99) }) }));
+		assert_eq!(validate(&[part(13, 4, 2000), Route { hops: Vec::new() }], 2000), Err(MppValidationError::InvalidPart { part_idx: 1, error: None }));
+		assert_eq!(validate(&[], 0), Err(MppValidationError::NoParts));
+	}
 
-			assert_eq!(route.hops[4].pubkey, node7);
-			assert_eq!(route.hops[4].short_channel_id, 8);
-			assert_eq!(route.hops[4].fee_msat, 2000);
-			assert_eq!(route.hops[4].cltv_expiry_delta, 42);
-			assert_eq!(route.hops[4].node_features.le_flags(), &Vec::new()); // We dont pass flags in from invoices yet
-			assert_eq!(route.hops[4].channel_features.le_flags(), &Vec::new()); // We can't learn any flags from invoices, sadly
+	#[test]
+	fn export_hops_test() {
+		let secp_ctx = Secp256k1::new();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let node3 = get_test_node_id(&secp_ctx, 4);
+
+		let route = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(1), 100, 20), RouteHop::new(node2, ShortChannelId(2), 10, 30), RouteHop::new(node3, ShortChannelId(3), 1000, 42)], 1000, 42).unwrap();
+		assert_eq!(route.export_hops(101), vec![
+			ExportedRouteHop { pubkey: node1, short_channel_id: 1, amount_msat: 1110, cltv_expiry: 193 },
+			ExportedRouteHop { pubkey: node2, short_channel_id: 2, amount_msat: 1010, cltv_expiry: 173 },
+			ExportedRouteHop { pubkey: node3, short_channel_id: 3, amount_msat: 1000, cltv_expiry: 143 },
+		]);
+
+		// The exported hops match the amounts and expiries of the onion we'd build ourselves
+		let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route, 101).unwrap();
+		let exported = route.export_hops(101);
+		assert_eq!(exported[0].amount_msat, htlc_msat);
+		assert_eq!(exported[0].cltv_expiry, htlc_cltv);
+		for (payload, next_hop) in onion_payloads.iter().zip(exported.iter().skip(1)) {
+			assert_eq!(payload.amt_to_forward, next_hop.amount_msat);
+			assert_eq!(payload.outgoing_cltv_value, next_hop.cltv_expiry);
 		}
+	}
 
-		{ // Test Router serialization/deserialization
-			let mut w = TestVecWriter(Vec::new());
-			let network = router.network_map.read().unwrap();
-			assert!(!network.channels.is_empty());
-			assert!(!network.nodes.is_empty());
-			network.write(&mut w).unwrap();
-			assert!(<NetworkMap>::read(&mut ::std::io::Cursor::new(&w.0)).unwrap() == *network);
+	#[test]
+	fn route_fingerprint_test() {
+		let secp_ctx = Secp256k1::new();
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+
+		let route = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(1), 100, 20), RouteHop::new(node2, ShortChannelId(2), 1000, 42)], 1000, 42).unwrap();
+		let fingerprint: RouteFingerprint = route.fingerprint();
+		assert_eq!(fingerprint, route.clone().fingerprint());
+
+		// The amount delivered and CLTV deltas don't matter, only the channels and total fee
+		let same_route = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(1), 100, 30), RouteHop::new(node2, ShortChannelId(2), 2000, 42)], 2000, 42).unwrap();
+		assert_eq!(same_route.fingerprint(), fingerprint);
+		let new_fee_route = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(1), 101, 20), RouteHop::new(node2, ShortChannelId(2), 1000, 42)], 1000, 42).unwrap();
+		assert_ne!(new_fee_route.fingerprint(), fingerprint);
+		let new_chan_route = Route::from_hops(vec![RouteHop::new(node1, ShortChannelId(3), 100, 20), RouteHop::new(node2, ShortChannelId(2), 1000, 42)], 1000, 42).unwrap();
+		assert_ne!(new_chan_route.fingerprint(), fingerprint);
+	}
+
+	#[test]
+	fn split_value_over_first_hops_test() {
+		let secp_ctx = Secp256k1::new();
+		let first_hop = |short_channel_id: Option<u64>, outbound_capacity_msat: u64, is_live: bool| channelmanager::ChannelDetails { short_channel_id, is_live, ..get_test_first_hop(&get_test_node_id(&secp_ctx, 2), 0, outbound_capacity_msat) };
+		let first_hops = vec![first_hop(Some(1), 3000, true), first_hop(Some(2), 5000, true), first_hop(Some(3), 3000, true),
+			first_hop(Some(4), 10000, false), first_hop(None, 10000, true)];
+
+		assert_eq!(split_value_over_first_hops(&first_hops, 4000).unwrap(), vec![(ShortChannelId(2), 4000)]);
+		assert_eq!(split_value_over_first_hops(&first_hops, 9000).unwrap(), vec![(ShortChannelId(2), 5000), (ShortChannelId(1), 3000), (ShortChannelId(3), 1000)]);
+		assert_eq!(split_value_over_first_hops(&first_hops, 11000).unwrap(), vec![(ShortChannelId(2), 5000), (ShortChannelId(1), 3000), (ShortChannelId(3), 3000)]);
+		assert_eq!(split_value_over_first_hops(&first_hops, 12000), Err(InsufficientCapacityError { available_msat: 11000, shortfall_msat: 1000 }));
+	}
+
+	#[test]
+	fn split_value_over_first_hops_randomized_test() {
+		let secp_ctx = Secp256k1::new();
+		let first_hop = |short_channel_id: u64, outbound_capacity_msat: u64| get_test_first_hop(&get_test_node_id(&secp_ctx, 2), short_channel_id, outbound_capacity_msat);
+		let first_hops = vec![first_hop(1, 3000), first_hop(2, 5000), first_hop(3, 3000), first_hop(4, 500)];
+
+		// A simple LCG stands in for a real RNG
+		let mut state = 42u64;
+		let mut random_u64 = || { state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407); state >> 11 };
+		let mut seen_splits = HashSet::new();
+		for _ in 0..100 {
+			let shares = split_value_over_first_hops_randomized(&first_hops, 9000, 1500, &mut random_u64).unwrap();
+			assert_eq!(shares.iter().map(|share| share.0).collect::<Vec<_>>(), vec![2, 1, 3]);
+			assert_eq!(shares.iter().map(|share| share.1).sum::<u64>(), 9000);
+			for &(short_channel_id, amount_msat) in shares.iter() {
+				assert!(amount_msat >= 1500);
+				assert!(amount_msat <= if short_channel_id == 2 { 5000 } else { 3000 });
+			}
+			seen_splits.insert(shares);
 		}
+		assert!(seen_splits.len() > 10);
+
+		// Each share is picked from the range which keeps the later shares within their limits
+		assert_eq!(split_value_over_first_hops_randomized(&first_hops, 9000, 1500, || 0).unwrap(), vec![(ShortChannelId(2), 3000), (ShortChannelId(1), 3000), (ShortChannelId(3), 3000)]);
+		assert_eq!(split_value_over_first_hops_randomized(&first_hops, 9000, 1500, || u64::max_value() / 2).unwrap(), vec![(ShortChannelId(2), 3301), (ShortChannelId(1), 2706), (ShortChannelId(3), 2993)]);
+		// Values which would bias the shares towards lower amounts are redrawn. The first share is
+		// picked from 2001 amounts, which don't divide 2^64, so u64::max_value() is discarded.
+		let mut draws = 0;
+		assert_eq!(split_value_over_first_hops_randomized(&first_hops, 9000, 1500, || { draws += 1; if draws == 1 { u64::max_value() } else { 0 } }).unwrap(),
+			vec![(ShortChannelId(2), 3000), (ShortChannelId(1), 3000), (ShortChannelId(3), 3000)]);
+		assert_eq!(draws, 3);
+		assert_eq!(split_value_over_first_hops_randomized(&first_hops, 4000, 0, || 7).unwrap(), vec![(ShortChannelId(2), 4000)]);
+
+		assert_eq!(split_value_over_first_hops_randomized(&first_hops, 9000, 3001, || 0), Err(SplitValueError::MinimumShareUnsatisfiable { share_count: 3 }));
+		assert_eq!(split_value_over_first_hops_randomized(&first_hops, 11200, 600, || 0), Err(SplitValueError::MinimumShareUnsatisfiable { share_count: 4 }));
+		assert_eq!(split_value_over_first_hops_randomized(&first_hops, 11200, 400, || 0).unwrap(), vec![(ShortChannelId(2), 4700), (ShortChannelId(1), 3000), (ShortChannelId(3), 3000), (ShortChannelId(4), 500)]);
+		assert_eq!(split_value_over_first_hops_randomized(&first_hops, 12000, 0, || 0),
+			Err(SplitValueError::InsufficientCapacity(InsufficientCapacityError { available_msat: 11500, shortfall_msat: 500 })));
+	}
+
+	#[test]
+	fn custom_hasher_test() {
+		use std::collections::hash_map::DefaultHasher;
+		use std::hash::BuildHasherDefault;
+
+		let secp_ctx = Secp256k1::new();
+		let our_id = get_test_node_id(&secp_ctx, 1);
+		let node1 = get_test_node_id(&secp_ctx, 2);
+		let node2 = get_test_node_id(&secp_ctx, 3);
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::<BuildHasherDefault<DefaultHasher>>::new_with_hasher(our_id, genesis_block(Network::Testnet).header.bitcoin_hash(), chain_monitor, Arc::clone(&logger));
+
+		add_test_channel(&router, 1, get_test_directional_info(&our_id, 10, 0, 0), get_test_directional_info(&node1, 10, 0, 0));
+		add_test_channel(&router, 2, get_test_directional_info(&node1, 20, 100, 0), get_test_directional_info(&node2, 20, 0, 0));
+
+		let route = router.get_route(&node2, None, &Vec::new(), 1000, 42).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].fee_msat, 100);
+		assert_eq!(route.hops[1].short_channel_id, 2);
+
+		let component: HashSet<PublicKey, BuildHasherDefault<DefaultHasher>> = router.component_containing(&our_id);
+		assert_eq!(component.len(), 3);
+		assert_eq!(router.count_nodes_outside_our_component(), 0);
+
+		let read_router = Router::<BuildHasherDefault<DefaultHasher>>::read_with_hasher(&mut ::std::io::Cursor::new(&router.encode()), RouterReadArgs {
+			chain_monitor: Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger))),
+			logger: Arc::clone(&logger),
+			genesis_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+		}).unwrap();
+		assert!(*read_router.network_map.read().unwrap() == *router.network_map.read().unwrap());
 	}
 
 	#[test]
@@ -3,6 +3,7 @@
 pub mod events;
 pub mod errors;
 pub mod ser;
+pub mod scid_utils;
 
 pub(crate) mod byte_utils;
 pub(crate) mod chacha20;
@@ -0,0 +1,175 @@
+//! Utilities for encoding and decoding short_channel_ids, which identify a channel's funding
+//! output by the height of the block it was confirmed in, its transaction's index within that
+//! block and its index within the transaction's outputs, as described in BOLT 7.
+
+use ln::msgs::DecodeError;
+use util::ser::{Readable, Writeable, Writer};
+
+use std::fmt;
+use std::io::Read;
+
+/// The largest block height which may be encoded in a short_channel_id
+pub const MAX_SCID_BLOCK: u64 = 0x00ffffff;
+
+/// The largest transaction index within a block which may be encoded in a short_channel_id
+pub const MAX_SCID_TX_INDEX: u64 = 0x00ffffff;
+
+/// The largest output index within a transaction which may be encoded in a short_channel_id
+pub const MAX_SCID_VOUT_INDEX: u64 = 0xffff;
+
+/// A part of a funding output's location which is too large to be encoded in a
+/// short_channel_id, as returned by scid_from_parts.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShortChannelIdError {
+	/// The block height exceeded MAX_SCID_BLOCK
+	BlockOverflow,
+	/// The transaction index exceeded MAX_SCID_TX_INDEX
+	TxIndexOverflow,
+	/// The output index exceeded MAX_SCID_VOUT_INDEX
+	VoutIndexOverflow,
+}
+
+/// Gets the height of the block the funding transaction of the given short_channel_id was
+/// confirmed in.
+pub fn block_from_scid(short_channel_id: u64) -> u32 {
+	(short_channel_id >> 40) as u32
+}
+
+/// Gets the index of the funding transaction of the given short_channel_id within its block.
+pub fn tx_index_from_scid(short_channel_id: u64) -> u32 {
+	((short_channel_id >> 16) & MAX_SCID_TX_INDEX) as u32
+}
+
+/// Gets the index of the funding output of the given short_channel_id within its transaction.
+pub fn vout_from_scid(short_channel_id: u64) -> u16 {
+	(short_channel_id & MAX_SCID_VOUT_INDEX) as u16
+}
+
+/// Constructs the short_channel_id of the funding output at the given block height, transaction
+/// index within the block and output index within the transaction, failing if any of them is too
+/// large to be encoded.
+pub fn scid_from_parts(block: u64, tx_index: u64, vout_index: u64) -> Result<u64, ShortChannelIdError> {
+	if block > MAX_SCID_BLOCK {
+		return Err(ShortChannelIdError::BlockOverflow);
+	}
+	if tx_index > MAX_SCID_TX_INDEX {
+		return Err(ShortChannelIdError::TxIndexOverflow);
+	}
+	if vout_index > MAX_SCID_VOUT_INDEX {
+		return Err(ShortChannelIdError::VoutIndexOverflow);
+	}
+	Ok((block << 40) | (tx_index << 16) | vout_index)
+}
+
+/// A short_channel_id, kept distinct from other u64s (eg amounts) so that they can't be mixed
+/// up. It is displayed, as is common, as its block height, transaction index and output index
+/// separated by 'x', and serialized as a big-endian u64, as on the wire.
+///
+/// It may be compared directly against a u64 short_channel_id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShortChannelId(pub u64);
+
+impl ShortChannelId {
+	/// Constructs the short_channel_id of the funding output at the given location, as in
+	/// scid_from_parts.
+	pub fn from_parts(block: u64, tx_index: u64, vout_index: u64) -> Result<ShortChannelId, ShortChannelIdError> {
+		scid_from_parts(block, tx_index, vout_index).map(ShortChannelId)
+	}
+
+	/// Gets the height of the block the channel's funding transaction was confirmed in.
+	pub fn block(&self) -> u32 {
+		block_from_scid(self.0)
+	}
+
+	/// Gets the index of the channel's funding transaction within its block.
+	pub fn tx_index(&self) -> u32 {
+		tx_index_from_scid(self.0)
+	}
+
+	/// Gets the index of the channel's funding output within its transaction.
+	pub fn vout(&self) -> u16 {
+		vout_from_scid(self.0)
+	}
+}
+
+impl From<u64> for ShortChannelId {
+	fn from(short_channel_id: u64) -> ShortChannelId {
+		ShortChannelId(short_channel_id)
+	}
+}
+
+impl From<ShortChannelId> for u64 {
+	fn from(short_channel_id: ShortChannelId) -> u64 {
+		short_channel_id.0
+	}
+}
+
+impl PartialEq<u64> for ShortChannelId {
+	fn eq(&self, other: &u64) -> bool {
+		self.0 == *other
+	}
+}
+
+impl PartialEq<ShortChannelId> for u64 {
+	fn eq(&self, other: &ShortChannelId) -> bool {
+		*self == other.0
+	}
+}
+
+impl fmt::Display for ShortChannelId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}x{}x{}", self.block(), self.tx_index(), self.vout())
+	}
+}
+
+impl Writeable for ShortChannelId {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.0.write(writer)
+	}
+}
+
+impl Readable for ShortChannelId {
+	fn read<R: Read>(reader: &mut R) -> Result<ShortChannelId, DecodeError> {
+		Ok(ShortChannelId(Readable::read(reader)?))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_scid_from_parts() {
+		assert_eq!(scid_from_parts(0x00000001, 0x00000002, 0x0003).unwrap(), 0x000001_000002_0003);
+		assert_eq!(scid_from_parts(MAX_SCID_BLOCK, MAX_SCID_TX_INDEX, MAX_SCID_VOUT_INDEX).unwrap(), 0xffffff_ffffff_ffff);
+		assert_eq!(scid_from_parts(MAX_SCID_BLOCK + 1, 0, 0), Err(ShortChannelIdError::BlockOverflow));
+		assert_eq!(scid_from_parts(0, MAX_SCID_TX_INDEX + 1, 0), Err(ShortChannelIdError::TxIndexOverflow));
+		assert_eq!(scid_from_parts(0, 0, MAX_SCID_VOUT_INDEX + 1), Err(ShortChannelIdError::VoutIndexOverflow));
+	}
+
+	#[test]
+	fn test_parts_from_scid() {
+		let scid = scid_from_parts(640_000, 1234, 1).unwrap();
+		assert_eq!(block_from_scid(scid), 640_000);
+		assert_eq!(tx_index_from_scid(scid), 1234);
+		assert_eq!(vout_from_scid(scid), 1);
+
+		assert_eq!(block_from_scid(0xffffff_ffffff_ffff), 0xffffff);
+		assert_eq!(tx_index_from_scid(0xffffff_ffffff_ffff), 0xffffff);
+		assert_eq!(vout_from_scid(0xffffff_ffffff_ffff), 0xffff);
+	}
+
+	#[test]
+	fn test_short_channel_id() {
+		let scid = ShortChannelId::from_parts(640_000, 1234, 1).unwrap();
+		assert_eq!(scid, scid_from_parts(640_000, 1234, 1).unwrap());
+		assert_eq!((scid.block(), scid.tx_index(), scid.vout()), (640_000, 1234, 1));
+		assert_eq!(format!("{}", scid), "640000x1234x1");
+		assert_eq!(ShortChannelId::from_parts(MAX_SCID_BLOCK + 1, 0, 0), Err(ShortChannelIdError::BlockOverflow));
+
+		let encoded = scid.encode();
+		assert_eq!(encoded, scid.0.encode());
+		assert_eq!(<ShortChannelId as Readable>::read(&mut &encoded[..]).unwrap(), scid);
+		assert!(<ShortChannelId as Readable>::read(&mut &encoded[..7]).is_err());
+	}
+}